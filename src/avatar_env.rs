@@ -6,29 +6,36 @@
 
 use std::env;
 use std::path::{PathBuf, MAIN_SEPARATOR};
-use std::process::exit;
 
-pub(crate) const CONFIG_LOCK_PATH: &str = "AVATAR_CLI_CONFIG_LOCK_PATH";
-pub(crate) const CONFIG_PATH: &str = "AVATAR_CLI_CONFIG_PATH";
-pub(crate) const FORCE_PROJECT_PATH: &str = "AVATAR_CLI_FORCE_PROJECT_PATH";
-pub(crate) const MOUNT_TMP_PATHS: &str = "AVATAR_CLI_MOUNT_TMP_PATHS";
-pub(crate) const PROCESS_ID: &str = "AVATAR_CLI_PROCESS_ID";
-pub(crate) const PROJECT_PATH: &str = "AVATAR_CLI_PROJECT_PATH";
-pub(crate) const PROJECT_INTERNAL_ID: &str = "AVATAR_CLI_PROJECT_INTERNAL_ID";
-pub(crate) const SESSION_TOKEN: &str = "AVATAR_CLI_SESSION_TOKEN";
-pub(crate) const STATE_PATH: &str = "AVATAR_CLI_STATE_PATH";
+use crate::error::{AvatarError, AvatarResult};
 
-pub(crate) struct AvatarEnv {
+pub const CONFIG_LOCK_PATH: &str = "AVATAR_CLI_CONFIG_LOCK_PATH";
+pub const CONFIG_PATH: &str = "AVATAR_CLI_CONFIG_PATH";
+pub const CONTAINER_ENGINE: &str = "AVATAR_CLI_CONTAINER_ENGINE";
+pub const DRY_RUN: &str = "AVATAR_CLI_DRY_RUN";
+pub const FORCE_PROJECT_PATH: &str = "AVATAR_CLI_FORCE_PROJECT_PATH";
+pub const MAX_PARALLEL_PULLS: &str = "AVATAR_CLI_MAX_PARALLEL_PULLS";
+pub const MOUNT_TMP_PATHS: &str = "AVATAR_CLI_MOUNT_TMP_PATHS";
+pub const OFFLINE: &str = "AVATAR_CLI_OFFLINE";
+pub const PROCESS_ID: &str = "AVATAR_CLI_PROCESS_ID";
+pub const PROJECT_PATH: &str = "AVATAR_CLI_PROJECT_PATH";
+pub const PROJECT_INTERNAL_ID: &str = "AVATAR_CLI_PROJECT_INTERNAL_ID";
+pub const SESSION_TOKEN: &str = "AVATAR_CLI_SESSION_TOKEN";
+pub const STATE_PATH: &str = "AVATAR_CLI_STATE_PATH";
+
+const DEFAULT_MAX_PARALLEL_PULLS: usize = 4;
+
+pub struct AvatarEnv {
     project_path: PathBuf,
     session_token: String,
 }
 
 impl AvatarEnv {
-    pub fn read() -> Self {
-        Self {
-            project_path: PathBuf::from(Self::get_var(PROJECT_PATH)),
-            session_token: Self::get_var(SESSION_TOKEN),
-        }
+    pub fn read() -> AvatarResult<Self> {
+        Ok(Self {
+            project_path: PathBuf::from(Self::get_var(PROJECT_PATH)?),
+            session_token: Self::get_var(SESSION_TOKEN)?,
+        })
     }
 
     pub fn get_project_path(&self) -> &PathBuf {
@@ -39,30 +46,59 @@ impl AvatarEnv {
         &self.session_token
     }
 
-    fn get_var(var_name: &str) -> String {
-        match env::var(var_name) {
-            Ok(v) => v,
-            Err(_) => {
-                eprintln!("The '{}' environment variable is not defined", var_name);
-                exit(exitcode::CONFIG)
-            }
-        }
+    fn get_var(var_name: &str) -> AvatarResult<String> {
+        env::var(var_name).map_err(|_| {
+            AvatarError::Environment(format!(
+                "The '{}' environment variable is not defined",
+                var_name
+            ))
+        })
     }
 }
 
-pub(crate) fn get_used_program_name() -> String {
+pub fn get_used_program_name() -> AvatarResult<String> {
     let first_arg = match env::args().next() {
         Some(a) => a,
-        None => {
-            eprintln!("Due to an unknown reason, it was impossible to retrieve the command arguments list");
-            exit(exitcode::OSERR);
-        }
+        None => return Err(AvatarError::Os(
+            "Due to an unknown reason, it was impossible to retrieve the command arguments list"
+                .to_string(),
+        )),
     };
-    match first_arg.split(MAIN_SEPARATOR).last() {
-      Some(pname) => pname,
-      None => {
-          eprintln!("Due to an unknown reason, an empty first command argument was passed to this process");
-          exit(exitcode::OSERR)
-      }
-  }.to_string()
+    match first_arg.split(MAIN_SEPARATOR).next_back() {
+        Some(pname) => Ok(pname.to_string()),
+        None => Err(AvatarError::Os(
+            "Due to an unknown reason, an empty first command argument was passed to this process"
+                .to_string(),
+        )),
+    }
+}
+
+/// How many images can be pulled (or inspected) at the same time
+pub fn get_max_parallel_pulls() -> AvatarResult<usize> {
+    match env::var(MAX_PARALLEL_PULLS) {
+        Ok(v) => match v.parse::<usize>() {
+            Ok(max_parallel_pulls) if max_parallel_pulls > 0 => Ok(max_parallel_pulls),
+            _ => Err(AvatarError::Environment(format!(
+                "Invalid value '{}' for the '{}' environment variable, expected a positive integer",
+                v, MAX_PARALLEL_PULLS
+            ))),
+        },
+        Err(_) => Ok(DEFAULT_MAX_PARALLEL_PULLS),
+    }
+}
+
+/// In offline mode images are never pulled, missing images are reported as
+/// errors instead.
+pub fn is_offline_mode() -> AvatarResult<bool> {
+    match env::var(OFFLINE) {
+        Ok(v) => match v.to_lowercase().as_str() {
+            "" | "0" | "false" => Ok(false),
+            "1" | "true" => Ok(true),
+            _ => Err(AvatarError::Environment(format!(
+                "Invalid value '{}' for the '{}' environment variable, expected one of: 0, 1, false, true",
+                v, OFFLINE
+            ))),
+        },
+        Err(_) => Ok(false),
+    }
 }
@@ -0,0 +1,507 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+//! Minimal line-based editor for Avatarfiles. Deserializing & serializing the
+//! whole file through `serde_yaml` would throw away comments and reorder keys,
+//! so instead we only touch the lines of the block mappings we have to modify.
+//! It understands block-style mappings (the style used by `avatar init` and in
+//! our documentation); values written in flow style are treated as opaque,
+//! except for empty ones (`{}`, `~`, `null`), that can be expanded.
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+pub(crate) struct AvatarfileEditor {
+    lines: Vec<String>,
+    indent_unit: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Node {
+    key_line: usize,
+    indent: usize,
+    content_end: usize, // exclusive, points right after the node's last content line
+}
+
+impl AvatarfileEditor {
+    pub fn new(text: &str) -> AvatarfileEditor {
+        let lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+        let indent_unit = lines
+            .iter()
+            .filter(|l| is_content_line(l))
+            .map(|l| get_indent(l))
+            .find(|indent| *indent > 0)
+            .unwrap_or(2);
+
+        AvatarfileEditor { lines, indent_unit }
+    }
+
+    pub fn get_text(&self) -> String {
+        let mut text = self.lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    /// Inserts or replaces the `key: value` entry of the mapping found at
+    /// `path`, creating the intermediate mappings when needed.
+    pub fn set_entry(&mut self, path: &[&str], key: &str, value: &str) {
+        let parent = self
+            .ensure_mapping(path)
+            .map(|node| self.expand_empty_value(node));
+        let child_indent = match parent {
+            Some(p) => self.get_child_indent(p),
+            None => 0,
+        };
+        let entry_line = format!(
+            "{}{}: {}",
+            " ".repeat(child_indent),
+            quote_scalar(key),
+            value
+        );
+
+        match self.find_child(parent, key) {
+            Some(existing_node) => {
+                let existing_line = &self.lines[existing_node.key_line];
+                let entry_line = match (
+                    existing_node.content_end - existing_node.key_line,
+                    existing_line.find(" #"),
+                ) {
+                    (1, Some(comment_start)) => {
+                        let comment_start = existing_line[..comment_start].trim_end().len();
+                        format!("{}{}", entry_line, &existing_line[comment_start..])
+                    }
+                    _ => entry_line,
+                };
+
+                self.lines.splice(
+                    existing_node.key_line..existing_node.content_end,
+                    vec![entry_line],
+                );
+            }
+            None => {
+                let insert_at = self.get_insertion_point(parent);
+                self.lines.insert(insert_at, entry_line);
+            }
+        }
+    }
+
+    /// Ensures that the mapping found at `path` exists, creating it (and its
+    /// ancestors) as an empty mapping if needed.
+    pub fn ensure_entry(&mut self, path: &[&str]) {
+        self.ensure_mapping(path);
+    }
+
+    /// Removes the entry found at `path` (including its nested values and the
+    /// comments placed right above it), and returns whether it existed.
+    pub fn remove_entry(&mut self, path: &[&str]) -> bool {
+        match self.find_path(path) {
+            Some(node) => {
+                let mut start = node.key_line;
+                while start > 0 {
+                    let previous_line = &self.lines[start - 1];
+                    if !previous_line.trim_start().starts_with('#')
+                        || get_indent(previous_line) != node.indent
+                    {
+                        break;
+                    }
+                    start -= 1;
+                }
+
+                self.lines.drain(start..node.content_end);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the keys of the mapping found at `path`, in document order.
+    pub fn get_keys(&self, path: &[&str]) -> Vec<String> {
+        let parent = match path.is_empty() {
+            true => None,
+            false => match self.find_path(path) {
+                Some(node) => Some(node),
+                None => return vec![],
+            },
+        };
+
+        self.get_children(parent)
+            .iter()
+            .filter_map(|node| parse_key(&self.lines[node.key_line]).map(|(key, _)| key))
+            .collect()
+    }
+
+    /// Returns `None` for the empty path, that represents the document root
+    fn ensure_mapping(&mut self, path: &[&str]) -> Option<Node> {
+        let mut parent: Option<Node> = None;
+
+        for key in path {
+            if let Some(p) = parent {
+                self.expand_empty_value(p);
+            }
+
+            let node = match self.find_child(parent, key) {
+                Some(node) => node,
+                None => {
+                    let child_indent = match parent {
+                        Some(p) => self.get_child_indent(p),
+                        None => 0,
+                    };
+                    let insert_at = self.get_insertion_point(parent);
+                    self.lines.insert(
+                        insert_at,
+                        format!("{}{}: {{}}", " ".repeat(child_indent), quote_scalar(key)),
+                    );
+                    self.get_node(insert_at)
+                }
+            };
+
+            parent = Some(node);
+        }
+
+        parent
+    }
+
+    /// Transforms `key: {}`, `key: ~`, ... into `key:`, so we can append
+    /// children to it
+    fn expand_empty_value(&mut self, node: Node) -> Node {
+        let line = &self.lines[node.key_line];
+        if let Some((key, value)) = parse_key(line) {
+            if ["{}", "~", "null", "Null", "NULL"].contains(&value.as_str()) {
+                self.lines[node.key_line] =
+                    format!("{}{}:", " ".repeat(node.indent), quote_scalar(&key));
+            }
+        }
+        node
+    }
+
+    fn find_path(&self, path: &[&str]) -> Option<Node> {
+        let mut parent: Option<Node> = None;
+        for key in path {
+            parent = Some(self.find_child(parent, key)?);
+        }
+        parent
+    }
+
+    fn find_child(&self, parent: Option<Node>, key: &str) -> Option<Node> {
+        self.get_children(parent).into_iter().find(|node| {
+            parse_key(&self.lines[node.key_line])
+                .map(|(k, _)| k == key)
+                .unwrap_or(false)
+        })
+    }
+
+    fn get_children(&self, parent: Option<Node>) -> Vec<Node> {
+        let (start, end) = match parent {
+            Some(p) => (p.key_line + 1, p.content_end),
+            None => (0, self.lines.len()),
+        };
+
+        let children_indent = match (start..end)
+            .map(|i| &self.lines[i])
+            .find(|l| is_content_line(l))
+        {
+            Some(l) => get_indent(l),
+            None => return vec![],
+        };
+        if let Some(p) = parent {
+            if children_indent <= p.indent {
+                return vec![];
+            }
+        }
+
+        (start..end)
+            .filter(|i| {
+                let line = &self.lines[*i];
+                is_content_line(line)
+                    && get_indent(line) == children_indent
+                    && parse_key(line).is_some()
+            })
+            .map(|i| self.get_node(i))
+            .collect()
+    }
+
+    fn get_node(&self, key_line: usize) -> Node {
+        let indent = get_indent(&self.lines[key_line]);
+        let mut content_end = key_line + 1;
+
+        for i in (key_line + 1)..self.lines.len() {
+            let line = &self.lines[i];
+            if !is_content_line(line) {
+                continue;
+            }
+            if get_indent(line) <= indent || line.trim_start().starts_with("---") {
+                break;
+            }
+            content_end = i + 1;
+        }
+
+        Node {
+            key_line,
+            indent,
+            content_end,
+        }
+    }
+
+    fn get_child_indent(&self, parent: Node) -> usize {
+        match self.get_children(Some(parent)).first() {
+            Some(child) => child.indent,
+            None => parent.indent + self.indent_unit,
+        }
+    }
+
+    fn get_insertion_point(&self, parent: Option<Node>) -> usize {
+        match parent {
+            Some(p) => p.content_end,
+            None => {
+                // Right after the last content line of the document
+                match self.lines.iter().rposition(|l| is_content_line(l)) {
+                    Some(i) => i + 1,
+                    None => self.lines.len(),
+                }
+            }
+        }
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+fn get_indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+fn is_content_line(line: &str) -> bool {
+    let trimmed = line.trim();
+    !trimmed.is_empty() && !trimmed.starts_with('#')
+}
+
+/// Returns the (unquoted) key and the inline value (without comments) of a
+/// block mapping entry
+fn parse_key(line: &str) -> Option<(String, String)> {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('-') || trimmed.starts_with('#') {
+        return None;
+    }
+
+    let (key, rest) = match trimmed.chars().next()? {
+        '\'' | '"' => {
+            let (key, rest) = parse_quoted_scalar(trimmed)?;
+            (key, rest.trim_start().strip_prefix(':')?)
+        }
+        _ => {
+            let bytes = trimmed.as_bytes();
+            let colon = (0..bytes.len())
+                .find(|i| bytes[*i] == b':' && (*i + 1 == bytes.len() || bytes[*i + 1] == b' '))?;
+            (
+                trimmed[..colon].trim_end().to_string(),
+                &trimmed[colon + 1..],
+            )
+        }
+    };
+
+    let value = match rest.find(" #") {
+        Some(comment_start) => &rest[..comment_start],
+        None => rest,
+    };
+
+    Some((key, value.trim().to_string()))
+}
+
+/// Returns the unescaped contents of the quoted scalar found at the beginning
+/// of `text`, and the text that follows it. Single-quoted scalars only escape
+/// quotes (`''`), while double-quoted ones use backslash escapes.
+fn parse_quoted_scalar(text: &str) -> Option<(String, &str)> {
+    let quote = text.chars().next()?;
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1).peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            ('\'', '\'') => match chars.peek() {
+                Some((_, '\'')) => {
+                    value.push('\'');
+                    chars.next();
+                }
+                _ => return Some((value, &text[i + 1..])),
+            },
+            ('"', '"') => return Some((value, &text[i + 1..])),
+            ('"', '\\') => match chars.next()? {
+                (_, 'n') => value.push('\n'),
+                (_, 't') => value.push('\t'),
+                (_, escaped) => value.push(escaped),
+            },
+            _ => value.push(c),
+        }
+    }
+
+    None
+}
+
+/// Quotes the scalar only when it can't be safely represented as a plain
+/// YAML string
+pub(crate) fn quote_scalar(value: &str) -> String {
+    let is_plain_safe = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./@".contains(c))
+        && !value.starts_with('-')
+        && !value.starts_with('.')
+        && value.parse::<f64>().is_err()
+        && !["true", "false", "null"].contains(&value.to_lowercase().as_str());
+
+    match is_plain_safe {
+        true => value.to_string(),
+        false => format!("'{}'", value.replace('\'', "''")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AVATARFILE: &str = "\
+---
+# Project settings
+avatarVersion: 0.18.2
+projectInternalId: abc # generated by avatar init
+
+images:
+    node:
+        tags:
+            14-buster:
+                # Node tools
+                binaries:
+                    node: {}
+                    npm: {}
+";
+
+    #[test]
+    fn set_entry_inserts_nested_mappings_with_the_document_indentation() {
+        let mut editor = AvatarfileEditor::new(AVATARFILE);
+        editor.set_entry(
+            &["images", "node", "tags", "14-buster", "runConfig", "env"],
+            "NODE_ENV",
+            "production",
+        );
+
+        assert_eq!(
+            editor.get_text(),
+            format!(
+                "{}{}",
+                AVATARFILE,
+                "                runConfig:\n                    env:\n                        NODE_ENV: production\n"
+            )
+        );
+    }
+
+    #[test]
+    fn set_entry_expands_empty_flow_values() {
+        let mut editor = AvatarfileEditor::new(AVATARFILE);
+        editor.set_entry(
+            &["images", "node", "tags", "14-buster", "binaries", "npm"],
+            "extraArgs",
+            "~",
+        );
+
+        assert!(editor
+            .get_text()
+            .ends_with("                    npm:\n                        extraArgs: ~\n"));
+    }
+
+    #[test]
+    fn set_entry_replaces_values_keeping_inline_comments() {
+        let mut editor = AvatarfileEditor::new(AVATARFILE);
+        editor.set_entry(&[], "projectInternalId", "def");
+
+        assert!(editor
+            .get_text()
+            .contains("\nprojectInternalId: def # generated by avatar init\n"));
+    }
+
+    #[test]
+    fn set_entry_replaces_nested_values_as_a_whole() {
+        let mut editor = AvatarfileEditor::new(AVATARFILE);
+        editor.set_entry(&["images", "node", "tags"], "14-buster", "{}");
+
+        assert_eq!(
+            editor.get_text(),
+            "---\n# Project settings\navatarVersion: 0.18.2\nprojectInternalId: abc # generated by avatar init\n\nimages:\n    node:\n        tags:\n            14-buster: {}\n"
+        );
+    }
+
+    #[test]
+    fn set_entry_treats_non_empty_flow_values_as_opaque() {
+        let mut editor = AvatarfileEditor::new("env: {A: b, 'C': \"d\"}\n");
+        editor.set_entry(&[], "env", "{A: c}");
+
+        assert_eq!(editor.get_text(), "env: {A: c}\n");
+        assert!(editor.get_keys(&["env"]).is_empty());
+    }
+
+    #[test]
+    fn set_entry_quotes_keys_that_are_not_plain_safe() {
+        let mut editor = AvatarfileEditor::new("volumes: {}\n");
+        editor.set_entry(&["volumes"], "/home/user's dir", "{}");
+        editor.set_entry(&["volumes"], "/home/user's dir", "{ scope: Project }");
+
+        assert_eq!(
+            editor.get_text(),
+            "volumes:\n  '/home/user''s dir': { scope: Project }\n"
+        );
+    }
+
+    #[test]
+    fn remove_entry_removes_nested_values_and_leading_comments() {
+        let mut editor = AvatarfileEditor::new(AVATARFILE);
+
+        assert!(editor.remove_entry(&["images", "node", "tags", "14-buster", "binaries"]));
+        assert!(!editor.remove_entry(&["images", "python"]));
+        assert_eq!(
+            editor.get_text(),
+            "---\n# Project settings\navatarVersion: 0.18.2\nprojectInternalId: abc # generated by avatar init\n\nimages:\n    node:\n        tags:\n            14-buster:\n"
+        );
+    }
+
+    #[test]
+    fn get_keys_returns_unquoted_keys_in_document_order() {
+        let editor = AvatarfileEditor::new(
+            "env:\n  'it''s': 1 # comment\n  \"say \\\"hi\\\"\": 2\n  \"a''b\": 3\n  plain key: 4\n  - not a key\n",
+        );
+
+        assert_eq!(
+            editor.get_keys(&["env"]),
+            vec!["it's", "say \"hi\"", "a''b", "plain key"]
+        );
+        assert_eq!(editor.get_keys(&[]), vec!["env"]);
+        assert!(editor.get_keys(&["missing"]).is_empty());
+    }
+
+    #[test]
+    fn parse_key_separates_values_from_comments() {
+        assert_eq!(
+            parse_key("  url: http://example.com # docs"),
+            Some(("url".to_string(), "http://example.com".to_string()))
+        );
+        assert_eq!(
+            parse_key("'a: b':  c"),
+            Some(("a: b".to_string(), "c".to_string()))
+        );
+        assert_eq!(parse_key("'unterminated: c"), None);
+        assert_eq!(parse_key("# comment: c"), None);
+        assert_eq!(parse_key("no colon"), None);
+    }
+
+    #[test]
+    fn quote_scalar_only_quotes_when_needed() {
+        assert_eq!(quote_scalar("node-14_x/y@z.1"), "node-14_x/y@z.1");
+        assert_eq!(quote_scalar(""), "''");
+        assert_eq!(quote_scalar("1.5"), "'1.5'");
+        assert_eq!(quote_scalar("True"), "'True'");
+        assert_eq!(quote_scalar("-flag"), "'-flag'");
+        assert_eq!(quote_scalar(".hidden"), "'.hidden'");
+        assert_eq!(quote_scalar("it's a: test"), "'it''s a: test'");
+    }
+}
@@ -0,0 +1,15 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use super::ContainerEngine;
+
+pub struct Docker {}
+
+impl ContainerEngine for Docker {
+    fn get_program_name(&self) -> &'static str {
+        "docker"
+    }
+}
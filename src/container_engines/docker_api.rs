@@ -0,0 +1,853 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+use serde_json::{json, Value};
+
+use super::ContainerEngine;
+use crate::{
+    error::{AvatarError, AvatarResult},
+    project_config::split_image_ref,
+};
+
+// Constants:
+// -----------------------------------------------------------------------------
+const DEFAULT_DOCKER_SOCKET_PATH: &str = "/var/run/docker.sock";
+const TAR_BLOCK_SIZE: usize = 512;
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+/// Talks to the Docker Engine HTTP API through its unix socket, avoiding the
+/// need to spawn `docker` processes (and to export whole containers' filesystems
+/// just to read a couple of files). Running the managed tools still relies on
+/// the `docker` client, because it already deals with TTYs & signals for us.
+pub struct DockerApi {
+    socket_path: PathBuf,
+}
+
+impl DockerApi {
+    pub fn new() -> AvatarResult<DockerApi> {
+        Ok(DockerApi {
+            socket_path: get_socket_path()?,
+        })
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&Value>,
+    ) -> AvatarResult<HttpResponse> {
+        let mut stream = UnixStream::connect(&self.socket_path).map_err(|e| {
+            AvatarError::ContainerEngineUnavailable(format!(
+                "Unable to connect to the Docker Engine socket {}\n\n{}\n",
+                self.socket_path.display(),
+                e
+            ))
+        })?;
+
+        let body_bytes = match body {
+            Some(_body) => _body.to_string().into_bytes(),
+            None => vec![],
+        };
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: docker\r\nConnection: close\r\n",
+            method, path
+        );
+        if body.is_some() {
+            request.push_str(&format!(
+                "Content-Type: application/json\r\nContent-Length: {}\r\n",
+                body_bytes.len()
+            ));
+        }
+        request.push_str("\r\n");
+
+        if let Err(e) = stream
+            .write_all(request.as_bytes())
+            .and_then(|_| stream.write_all(&body_bytes))
+        {
+            return Err(AvatarError::Io(format!(
+                "Unable to send request to the Docker Engine ({} {})\n\n{}\n",
+                method, path, e
+            )));
+        }
+
+        HttpResponse::read_head(BufReader::new(stream), method == "HEAD").map_err(|e| {
+            AvatarError::ContainerEngineProtocol(format!(
+                "Unable to read response from the Docker Engine ({} {})\n\n{}\n",
+                method, path, e
+            ))
+        })
+    }
+
+    fn request_json(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&Value>,
+    ) -> AvatarResult<(u16, Value)> {
+        let mut response = self.request(method, path, body)?;
+        let status = response.status;
+        let body = response.read_body()?;
+
+        if body.is_empty() {
+            return Ok((status, Value::Null));
+        }
+
+        match serde_json::from_slice::<Value>(&body) {
+            Ok(value) => Ok((status, value)),
+            Err(e) => Err(AvatarError::ContainerEngineProtocol(format!(
+                "The Docker Engine returned an unexpected output ({} {})\n\n{}\n",
+                method, path, e
+            ))),
+        }
+    }
+
+    fn inspect_image(&self, image_ref: &str) -> AvatarResult<Option<Value>> {
+        match self.request_json("GET", &format!("/images/{}/json", image_ref), None)? {
+            (200, image_info) => Ok(Some(image_info)),
+            (404, _) => Ok(None),
+            (status, error) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to inspect OCI image {} (status {}):\n\n{}\n",
+                image_ref,
+                status,
+                get_error_message(&error)
+            ))),
+        }
+    }
+}
+
+impl ContainerEngine for DockerApi {
+    fn get_program_name(&self) -> &'static str {
+        "docker"
+    }
+
+    fn get_image_env_var(&self, image_ref: &str, var_name: &str) -> AvatarResult<Option<String>> {
+        let image_info = self.inspect_image(image_ref)?.ok_or_else(|| {
+            AvatarError::ContainerEngine(
+                "Docker Engine failed to return image env vars".to_string(),
+            )
+        })?;
+
+        let env_vars = match image_info["ContainerConfig"]["Env"]
+            .as_array()
+            .or_else(|| image_info["Config"]["Env"].as_array())
+        {
+            Some(env_vars) => env_vars,
+            None => return Ok(None),
+        };
+
+        Ok(super::get_var_from_env_list(
+            env_vars.iter().filter_map(|v| v.as_str()),
+            var_name,
+        ))
+    }
+
+    fn get_image_repo_digests(&self, image_ref: &str) -> AvatarResult<Option<Vec<String>>> {
+        let image_info = match self.inspect_image(image_ref)? {
+            Some(image_info) => image_info,
+            None => return Ok(None),
+        };
+
+        Ok(Some(match image_info["RepoDigests"].as_array() {
+            Some(repo_digests) => repo_digests
+                .iter()
+                .filter_map(|repo_digest| repo_digest.as_str())
+                .map(|repo_digest| repo_digest.to_string())
+                .collect(),
+            None => vec![],
+        }))
+    }
+
+    fn has_image(&self, image_ref: &str) -> AvatarResult<bool> {
+        Ok(self.inspect_image(image_ref)?.is_some())
+    }
+
+    fn pull_image(&self, image_ref: &str, show_output: bool) -> AvatarResult<()> {
+        let (image_name, image_tag) = split_image_ref(image_ref);
+        let image_tag = image_tag.unwrap_or("latest");
+        let mut response = self.request(
+            "POST",
+            &format!(
+                "/images/create?fromImage={}&tag={}",
+                percent_encode(image_name),
+                percent_encode(image_tag)
+            ),
+            None,
+        )?;
+
+        if response.status != 200 {
+            let error = serde_json::from_slice::<Value>(&response.read_body()?).unwrap_or_default();
+            return Err(AvatarError::ContainerEngineUnavailable(format!(
+                "Unable to pull OCI image {}\n\n{}\n",
+                image_ref,
+                get_error_message(&error)
+            )));
+        }
+
+        // The pull progress is streamed as a sequence of JSON objects, they're
+        // processed as soon as they arrive
+        for progress_line in BufReader::new(response).split(b'\n') {
+            let progress_line = progress_line.map_err(|e| {
+                AvatarError::Io(format!(
+                    "Unable to read response from the Docker Engine\n\n{}\n",
+                    e
+                ))
+            })?;
+            let progress = match serde_json::from_slice::<Value>(&progress_line) {
+                Ok(_progress) => _progress,
+                Err(_) => continue,
+            };
+
+            if progress.get("error").is_some() {
+                return Err(AvatarError::ContainerEngineUnavailable(format!(
+                    "Unable to pull OCI image {}\n\n{}\n",
+                    image_ref,
+                    get_error_message(&progress)
+                )));
+            }
+
+            if show_output {
+                if let Some(status) = progress["status"].as_str() {
+                    match progress["id"].as_str() {
+                        Some(id) => println!("{}: {}", id, status),
+                        None => println!("{}", status),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn has_volume(&self, volume_name: &str) -> AvatarResult<bool> {
+        match self.request_json("GET", &format!("/volumes/{}", volume_name), None)? {
+            (200, _) => Ok(true),
+            (404, _) => Ok(false),
+            (status, error) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to inspect volume {} (status {})\n\n{}\n",
+                volume_name,
+                status,
+                get_error_message(&error)
+            ))),
+        }
+    }
+
+    fn create_volume(&self, volume_name: &str, labels: &[&str]) -> AvatarResult<()> {
+        let body = json!({ "Name": volume_name, "Labels": get_labels_map(labels) });
+
+        match self.request_json("POST", "/volumes/create", Some(&body))? {
+            (201, _) => Ok(()),
+            (_, error) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to create volume {}\n\n{}\n",
+                volume_name,
+                get_error_message(&error)
+            ))),
+        }
+    }
+
+    fn has_network(&self, network_name: &str) -> AvatarResult<bool> {
+        match self.request_json("GET", &format!("/networks/{}", network_name), None)? {
+            (200, _) => Ok(true),
+            (404, _) => Ok(false),
+            (status, error) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to inspect network {} (status {})\n\n{}\n",
+                network_name,
+                status,
+                get_error_message(&error)
+            ))),
+        }
+    }
+
+    fn create_network(&self, network_name: &str, labels: &[&str]) -> AvatarResult<()> {
+        let body = json!({ "Name": network_name, "Labels": get_labels_map(labels) });
+
+        match self.request_json("POST", "/networks/create", Some(&body))? {
+            (201, _) => Ok(()),
+            (_, error) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to create network {}\n\n{}\n",
+                network_name,
+                get_error_message(&error)
+            ))),
+        }
+    }
+
+    fn create_container(
+        &self,
+        container_name: &str,
+        labels: &[&str],
+        image_ref: &str,
+    ) -> AvatarResult<()> {
+        let body = json!({ "Image": image_ref, "Labels": get_labels_map(labels) });
+
+        match self.request_json(
+            "POST",
+            &format!("/containers/create?name={}", percent_encode(container_name)),
+            Some(&body),
+        )? {
+            (201, _) => Ok(()),
+            (_, error) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to create temporary install container\n\n{}\n",
+                get_error_message(&error)
+            ))),
+        }
+    }
+
+    fn can_read_container_files(&self) -> bool {
+        true
+    }
+
+    fn find_container_files(
+        &self,
+        container_name: &str,
+        paths: &[&str],
+    ) -> AvatarResult<BTreeSet<String>> {
+        let mut found_paths = BTreeSet::<String>::new();
+
+        for path in paths {
+            match self
+                .request(
+                    "HEAD",
+                    &get_archive_endpoint_path(container_name, path),
+                    None,
+                )?
+                .status
+            {
+                200 => {
+                    found_paths.insert(path.to_string());
+                }
+                404 => {}
+                status => {
+                    return Err(AvatarError::ContainerEngine(format!(
+                        "Unable to check existence of {} in container {} (status {})",
+                        path, container_name, status
+                    )))
+                }
+            }
+        }
+
+        Ok(found_paths)
+    }
+
+    fn read_container_file(&self, container_name: &str, path: &str) -> AvatarResult<String> {
+        let mut response = self.request(
+            "GET",
+            &get_archive_endpoint_path(container_name, path),
+            None,
+        )?;
+        let archive = response.read_body()?;
+
+        if response.status != 200 {
+            return Err(AvatarError::ContainerEngine(format!(
+                "Unable to export {} file from container {} (status {})",
+                path, container_name, response.status
+            )));
+        }
+
+        match read_first_tar_entry(&archive) {
+            Some(contents) => Ok(String::from_utf8_lossy(contents).to_string()),
+            None => Err(AvatarError::ContainerEngineProtocol(format!(
+                "Unable to export {} file from container {}, received a malformed archive",
+                path, container_name
+            ))),
+        }
+    }
+
+    fn prune_containers(&self, labels: &[&str]) -> AvatarResult<()> {
+        let filters = json!({ "label": labels });
+
+        match self.request_json(
+            "POST",
+            &format!(
+                "/containers/prune?filters={}",
+                percent_encode(&filters.to_string())
+            ),
+            None,
+        )? {
+            (200, _) => Ok(()),
+            (_, error) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to prune containers generated during install step\n\n{}\n",
+                get_error_message(&error)
+            ))),
+        }
+    }
+}
+
+/// Response whose body is decoded on the fly (through its `Read`
+/// implementation), so streamed endpoints can be processed as they progress.
+struct HttpResponse {
+    status: u16,
+    reader: BufReader<UnixStream>,
+    is_chunked: bool,
+    pending_chunk_size: usize,
+    is_body_consumed: bool,
+}
+
+impl HttpResponse {
+    fn read_head(
+        mut reader: BufReader<UnixStream>,
+        without_body: bool,
+    ) -> std::io::Result<HttpResponse> {
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line)?;
+
+        let status = match status_line.split_whitespace().nth(1) {
+            Some(_status) => _status.parse::<u16>().unwrap_or(0),
+            None => 0,
+        };
+        if status == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("malformed status line: {}", status_line.trim()),
+            ));
+        }
+
+        let mut headers = BTreeMap::<String, String>::new();
+        loop {
+            let mut header_line = String::new();
+            if reader.read_line(&mut header_line)? == 0 || header_line.trim().is_empty() {
+                break;
+            }
+            let mut header_parts = header_line.splitn(2, ':');
+            if let (Some(name), Some(value)) = (header_parts.next(), header_parts.next()) {
+                headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+
+        Ok(HttpResponse {
+            status,
+            reader,
+            is_chunked: matches!(
+                headers.get("transfer-encoding"),
+                Some(encoding) if encoding == "chunked"
+            ),
+            pending_chunk_size: 0,
+            is_body_consumed: without_body,
+        })
+    }
+
+    fn read_body(&mut self) -> AvatarResult<Vec<u8>> {
+        let mut body = Vec::<u8>::new();
+
+        match self.read_to_end(&mut body) {
+            Ok(_) => Ok(body),
+            Err(e) => Err(AvatarError::Io(format!(
+                "Unable to read response from the Docker Engine\n\n{}\n",
+                e
+            ))),
+        }
+    }
+
+    /// Returns 0 once the last chunk is reached
+    fn read_chunk_size(&mut self) -> std::io::Result<usize> {
+        let mut size_line = String::new();
+        if self.reader.read_line(&mut size_line)? == 0 {
+            return Ok(0);
+        }
+
+        let size_str = size_line.trim().split(';').next().unwrap_or("");
+        usize::from_str_radix(size_str, 16).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("malformed chunk size: {}", size_line.trim()),
+            )
+        })
+    }
+}
+
+impl Read for HttpResponse {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.is_body_consumed || buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.is_chunked {
+            return self.reader.read(buf);
+        }
+
+        if self.pending_chunk_size == 0 {
+            self.pending_chunk_size = self.read_chunk_size()?;
+            if self.pending_chunk_size == 0 {
+                self.is_body_consumed = true;
+                return Ok(0);
+            }
+        }
+
+        let max_size = buf.len().min(self.pending_chunk_size);
+        let read_size = self.reader.read(&mut buf[..max_size])?;
+        if read_size == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "the response ended in the middle of a chunk",
+            ));
+        }
+
+        self.pending_chunk_size -= read_size;
+        if self.pending_chunk_size == 0 {
+            let mut chunk_end = [0u8; 2]; // CRLF
+            self.reader.read_exact(&mut chunk_end)?;
+        }
+
+        Ok(read_size)
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+fn get_archive_endpoint_path(container_name: &str, path: &str) -> String {
+    format!(
+        "/containers/{}/archive?path={}",
+        container_name,
+        percent_encode(&format!("/{}", path.trim_start_matches('/')))
+    )
+}
+
+fn get_error_message(error: &Value) -> String {
+    match error["message"]
+        .as_str()
+        .or_else(|| error["error"].as_str())
+    {
+        Some(message) => message.to_string(),
+        None => error.to_string(),
+    }
+}
+
+fn get_labels_map(labels: &[&str]) -> BTreeMap<String, String> {
+    labels
+        .iter()
+        .map(|label| (label.to_string(), "".to_string()))
+        .collect()
+}
+
+/// Honors the DOCKER_HOST environment variable, as long as it points to a unix
+/// socket.
+pub fn get_socket_path() -> AvatarResult<PathBuf> {
+    match env::var("DOCKER_HOST") {
+        Ok(docker_host) if !docker_host.is_empty() => match docker_host.strip_prefix("unix://") {
+            Some(socket_path) => Ok(PathBuf::from(socket_path)),
+            None => Err(AvatarError::Environment(format!(
+                "The docker-api container engine only supports unix sockets, but DOCKER_HOST is set to '{}'",
+                docker_host
+            ))),
+        },
+        _ => Ok(PathBuf::from(DEFAULT_DOCKER_SOCKET_PATH)),
+    }
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The archive endpoint returns a tarball, for single files we only care about
+/// the first regular entry (skipping extended headers).
+fn read_first_tar_entry(archive: &[u8]) -> Option<&[u8]> {
+    let mut offset = 0;
+
+    loop {
+        let header = archive.get(offset..offset + TAR_BLOCK_SIZE)?;
+        let size_field = std::str::from_utf8(&header[124..136]).ok()?;
+        let size =
+            usize::from_str_radix(size_field.trim_matches(|c| c == '\0' || c == ' '), 8).ok()?;
+        let contents_offset = offset + TAR_BLOCK_SIZE;
+
+        match header[156] {
+            b'x' | b'g' | b'L' | b'K' => {
+                let padded_size = size.div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE;
+                offset = contents_offset + padded_size;
+            }
+            _ => return archive.get(contents_offset..contents_offset + size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        os::unix::net::UnixListener,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc::{channel, Receiver},
+        },
+        thread::{spawn, JoinHandle},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::tar::TarWriter;
+
+    static SOCKET_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    /// Serves a single connection, answering with the response parts (sent
+    /// separately), and returns the received request head.
+    struct FakeServer {
+        socket_path: PathBuf,
+        handle: JoinHandle<String>,
+    }
+
+    impl FakeServer {
+        fn start(response_parts: Vec<Vec<u8>>, gate: Option<Receiver<()>>) -> FakeServer {
+            let socket_path = env::temp_dir().join(format!(
+                "avatar-cli-test-{}-{}.sock",
+                std::process::id(),
+                SOCKET_COUNTER.fetch_add(1, Ordering::SeqCst)
+            ));
+            let listener = UnixListener::bind(&socket_path).unwrap();
+
+            let handle = spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_head = String::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    request_head.push_str(&line);
+                }
+
+                let mut stream = stream;
+                for (part_index, part) in response_parts.iter().enumerate() {
+                    if part_index > 0 {
+                        if let Some(gate) = &gate {
+                            let _ = gate.recv_timeout(Duration::from_secs(10));
+                        }
+                    }
+                    // The client can hang up early (e.g. on pull errors)
+                    if stream.write_all(part).and_then(|_| stream.flush()).is_err() {
+                        break;
+                    }
+                }
+
+                request_head
+            });
+
+            FakeServer {
+                socket_path,
+                handle,
+            }
+        }
+
+        fn get_engine(&self) -> DockerApi {
+            DockerApi {
+                socket_path: self.socket_path.clone(),
+            }
+        }
+
+        fn finish(self) -> String {
+            let request_head = self.handle.join().unwrap();
+            let _ = std::fs::remove_file(&self.socket_path);
+            request_head
+        }
+    }
+
+    fn get_chunk(data: &str) -> Vec<u8> {
+        format!("{:x}\r\n{}\r\n", data.len(), data).into_bytes()
+    }
+
+    #[test]
+    fn request_json_reads_bodies_delimited_by_connection_close() {
+        let server = FakeServer::start(
+            vec![
+                b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{\"Name\":\"vol\"}"
+                    .to_vec(),
+            ],
+            None,
+        );
+
+        let (status, value) = server
+            .get_engine()
+            .request_json("GET", "/volumes/vol", None)
+            .unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(value["Name"], "vol");
+        assert!(server.finish().starts_with("GET /volumes/vol HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn request_json_decodes_chunked_bodies() {
+        let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        response.extend(get_chunk("{\"RepoDigests\":"));
+        response.extend(b"d;ext=1\r\n[\"node@sha256\r\n".to_vec());
+        response.extend(get_chunk(":abc\"]}"));
+        response.extend(b"0\r\n\r\n".to_vec());
+        let server = FakeServer::start(vec![response], None);
+
+        let repo_digests = server
+            .get_engine()
+            .get_image_repo_digests("node:14")
+            .unwrap();
+        assert_eq!(repo_digests, Some(vec!["node@sha256:abc".to_string()]));
+        server.finish();
+    }
+
+    #[test]
+    fn has_image_is_false_when_not_found() {
+        let server = FakeServer::start(
+            vec![b"HTTP/1.1 404 Not Found\r\n\r\n{\"message\":\"No such image\"}".to_vec()],
+            None,
+        );
+
+        assert!(!server.get_engine().has_image("node:14").unwrap());
+        assert!(server.finish().starts_with("GET /images/node:14/json "));
+    }
+
+    #[test]
+    fn request_fails_on_malformed_status_line() {
+        let server = FakeServer::start(vec![b"garbage\r\n\r\n".to_vec()], None);
+
+        assert!(matches!(
+            server.get_engine().has_volume("vol"),
+            Err(AvatarError::ContainerEngineProtocol(_))
+        ));
+        server.finish();
+    }
+
+    #[test]
+    fn pull_image_processes_progress_as_it_arrives() {
+        let (gate_sender, gate_receiver) = channel::<()>();
+        let mut head = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        head.extend(get_chunk("{\"status\":\"Pulling\",\"id\":\"14\"}\n"));
+        head.extend(get_chunk("{\"error\":\"manifest unknown\"}\n"));
+        let server = FakeServer::start(
+            vec![
+                head,
+                get_chunk("{\"status\":\"Done\"}\n"),
+                b"0\r\n\r\n".to_vec(),
+            ],
+            Some(gate_receiver),
+        );
+
+        // The server holds the rest of the stream until the pull returns, so
+        // a buffered implementation would only fail after the gate's timeout
+        let started_at = Instant::now();
+        let result = server
+            .get_engine()
+            .pull_image("localhost:5000/node:14", false);
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        drop(gate_sender);
+
+        match result {
+            Err(AvatarError::ContainerEngineUnavailable(message)) => {
+                assert!(message.contains("manifest unknown"))
+            }
+            _ => panic!("the pull should fail"),
+        }
+        assert!(server
+            .finish()
+            .starts_with("POST /images/create?fromImage=localhost%3A5000/node&tag=14 "));
+    }
+
+    #[test]
+    fn pull_image_succeeds_when_the_stream_has_no_errors() {
+        let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        response.extend(get_chunk("{\"status\":\"Pulling\"}\n{\"status\":"));
+        response.extend(get_chunk("\"Done\"}\n"));
+        response.extend(b"0\r\n\r\n".to_vec());
+        let server = FakeServer::start(vec![response], None);
+
+        assert!(server
+            .get_engine()
+            .pull_image("node@sha256:abc", false)
+            .is_ok());
+        assert!(server
+            .finish()
+            .starts_with("POST /images/create?fromImage=node&tag=sha256%3Aabc "));
+    }
+
+    #[test]
+    fn read_container_file_extracts_the_archived_file() {
+        let mut archive = TarWriter::new(Vec::<u8>::new());
+        archive
+            .append_file("passwd", 9, &mut "root:x:0\n".as_bytes())
+            .unwrap();
+        let archive = archive.finish().unwrap();
+
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+            archive.len()
+        )
+        .into_bytes();
+        response.extend(archive);
+        let server = FakeServer::start(vec![response], None);
+
+        let contents = server
+            .get_engine()
+            .read_container_file("helper", "etc/passwd")
+            .unwrap();
+        assert_eq!(contents, "root:x:0\n");
+        assert!(server
+            .finish()
+            .starts_with("GET /containers/helper/archive?path=/etc/passwd "));
+    }
+
+    #[test]
+    fn percent_encode_keeps_unreserved_characters_and_slashes() {
+        assert_eq!(
+            percent_encode("library/node-14_x.y~z"),
+            "library/node-14_x.y~z"
+        );
+        assert_eq!(
+            percent_encode("{\"label\":[\"a b\"]}"),
+            "%7B%22label%22%3A%5B%22a%20b%22%5D%7D"
+        );
+        assert_eq!(percent_encode("ñ"), "%C3%B1");
+    }
+
+    #[test]
+    fn read_first_tar_entry_skips_extended_headers() {
+        let mut pax_header = [0u8; TAR_BLOCK_SIZE];
+        pax_header[124..136].copy_from_slice(b"00000000020\0");
+        pax_header[156] = b'x';
+
+        let mut archive = pax_header.to_vec();
+        archive.extend_from_slice(b"16 path=passwd\n\0");
+        archive.extend(vec![0u8; TAR_BLOCK_SIZE - 16]);
+        let mut writer = TarWriter::new(Vec::<u8>::new());
+        writer
+            .append_file("passwd", 4, &mut "root".as_bytes())
+            .unwrap();
+        archive.extend(writer.finish().unwrap());
+
+        assert_eq!(read_first_tar_entry(&archive), Some(&b"root"[..]));
+        assert_eq!(read_first_tar_entry(&archive[..100]), None);
+    }
+
+    #[test]
+    fn read_body_keeps_reading_after_head_requests() {
+        let server = FakeServer::start(
+            vec![b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n".to_vec()],
+            None,
+        );
+        let mut response = server
+            .get_engine()
+            .request("HEAD", "/containers/helper/archive?path=/etc/passwd", None)
+            .unwrap();
+
+        assert_eq!(response.status, 200);
+        assert!(response.read_body().unwrap().is_empty());
+        let mut rest = vec![];
+        assert_eq!(response.read(&mut rest).unwrap(), 0);
+        server.finish();
+    }
+}
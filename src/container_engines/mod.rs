@@ -0,0 +1,848 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::from_utf8,
+};
+
+use duct::cmd;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    avatar_env::CONTAINER_ENGINE,
+    error::{AvatarError, AvatarResult},
+};
+
+pub mod docker;
+pub mod docker_api;
+pub mod podman;
+
+// Constants:
+// -----------------------------------------------------------------------------
+pub const VOLUME_PERMISSIONS_HELPER_IMAGE: &str = "alpine:3.12";
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerEngineKind {
+    Docker,
+    #[serde(rename = "docker-api")]
+    DockerApi,
+    Podman,
+}
+
+impl ContainerEngineKind {
+    fn default() -> Self {
+        ContainerEngineKind::Docker
+    }
+
+    fn from_name(engine_name: &str) -> Option<Self> {
+        match engine_name.to_lowercase().as_str() {
+            "docker" => Some(ContainerEngineKind::Docker),
+            "docker-api" => Some(ContainerEngineKind::DockerApi),
+            "podman" => Some(ContainerEngineKind::Podman),
+            _ => None,
+        }
+    }
+}
+
+/// Every interaction with the container engine goes through this trait. The
+/// default implementations rely on the Docker CLI interface, that is also
+/// implemented by other engines' clients (like Podman), so each backend only
+/// has to override the bits where its behaviour differs.
+pub trait ContainerEngine: Sync {
+    fn get_program_name(&self) -> &'static str;
+
+    /// Format passed to `inspect` to list the environment variables of an image
+    fn get_image_env_inspect_format(&self) -> &'static str {
+        "--format={{range .ContainerConfig.Env}}{{println .}}{{end}}"
+    }
+
+    /// Extra arguments passed to `run` to map the host user into the container
+    fn get_user_args(&self, uid: nix::unistd::Uid, gid: nix::unistd::Gid) -> Vec<String> {
+        vec!["--user".to_string(), format!("{}:{}", uid, gid)]
+    }
+
+    /// Whether the engine needs us to generate custom passwd files to expose
+    /// the host user inside the containers
+    fn needs_passwd_files(&self) -> bool {
+        true
+    }
+
+    /// Extra arguments passed to `run` for the helper containers that need to
+    /// act as root (as the one used to change volume permissions)
+    fn get_root_helper_args(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn check_client_availability(&self) -> AvatarResult<()> {
+        if which::which(self.get_program_name()).is_err() {
+            return Err(AvatarError::ContainerEngineUnavailable(format!(
+                "{} client is not available",
+                self.get_program_name()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that the client can talk to the engine's daemon (or service)
+    fn check_server_availability(&self) -> AvatarResult<()> {
+        match self.get_command().arg("info").output() {
+            Ok(output) => match output.status.success() {
+                true => Ok(()),
+                false => Err(AvatarError::ContainerEngineUnavailable(format!(
+                    "{} is not able to reach its server\n\n{}",
+                    self.get_program_name(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))),
+            },
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to run {}\n\n{}\n",
+                self.get_program_name(),
+                e
+            ))),
+        }
+    }
+
+    fn get_command(&self) -> Command {
+        Command::new(self.get_program_name())
+    }
+
+    fn get_image_env_var(&self, image_ref: &str, var_name: &str) -> AvatarResult<Option<String>> {
+        let output = self
+            .get_command()
+            .args(["inspect", self.get_image_env_inspect_format(), image_ref])
+            .output()
+            .map_err(|_| {
+                AvatarError::Os(format!(
+                    "unable to call {} inspect command",
+                    self.get_program_name()
+                ))
+            })?;
+
+        if !output.status.success() {
+            return Err(AvatarError::ContainerEngine(format!(
+                "{} inspect call failed to return image env vars",
+                self.get_program_name()
+            )));
+        }
+
+        match from_utf8(&output.stdout) {
+            Ok(stdout) => Ok(get_var_from_env_list(stdout.trim().lines(), var_name)),
+            Err(_) => Err(AvatarError::ContainerEngineProtocol(
+                get_inspect_output_error_msg(self.get_program_name()),
+            )),
+        }
+    }
+
+    /// Returns `None` when the image is not locally available
+    fn get_image_repo_digests(&self, image_ref: &str) -> AvatarResult<Option<Vec<String>>> {
+        let output = self
+            .get_command()
+            .args([
+                "inspect",
+                "--format={{range .RepoDigests}}{{println .}}{{end}}",
+                image_ref,
+            ])
+            .output()
+            .map_err(|e| {
+                AvatarError::Os(format!(
+                    "Unknow error while trying to inspect OCI image {}:\n\n{}\n",
+                    image_ref, e
+                ))
+            })?;
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        match from_utf8(&output.stdout) {
+            Ok(stdout) => Ok(Some(
+                stdout
+                    .trim()
+                    .lines()
+                    .map(|repo_digest| repo_digest.to_string())
+                    .collect(),
+            )),
+            Err(e) => Err(AvatarError::ContainerEngineProtocol(format!(
+                "{}.\n\n{}\n",
+                get_inspect_output_error_msg(self.get_program_name()),
+                e
+            ))),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn build_image(
+        &self,
+        context_path: &PathBuf,
+        dockerfile_path: &PathBuf,
+        build_args: &Option<BTreeMap<String, String>>,
+        target: &Option<String>,
+        image_refs: &[String],
+        labels: &[&str],
+        show_output: bool,
+    ) -> AvatarResult<()> {
+        let mut build_command = self.get_command();
+        build_command
+            .arg("build")
+            .arg("--file")
+            .arg(dockerfile_path);
+        for (arg_name, arg_value) in build_args.iter().flatten() {
+            build_command
+                .arg("--build-arg")
+                .arg(format!("{}={}", arg_name, arg_value));
+        }
+        if let Some(target) = target {
+            build_command.args(["--target", target]);
+        }
+        for image_ref in image_refs {
+            build_command.args(["--tag", image_ref]);
+        }
+        for label in labels {
+            build_command.args(["--label", label]);
+        }
+        build_command.arg(context_path);
+
+        let build_output = match show_output {
+            true => build_command.status().map(|status| (status, vec![])),
+            false => build_command
+                .output()
+                .map(|output| (output.status, output.stderr)),
+        };
+
+        match build_output {
+            Ok((status, _)) if status.success() => Ok(()),
+            Ok((_, stderr)) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to build the image {} from {}\n\n{}\n",
+                image_refs.join(", "),
+                dockerfile_path.display(),
+                String::from_utf8_lossy(&stderr).trim()
+            ))),
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to build the image {}\n\n{}\n",
+                image_refs.join(", "),
+                e
+            ))),
+        }
+    }
+
+    /// Returns the image ID (without the `sha256:` prefix)
+    fn get_image_id(&self, image_ref: &str) -> AvatarResult<Option<String>> {
+        let output = match self
+            .get_command()
+            .args(["inspect", "--format={{.Id}}", image_ref])
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) => {
+                return Err(AvatarError::Os(format!(
+                    "Unable to use {} to inspect image {}.\n\n{}\n",
+                    self.get_program_name(),
+                    image_ref,
+                    e
+                )))
+            }
+        };
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+        match from_utf8(&output.stdout) {
+            Ok(image_id) => Ok(Some(
+                image_id.trim().trim_start_matches("sha256:").to_string(),
+            )),
+            Err(_) => Err(AvatarError::ContainerEngineProtocol(
+                get_inspect_output_error_msg(self.get_program_name()),
+            )),
+        }
+    }
+
+    /// Returns the digests (without the `sha256:` prefix) of the locally
+    /// available images of a repository
+    fn find_image_digests(&self, image_name: &str) -> AvatarResult<Vec<String>> {
+        let mut digests: Vec<String> = self
+            .get_list_output(
+                &["images", "--digests", "--format", "{{.Digest}}", image_name],
+                "images",
+            )?
+            .into_iter()
+            .filter_map(|digest| digest.strip_prefix("sha256:").map(|d| d.to_string()))
+            .collect();
+        // The same digest is listed once per tag
+        digests.sort();
+        digests.dedup();
+        Ok(digests)
+    }
+
+    /// Returns the IDs (without the `sha256:` prefix) of the images having all
+    /// the passed labels
+    fn find_image_ids(&self, labels: &[&str]) -> AvatarResult<Vec<String>> {
+        let mut args = vec!["images", "--quiet", "--no-trunc"];
+        let filters = get_label_filters(labels);
+        args.extend(filters.iter().map(|filter| filter.as_str()));
+
+        let mut image_ids: Vec<String> = self
+            .get_list_output(&args, "images")?
+            .into_iter()
+            .map(|image_id| image_id.trim_start_matches("sha256:").to_string())
+            .collect();
+        image_ids.sort();
+        image_ids.dedup();
+        Ok(image_ids)
+    }
+
+    /// Returns the image size in bytes
+    fn get_image_size(&self, image_ref: &str) -> AvatarResult<Option<u64>> {
+        let output = match self
+            .get_command()
+            .args(["inspect", "--format={{.Size}}", image_ref])
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) => {
+                return Err(AvatarError::Os(format!(
+                    "Unable to use {} to inspect image {}.\n\n{}\n",
+                    self.get_program_name(),
+                    image_ref,
+                    e
+                )))
+            }
+        };
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+        match from_utf8(&output.stdout).map(|size| size.trim().parse::<u64>()) {
+            Ok(Ok(size)) => Ok(Some(size)),
+            _ => Err(AvatarError::ContainerEngineProtocol(
+                get_inspect_output_error_msg(self.get_program_name()),
+            )),
+        }
+    }
+
+    /// Runs a listing command, returning its non-empty output lines
+    fn get_list_output(&self, args: &[&str], listed_kind: &str) -> AvatarResult<Vec<String>> {
+        match self.get_command().args(args).output() {
+            Ok(output) => match (output.status.success(), from_utf8(&output.stdout)) {
+                (true, Ok(stdout)) => Ok(stdout
+                    .lines()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty())
+                    .map(|line| line.to_string())
+                    .collect()),
+                (true, Err(_)) => Err(AvatarError::ContainerEngineProtocol(format!(
+                    "The command `{} {}` returned an unexpected output",
+                    self.get_program_name(),
+                    args.join(" ")
+                ))),
+                (false, _) => Err(AvatarError::ContainerEngine(format!(
+                    "Unable to list {}\n\n{}",
+                    listed_kind,
+                    String::from_utf8_lossy(&output.stderr)
+                ))),
+            },
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to list {}\n\n{}\n",
+                listed_kind, e
+            ))),
+        }
+    }
+
+    fn has_image(&self, image_ref: &str) -> AvatarResult<bool> {
+        match self.get_command().args(["inspect", image_ref]).output() {
+            Ok(output) => Ok(output.status.success()),
+            Err(err) => Err(AvatarError::Os(format!(
+                "Unable to use {} to inspect image {}.\n\n{}\n",
+                self.get_program_name(),
+                image_ref,
+                err
+            ))),
+        }
+    }
+
+    fn pull_image(&self, image_ref: &str, show_output: bool) -> AvatarResult<()> {
+        let mut pull_command = self.get_command();
+        pull_command.args(["pull", image_ref]);
+
+        let pull_status = match show_output {
+            true => pull_command.status(),
+            false => pull_command.output().map(|output| output.status),
+        };
+
+        match pull_status {
+            Ok(status) => match status.success() {
+                true => Ok(()),
+                false => Err(AvatarError::ContainerEngineUnavailable(format!(
+                    "Unable to pull OCI image {}",
+                    image_ref
+                ))),
+            },
+            Err(err) => Err(AvatarError::Os(format!(
+                "Unable to pull OCI image {}.\n\n{}\n",
+                image_ref, err
+            ))),
+        }
+    }
+
+    /// Streams the image archive (as produced by `docker save`) to `reader_fn`
+    fn save_image(
+        &self,
+        image_ref: &str,
+        reader_fn: &mut dyn FnMut(&mut dyn Read) -> AvatarResult<()>,
+    ) -> AvatarResult<()> {
+        let mut child = self
+            .get_command()
+            .args(["save", image_ref])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                AvatarError::Os(format!("Unable to save image {}\n\n{}\n", image_ref, e))
+            })?;
+
+        // We can safely unwrap, stdout is piped
+        if let Err(e) = reader_fn(child.stdout.as_mut().unwrap()) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+
+        match child.wait_with_output() {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to save image {}\n\n{}\n",
+                image_ref,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to save image {}\n\n{}\n",
+                image_ref, e
+            ))),
+        }
+    }
+
+    /// Loads the image archive that `writer_fn` writes (in the `docker save`
+    /// format)
+    fn load_image(
+        &self,
+        writer_fn: &mut dyn FnMut(&mut dyn Write) -> AvatarResult<()>,
+    ) -> AvatarResult<()> {
+        let mut child = self
+            .get_command()
+            .args(["load", "--quiet"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| AvatarError::Os(format!("Unable to load image archive\n\n{}\n", e)))?;
+
+        // We can safely unwrap, stdin is piped. It has to be closed (dropped)
+        // before waiting, so the engine knows the archive is complete.
+        let mut stdin = child.stdin.take().unwrap();
+        if let Err(e) = writer_fn(&mut stdin) {
+            drop(stdin);
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+        drop(stdin);
+
+        match child.wait_with_output() {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to load image archive\n\n{}\n",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to load image archive\n\n{}\n",
+                e
+            ))),
+        }
+    }
+
+    fn has_volume(&self, volume_name: &str) -> AvatarResult<bool> {
+        match self
+            .get_command()
+            .args(["volume", "inspect", volume_name])
+            .output()
+        {
+            Ok(output) => Ok(output.status.success()),
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to inspect volume {}\n\n{}\n",
+                volume_name, e
+            ))),
+        }
+    }
+
+    fn create_volume(&self, volume_name: &str, labels: &[&str]) -> AvatarResult<()> {
+        match self
+            .get_command()
+            .args(["volume", "create", volume_name])
+            .args(get_label_args(labels))
+            .output()
+        {
+            Ok(output) => match output.status.success() {
+                true => Ok(()),
+                false => Err(AvatarError::ContainerEngine(format!(
+                    "Unable to create volume {}",
+                    volume_name
+                ))),
+            },
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to create volume {}\n\n{}\n",
+                volume_name, e
+            ))),
+        }
+    }
+
+    /// Returns the names of the volumes having all the passed labels
+    fn find_volumes(&self, labels: &[&str]) -> AvatarResult<Vec<String>> {
+        let mut args = vec!["volume", "ls", "--quiet"];
+        let filters = get_label_filters(labels);
+        args.extend(filters.iter().map(|filter| filter.as_str()));
+
+        self.get_list_output(&args, "volumes")
+    }
+
+    fn remove_volume(&self, volume_name: &str) -> AvatarResult<()> {
+        self.remove_resource(&["volume", "rm", volume_name], "volume", volume_name)
+    }
+
+    fn has_network(&self, network_name: &str) -> AvatarResult<bool> {
+        match self
+            .get_command()
+            .args(["network", "inspect", network_name])
+            .output()
+        {
+            Ok(output) => Ok(output.status.success()),
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to inspect network {}\n\n{}\n",
+                network_name, e
+            ))),
+        }
+    }
+
+    fn create_network(&self, network_name: &str, labels: &[&str]) -> AvatarResult<()> {
+        match self
+            .get_command()
+            .args(["network", "create", network_name])
+            .args(get_label_args(labels))
+            .output()
+        {
+            Ok(output) => match output.status.success() {
+                true => Ok(()),
+                false => Err(AvatarError::ContainerEngine(format!(
+                    "Unable to create network {}",
+                    network_name
+                ))),
+            },
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to create network {}\n\n{}\n",
+                network_name, e
+            ))),
+        }
+    }
+
+    fn change_volume_permissions(
+        &self,
+        volume_name: &str,
+        container_path: &Path,
+    ) -> AvatarResult<()> {
+        match self
+            .get_command()
+            .args(["run", "--rm"])
+            .args(self.get_root_helper_args())
+            .args([
+                "--volume",
+                &format!("{}:{}", volume_name, container_path.display()),
+                VOLUME_PERMISSIONS_HELPER_IMAGE,
+                "sh",
+                "-c",
+                &format!(
+                    "chown -R {}:{} {}",
+                    nix::unistd::getuid(),
+                    nix::unistd::getgid(),
+                    container_path.display()
+                ),
+            ])
+            .output()
+        {
+            Ok(output) => match output.status.success() {
+                true => Ok(()),
+                false => Err(AvatarError::ContainerEngine(format!(
+                    "Unable to change permissions for volume {}",
+                    volume_name
+                ))),
+            },
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to change permissions for volume {}\n\n{}\n",
+                volume_name, e
+            ))),
+        }
+    }
+
+    /// Whether the engine is able to list & read files from containers'
+    /// filesystems, in the default case through `export` and the `tar` tool
+    fn can_read_container_files(&self) -> bool {
+        which::which("tar").is_ok()
+    }
+
+    fn create_container(
+        &self,
+        container_name: &str,
+        labels: &[&str],
+        image_ref: &str,
+    ) -> AvatarResult<()> {
+        match self
+            .get_command()
+            .args(["create", "--name", container_name])
+            .args(get_label_args(labels))
+            .arg(image_ref)
+            .output()
+        {
+            Ok(output) => match output.status.success() {
+                true => Ok(()),
+                false => Err(AvatarError::ContainerEngine(format!(
+                    "Unable to create temporary install container\n\n{}",
+                    String::from_utf8_lossy(&output.stderr)
+                ))),
+            },
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to create temporary install container\n\n{}\n",
+                e
+            ))),
+        }
+    }
+
+    /// Returns the subset of `paths` (relative to the container's root) that
+    /// exist in the container's filesystem
+    fn find_container_files(
+        &self,
+        container_name: &str,
+        paths: &[&str],
+    ) -> AvatarResult<BTreeSet<String>> {
+        match cmd!(self.get_program_name(), "export", container_name)
+            .pipe(cmd!("tar", "t"))
+            .read()
+        {
+            Ok(output) => Ok(output
+                .lines()
+                .map(|file_name| file_name.trim())
+                .filter(|file_name| paths.contains(file_name))
+                .map(|file_name| file_name.to_string())
+                .collect()),
+            Err(e) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to list contents of container {}\n\n{}\n",
+                container_name, e
+            ))),
+        }
+    }
+
+    /// Reads a text file (path relative to the container's root) from the
+    /// container's filesystem
+    fn read_container_file(&self, container_name: &str, path: &str) -> AvatarResult<String> {
+        match cmd!(self.get_program_name(), "export", container_name)
+            .pipe(cmd!("tar", "--extract", "-O", path))
+            .read()
+        {
+            Ok(contents) => Ok(contents),
+            Err(e) => Err(AvatarError::ContainerEngine(format!(
+                "Unable to export {} file from container {}\n\n{}\n",
+                path, container_name, e
+            ))),
+        }
+    }
+
+    fn prune_containers(&self, labels: &[&str]) -> AvatarResult<()> {
+        let mut prune_command = self.get_command();
+        prune_command.args(["container", "prune", "--force"]);
+        for label in labels {
+            prune_command.args(["--filter", &format!("label={}", label)]);
+        }
+
+        match prune_command.output() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to prune containers generated during install step\n\n{}\n",
+                e
+            ))),
+        }
+    }
+
+    /// Starts a long running container in the background
+    fn run_detached_container(
+        &self,
+        container_name: &str,
+        labels: &[&str],
+        args: &[String],
+        image_ref: &str,
+    ) -> AvatarResult<()> {
+        match self
+            .get_command()
+            .args(["run", "--detach", "--name", container_name])
+            .args(get_label_args(labels))
+            .args(args)
+            .arg(image_ref)
+            .output()
+        {
+            Ok(output) => match output.status.success() {
+                true => Ok(()),
+                false => Err(AvatarError::ContainerEngine(format!(
+                    "Unable to start container {}\n\n{}",
+                    container_name,
+                    String::from_utf8_lossy(&output.stderr)
+                ))),
+            },
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to start container {}\n\n{}\n",
+                container_name, e
+            ))),
+        }
+    }
+
+    /// Returns the names of the containers having all the passed labels
+    fn find_containers(&self, labels: &[&str], include_stopped: bool) -> AvatarResult<Vec<String>> {
+        let mut args = vec!["ps", "--format", "{{.Names}}"];
+        if include_stopped {
+            args.push("--all");
+        }
+        let filters = get_label_filters(labels);
+        args.extend(filters.iter().map(|filter| filter.as_str()));
+
+        self.get_list_output(&args, "containers")
+    }
+
+    /// Images used by containers (even stopped ones) are not removed
+    fn remove_image(&self, image_ref: &str) -> AvatarResult<()> {
+        self.remove_resource(&["rmi", image_ref], "image", image_ref)
+    }
+
+    fn remove_resource(
+        &self,
+        args: &[&str],
+        resource_kind: &str,
+        resource_name: &str,
+    ) -> AvatarResult<()> {
+        match self.get_command().args(args).output() {
+            Ok(output) => match output.status.success() {
+                true => Ok(()),
+                false => Err(AvatarError::ContainerEngine(format!(
+                    "Unable to remove {} {}\n\n{}",
+                    resource_kind,
+                    resource_name,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))),
+            },
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to remove {} {}\n\n{}\n",
+                resource_kind, resource_name, e
+            ))),
+        }
+    }
+
+    /// Stops (if needed) and removes the containers
+    fn remove_containers(&self, container_names: &[String]) -> AvatarResult<()> {
+        if container_names.is_empty() {
+            return Ok(());
+        }
+
+        match self
+            .get_command()
+            .args(["rm", "--force"])
+            .args(container_names)
+            .output()
+        {
+            Ok(output) => match output.status.success() {
+                true => Ok(()),
+                false => Err(AvatarError::ContainerEngine(format!(
+                    "Unable to remove containers {}\n\n{}",
+                    container_names.join(", "),
+                    String::from_utf8_lossy(&output.stderr)
+                ))),
+            },
+            Err(e) => Err(AvatarError::Os(format!(
+                "Unable to remove containers {}\n\n{}\n",
+                container_names.join(", "),
+                e
+            ))),
+        }
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+/// The engine can be chosen through the AVATAR_CLI_CONTAINER_ENGINE environment
+/// variable, which takes precedence over the `containerEngine` Avatarfile
+/// setting. Docker is used when none of them is defined.
+pub fn get_container_engine(
+    configured_engine: &Option<ContainerEngineKind>,
+) -> AvatarResult<Box<dyn ContainerEngine>> {
+    let engine_kind = match env::var(CONTAINER_ENGINE) {
+        Ok(engine_name) => match ContainerEngineKind::from_name(&engine_name) {
+            Some(engine_kind) => engine_kind,
+            None => {
+                return Err(AvatarError::Environment(format!(
+                    "Unknown container engine '{}' set in {}, allowed values are 'docker', 'docker-api' and 'podman'",
+                    engine_name, CONTAINER_ENGINE
+                )))
+            }
+        },
+        Err(_) => configured_engine.unwrap_or_else(ContainerEngineKind::default),
+    };
+
+    Ok(match engine_kind {
+        ContainerEngineKind::Docker => Box::new(docker::Docker {}),
+        ContainerEngineKind::DockerApi => Box::new(docker_api::DockerApi::new()?),
+        ContainerEngineKind::Podman => Box::new(podman::Podman {}),
+    })
+}
+
+pub fn get_inspect_output_error_msg(program_name: &str) -> String {
+    format!(
+        "The command `{} inspect` returned an unexpected output",
+        program_name
+    )
+}
+
+fn get_label_filters(labels: &[&str]) -> Vec<String> {
+    labels
+        .iter()
+        .flat_map(|label| vec!["--filter".to_string(), format!("label={}", label)])
+        .collect()
+}
+
+fn get_label_args(labels: &[&str]) -> Vec<String> {
+    labels
+        .iter()
+        .flat_map(|label| vec!["--label".to_string(), label.to_string()])
+        .collect()
+}
+
+pub fn get_var_from_env_list<'a>(
+    var_defs: impl Iterator<Item = &'a str>,
+    var_name: &str,
+) -> Option<String> {
+    for var_def in var_defs {
+        let mut var_def_parts = var_def.splitn(2, '=');
+        if var_def_parts.next() != Some(var_name) {
+            continue;
+        }
+        if let Some(var_value) = var_def_parts.next() {
+            return Some(var_value.to_string());
+        }
+    }
+
+    None
+}
@@ -0,0 +1,43 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use super::ContainerEngine;
+
+pub struct Podman {}
+
+impl ContainerEngine for Podman {
+    fn get_program_name(&self) -> &'static str {
+        "podman"
+    }
+
+    fn get_image_env_inspect_format(&self) -> &'static str {
+        "--format={{range .Config.Env}}{{println .}}{{end}}"
+    }
+
+    // With `keep-id`, rootless Podman maps the host user to the same uid & gid
+    // inside the container, and adds it to the container's /etc/passwd file.
+    fn get_user_args(&self, uid: nix::unistd::Uid, gid: nix::unistd::Gid) -> Vec<String> {
+        vec![
+            "--userns=keep-id".to_string(),
+            "--user".to_string(),
+            format!("{}:{}", uid, gid),
+        ]
+    }
+
+    fn needs_passwd_files(&self) -> bool {
+        false
+    }
+
+    // The container's root has to be in the same user namespace as the host
+    // user, otherwise it won't be able to transfer files' ownership to it.
+    fn get_root_helper_args(&self) -> Vec<String> {
+        vec![
+            "--userns=keep-id".to_string(),
+            "--user".to_string(),
+            "0:0".to_string(),
+        ]
+    }
+}
@@ -4,57 +4,67 @@
  *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
  */
 
-use crate::avatar_env::FORCE_PROJECT_PATH;
+use crate::{
+    avatar_env::FORCE_PROJECT_PATH,
+    error::{AvatarError, AvatarResult},
+};
 use std::env;
-use std::path::PathBuf;
-use std::process::exit;
+use std::path::{Path, PathBuf};
 
-pub(crate) const AVATARFILE_NAME: &str = "Avatarfile";
-pub(crate) const AVATARFILE_LOCK_NAME: &str = "Avatarfile.lock";
-pub(crate) const CONFIG_DIR_NAME: &str = ".avatar-cli";
-pub(crate) const CONTAINER_HOME_PATH: &str = "/home/avatar-cli";
-pub(crate) const STATEFILE_NAME: &str = "state.yml";
-pub(crate) const VOLATILE_DIR_NAME: &str = "volatile";
+pub const AVATARFILE_NAME: &str = "Avatarfile";
+pub const AVATARFILE_LOCK_NAME: &str = "Avatarfile.lock";
+pub const CONFIG_DIR_NAME: &str = ".avatar-cli";
+pub const CONTAINER_HOME_PATH: &str = "/home/avatar-cli";
+pub const PINNED_IMAGES_FILE_NAME: &str = "pinned_images.yml";
+pub const STATEFILE_NAME: &str = "state.yml";
+pub const VOLATILE_DIR_NAME: &str = "volatile";
 
-pub(crate) fn check_if_inside_project_dir(project_path: &PathBuf, current_dir: &PathBuf) {
+pub fn check_if_inside_project_dir(project_path: &Path, current_dir: &Path) -> AvatarResult<()> {
     if env::var(FORCE_PROJECT_PATH).is_ok() {
-        return;
+        return Ok(());
     }
 
     if !is_inside_project_dir(project_path, current_dir) {
-        eprintln!(
+        return Err(AvatarError::Usage(format!(
             "The configured project directory is '{}', but you are in '{}'",
             project_path.display(),
             current_dir.display()
-        );
-        exit(exitcode::USAGE)
+        )));
     }
+
+    Ok(())
 }
 
-pub(crate) fn get_project_path() -> Option<PathBuf> {
+pub fn get_project_path() -> AvatarResult<Option<PathBuf>> {
     if let Ok(project_path) = env::var(FORCE_PROJECT_PATH) {
-        return Some(PathBuf::from(project_path));
+        return Ok(Some(PathBuf::from(project_path)));
     }
 
-    let current_dir = match env::current_dir() {
-        Ok(p) => p,
-        Err(_) => {
-            eprintln!("Unable to get current working directory");
-            exit(exitcode::NOINPUT)
-        }
-    };
+    let current_dir = env::current_dir().map_err(|_| {
+        AvatarError::MissingFile("Unable to get current working directory".to_string())
+    })?;
 
     for ancestor in current_dir.ancestors() {
         let config_path = ancestor.join(CONFIG_DIR_NAME).join(AVATARFILE_NAME);
         if config_path.exists() && config_path.is_file() {
-            return Some(ancestor.to_owned());
+            return Ok(Some(ancestor.to_owned()));
         }
     }
 
-    None
+    Ok(None)
+}
+
+/// Like `get_project_path`, but failing when the current directory is not part
+/// of an Avatar CLI project
+pub fn get_required_project_path() -> AvatarResult<PathBuf> {
+    get_project_path()?.ok_or_else(|| {
+        AvatarError::Usage(
+            "The command was not executed inside an Avatar CLI project directory".to_string(),
+        )
+    })
 }
 
-pub(crate) fn is_inside_project_dir(project_path: &PathBuf, current_dir: &PathBuf) -> bool {
+pub fn is_inside_project_dir(project_path: &Path, current_dir: &Path) -> bool {
     let mut in_project_dir = false;
     for ancestor in current_dir.ancestors() {
         if ancestor == project_path {
@@ -0,0 +1,81 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::fmt;
+
+/// Every variant carries the message that will be shown to the user. The exit
+/// codes are assigned by the `avatar` binary.
+#[derive(Debug)]
+pub enum AvatarError {
+    /// The Avatarfile, its lock file or the project state can't be parsed
+    ConfigParse(String),
+    /// Well formed, but semantically wrong project configuration
+    InvalidConfig(String),
+    /// The project files were written for another Avatar CLI version
+    IncompatibleVersion(String),
+    /// The project files are out of sync
+    HashMismatch(String),
+    /// Missing or wrong environment variables
+    Environment(String),
+    /// The command was used in the wrong context, or with wrong arguments
+    Usage(String),
+    /// A file or directory is not available
+    MissingFile(String),
+    /// A file or directory can't be created
+    CantCreate(String),
+    /// Errors while reading or writing files
+    Io(String),
+    /// Errors while spawning processes or calling the operating system
+    Os(String),
+    /// The container engine failed while performing an operation
+    ContainerEngine(String),
+    /// The container engine (or the registry behind it) is not available
+    ContainerEngineUnavailable(String),
+    /// The container engine returned something we are unable to interpret
+    ContainerEngineProtocol(String),
+    /// The project configuration does not follow its own policy
+    PolicyViolation(String),
+    /// Some locked image tags point to outdated digests (only reported as an
+    /// error when asked to)
+    OutdatedLock(String),
+    /// A host port needed to publish a container port is already taken
+    PortInUse(String),
+    /// A task step (or the project shell) failed, carrying the exit code of its
+    /// process
+    TaskFailed(String, i32),
+    /// Bugs & "impossible" situations
+    Internal(String),
+}
+
+pub type AvatarResult<T> = Result<T, AvatarError>;
+
+impl fmt::Display for AvatarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            AvatarError::ConfigParse(m)
+            | AvatarError::InvalidConfig(m)
+            | AvatarError::IncompatibleVersion(m)
+            | AvatarError::HashMismatch(m)
+            | AvatarError::Environment(m)
+            | AvatarError::Usage(m)
+            | AvatarError::MissingFile(m)
+            | AvatarError::CantCreate(m)
+            | AvatarError::Io(m)
+            | AvatarError::Os(m)
+            | AvatarError::ContainerEngine(m)
+            | AvatarError::ContainerEngineUnavailable(m)
+            | AvatarError::ContainerEngineProtocol(m)
+            | AvatarError::PolicyViolation(m)
+            | AvatarError::OutdatedLock(m)
+            | AvatarError::PortInUse(m)
+            | AvatarError::TaskFailed(m, _)
+            | AvatarError::Internal(m) => m,
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for AvatarError {}
@@ -0,0 +1,349 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use ring::digest::{Context, SHA256};
+use serde_json::Value;
+
+use crate::tar::{read_tar_entries, TarEntryKind};
+
+// Constants:
+// -----------------------------------------------------------------------------
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Only the small files (manifests, configs & indexes) are kept in memory, the
+/// other ones are just hashed
+const MAX_KEPT_FILE_SIZE: u64 = 4 * 1024 * 1024;
+const OCI_BLOBS_DIR: &str = "blobs/sha256/";
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+/// The digests of the files contained in an image archive (as produced by
+/// `docker save`), computed while streaming it.
+pub struct ImageArchive {
+    digests: BTreeMap<String, String>, // path -> sha256 hex digest
+    small_files: BTreeMap<String, Vec<u8>>,
+    symlinks: BTreeMap<String, String>, // path -> target path
+}
+
+/// What could be checked for an image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageVerification {
+    /// The locked manifest digest (or image ID, for built images), and the
+    /// layers it references
+    Manifest { layers: usize },
+    /// The archive does not contain the registry manifest (older engines), so
+    /// only the layers could be checked against the image config
+    Layers { layers: usize },
+}
+
+impl ImageArchive {
+    pub fn read(reader: &mut dyn Read) -> io::Result<ImageArchive> {
+        let mut archive = ImageArchive {
+            digests: BTreeMap::new(),
+            small_files: BTreeMap::new(),
+            symlinks: BTreeMap::new(),
+        };
+
+        read_tar_entries(reader, &mut |entry, entry_reader| {
+            let path = normalize_path(entry.get_path());
+            match entry.get_kind() {
+                TarEntryKind::File => {
+                    let (digest, content) = hash_content(entry_reader, entry.get_size())?;
+                    archive.digests.insert(path.clone(), digest);
+                    if let Some(content) = content {
+                        archive.small_files.insert(path, content);
+                    }
+                }
+                TarEntryKind::Symlink(link_path) => {
+                    let target = match Path::new(&path).parent() {
+                        Some(parent) => parent.join(link_path),
+                        None => PathBuf::from(link_path),
+                    };
+                    archive
+                        .symlinks
+                        .insert(path, normalize_path(&target.to_string_lossy()));
+                }
+                TarEntryKind::Other => {}
+            }
+            Ok(())
+        })?;
+
+        Ok(archive)
+    }
+
+    fn resolve_path(&self, path: &str) -> String {
+        let mut path = normalize_path(path);
+        // Bounded, to protect ourselves against symlink cycles
+        for _ in 0..16 {
+            match self.symlinks.get(&path) {
+                Some(target) => path = target.clone(),
+                None => break,
+            }
+        }
+        path
+    }
+
+    fn get_digest(&self, path: &str) -> Option<&String> {
+        self.digests.get(&self.resolve_path(path))
+    }
+
+    fn get_json(&self, path: &str) -> Result<Value, String> {
+        let content = match self.small_files.get(&self.resolve_path(path)) {
+            Some(content) => content,
+            None => return Err(format!("the file '{}' is missing or too big", path)),
+        };
+        serde_json::from_slice(content)
+            .map_err(|e| format!("the file '{}' is malformed: {}", path, e))
+    }
+
+    /// Checks the archive contents against the locked manifest digest, or
+    /// image ID for built images (`sha256:` prefix not included).
+    pub fn verify(&self, manifest_digest: &str) -> Result<ImageVerification, String> {
+        // Blobs are content-addressed, their names are their digests
+        for (path, digest) in &self.digests {
+            if let Some(blob_digest) = path.strip_prefix(OCI_BLOBS_DIR) {
+                if blob_digest != digest {
+                    return Err(format!("the blob {} hashes to {}", blob_digest, digest));
+                }
+            }
+        }
+
+        let manifest_path = format!("{}{}", OCI_BLOBS_DIR, manifest_digest);
+        if self.get_digest(&manifest_path).is_some()
+            && self.get_json(&manifest_path)?.get("rootfs").is_none()
+        {
+            return Ok(ImageVerification::Manifest {
+                layers: self.verify_oci_manifest(&manifest_path)?,
+            });
+        }
+
+        // Built images are locked by their image ID, which is the digest of
+        // their config
+        let (config_digest, layers) = self.verify_legacy_manifest()?;
+        match config_digest == manifest_digest {
+            true => Ok(ImageVerification::Manifest { layers }),
+            false => Ok(ImageVerification::Layers { layers }),
+        }
+    }
+
+    /// Returns the number of verified layers
+    fn verify_oci_manifest(&self, manifest_path: &str) -> Result<usize, String> {
+        let manifest = self.get_json(manifest_path)?;
+
+        // Image indexes (multi-platform images) only include the manifests
+        // of the platforms that were pulled
+        if let Some(manifests) = manifest["manifests"].as_array() {
+            let mut layers = 0;
+            let mut found_manifest = false;
+            for digest in manifests.iter().filter_map(|m| m["digest"].as_str()) {
+                let path = get_blob_path(digest)?;
+                if self.get_digest(&path).is_some() {
+                    found_manifest = true;
+                    layers += self.verify_oci_manifest(&path)?;
+                }
+            }
+
+            return match found_manifest {
+                true => Ok(layers),
+                false => {
+                    Err("none of the manifests listed in the image index is available".to_string())
+                }
+            };
+        }
+
+        let config_digest = match manifest["config"]["digest"].as_str() {
+            Some(digest) => digest,
+            None => return Err("the image manifest has no config".to_string()),
+        };
+        if self.get_digest(&get_blob_path(config_digest)?).is_none() {
+            return Err(format!("the image config {} is missing", config_digest));
+        }
+
+        let layers = manifest["layers"].as_array().cloned().unwrap_or_default();
+        for layer_digest in layers.iter().filter_map(|l| l["digest"].as_str()) {
+            if self.get_digest(&get_blob_path(layer_digest)?).is_none() {
+                return Err(format!("the layer {} is missing", layer_digest));
+            }
+        }
+
+        Ok(layers.len())
+    }
+
+    /// Returns the image config digest, and the number of verified layers
+    fn verify_legacy_manifest(&self) -> Result<(String, usize), String> {
+        let manifest = self.get_json("manifest.json")?;
+        let image_manifest = match manifest.as_array().and_then(|m| m.first()) {
+            Some(image_manifest) => image_manifest,
+            None => return Err("the archive does not contain any image".to_string()),
+        };
+
+        let config_path = match image_manifest["Config"].as_str() {
+            Some(path) => path,
+            None => return Err("the archive manifest has no config".to_string()),
+        };
+        let config_digest = match self.get_digest(config_path) {
+            Some(digest) => digest,
+            None => return Err(format!("the image config '{}' is missing", config_path)),
+        };
+        // The config file is named after its digest (the image ID)
+        if !config_path.contains(config_digest.as_str()) {
+            return Err(format!(
+                "the image config '{}' hashes to {}",
+                config_path, config_digest
+            ));
+        }
+
+        let config = self.get_json(config_path)?;
+        let diff_ids: Vec<&str> = config["rootfs"]["diff_ids"]
+            .as_array()
+            .map(|ids| ids.iter().filter_map(|id| id.as_str()).collect())
+            .unwrap_or_default();
+        let layer_paths: Vec<&str> = image_manifest["Layers"]
+            .as_array()
+            .map(|paths| paths.iter().filter_map(|p| p.as_str()).collect())
+            .unwrap_or_default();
+
+        if diff_ids.len() != layer_paths.len() {
+            return Err(format!(
+                "the image config lists {} layers, but the archive contains {}",
+                diff_ids.len(),
+                layer_paths.len()
+            ));
+        }
+
+        for (diff_id, layer_path) in diff_ids.iter().zip(layer_paths.iter()) {
+            let expected_digest = diff_id.trim_start_matches("sha256:");
+            match self.get_digest(layer_path) {
+                Some(digest) if digest == expected_digest => {}
+                Some(digest) => return Err(format!("the layer {} hashes to {}", diff_id, digest)),
+                None => return Err(format!("the layer {} is missing", diff_id)),
+            }
+        }
+
+        Ok((config_digest.clone(), layer_paths.len()))
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+fn get_blob_path(digest: &str) -> Result<String, String> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex_digest) => Ok(format!("{}{}", OCI_BLOBS_DIR, hex_digest)),
+        None => Err(format!("unsupported digest algorithm in '{}'", digest)),
+    }
+}
+
+/// Returns the hex digest of the content, and the content itself (if small enough)
+fn hash_content(reader: &mut dyn Read, size: u64) -> io::Result<(String, Option<Vec<u8>>)> {
+    let mut context = Context::new(&SHA256);
+    let mut content = match size <= MAX_KEPT_FILE_SIZE {
+        true => Some(Vec::with_capacity(size as usize)),
+        false => None,
+    };
+
+    let mut remaining = size;
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    while remaining > 0 {
+        let chunk_size = remaining.min(CHUNK_SIZE as u64) as usize;
+        reader.read_exact(&mut chunk[..chunk_size])?;
+        context.update(&chunk[..chunk_size]);
+        if let Some(content) = &mut content {
+            content.extend_from_slice(&chunk[..chunk_size]);
+        }
+        remaining -= chunk_size as u64;
+    }
+
+    Ok((hex::encode(context.finish().as_ref()), content))
+}
+
+fn normalize_path(path: &str) -> String {
+    let mut components: Vec<String> = vec![];
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(c) => components.push(c.to_string_lossy().to_string()),
+            Component::ParentDir => {
+                components.pop();
+            }
+            _ => {}
+        }
+    }
+    components.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tar::TarWriter;
+
+    fn get_hex_digest(content: &[u8]) -> String {
+        hex::encode(ring::digest::digest(&SHA256, content).as_ref())
+    }
+
+    /// Archives an image the way the classic Docker store saves it, returning
+    /// the archive & the image ID
+    fn new_legacy_archive(layer: &[u8], stored_layer: &[u8]) -> (Vec<u8>, String) {
+        let config = format!(
+            r#"{{"rootfs":{{"type":"layers","diff_ids":["sha256:{}"]}}}}"#,
+            get_hex_digest(layer)
+        );
+        let image_id = get_hex_digest(config.as_bytes());
+        let config_path = format!("{}.json", image_id);
+        let manifest = format!(
+            r#"[{{"Config":"{}","Layers":["layer/layer.tar"]}}]"#,
+            config_path
+        );
+
+        let mut tar_writer = TarWriter::new(Vec::new());
+        for (path, content) in &[
+            (config_path.as_str(), config.as_bytes()),
+            ("layer/layer.tar", stored_layer),
+            ("manifest.json", manifest.as_bytes()),
+        ] {
+            tar_writer
+                .append_file(path, content.len() as u64, &mut &content[..])
+                .unwrap();
+        }
+
+        (tar_writer.finish().unwrap(), image_id)
+    }
+
+    #[test]
+    fn verify_ties_legacy_archives_to_their_image_id() {
+        let (archive, image_id) = new_legacy_archive(b"layer", b"layer");
+        let archive = ImageArchive::read(&mut &archive[..]).unwrap();
+
+        assert_eq!(
+            archive.verify(&image_id),
+            Ok(ImageVerification::Manifest { layers: 1 })
+        );
+    }
+
+    #[test]
+    fn verify_only_checks_the_layers_of_legacy_archives_for_registry_digests() {
+        let (archive, _) = new_legacy_archive(b"layer", b"layer");
+        let archive = ImageArchive::read(&mut &archive[..]).unwrap();
+
+        assert_eq!(
+            archive.verify(&get_hex_digest(b"registry manifest")),
+            Ok(ImageVerification::Layers { layers: 1 })
+        );
+    }
+
+    #[test]
+    fn verify_detects_altered_layers() {
+        let (archive, image_id) = new_legacy_archive(b"layer", b"altered");
+        let archive = ImageArchive::read(&mut &archive[..]).unwrap();
+
+        assert!(archive.verify(&image_id).is_err());
+    }
+}
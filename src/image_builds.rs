@@ -0,0 +1,532 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+//! Fingerprinting of the project images' build inputs, so they're only rebuilt
+//! when something that could affect the result changes.
+
+use std::{
+    collections::BTreeMap,
+    fs::{read, read_dir, read_link, read_to_string, symlink_metadata, File},
+    io::Read,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use ring::digest::{Context, SHA256};
+
+use crate::{
+    directories::{CONFIG_DIR_NAME, VOLATILE_DIR_NAME},
+    error::{AvatarError, AvatarResult},
+    project_config::ImageBuildConfig,
+};
+
+// Constants:
+// -----------------------------------------------------------------------------
+const DOCKERIGNORE_NAME: &str = ".dockerignore";
+const GIT_DIR_NAME: &str = ".git";
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+/// Expands `$NAME`, `${NAME}` & `${NAME:-default}`, returns `None` when a
+/// variable is not defined
+fn expand_args(text: &str, args: &BTreeMap<String, String>) -> Option<String> {
+    let mut expanded_text = String::new();
+    let mut rest = text;
+
+    while let Some(var_start) = rest.find('$') {
+        expanded_text.push_str(&rest[..var_start]);
+        rest = &rest[var_start + 1..];
+
+        let (var_expression, var_end) = match rest.strip_prefix('{') {
+            Some(braced_rest) => {
+                let expression_end = braced_rest.find('}')?;
+                (&braced_rest[..expression_end], expression_end + 2)
+            }
+            None => {
+                let name_end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                (&rest[..name_end], name_end)
+            }
+        };
+        let (var_name, default_value) = match var_expression.split_once(":-") {
+            Some((var_name, default_value)) => (var_name, Some(default_value)),
+            None => (var_expression, None),
+        };
+
+        match (args.get(var_name), default_value) {
+            (Some(value), None) => expanded_text.push_str(value),
+            (Some(value), Some(_)) if !value.is_empty() => expanded_text.push_str(value),
+            (_, default_value) => expanded_text.push_str(default_value?),
+        }
+        rest = &rest[var_end..];
+    }
+
+    expanded_text.push_str(rest);
+    Some(expanded_text)
+}
+
+/// Returns the images a Dockerfile is built from (its `FROM` & `COPY --from`
+/// references, skipping the build stages & `scratch`), with the global `ARG`
+/// values (or the given build arguments) expanded. References depending on
+/// undefined arguments are returned as errors.
+pub fn get_base_image_refs(
+    dockerfile: &str,
+    build_args: &Option<BTreeMap<String, String>>,
+) -> Vec<Result<String, String>> {
+    let mut global_args: BTreeMap<String, String> = BTreeMap::new();
+    let mut is_before_first_stage = true;
+    let mut stage_names: Vec<String> = vec![];
+    let mut base_image_refs: Vec<Result<String, String>> = vec![];
+
+    for instruction in get_dockerfile_instructions(dockerfile) {
+        let mut words = instruction.split_whitespace();
+        let keyword = words.next().unwrap_or_default().to_uppercase();
+        let words: Vec<&str> = words.collect();
+        let mut new_stage_name: Option<String> = None;
+
+        let raw_image_ref = match keyword.as_str() {
+            // Only the arguments declared before the first stage can be used
+            // in the `FROM` instructions
+            "ARG" if is_before_first_stage => {
+                for declaration in words {
+                    let (arg_name, default_value) = match declaration.split_once('=') {
+                        Some((arg_name, default_value)) => {
+                            (arg_name, Some(default_value.trim_matches('"')))
+                        }
+                        None => (declaration, None),
+                    };
+                    let value = build_args
+                        .as_ref()
+                        .and_then(|build_args| build_args.get(arg_name))
+                        .map(|value| value.as_str())
+                        .or(default_value);
+                    if let Some(value) = value {
+                        global_args.insert(arg_name.to_string(), value.to_string());
+                    }
+                }
+                continue;
+            }
+            "FROM" => {
+                is_before_first_stage = false;
+                let mut words = words.iter().filter(|word| !word.starts_with("--"));
+                let raw_image_ref = words.next();
+                if let (Some(as_keyword), Some(stage_name)) = (words.next(), words.next()) {
+                    if as_keyword.eq_ignore_ascii_case("AS") {
+                        new_stage_name = Some(stage_name.to_lowercase());
+                    }
+                }
+                match raw_image_ref {
+                    Some(raw_image_ref) => *raw_image_ref,
+                    None => continue,
+                }
+            }
+            "COPY" => match words.iter().find_map(|word| word.strip_prefix("--from=")) {
+                Some(raw_image_ref) => raw_image_ref,
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        match expand_args(raw_image_ref, &global_args) {
+            Some(image_ref) => {
+                // Stages can be referenced by their names, or by their indexes
+                let is_stage_ref = stage_names.contains(&image_ref.to_lowercase())
+                    || image_ref.chars().all(|c| c.is_ascii_digit());
+                if !is_stage_ref && image_ref != "scratch" {
+                    base_image_refs.push(Ok(image_ref));
+                }
+            }
+            None => base_image_refs.push(Err(raw_image_ref.to_string())),
+        }
+        stage_names.extend(new_stage_name);
+    }
+
+    base_image_refs
+}
+
+/// Returns the absolute paths of the build context & the Dockerfile
+pub fn get_build_paths(
+    project_path: &Path,
+    build_config: &ImageBuildConfig,
+) -> AvatarResult<(PathBuf, PathBuf)> {
+    let context_path = project_path.join(build_config.get_context());
+    if !context_path.is_dir() {
+        return Err(AvatarError::MissingFile(format!(
+            "The build context {} is not a directory",
+            context_path.display()
+        )));
+    }
+
+    let dockerfile_path = context_path.join(build_config.get_dockerfile());
+    if !dockerfile_path.is_file() {
+        return Err(AvatarError::MissingFile(format!(
+            "The Dockerfile {} is not available",
+            dockerfile_path.display()
+        )));
+    }
+
+    Ok((context_path, dockerfile_path))
+}
+
+/// Hashes the build context (honouring its `.dockerignore` file), the
+/// Dockerfile, and the build arguments & target.
+pub fn get_build_hash(
+    project_path: &Path,
+    build_config: &ImageBuildConfig,
+) -> AvatarResult<String> {
+    let (context_path, dockerfile_path) = get_build_paths(project_path, build_config)?;
+    let ignore_patterns = get_dockerignore_patterns(&context_path)?;
+
+    let mut context = Context::new(&SHA256);
+    for (arg_name, arg_value) in build_config.get_args().iter().flatten() {
+        update_with_field(&mut context, b"arg", arg_name.as_bytes());
+        update_with_field(&mut context, b"value", arg_value.as_bytes());
+    }
+    if let Some(target) = build_config.get_target() {
+        update_with_field(&mut context, b"target", target.as_bytes());
+    }
+    update_with_field(
+        &mut context,
+        b"dockerfile",
+        &read(&dockerfile_path).map_err(|e| get_read_error(&dockerfile_path, e))?,
+    );
+
+    hash_directory(&mut context, &context_path, "", &ignore_patterns)?;
+
+    Ok(hex::encode(context.finish().as_ref()))
+}
+
+/// Joins the lines continued with `\`, and skips the comments
+fn get_dockerfile_instructions(dockerfile: &str) -> Vec<String> {
+    let mut instructions: Vec<String> = vec![];
+    let mut current_instruction = String::new();
+
+    for line in dockerfile.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+
+        match line.strip_suffix('\\') {
+            Some(continued_line) => {
+                current_instruction.push_str(continued_line);
+                current_instruction.push(' ');
+            }
+            None => {
+                current_instruction.push_str(line);
+                instructions.push(current_instruction.trim().to_string());
+                current_instruction = String::new();
+            }
+        }
+    }
+    if !current_instruction.trim().is_empty() {
+        instructions.push(current_instruction.trim().to_string());
+    }
+
+    instructions
+}
+
+/// Exclusion exceptions (`!pattern`) are not supported: when present, the
+/// whole `.dockerignore` file is disregarded. Hashing more files than the ones
+/// sent to the engine only causes extra rebuilds, never missed ones.
+fn get_dockerignore_patterns(context_path: &Path) -> AvatarResult<Vec<String>> {
+    let dockerignore_path = context_path.join(DOCKERIGNORE_NAME);
+    if !dockerignore_path.is_file() {
+        return Ok(vec![]);
+    }
+
+    let content =
+        read_to_string(&dockerignore_path).map_err(|e| get_read_error(&dockerignore_path, e))?;
+    let patterns: Vec<String> = content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.trim_matches('/').to_string())
+        .collect();
+
+    match patterns.iter().any(|pattern| pattern.starts_with('!')) {
+        true => Ok(vec![]),
+        false => Ok(patterns),
+    }
+}
+
+fn get_read_error(path: &Path, e: std::io::Error) -> AvatarError {
+    AvatarError::Io(format!("Unable to read {}\n\n{}\n", path.display(), e))
+}
+
+/// The `.git` directories and the Avatar CLI volatile state are never hashed,
+/// even without `.dockerignore` file, as they change all the time.
+fn hash_directory(
+    context: &mut Context,
+    dir_path: &Path,
+    relative_dir_path: &str,
+    ignore_patterns: &[String],
+) -> AvatarResult<()> {
+    let mut entries: Vec<(String, PathBuf)> = read_dir(dir_path)
+        .map_err(|e| get_read_error(dir_path, e))?
+        .map(|entry| {
+            entry
+                .map(|e| (e.file_name().to_string_lossy().to_string(), e.path()))
+                .map_err(|e| get_read_error(dir_path, e))
+        })
+        .collect::<AvatarResult<_>>()?;
+    // The hash must not depend on the order in which the filesystem lists them
+    entries.sort();
+    let is_config_dir = dir_path
+        .file_name()
+        .is_some_and(|name| name == CONFIG_DIR_NAME);
+
+    for (entry_name, entry_path) in entries {
+        if entry_name == GIT_DIR_NAME || (is_config_dir && entry_name == VOLATILE_DIR_NAME) {
+            continue;
+        }
+        let relative_path = match relative_dir_path.is_empty() {
+            true => entry_name,
+            false => format!("{}/{}", relative_dir_path, entry_name),
+        };
+        if ignore_patterns
+            .iter()
+            .any(|pattern| matches_pattern(pattern, &relative_path))
+        {
+            continue;
+        }
+
+        let metadata = symlink_metadata(&entry_path).map_err(|e| get_read_error(&entry_path, e))?;
+        let mode = format!("{:o}", metadata.permissions().mode() & 0o7777);
+        update_with_field(context, b"path", relative_path.as_bytes());
+        update_with_field(context, b"mode", mode.as_bytes());
+
+        if metadata.file_type().is_symlink() {
+            let target = read_link(&entry_path).map_err(|e| get_read_error(&entry_path, e))?;
+            update_with_field(context, b"symlink", target.to_string_lossy().as_bytes());
+        } else if metadata.is_dir() {
+            update_with_field(context, b"dir", b"");
+            hash_directory(context, &entry_path, &relative_path, ignore_patterns)?;
+        } else if metadata.is_file() {
+            update_with_file_field(context, b"file", &entry_path, metadata.len())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Simplified `.dockerignore` matching: `*` & `?` don't cross path separators,
+/// `**` matches any number of directories, and excluded directories exclude
+/// all their content.
+fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let pattern_parts: Vec<&str> = pattern.split('/').filter(|p| !p.is_empty()).collect();
+    let path_parts: Vec<&str> = path.split('/').collect();
+
+    // A pattern matching any of the path's ancestors also matches the path
+    (1..=path_parts.len()).any(|len| matches_parts(&pattern_parts, &path_parts[..len]))
+}
+
+fn matches_parts(pattern_parts: &[&str], path_parts: &[&str]) -> bool {
+    match (pattern_parts.first(), path_parts.first()) {
+        (None, None) => true,
+        (Some(&"**"), _) => {
+            matches_parts(&pattern_parts[1..], path_parts)
+                || (!path_parts.is_empty() && matches_parts(pattern_parts, &path_parts[1..]))
+        }
+        (Some(pattern_part), Some(path_part)) => {
+            matches_wildcards(pattern_part.as_bytes(), path_part.as_bytes())
+                && matches_parts(&pattern_parts[1..], &path_parts[1..])
+        }
+        _ => false,
+    }
+}
+
+fn matches_wildcards(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            matches_wildcards(&pattern[1..], name)
+                || (!name.is_empty() && matches_wildcards(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => matches_wildcards(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => matches_wildcards(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Length-prefixed fields, so different inputs can't produce the same stream
+fn update_with_field(context: &mut Context, field_name: &[u8], value: &[u8]) {
+    context.update(field_name);
+    context.update(&(value.len() as u64).to_be_bytes());
+    context.update(value);
+}
+
+/// Same as `update_with_field`, but streams the file instead of loading it in
+/// memory (build contexts can contain big files).
+fn update_with_file_field(
+    context: &mut Context,
+    field_name: &[u8],
+    file_path: &Path,
+    file_len: u64,
+) -> AvatarResult<()> {
+    context.update(field_name);
+    context.update(&file_len.to_be_bytes());
+
+    let mut file = File::open(file_path).map_err(|e| get_read_error(file_path, e))?;
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+    let mut read_len: u64 = 0;
+    loop {
+        let chunk_len = file
+            .read(&mut buffer)
+            .map_err(|e| get_read_error(file_path, e))?;
+        if chunk_len == 0 {
+            break;
+        }
+        context.update(&buffer[..chunk_len]);
+        read_len += chunk_len as u64;
+    }
+
+    // Otherwise, the length prefix would not describe the hashed content
+    match read_len == file_len {
+        true => Ok(()),
+        false => Err(AvatarError::Io(format!(
+            "The file {} changed while computing the build hash",
+            file_path.display()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        env::temp_dir,
+        fs::{create_dir_all, remove_dir_all, remove_file, write},
+        process,
+    };
+
+    fn get_refs(dockerfile: &str, build_args: &[(&str, &str)]) -> Vec<Result<String, String>> {
+        let build_args: BTreeMap<String, String> = build_args
+            .iter()
+            .map(|(arg_name, arg_value)| (arg_name.to_string(), arg_value.to_string()))
+            .collect();
+        get_base_image_refs(dockerfile, &Some(build_args))
+    }
+
+    #[test]
+    fn get_base_image_refs_skips_stages_and_scratch() {
+        let dockerfile = "# syntax=docker/dockerfile:1
+FROM --platform=linux/amd64 node:14 AS Builder
+RUN npm ci
+from builder as tester
+COPY --from=0 /app /app
+COPY --from=evil.example/tools:1 /bin/tool /bin/tool
+FROM scratch
+COPY --from=tester /app /app
+";
+
+        assert_eq!(
+            get_refs(dockerfile, &[]),
+            vec![
+                Ok("node:14".to_string()),
+                Ok("evil.example/tools:1".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn get_base_image_refs_checks_stages_named_like_their_base_image() {
+        assert_eq!(
+            get_refs("FROM node AS node\nFROM node\n", &[]),
+            vec![Ok("node".to_string())]
+        );
+    }
+
+    #[test]
+    fn get_base_image_refs_expands_global_args() {
+        let dockerfile = "ARG REGISTRY=docker.io \\
+    VERSION
+ARG VARIANT
+FROM ${REGISTRY}/library/node:$VERSION
+FROM node:${VARIANT:-buster}
+FROM $UNDEFINED/node
+ARG LATE=ignored
+FROM $LATE/node
+";
+
+        assert_eq!(
+            get_refs(dockerfile, &[("VERSION", "14")]),
+            vec![
+                Ok("docker.io/library/node:14".to_string()),
+                Ok("node:buster".to_string()),
+                Err("$UNDEFINED/node".to_string()),
+                Err("$LATE/node".to_string()),
+            ]
+        );
+        assert_eq!(
+            get_refs(dockerfile, &[("REGISTRY", "ghcr.io"), ("VARIANT", "slim")])[..2],
+            [
+                Err("${REGISTRY}/library/node:$VERSION".to_string()),
+                Ok("node:slim".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_build_hash_ignores_git_and_volatile_dirs() {
+        let project_path = temp_dir().join(format!("avatar-test-{}-build-hash", process::id()));
+        let volatile_path = project_path.join(CONFIG_DIR_NAME).join(VOLATILE_DIR_NAME);
+        let git_path = project_path.join("src").join(GIT_DIR_NAME);
+        create_dir_all(&volatile_path).unwrap();
+        create_dir_all(&git_path).unwrap();
+        write(project_path.join("Dockerfile"), "FROM node:14\n").unwrap();
+        write(project_path.join("src").join("main.js"), "main();\n").unwrap();
+
+        let build_config: ImageBuildConfig = serde_yaml::from_str("context: .").unwrap();
+        let get_hash = || get_build_hash(&project_path, &build_config).unwrap();
+
+        let original_hash = get_hash();
+        write(volatile_path.join("run_plan.json"), "{}").unwrap();
+        write(git_path.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        let hash_with_ignored_changes = get_hash();
+        write(project_path.join("src").join("main.js"), "main(1);\n").unwrap();
+        let hash_with_source_changes = get_hash();
+        remove_dir_all(&project_path).unwrap();
+
+        assert_eq!(original_hash, hash_with_ignored_changes);
+        assert_ne!(original_hash, hash_with_source_changes);
+    }
+
+    #[test]
+    fn update_with_file_field_matches_update_with_field() {
+        let file_path = temp_dir().join(format!("avatar-test-{}-file-field", process::id()));
+        let content: Vec<u8> = (0..HASH_BUFFER_SIZE * 2 + 7).map(|i| i as u8).collect();
+        write(&file_path, &content).unwrap();
+
+        let mut streamed_context = Context::new(&SHA256);
+        let streamed_result = update_with_file_field(
+            &mut streamed_context,
+            b"file",
+            &file_path,
+            content.len() as u64,
+        );
+        let wrong_len_result = update_with_file_field(
+            &mut Context::new(&SHA256),
+            b"file",
+            &file_path,
+            content.len() as u64 + 1,
+        );
+        remove_file(&file_path).unwrap();
+
+        let mut context = Context::new(&SHA256);
+        update_with_field(&mut context, b"file", &content);
+        assert!(streamed_result.is_ok());
+        assert!(wrong_len_result.is_err());
+        assert_eq!(
+            streamed_context.finish().as_ref(),
+            context.finish().as_ref()
+        );
+    }
+}
@@ -0,0 +1,161 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+//! Runs image operations (pulls & tag resolutions) concurrently, with a
+//! combined progress display instead of the engines' own (interleaved) output.
+
+use std::{
+    io::{stdout, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::{
+    avatar_env::get_max_parallel_pulls,
+    error::{AvatarError, AvatarResult},
+};
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy, PartialEq)]
+enum JobState {
+    Waiting,
+    Running,
+    Done,
+    Failed,
+}
+
+/// One line per image. On terminals the lines are redrawn in place, otherwise
+/// (e.g. in CI logs) a new line is printed for every state change.
+struct JobsProgress {
+    action: &'static str,
+    labels: Vec<String>,
+    states: Vec<JobState>,
+    interactive: bool,
+    drawn: bool,
+}
+
+impl JobsProgress {
+    fn new(action: &'static str, labels: Vec<String>) -> JobsProgress {
+        JobsProgress {
+            action,
+            states: vec![JobState::Waiting; labels.len()],
+            labels,
+            interactive: atty::is(atty::Stream::Stdout),
+            drawn: false,
+        }
+    }
+
+    fn set_state(&mut self, job_index: usize, state: JobState) {
+        self.states[job_index] = state;
+
+        match self.interactive {
+            true => self.redraw(),
+            false => println!("{}", self.get_line(job_index)),
+        }
+    }
+
+    fn get_line(&self, job_index: usize) -> String {
+        let state = match self.states[job_index] {
+            JobState::Waiting => "waiting",
+            JobState::Running => self.action,
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+        };
+        format!("{}: {}", self.labels[job_index], state)
+    }
+
+    fn redraw(&mut self) {
+        let mut out = stdout();
+        if self.drawn {
+            // Moves the cursor back to the first line of the block
+            let _ = write!(out, "\x1b[{}A", self.labels.len());
+        }
+        for job_index in 0..self.labels.len() {
+            let _ = writeln!(out, "\x1b[2K{}", self.get_line(job_index));
+        }
+        let _ = out.flush();
+        self.drawn = true;
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+/// Applies `job` to every item, running up to `AVATAR_CLI_MAX_PARALLEL_PULLS`
+/// jobs at the same time. The results keep the items' order, and when several
+/// jobs fail, the error of the first one (in the items' order) is returned, so
+/// the outcome does not depend on the order in which the jobs finish.
+pub fn run_image_jobs<T, R>(
+    items: &[(String, T)], // (label, item)
+    action: &'static str,
+    show_output: bool,
+    job: &(dyn Fn(&T) -> AvatarResult<R> + Sync),
+) -> AvatarResult<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+{
+    let max_parallel_jobs = get_max_parallel_pulls()?.min(items.len());
+    if max_parallel_jobs == 0 {
+        return Ok(vec![]);
+    }
+
+    let progress = Mutex::new(JobsProgress::new(
+        action,
+        items.iter().map(|(label, _)| label.clone()).collect(),
+    ));
+    let set_state = |job_index: usize, state: JobState| {
+        if show_output {
+            // The progress is only used for display purposes, a poisoned
+            // lock is not worth failing the whole operation
+            if let Ok(mut progress) = progress.lock() {
+                progress.set_state(job_index, state);
+            }
+        }
+    };
+
+    let next_job_index = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<AvatarResult<R>>>> =
+        Mutex::new(items.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..max_parallel_jobs {
+            scope.spawn(|| loop {
+                let job_index = next_job_index.fetch_add(1, Ordering::SeqCst);
+                if job_index >= items.len() {
+                    break;
+                }
+
+                set_state(job_index, JobState::Running);
+                let result = job(&items[job_index].1);
+                set_state(
+                    job_index,
+                    match result.is_ok() {
+                        true => JobState::Done,
+                        false => JobState::Failed,
+                    },
+                );
+
+                if let Ok(mut results) = results.lock() {
+                    results[job_index] = Some(result);
+                }
+            });
+        }
+    });
+
+    // Every job stored its result before its thread finished
+    match results.into_inner() {
+        Ok(results) => results.into_iter().flatten().collect(),
+        Err(_) => Err(AvatarError::Internal(
+            "A concurrent image operation panicked".to_string(),
+        )),
+    }
+}
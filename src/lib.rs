@@ -0,0 +1,25 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+//! The `avatar` binary is a thin layer over this crate, which exposes the
+//! project configuration & lock models, and the install and run planning
+//! steps, so they can be reused by other tools.
+
+pub mod avatar_env;
+mod avatarfile_editor;
+pub mod container_engines;
+pub mod directories;
+pub mod error;
+pub mod image_archive;
+mod image_builds;
+mod image_pulls;
+mod migrations;
+pub mod policy;
+pub mod ports;
+pub mod project_config;
+pub mod run_plan;
+pub mod subcommands;
+mod tar;
@@ -4,17 +4,52 @@
  *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
  */
 
-mod avatar_env;
-mod directories;
-mod docker;
-mod project_config;
-mod subcommands;
+use std::process::exit;
+
+use avatar_cli::{
+    avatar_env::get_used_program_name,
+    error::{AvatarError, AvatarResult},
+    subcommands,
+};
 
 fn main() {
-    let used_program_name = avatar_env::get_used_program_name();
+    if let Err(e) = run_main() {
+        let message = e.to_string();
+        if !message.is_empty() {
+            eprintln!("{}", message);
+        }
+        exit(get_exit_code(&e))
+    }
+}
+
+fn run_main() -> AvatarResult<()> {
+    let used_program_name = get_used_program_name()?;
     if used_program_name == "avatar" {
         subcommands::select()
     } else {
         subcommands::run::run_in_subshell_mode(&used_program_name)
     }
 }
+
+/// The only place where errors are translated into exit codes
+fn get_exit_code(error: &AvatarError) -> exitcode::ExitCode {
+    match error {
+        AvatarError::ConfigParse(_)
+        | AvatarError::InvalidConfig(_)
+        | AvatarError::HashMismatch(_) => exitcode::DATAERR,
+        AvatarError::IncompatibleVersion(_) | AvatarError::Environment(_) => exitcode::CONFIG,
+        AvatarError::Usage(_) => exitcode::USAGE,
+        AvatarError::MissingFile(_) => exitcode::NOINPUT,
+        AvatarError::CantCreate(_) => exitcode::CANTCREAT,
+        AvatarError::Io(_) => exitcode::IOERR,
+        AvatarError::Os(_) => exitcode::OSERR,
+        AvatarError::ContainerEngine(_) | AvatarError::Internal(_) => exitcode::SOFTWARE,
+        AvatarError::ContainerEngineUnavailable(_) => exitcode::UNAVAILABLE,
+        AvatarError::ContainerEngineProtocol(_) => exitcode::PROTOCOL,
+        AvatarError::PolicyViolation(_) => exitcode::NOPERM,
+        AvatarError::PortInUse(_) => exitcode::TEMPFAIL,
+        // Like `git diff --exit-code`, so it's not mistaken for a failure
+        AvatarError::OutdatedLock(_) => 1,
+        AvatarError::TaskFailed(_, exit_code) => *exit_code,
+    }
+}
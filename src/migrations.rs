@@ -0,0 +1,233 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::path::Path;
+
+use semver::Version;
+use serde_yaml::{Mapping, Value};
+
+use crate::{
+    avatarfile_editor::AvatarfileEditor,
+    error::{AvatarError, AvatarResult},
+    subcommands::AVATAR_CLI_VERSION,
+};
+
+type ConfigMigrationStep = (&'static str, fn(&mut AvatarfileEditor));
+
+/// Avatarfile layout changes, each one tagged with the first Avatar CLI version
+/// expecting the new layout. Avatarfiles declaring an older `avatarVersion` have
+/// to be upgraded (through `avatar migrate`) before being used.
+pub(crate) const CONFIG_MIGRATIONS: &[ConfigMigrationStep] = &[];
+
+/// Lock file layout changes, the step at index `i` upgrades a lock file from
+/// `lockVersion: i` to `lockVersion: i + 1`. The steps only depend on the lock
+/// itself, so older lock files are upgraded in memory when loaded, and
+/// `avatar migrate` persists the result.
+pub(crate) const LOCK_MIGRATIONS: &[fn(&mut Mapping)] = &[add_lock_version];
+
+pub(crate) const LOCK_VERSION: u64 = LOCK_MIGRATIONS.len() as u64;
+
+const AVATAR_VERSION_KEY: &str = "avatarVersion";
+const LOCK_VERSION_KEY: &str = "lockVersion";
+
+/// Refuses Avatarfiles written for a newer Avatar CLI, or for an older one
+/// when their layout must be migrated.
+pub(crate) fn check_config_version(
+    config_bytes: &[u8],
+    config_filepath: &Path,
+) -> AvatarResult<()> {
+    let config_version = match get_config_version(config_bytes, config_filepath)? {
+        Some(v) => v,
+        None => return Ok(()), // The error will be reported when deserializing the file
+    };
+
+    if config_version > get_cli_version() {
+        return Err(get_newer_config_error(&config_version, config_filepath));
+    }
+
+    if !get_pending_config_migrations(&config_version).is_empty() {
+        return Err(AvatarError::IncompatibleVersion(format!(
+            "The file '{}' was written for Avatar CLI {}, and its layout must be upgraded. Run 'avatar migrate' to do so.",
+            config_filepath.display(),
+            config_version
+        )));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn get_cli_version() -> Version {
+    // We can safely unwrap, Cargo enforces valid semver versions
+    Version::parse(AVATAR_CLI_VERSION).unwrap()
+}
+
+pub(crate) fn get_config_version(
+    config_bytes: &[u8],
+    config_filepath: &Path,
+) -> AvatarResult<Option<Version>> {
+    let raw_version = match serde_yaml::from_slice::<Value>(config_bytes) {
+        Ok(Value::Mapping(config)) => {
+            match config.get(&Value::String(AVATAR_VERSION_KEY.to_string())) {
+                Some(Value::String(v)) => v.clone(),
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    match Version::parse(&raw_version) {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => Err(AvatarError::InvalidConfig(format!(
+            "Invalid {} '{}' in file '{}':\n\t{}",
+            AVATAR_VERSION_KEY,
+            raw_version,
+            config_filepath.display(),
+            e
+        ))),
+    }
+}
+
+/// Refuses lock files generated by a newer Avatar CLI, and upgrades the older
+/// ones in memory. Returns `None` when the lock already uses the current layout.
+pub(crate) fn get_migrated_lock(
+    lock_bytes: &[u8],
+    lock_filepath: &Path,
+) -> AvatarResult<Option<Value>> {
+    let mut lock = match serde_yaml::from_slice::<Value>(lock_bytes) {
+        Ok(Value::Mapping(lock)) => lock,
+        _ => return Ok(None), // The error will be reported when deserializing the file
+    };
+
+    match get_lock_version(&lock) {
+        lock_version if lock_version > LOCK_VERSION => {
+            Err(get_newer_lock_error(lock_version, lock_filepath))
+        }
+        LOCK_VERSION => Ok(None),
+        _ => {
+            migrate_lock(&mut lock);
+            Ok(Some(Value::Mapping(lock)))
+        }
+    }
+}
+
+pub(crate) fn get_newer_config_error(
+    config_version: &Version,
+    config_filepath: &Path,
+) -> AvatarError {
+    AvatarError::IncompatibleVersion(format!(
+        "The file '{}' requires Avatar CLI {} or newer, but you are using version {}. Please upgrade Avatar CLI.",
+        config_filepath.display(),
+        config_version,
+        AVATAR_CLI_VERSION
+    ))
+}
+
+pub(crate) fn get_newer_lock_error(lock_version: u64, lock_filepath: &Path) -> AvatarError {
+    AvatarError::IncompatibleVersion(format!(
+        "The file '{}' (lockVersion {}) was generated by a newer Avatar CLI version. Please upgrade Avatar CLI.",
+        lock_filepath.display(),
+        lock_version
+    ))
+}
+
+pub(crate) fn get_lock_version(lock: &Mapping) -> u64 {
+    match lock.get(&Value::String(LOCK_VERSION_KEY.to_string())) {
+        Some(Value::Number(n)) => n.as_u64().unwrap_or(0),
+        _ => 0, // Lock files did not have an explicit version before
+    }
+}
+
+pub(crate) fn get_pending_config_migrations(config_version: &Version) -> Vec<ConfigMigrationStep> {
+    let cli_version = get_cli_version();
+
+    CONFIG_MIGRATIONS
+        .iter()
+        .filter(|(step_version, _)| {
+            // We can safely unwrap, the steps' versions are hardcoded
+            let step_version = Version::parse(step_version).unwrap();
+            &step_version > config_version && step_version <= cli_version
+        })
+        .cloned()
+        .collect()
+}
+
+/// Applies the pending lock migration steps, and returns the original version
+pub(crate) fn migrate_lock(lock: &mut Mapping) -> u64 {
+    let original_version = get_lock_version(lock);
+
+    for (version, migration_step) in LOCK_MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(original_version as usize)
+    {
+        migration_step(lock);
+        lock.insert(
+            Value::String(LOCK_VERSION_KEY.to_string()),
+            Value::Number((version as u64 + 1).into()),
+        );
+    }
+
+    original_version
+}
+
+// Migration steps:
+// -----------------------------------------------------------------------------
+
+/// 0 → 1: Besides the new `lockVersion` field, the layout did not change
+fn add_lock_version(_lock: &mut Mapping) {}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::project_config::ProjectConfigLock;
+
+    const REPO_CONFIG_LOCK: &str = include_str!("../.avatar-cli/Avatarfile.lock");
+
+    fn get_unversioned_lock() -> String {
+        REPO_CONFIG_LOCK
+            .lines()
+            .filter(|line| !line.starts_with(LOCK_VERSION_KEY))
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn unversioned_locks_are_upgraded_in_memory() {
+        let lock_path = PathBuf::from("Avatarfile.lock");
+        let migrated_lock = get_migrated_lock(get_unversioned_lock().as_bytes(), &lock_path)
+            .unwrap()
+            .unwrap();
+
+        match &migrated_lock {
+            Value::Mapping(lock) => assert_eq!(get_lock_version(lock), LOCK_VERSION),
+            _ => panic!("the migrated lock should be a mapping"),
+        }
+        assert!(serde_yaml::from_value::<ProjectConfigLock>(migrated_lock).is_ok());
+    }
+
+    #[test]
+    fn current_locks_are_not_migrated() {
+        let lock_path = PathBuf::from("Avatarfile.lock");
+
+        assert!(get_migrated_lock(REPO_CONFIG_LOCK.as_bytes(), &lock_path)
+            .unwrap()
+            .is_none());
+        assert!(serde_yaml::from_str::<ProjectConfigLock>(REPO_CONFIG_LOCK).is_ok());
+    }
+
+    #[test]
+    fn newer_locks_are_refused() {
+        let lock_path = PathBuf::from("Avatarfile.lock");
+        let newer_lock = format!("{}: {}\n", LOCK_VERSION_KEY, LOCK_VERSION + 1);
+
+        assert!(matches!(
+            get_migrated_lock(newer_lock.as_bytes(), &lock_path),
+            Err(AvatarError::IncompatibleVersion(_))
+        ));
+    }
+}
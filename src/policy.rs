@@ -0,0 +1,422 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::{fs::read_to_string, path::Path};
+
+use serde::Serialize;
+
+use crate::{
+    error::{AvatarError, AvatarResult},
+    image_builds::{get_base_image_refs, get_build_paths},
+    project_config::{split_image_ref, PolicyConfig, ProjectConfig},
+};
+
+// Constants:
+// -----------------------------------------------------------------------------
+const DEFAULT_REGISTRY: &str = "docker.io";
+const DEFAULT_NAMESPACE: &str = "library";
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyViolation {
+    rule: String,
+    subject: String,
+    message: String,
+}
+
+impl PolicyViolation {
+    fn new(rule: &str, subject: String, message: String) -> PolicyViolation {
+        PolicyViolation {
+            rule: rule.to_string(),
+            subject,
+            message,
+        }
+    }
+
+    pub fn get_rule(&self) -> &String {
+        &self.rule
+    }
+
+    pub fn get_subject(&self) -> &String {
+        &self.subject
+    }
+
+    pub fn get_message(&self) -> &String {
+        &self.message
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+/// Refuses project configurations violating their own image rules, it has to
+/// be called before pulling (or building) any image. The `requireLock` rule is
+/// enforced by the install step, as it's the one (re)generating lock files.
+pub fn check_policy(config: &ProjectConfig, project_path: &Path) -> AvatarResult<()> {
+    let violations = get_image_policy_violations(config, project_path)?;
+
+    match violations.is_empty() {
+        true => Ok(()),
+        false => Err(get_policy_error(&violations)),
+    }
+}
+
+/// Checks the images (the services' images, and the base images of the built
+/// ones) against the `allowedRegistries` and `forbiddenTags` rules.
+pub fn get_image_policy_violations(
+    config: &ProjectConfig,
+    project_path: &Path,
+) -> AvatarResult<Vec<PolicyViolation>> {
+    let policy = match config.get_policy() {
+        Some(policy) => policy,
+        None => return Ok(vec![]),
+    };
+
+    let mut violations: Vec<PolicyViolation> = vec![];
+    // The same image can be used by tools, services & builds
+    let mut add_violations = |new_violations: Vec<PolicyViolation>| {
+        for violation in new_violations {
+            if !violations.contains(&violation) {
+                violations.push(violation);
+            }
+        }
+    };
+
+    for (image_name, image_config) in config.get_images().iter().flatten() {
+        let build_config = match image_config.get_build() {
+            Some(build_config) => build_config,
+            None => {
+                for image_tag in image_config.get_tags().keys() {
+                    add_violations(get_image_tag_violations(
+                        policy,
+                        image_name,
+                        Some(image_tag),
+                        None,
+                    ));
+                }
+                continue;
+            }
+        };
+
+        // Built images don't come from any registry, but their base images do
+        let (_, dockerfile_path) = get_build_paths(project_path, build_config)?;
+        let dockerfile = read_to_string(&dockerfile_path).map_err(|e| {
+            AvatarError::Io(format!(
+                "Unable to read {}\n\n{}\n",
+                dockerfile_path.display(),
+                e
+            ))
+        })?;
+        for base_image_ref in get_base_image_refs(&dockerfile, build_config.get_args()) {
+            match base_image_ref {
+                Ok(base_image_ref) => {
+                    let (base_image_name, base_image_tag) = split_base_image_ref(&base_image_ref);
+                    add_violations(get_image_tag_violations(
+                        policy,
+                        base_image_name,
+                        base_image_tag,
+                        Some(image_name),
+                    ));
+                }
+                Err(raw_image_ref) => add_violations(vec![PolicyViolation::new(
+                    match policy.get_allowed_registries() {
+                        Some(_) => "allowedRegistries",
+                        None => "forbiddenTags",
+                    },
+                    raw_image_ref.clone(),
+                    format!(
+                        "The base image '{}' of the built image '{}' can't be checked, it depends on undefined build arguments",
+                        raw_image_ref, image_name
+                    ),
+                )]),
+            }
+        }
+    }
+
+    for service_config in config.get_services().iter().flat_map(|s| s.values()) {
+        // Services can use the built images, already covered by their builds
+        let uses_built_image = config
+            .get_images()
+            .as_ref()
+            .and_then(|images| images.get(service_config.get_image()))
+            .is_some_and(|image_config| {
+                image_config.get_build().is_some()
+                    && image_config
+                        .get_tags()
+                        .contains_key(service_config.get_tag())
+            });
+        if !uses_built_image {
+            add_violations(get_image_tag_violations(
+                policy,
+                service_config.get_image(),
+                Some(service_config.get_tag()),
+                None,
+            ));
+        }
+    }
+
+    Ok(violations)
+}
+
+pub fn get_missing_lock_violation(config_lock_path: &Path) -> PolicyViolation {
+    PolicyViolation::new(
+        "requireLock",
+        config_lock_path.display().to_string(),
+        "The lock file is missing, it must be generated and committed along with the Avatarfile"
+            .to_string(),
+    )
+}
+
+pub fn get_outdated_lock_violation(config_lock_path: &Path) -> PolicyViolation {
+    PolicyViolation::new(
+        "requireLock",
+        config_lock_path.display().to_string(),
+        "The lock file is out of sync with the Avatarfile, remove it and run 'avatar install' to \
+         generate it again, then commit it"
+            .to_string(),
+    )
+}
+
+pub fn get_policy_error(violations: &[PolicyViolation]) -> AvatarError {
+    AvatarError::PolicyViolation(format!(
+        "The project configuration violates its policy:\n{}",
+        violations
+            .iter()
+            .map(|violation| format!("\t- {}", violation.message))
+            .collect::<Vec<String>>()
+            .join("\n")
+    ))
+}
+
+/// Expands the image names to their fully qualified form (e.g. `node` becomes
+/// `docker.io/library/node`), so they can be matched against registry prefixes.
+pub fn get_fully_qualified_image_name(image_name: &str) -> String {
+    let (first_component, rest) = match image_name.split_once('/') {
+        Some(components) => components,
+        None => return format!("{}/{}/{}", DEFAULT_REGISTRY, DEFAULT_NAMESPACE, image_name),
+    };
+
+    // Same heuristic as the Docker client to tell registries from namespaces
+    if first_component.contains('.')
+        || first_component.contains(':')
+        || first_component == "localhost"
+    {
+        format!("{}/{}", first_component, rest)
+    } else {
+        format!("{}/{}", DEFAULT_REGISTRY, image_name)
+    }
+}
+
+pub fn is_lock_required(config: &ProjectConfig) -> bool {
+    config
+        .get_policy()
+        .as_ref()
+        .is_some_and(|policy| policy.get_require_lock())
+}
+
+/// Tags are `None` for images pinned to digests. Base images mention the
+/// built image using them.
+fn get_image_tag_violations(
+    policy: &PolicyConfig,
+    image_name: &str,
+    image_tag: Option<&str>,
+    built_image_name: Option<&str>,
+) -> Vec<PolicyViolation> {
+    let mut violations: Vec<PolicyViolation> = vec![];
+    let subject = match image_tag {
+        Some(image_tag) => format!("{}:{}", image_name, image_tag),
+        None => image_name.to_string(),
+    };
+    let context = match built_image_name {
+        Some(built_image_name) => {
+            format!(" (base image of the built image '{}')", built_image_name)
+        }
+        None => String::new(),
+    };
+
+    if let Some(allowed_registries) = policy.get_allowed_registries() {
+        let fq_image_name = get_fully_qualified_image_name(image_name);
+        if !allowed_registries
+            .iter()
+            .any(|prefix| is_image_name_under_prefix(&fq_image_name, prefix))
+        {
+            violations.push(PolicyViolation::new(
+                "allowedRegistries",
+                subject.clone(),
+                format!(
+                    "The image '{}' ({}) does not come from an allowed registry{}",
+                    image_name, fq_image_name, context
+                ),
+            ));
+        }
+    }
+
+    if let (Some(forbidden_tags), Some(image_tag)) = (policy.get_forbidden_tags(), image_tag) {
+        if forbidden_tags.contains(image_tag) {
+            violations.push(PolicyViolation::new(
+                "forbiddenTags",
+                subject.clone(),
+                format!("The image tag '{}' is forbidden{}", subject, context),
+            ));
+        }
+    }
+
+    violations
+}
+
+/// Compares whole path components, so `ghcr.io` does not allow
+/// `ghcr.io.evil.com/x`, and `docker.io/library` does not allow
+/// `docker.io/library-evil/x`
+fn is_image_name_under_prefix(fq_image_name: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        return false;
+    }
+
+    let mut image_name_components = fq_image_name.split('/');
+    prefix
+        .split('/')
+        .all(|prefix_component| image_name_components.next() == Some(prefix_component))
+}
+
+/// Base images without tag use `latest`, unless they are pinned to a digest
+fn split_base_image_ref(image_ref: &str) -> (&str, Option<&str>) {
+    match image_ref.split_once('@') {
+        Some((name_and_tag, _)) => split_image_ref(name_and_tag),
+        None => {
+            let (image_name, image_tag) = split_image_ref(image_ref);
+            (image_name, Some(image_tag.unwrap_or("latest")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env::temp_dir, fs, process};
+
+    fn get_messages(violations: &[PolicyViolation]) -> Vec<&str> {
+        violations
+            .iter()
+            .map(|violation| violation.get_message().as_str())
+            .collect()
+    }
+
+    #[test]
+    fn get_fully_qualified_image_name_expands_docker_hub_images() {
+        assert_eq!(
+            get_fully_qualified_image_name("node"),
+            "docker.io/library/node"
+        );
+        assert_eq!(
+            get_fully_qualified_image_name("bitnami/redis"),
+            "docker.io/bitnami/redis"
+        );
+        assert_eq!(
+            get_fully_qualified_image_name("docker.io/library/node"),
+            "docker.io/library/node"
+        );
+    }
+
+    #[test]
+    fn get_fully_qualified_image_name_keeps_explicit_registries() {
+        assert_eq!(
+            get_fully_qualified_image_name("ghcr.io/owner/tool"),
+            "ghcr.io/owner/tool"
+        );
+        assert_eq!(
+            get_fully_qualified_image_name("registry:5000/tool"),
+            "registry:5000/tool"
+        );
+        assert_eq!(
+            get_fully_qualified_image_name("localhost/tool"),
+            "localhost/tool"
+        );
+    }
+
+    #[test]
+    fn is_image_name_under_prefix_respects_path_boundaries() {
+        assert!(is_image_name_under_prefix("ghcr.io/owner/tool", "ghcr.io"));
+        assert!(is_image_name_under_prefix("ghcr.io/owner/tool", "ghcr.io/"));
+        assert!(is_image_name_under_prefix(
+            "ghcr.io/owner/tool",
+            "ghcr.io/owner"
+        ));
+        assert!(is_image_name_under_prefix(
+            "ghcr.io/owner/tool",
+            "ghcr.io/owner/tool"
+        ));
+        assert!(!is_image_name_under_prefix("ghcr.io.evil.com/x", "ghcr.io"));
+        assert!(!is_image_name_under_prefix(
+            "docker.io/library-evil/node",
+            "docker.io/library/"
+        ));
+        assert!(!is_image_name_under_prefix(
+            "ghcr.io/owner",
+            "ghcr.io/owner/tool"
+        ));
+        assert!(!is_image_name_under_prefix("ghcr.io/owner/tool", ""));
+    }
+
+    #[test]
+    fn split_base_image_ref_defaults_to_latest_unless_pinned() {
+        assert_eq!(split_base_image_ref("node"), ("node", Some("latest")));
+        assert_eq!(
+            split_base_image_ref("registry:5000/node:14"),
+            ("registry:5000/node", Some("14"))
+        );
+        assert_eq!(split_base_image_ref("node@sha256:abcd"), ("node", None));
+        assert_eq!(
+            split_base_image_ref("node:14@sha256:abcd"),
+            ("node", Some("14"))
+        );
+    }
+
+    #[test]
+    fn get_image_policy_violations_checks_base_images_of_built_images() {
+        let project_path = temp_dir().join(format!("avatar-test-{}-policy", process::id()));
+        fs::create_dir_all(project_path.join("docker")).unwrap();
+        fs::write(
+            project_path.join("docker").join("Dockerfile"),
+            "ARG BASE\nFROM ghcr.io/owner/base:1 AS base\nFROM $BASE\nCOPY --from=evil.example/x /x /x\n",
+        )
+        .unwrap();
+
+        let config: ProjectConfig = serde_yaml::from_str(
+            "avatarVersion: 0.18.0
+projectInternalId: abcdefghijklmnop
+images:
+  tool:
+    build: {context: docker}
+    tags: {latest: {}}
+services:
+  db:
+    image: tool
+    tag: other
+policy:
+  allowedRegistries: [ghcr.io/owner]
+  forbiddenTags: [latest]
+",
+        )
+        .unwrap();
+        let violations = get_image_policy_violations(&config, &project_path);
+        fs::remove_dir_all(&project_path).unwrap();
+
+        assert_eq!(
+            get_messages(&violations.unwrap()),
+            vec![
+                "The base image '$BASE' of the built image 'tool' can't be checked, it depends on undefined build arguments",
+                "The image 'evil.example/x' (evil.example/x) does not come from an allowed registry (base image of the built image 'tool')",
+                "The image tag 'evil.example/x:latest' is forbidden (base image of the built image 'tool')",
+                "The image 'tool' (docker.io/library/tool) does not come from an allowed registry",
+            ]
+        );
+    }
+}
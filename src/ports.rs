@@ -0,0 +1,297 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, TcpListener, UdpSocket},
+};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+/// A published port (or port range), as declared in the `ports` section of a
+/// run config: `[HOST_IP:]HOST_PORT[-HOST_PORT]` → `PORT[-PORT][/PROTOCOL]`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortMapping {
+    host_ip: Option<IpAddr>,
+    host_ports: (u16, u16),
+    container_ports: (u16, u16),
+    protocol: String,
+}
+
+impl PortMapping {
+    pub fn parse(host_spec: &str, container_spec: &str) -> Result<PortMapping, String> {
+        let (host_ip, host_ports) = parse_host_spec(host_spec)?;
+
+        let (container_ports, protocol) = match container_spec.split_once('/') {
+            Some((ports, protocol)) => (ports, protocol.to_lowercase()),
+            None => (container_spec, "tcp".to_string()),
+        };
+        if protocol != "tcp" && protocol != "udp" {
+            return Err(format!(
+                "unknown protocol '{}', expected 'tcp' or 'udp'",
+                protocol
+            ));
+        }
+        let container_ports = parse_port_range(container_ports)?;
+
+        // A single container port can be published on any port of a host range
+        let container_range_len = container_ports.1 - container_ports.0;
+        if container_range_len > 0 && container_range_len != host_ports.1 - host_ports.0 {
+            return Err("the host and container port ranges have different sizes".to_string());
+        }
+
+        Ok(PortMapping {
+            host_ip,
+            host_ports,
+            container_ports,
+            protocol,
+        })
+    }
+
+    /// The value passed to the `--publish` option of the container engine
+    pub fn get_publish_arg(&self) -> String {
+        let host_ip = match self.host_ip {
+            Some(IpAddr::V4(ip)) => format!("{}:", ip),
+            Some(IpAddr::V6(ip)) => format!("[{}]:", ip),
+            None => "".to_string(),
+        };
+
+        format!(
+            "{}{}:{}/{}",
+            host_ip,
+            format_port_range(self.host_ports),
+            format_port_range(self.container_ports),
+            self.protocol
+        )
+    }
+
+    /// Returns the first host port that is already taken, or `None` if the
+    /// mapping can be published (for single container ports published on a
+    /// host range, one free host port is enough).
+    pub fn find_host_port_in_use(&self) -> Option<u16> {
+        let host_ip = self.host_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let host_ports = self.host_ports.0..=self.host_ports.1;
+
+        let used_ports: Vec<u16> = host_ports
+            .clone()
+            .filter(|port| self.is_host_port_in_use(host_ip, *port))
+            .collect();
+
+        match self.container_ports.0 == self.container_ports.1 {
+            true if used_ports.len() < host_ports.count() => None,
+            _ => used_ports.first().cloned(),
+        }
+    }
+
+    /// Other errors (like not having permissions to bind privileged ports) are
+    /// left to be reported by the container engine.
+    fn is_host_port_in_use(&self, host_ip: IpAddr, port: u16) -> bool {
+        let bind_error = match self.protocol.as_str() {
+            "udp" => UdpSocket::bind((host_ip, port)).err(),
+            _ => TcpListener::bind((host_ip, port)).err(),
+        };
+
+        matches!(bind_error, Some(e) if e.kind() == ErrorKind::AddrInUse)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(untagged)]
+enum PortValue {
+    Number(u64),
+    Text(String),
+}
+
+impl PortValue {
+    fn into_string(self) -> String {
+        match self {
+            PortValue::Number(n) => n.to_string(),
+            PortValue::Text(s) => s,
+        }
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+/// Allows writing ports both as numbers and as strings in the YAML files
+pub fn deserialize_ports<'de, D>(
+    deserializer: D,
+) -> Result<Option<BTreeMap<String, String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        Option::<BTreeMap<PortValue, PortValue>>::deserialize(deserializer)?.map(|ports| {
+            ports
+                .into_iter()
+                .map(|(host_spec, container_spec)| {
+                    (host_spec.into_string(), container_spec.into_string())
+                })
+                .collect()
+        }),
+    )
+}
+
+fn format_port_range(ports: (u16, u16)) -> String {
+    match ports.0 == ports.1 {
+        true => ports.0.to_string(),
+        false => format!("{}-{}", ports.0, ports.1),
+    }
+}
+
+fn parse_host_spec(host_spec: &str) -> Result<(Option<IpAddr>, (u16, u16)), String> {
+    let (raw_ip, raw_ports) = if let Some(bracketed_spec) = host_spec.strip_prefix('[') {
+        match bracketed_spec.split_once("]:") {
+            Some((ip, ports)) => (Some(ip), ports),
+            None => return Err(format!("invalid host address in '{}'", host_spec)),
+        }
+    } else {
+        match host_spec.rsplit_once(':') {
+            Some((ip, ports)) => (Some(ip), ports),
+            None => (None, host_spec),
+        }
+    };
+
+    let host_ip = raw_ip
+        .map(|ip| {
+            ip.parse::<IpAddr>()
+                .map_err(|_| format!("invalid host IP address '{}'", ip))
+        })
+        .transpose()?;
+
+    Ok((host_ip, parse_port_range(raw_ports)?))
+}
+
+fn parse_port_range(raw_ports: &str) -> Result<(u16, u16), String> {
+    let parse_port = |raw_port: &str| match raw_port.trim().parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(format!("invalid port '{}'", raw_port)),
+    };
+
+    let ports = match raw_ports.split_once('-') {
+        Some((first_port, last_port)) => (parse_port(first_port)?, parse_port(last_port)?),
+        None => {
+            let port = parse_port(raw_ports)?;
+            (port, port)
+        }
+    };
+
+    match ports.0 <= ports.1 {
+        true => Ok(ports),
+        false => Err(format!("invalid port range '{}'", raw_ports)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_publish_arg(host_spec: &str, container_spec: &str) -> Result<String, String> {
+        PortMapping::parse(host_spec, container_spec).map(|mapping| mapping.get_publish_arg())
+    }
+
+    #[test]
+    fn parse_accepts_single_ports_and_ranges() {
+        assert_eq!(
+            get_publish_arg("3000", "3000"),
+            Ok("3000:3000/tcp".to_string())
+        );
+        assert_eq!(
+            get_publish_arg("8000-8010", "9000-9010"),
+            Ok("8000-8010:9000-9010/tcp".to_string())
+        );
+        // A single container port can be published on any port of a host range
+        assert_eq!(
+            get_publish_arg("8000-8010", "80"),
+            Ok("8000-8010:80/tcp".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_accepts_host_ips() {
+        assert_eq!(
+            get_publish_arg("127.0.0.1:9229", "9229"),
+            Ok("127.0.0.1:9229:9229/tcp".to_string())
+        );
+        assert_eq!(
+            get_publish_arg("[::1]:9229", "9229"),
+            Ok("[::1]:9229:9229/tcp".to_string())
+        );
+        // The last colon separates the port, and the engine gets brackets
+        assert_eq!(
+            get_publish_arg("::1:9229", "9229"),
+            Ok("[::1]:9229:9229/tcp".to_string())
+        );
+        assert!(get_publish_arg("[::1:9229", "9229").is_err());
+        assert!(get_publish_arg("localhost:9229", "9229").is_err());
+    }
+
+    #[test]
+    fn parse_accepts_tcp_and_udp_protocols() {
+        assert_eq!(get_publish_arg("53", "53/UDP"), Ok("53:53/udp".to_string()));
+        assert_eq!(get_publish_arg("80", "80/tcp"), Ok("80:80/tcp".to_string()));
+        assert!(get_publish_arg("80", "80/sctp").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_ports_and_ranges() {
+        for (host_spec, container_spec) in &[
+            ("0", "80"),
+            ("65536", "80"),
+            ("http", "80"),
+            ("80", ""),
+            ("90-80", "90-80"),
+            ("8000-8010", "9000-9005"),
+        ] {
+            assert!(
+                get_publish_arg(host_spec, container_spec).is_err(),
+                "'{}: {}' should be rejected",
+                host_spec,
+                container_spec
+            );
+        }
+    }
+
+    #[test]
+    fn deserialize_ports_accepts_numbers_and_strings() {
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(default, deserialize_with = "deserialize_ports")]
+            ports: Option<BTreeMap<String, String>>,
+        }
+
+        let config: Config =
+            serde_yaml::from_str("ports:\n  3000: 3000\n  \"127.0.0.1:53\": 53/udp\n").unwrap();
+        let expected_ports: BTreeMap<String, String> = vec![
+            ("127.0.0.1:53".to_string(), "53/udp".to_string()),
+            ("3000".to_string(), "3000".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(config.ports, Some(expected_ports));
+
+        let config: Config = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(config.ports, None);
+    }
+
+    #[test]
+    fn find_host_port_in_use_detects_taken_ports() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mapping = PortMapping::parse(&format!("127.0.0.1:{}", port), "80").unwrap();
+        assert_eq!(mapping.find_host_port_in_use(), Some(port));
+
+        drop(listener);
+        assert_eq!(mapping.find_host_port_in_use(), None);
+    }
+}
@@ -7,28 +7,36 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{read, write};
 use std::io::ErrorKind;
-use std::path::PathBuf;
-use std::process::exit;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::vec::Vec;
 
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use ring::digest::{digest, Digest, SHA256};
 use serde::{Deserialize, Serialize};
 
-use crate::{docker::get_path_env_var_from_oci_image, subcommands::AVATAR_CLI_VERSION};
+use crate::{
+    container_engines::{ContainerEngine, ContainerEngineKind},
+    error::{AvatarError, AvatarResult},
+    migrations::{check_config_version, get_migrated_lock, LOCK_VERSION},
+    ports::{deserialize_ports, PortMapping},
+    subcommands::AVATAR_CLI_VERSION,
+};
 
 // Constants:
 // -----------------------------------------------------------------------------
-pub(crate) const ERROR_MSG_FORBIDDEN_PATH_ENV_VAR: &str =
+pub const ERROR_MSG_FORBIDDEN_PATH_ENV_VAR: &str =
     "Passing a custom PATH environment variable is forbidden";
+const BUILT_IMAGE_NAME_PREFIX: &str = "avatar-cli.local/";
 
 // Structs, Enums & their Impl blocks:
 // -----------------------------------------------------------------------------
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct ImageBinaryConfig {
+pub struct ImageBinaryConfig {
     path: Option<PathBuf>,
+    args: Option<Vec<String>>, // prepended to the user's arguments
     run_config: Option<OCIContainerRunConfig>,
 }
 
@@ -37,6 +45,10 @@ impl ImageBinaryConfig {
         &self.path
     }
 
+    pub fn get_args(&self) -> &Option<Vec<String>> {
+        &self.args
+    }
+
     pub fn get_run_config(&self) -> &Option<OCIContainerRunConfig> {
         &self.run_config
     }
@@ -44,10 +56,11 @@ impl ImageBinaryConfig {
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct ImageBinaryConfigLock {
+pub struct ImageBinaryConfigLock {
     oci_image_name: String,
     oci_image_hash: String,
     path: PathBuf,
+    args: Option<Vec<String>>,
     run_config: Option<OCIContainerRunConfigLock>,
 }
 
@@ -56,12 +69,14 @@ impl ImageBinaryConfigLock {
         oci_image_name: String,
         oci_image_hash: String,
         path: PathBuf,
+        args: Option<Vec<String>>,
         run_config: Option<OCIContainerRunConfigLock>,
     ) -> ImageBinaryConfigLock {
         ImageBinaryConfigLock {
             oci_image_name,
             oci_image_hash,
             path,
+            args,
             run_config,
         }
     }
@@ -78,19 +93,75 @@ impl ImageBinaryConfigLock {
         &self.path
     }
 
+    pub fn get_args(&self) -> &Option<Vec<String>> {
+        &self.args
+    }
+
     pub fn get_run_config(&self) -> &Option<OCIContainerRunConfigLock> {
         &self.run_config
     }
 }
 
+/// How to build a project image, the paths are relative to the project
+/// directory (and the Dockerfile's path, to the build context)
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct OCIContainerRunConfig {
+pub struct ImageBuildConfig {
+    context: PathBuf,
+    dockerfile: Option<PathBuf>,
+    args: Option<BTreeMap<String, String>>,
+    target: Option<String>,
+}
+
+impl ImageBuildConfig {
+    pub fn get_context(&self) -> &PathBuf {
+        &self.context
+    }
+
+    pub fn get_dockerfile(&self) -> PathBuf {
+        self.dockerfile
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("Dockerfile"))
+    }
+
+    pub fn get_args(&self) -> &Option<BTreeMap<String, String>> {
+        &self.args
+    }
+
+    pub fn get_target(&self) -> &Option<String> {
+        &self.target
+    }
+}
+
+/// Networking modes for the tool containers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// The project network, shared with the services and the other tools
+    #[default]
+    Project,
+    /// The default network of the container engine
+    Default,
+    /// The host network stack
+    Host,
+    /// No networking at all, only the loopback interface
+    None,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OCIContainerRunConfig {
     env: Option<BTreeMap<String, String>>,
     env_from_host: Option<BTreeSet<String>>,
     extra_paths: Option<BTreeSet<PathBuf>>,
     volumes: Option<BTreeMap<PathBuf, VolumeConfig>>, // container path -> volume config
     bindings: Option<BTreeMap<PathBuf, PathBuf>>,     // container path -> host path
+    network: Option<NetworkMode>,
+    extra_hosts: Option<BTreeMap<String, String>>, // host name -> IP address
+    #[serde(default, deserialize_with = "deserialize_ports")]
+    ports: Option<BTreeMap<String, String>>, // host [ip:]port(s) -> container port(s)
+    resources: Option<ResourcesConfig>,
+    security: Option<SecurityConfig>,
 }
 
 impl OCIContainerRunConfig {
@@ -113,16 +184,41 @@ impl OCIContainerRunConfig {
     pub fn get_bindings(&self) -> &Option<BTreeMap<PathBuf, PathBuf>> {
         &self.bindings
     }
+
+    pub fn get_network(&self) -> &Option<NetworkMode> {
+        &self.network
+    }
+
+    pub fn get_extra_hosts(&self) -> &Option<BTreeMap<String, String>> {
+        &self.extra_hosts
+    }
+
+    pub fn get_ports(&self) -> &Option<BTreeMap<String, String>> {
+        &self.ports
+    }
+
+    pub fn get_resources(&self) -> &Option<ResourcesConfig> {
+        &self.resources
+    }
+
+    pub fn get_security(&self) -> &Option<SecurityConfig> {
+        &self.security
+    }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct OCIContainerRunConfigLock {
+pub struct OCIContainerRunConfigLock {
     env: Option<BTreeMap<String, String>>,
     env_from_host: Option<BTreeSet<String>>,
     extra_paths: Option<BTreeSet<PathBuf>>,
     volumes: Option<Vec<VolumeConfigLock>>,
     bindings: Option<BTreeMap<PathBuf, PathBuf>>,
+    network: Option<NetworkMode>,
+    extra_hosts: Option<BTreeMap<String, String>>,
+    ports: Option<BTreeMap<String, String>>,
+    resources: Option<ResourcesConfig>,
+    security: Option<SecurityConfig>,
 }
 
 impl OCIContainerRunConfigLock {
@@ -141,24 +237,54 @@ impl OCIContainerRunConfigLock {
     pub fn get_bindings(&self) -> &Option<BTreeMap<PathBuf, PathBuf>> {
         &self.bindings
     }
+
+    /// The network the container is attached to (the project one by default)
+    pub fn get_network(&self) -> NetworkMode {
+        self.network.unwrap_or_default()
+    }
+
+    pub fn get_extra_hosts(&self) -> &Option<BTreeMap<String, String>> {
+        &self.extra_hosts
+    }
+
+    pub fn get_ports(&self) -> &Option<BTreeMap<String, String>> {
+        &self.ports
+    }
+
+    pub fn get_resources(&self) -> &Option<ResourcesConfig> {
+        &self.resources
+    }
+
+    pub fn get_security(&self) -> &Option<SecurityConfig> {
+        &self.security
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct OCIImageConfig {
+pub struct OCIImageConfig {
     tags: BTreeMap<String, OCIImageTagConfig>, //image tag -> oci image tag config
     run_config: Option<OCIContainerRunConfig>,
+    build: Option<ImageBuildConfig>, // when set, the image is built instead of pulled
 }
 
 impl OCIImageConfig {
     pub fn get_tags(&self) -> &BTreeMap<String, OCIImageTagConfig> {
         &self.tags
     }
+
+    pub fn get_build(&self) -> &Option<ImageBuildConfig> {
+        &self.build
+    }
+
+    pub fn get_run_config(&self) -> &Option<OCIContainerRunConfig> {
+        &self.run_config
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct OCIImageTagConfig {
+pub struct OCIImageTagConfig {
     binaries: Option<BTreeMap<String, ImageBinaryConfig>>,
     run_config: Option<OCIContainerRunConfig>,
 }
@@ -175,33 +301,79 @@ impl OCIImageTagConfig {
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct OCIImageTagConfigLock {
-    hash: String,
+pub struct OCIImageTagConfigLock {
+    hash: String, // the manifest digest, or the image ID for built images
     run_config: Option<OCIContainerRunConfig>,
+    build_hash: Option<String>, // digest of the build context, args & target
 }
 
 impl OCIImageTagConfigLock {
-    pub fn new(hash: String, run_config: Option<OCIContainerRunConfig>) -> OCIImageTagConfigLock {
-        OCIImageTagConfigLock { hash, run_config }
+    pub fn new(
+        hash: String,
+        run_config: Option<OCIContainerRunConfig>,
+        build_hash: Option<String>,
+    ) -> OCIImageTagConfigLock {
+        OCIImageTagConfigLock {
+            hash,
+            run_config,
+            build_hash,
+        }
     }
 
     pub fn get_hash(&self) -> &String {
         &self.hash
     }
 
+    pub fn get_build_hash(&self) -> &Option<String> {
+        &self.build_hash
+    }
+
     pub fn get_run_config(&self) -> &Option<OCIContainerRunConfig> {
         &self.run_config
     }
 }
 
+/// Rules about the images a project is allowed to use
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyConfig {
+    allowed_registries: Option<Vec<String>>, // prefixes of the fully qualified image names
+    forbidden_tags: Option<BTreeSet<String>>,
+    require_lock: Option<bool>,
+}
+
+impl PolicyConfig {
+    pub fn get_allowed_registries(&self) -> &Option<Vec<String>> {
+        &self.allowed_registries
+    }
+
+    pub fn get_forbidden_tags(&self) -> &Option<BTreeSet<String>> {
+        &self.forbidden_tags
+    }
+
+    pub fn get_require_lock(&self) -> bool {
+        self.require_lock.unwrap_or(false)
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct ProjectConfig {
+pub struct ProjectConfig {
     avatar_version: String,
     project_internal_id: String,
+    container_engine: Option<ContainerEngineKind>,
     run_config: Option<OCIContainerRunConfig>,
     shell_config: Option<ShellConfig>,
     images: Option<BTreeMap<String, OCIImageConfig>>, // image name -> "tags" -> image tag -> oci image tag config
+    services: Option<BTreeMap<String, ServiceConfig>>, // service name -> service config
+    tasks: Option<BTreeMap<String, TaskConfig>>,      // task name -> task config
+    policy: Option<PolicyConfig>,
+}
+
+impl Default for ProjectConfig {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ProjectConfig {
@@ -210,13 +382,25 @@ impl ProjectConfig {
 
         ProjectConfig {
             avatar_version: AVATAR_CLI_VERSION.to_string(),
+            container_engine: None,
             run_config: None,
             shell_config: None,
             project_internal_id: prj_internal_id,
             images: None,
+            services: None,
+            tasks: None,
+            policy: None,
         }
     }
 
+    pub fn get_container_engine(&self) -> &Option<ContainerEngineKind> {
+        &self.container_engine
+    }
+
+    pub fn get_run_config(&self) -> &Option<OCIContainerRunConfig> {
+        &self.run_config
+    }
+
     pub fn get_shell_config(&self) -> &Option<ShellConfig> {
         &self.shell_config
     }
@@ -228,17 +412,42 @@ impl ProjectConfig {
     pub fn get_images(&self) -> &Option<BTreeMap<String, OCIImageConfig>> {
         &self.images
     }
+
+    pub fn get_services(&self) -> &Option<BTreeMap<String, ServiceConfig>> {
+        &self.services
+    }
+
+    pub fn get_tasks(&self) -> &Option<BTreeMap<String, TaskConfig>> {
+        &self.tasks
+    }
+
+    pub fn get_policy(&self) -> &Option<PolicyConfig> {
+        &self.policy
+    }
+
+    pub fn get_binary_names(&self) -> BTreeSet<&String> {
+        self.images
+            .iter()
+            .flat_map(|images| images.values())
+            .flat_map(|image_config| image_config.get_tags().values())
+            .filter_map(|tag_config| tag_config.get_binaries().as_ref())
+            .flat_map(|binaries| binaries.keys())
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct ProjectConfigLock {
+pub struct ProjectConfigLock {
+    lock_version: u64,
     #[serde(with = "hex")]
     project_config_hash: Vec<u8>,
     project_internal_id: String,
+    container_engine: Option<ContainerEngineKind>,
     shell_config: Option<ShellConfig>,
     images: BTreeMap<String, BTreeMap<String, OCIImageTagConfigLock>>, // image_name -> image_tag -> image config & hash
     binaries: BTreeMap<String, ImageBinaryConfigLock>,
+    services: Option<BTreeMap<String, ServiceConfigLock>>,
 }
 
 impl ProjectConfigLock {
@@ -255,6 +464,10 @@ impl ProjectConfigLock {
         &self.project_internal_id
     }
 
+    pub fn get_container_engine(&self) -> &Option<ContainerEngineKind> {
+        &self.container_engine
+    }
+
     pub fn get_shell_config(&self) -> &Option<ShellConfig> {
         &self.shell_config
     }
@@ -279,31 +492,166 @@ impl ProjectConfigLock {
         self.binaries.iter()
     }
 
+    pub fn get_services(&self) -> &Option<BTreeMap<String, ServiceConfigLock>> {
+        &self.services
+    }
+
     pub fn new(
         project_config_hash: Vec<u8>,
         project_internal_id: String,
+        container_engine: Option<ContainerEngineKind>,
         shell_config: Option<ShellConfig>,
         images: BTreeMap<String, BTreeMap<String, OCIImageTagConfigLock>>,
         binaries: BTreeMap<String, ImageBinaryConfigLock>,
+        services: Option<BTreeMap<String, ServiceConfigLock>>,
     ) -> ProjectConfigLock {
         ProjectConfigLock {
+            lock_version: LOCK_VERSION,
             project_config_hash,
             project_internal_id,
+            container_engine,
             shell_config,
             images,
             binaries,
+            services,
         }
     }
 }
 
+/// Limits applied to the containers, the sizes use the container engine syntax
+/// (a number, optionally followed by `b`, `k`, `m` or `g`)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourcesConfig {
+    memory: Option<String>,
+    cpus: Option<f64>,
+    pids: Option<i64>,
+    shm_size: Option<String>,
+    ulimits: Option<BTreeMap<String, UlimitConfig>>, // ulimit name -> limits
+}
+
+impl ResourcesConfig {
+    pub fn get_memory(&self) -> &Option<String> {
+        &self.memory
+    }
+
+    pub fn get_cpus(&self) -> &Option<f64> {
+        &self.cpus
+    }
+
+    pub fn get_pids(&self) -> &Option<i64> {
+        &self.pids
+    }
+
+    pub fn get_shm_size(&self) -> &Option<String> {
+        &self.shm_size
+    }
+
+    pub fn get_ulimits(&self) -> &Option<BTreeMap<String, UlimitConfig>> {
+        &self.ulimits
+    }
+}
+
+/// Hardening options for the containers
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct ShellConfig {
+pub struct SecurityConfig {
+    cap_drop: Option<BTreeSet<String>>,
+    cap_add: Option<BTreeSet<String>>,
+    read_only_rootfs: Option<bool>,
+    no_new_privileges: Option<bool>,
+    seccomp_profile: Option<PathBuf>, // relative paths start at the project directory
+}
+
+impl SecurityConfig {
+    pub fn get_cap_drop(&self) -> &Option<BTreeSet<String>> {
+        &self.cap_drop
+    }
+
+    pub fn get_cap_add(&self) -> &Option<BTreeSet<String>> {
+        &self.cap_add
+    }
+
+    pub fn get_read_only_rootfs(&self) -> bool {
+        self.read_only_rootfs.unwrap_or(false)
+    }
+
+    pub fn get_no_new_privileges(&self) -> bool {
+        self.no_new_privileges.unwrap_or(false)
+    }
+
+    pub fn get_seccomp_profile(&self) -> &Option<PathBuf> {
+        &self.seccomp_profile
+    }
+}
+
+/// Long running containers (like databases) used by the project tools
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceConfig {
+    image: String,
+    tag: String,
+    env: Option<BTreeMap<String, String>>,
+    volumes: Option<BTreeMap<PathBuf, VolumeConfig>>, // container path -> volume config
+    #[serde(default, deserialize_with = "deserialize_ports")]
+    ports: Option<BTreeMap<String, String>>, // host [ip:]port(s) -> container port(s)
+}
+
+impl ServiceConfig {
+    pub fn get_image(&self) -> &String {
+        &self.image
+    }
+
+    pub fn get_tag(&self) -> &String {
+        &self.tag
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceConfigLock {
+    oci_image_name: String,
+    oci_image_hash: String,
+    env: Option<BTreeMap<String, String>>,
+    volumes: Option<Vec<VolumeConfigLock>>,
+    ports: Option<BTreeMap<String, String>>,
+}
+
+impl ServiceConfigLock {
+    pub fn get_oci_image_name(&self) -> &String {
+        &self.oci_image_name
+    }
+
+    pub fn get_oci_image_hash(&self) -> &String {
+        &self.oci_image_hash
+    }
+
+    pub fn get_env(&self) -> &Option<BTreeMap<String, String>> {
+        &self.env
+    }
+
+    pub fn get_volumes(&self) -> &Option<Vec<VolumeConfigLock>> {
+        &self.volumes
+    }
+
+    pub fn get_ports(&self) -> &Option<BTreeMap<String, String>> {
+        &self.ports
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShellConfig {
     env: Option<BTreeMap<String, String>>,
     extra_paths: Option<BTreeSet<PathBuf>>,
+    start_services: Option<bool>, // started on `avatar shell` entry, stopped on exit
 }
 
 impl ShellConfig {
+    pub fn get_start_services(&self) -> bool {
+        self.start_services.unwrap_or(false)
+    }
+
     pub fn get_env(&self) -> &Option<BTreeMap<String, String>> {
         &self.env
     }
@@ -315,7 +663,69 @@ impl ShellConfig {
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct VolumeConfig {
+pub struct TaskConfig {
+    depends_on: Option<Vec<String>>, // tasks executed before this one
+    env: Option<BTreeMap<String, String>>,
+    steps: Option<Vec<TaskStepConfig>>,
+}
+
+impl TaskConfig {
+    pub fn get_depends_on(&self) -> &Option<Vec<String>> {
+        &self.depends_on
+    }
+
+    pub fn get_env(&self) -> &Option<BTreeMap<String, String>> {
+        &self.env
+    }
+
+    pub fn get_steps(&self) -> &Option<Vec<TaskStepConfig>> {
+        &self.steps
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskStepConfig {
+    bin: String,
+    args: Option<Vec<String>>,
+    env: Option<BTreeMap<String, String>>, // overrides the task's env
+}
+
+impl TaskStepConfig {
+    pub fn get_bin(&self) -> &String {
+        &self.bin
+    }
+
+    pub fn get_args(&self) -> &Option<Vec<String>> {
+        &self.args
+    }
+
+    pub fn get_env(&self) -> &Option<BTreeMap<String, String>> {
+        &self.env
+    }
+}
+
+/// A single value sets both the soft and hard limits
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UlimitConfig {
+    Limit(i64),
+    Range { soft: i64, hard: i64 },
+}
+
+impl UlimitConfig {
+    /// The value passed to the `--ulimit` option of the container engine
+    pub fn get_ulimit_arg(&self, ulimit_name: &str) -> String {
+        match self {
+            UlimitConfig::Limit(limit) => format!("{}={}", ulimit_name, limit),
+            UlimitConfig::Range { soft, hard } => format!("{}={}:{}", ulimit_name, soft, hard),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeConfig {
     name: Option<String>,
     #[serde(default = "VolumeScope::default")]
     scope: VolumeScope,
@@ -323,7 +733,7 @@ pub(crate) struct VolumeConfig {
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct VolumeConfigLock {
+pub struct VolumeConfigLock {
     container_path: PathBuf,
     volume_name: String,
 }
@@ -357,25 +767,225 @@ impl VolumeScope {
 fn check_against_forbidden_path_var(
     shell_config: &ShellConfig,
     run_config_lock: &OCIContainerRunConfigLock,
-) {
+) -> AvatarResult<()> {
+    let forbidden_path_error =
+        || AvatarError::InvalidConfig(ERROR_MSG_FORBIDDEN_PATH_ENV_VAR.to_string());
+
     if let Some(_shell_env) = &shell_config.env {
         if _shell_env.contains_key("PATH") {
-            eprintln!("{}", ERROR_MSG_FORBIDDEN_PATH_ENV_VAR);
-            exit(exitcode::USAGE)
+            return Err(forbidden_path_error());
         }
     }
     if let Some(_env) = &run_config_lock.env {
         if _env.contains_key("PATH") {
-            eprintln!("{}", ERROR_MSG_FORBIDDEN_PATH_ENV_VAR);
-            exit(exitcode::USAGE)
+            return Err(forbidden_path_error());
         }
     }
     if let Some(_env_from_host) = &run_config_lock.env_from_host {
         if _env_from_host.contains("PATH") {
-            eprintln!("{}", ERROR_MSG_FORBIDDEN_PATH_ENV_VAR);
-            exit(exitcode::USAGE)
+            return Err(forbidden_path_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Adding and dropping the same capability at the same level is ambiguous, so
+/// it's refused (more specific levels can still override inherited ones).
+fn check_capabilities(config: &ProjectConfig, config_filepath: &Path) -> AvatarResult<()> {
+    let mut run_configs: Vec<(String, &Option<OCIContainerRunConfig>)> =
+        vec![("the project runConfig".to_string(), config.get_run_config())];
+    for (image_name, image_config) in config.get_images().iter().flatten() {
+        run_configs.push((
+            format!("the runConfig of the image '{}'", image_name),
+            image_config.get_run_config(),
+        ));
+        for (image_tag, tag_config) in image_config.get_tags() {
+            run_configs.push((
+                format!(
+                    "the runConfig of the image tag '{}:{}'",
+                    image_name, image_tag
+                ),
+                tag_config.get_run_config(),
+            ));
+            for (binary_name, binary_config) in tag_config.get_binaries().iter().flatten() {
+                run_configs.push((
+                    format!("the runConfig of the binary '{}'", binary_name),
+                    binary_config.get_run_config(),
+                ));
+            }
+        }
+    }
+
+    for (owner, run_config) in run_configs {
+        if let Err(capability) = check_run_config_capabilities(run_config) {
+            return Err(AvatarError::InvalidConfig(format!(
+                "The capability '{}' is both added and dropped in {}, in '{}'",
+                capability,
+                owner,
+                config_filepath.display()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_extra_hosts(run_config: &OCIContainerRunConfig, binary_name: &str) -> AvatarResult<()> {
+    for (host_name, ip_address) in run_config.extra_hosts.iter().flatten() {
+        // "host-gateway" is resolved by the container engine to the host's IP
+        if ip_address != "host-gateway" && ip_address.parse::<IpAddr>().is_err() {
+            return Err(AvatarError::InvalidConfig(format!(
+                "Invalid IP address '{}' for the extra host '{}' of the binary '{}'",
+                ip_address, host_name, binary_name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_port_mappings(ports: &BTreeMap<String, String>, owner: &str) -> AvatarResult<()> {
+    for (host_spec, container_spec) in ports {
+        if let Err(e) = PortMapping::parse(host_spec, container_spec) {
+            return Err(AvatarError::InvalidConfig(format!(
+                "Invalid port mapping '{}: {}' for {}: {}",
+                host_spec, container_spec, owner, e
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_ports(run_config: &OCIContainerRunConfig, binary_name: &str) -> AvatarResult<()> {
+    let ports = match &run_config.ports {
+        Some(ports) if !ports.is_empty() => ports,
+        _ => return Ok(()),
+    };
+
+    let network_mode = match run_config.network.unwrap_or_default() {
+        NetworkMode::Project | NetworkMode::Default => None,
+        NetworkMode::Host => Some("host"),
+        NetworkMode::None => Some("none"),
+    };
+    if let Some(network_mode) = network_mode {
+        return Err(AvatarError::InvalidConfig(format!(
+            "The binary '{}' publishes ports, but its network mode is '{}'. Ports can only be published from the project or default networks",
+            binary_name, network_mode
+        )));
+    }
+
+    check_port_mappings(ports, &format!("the binary '{}'", binary_name))
+}
+
+fn check_resources(run_config: &OCIContainerRunConfig, binary_name: &str) -> AvatarResult<()> {
+    let resources = match &run_config.resources {
+        Some(resources) => resources,
+        None => return Ok(()),
+    };
+
+    let invalid_resource_error = |setting: &str, value: &dyn std::fmt::Display| {
+        AvatarError::InvalidConfig(format!(
+            "Invalid value '{}' for resources.{} of the binary '{}'",
+            value, setting, binary_name
+        ))
+    };
+
+    for (setting, size) in [
+        ("memory", &resources.memory),
+        ("shmSize", &resources.shm_size),
+    ] {
+        if let Some(size) = size {
+            if !is_valid_size(size) {
+                return Err(invalid_resource_error(setting, size));
+            }
+        }
+    }
+    if let Some(cpus) = resources.cpus {
+        if cpus.is_nan() || cpus <= 0.0 {
+            return Err(invalid_resource_error("cpus", &cpus));
+        }
+    }
+    if let Some(pids) = resources.pids {
+        // -1 means unlimited
+        if pids == 0 || pids < -1 {
+            return Err(invalid_resource_error("pids", &pids));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the first capability that appears in both `capAdd` and `capDrop`
+fn check_run_config_capabilities(run_config: &Option<OCIContainerRunConfig>) -> Result<(), String> {
+    let security = match run_config.as_ref().and_then(|c| c.security.as_ref()) {
+        Some(security) => security,
+        None => return Ok(()),
+    };
+
+    let dropped_keys: BTreeSet<String> = security
+        .cap_drop
+        .iter()
+        .flatten()
+        .map(|capability| get_capability_key(capability))
+        .collect();
+    match security
+        .cap_add
+        .iter()
+        .flatten()
+        .find(|capability| dropped_keys.contains(&get_capability_key(capability)))
+    {
+        Some(capability) => Err(capability.clone()),
+        None => Ok(()),
+    }
+}
+
+fn check_tasks(config: &ProjectConfig, config_filepath: &Path) -> AvatarResult<()> {
+    let tasks = match config.get_tasks() {
+        Some(tasks) => tasks,
+        None => return Ok(()),
+    };
+    let binary_names = config.get_binary_names();
+
+    for (task_name, task_config) in tasks {
+        let steps = task_config.get_steps().as_deref().unwrap_or_default();
+        if steps.is_empty() && task_config.get_depends_on().is_none() {
+            return Err(AvatarError::InvalidConfig(format!(
+                "The task '{}' in '{}' has no steps nor dependencies",
+                task_name,
+                config_filepath.display()
+            )));
+        }
+
+        for step in steps {
+            if !binary_names.contains(step.get_bin()) {
+                return Err(AvatarError::InvalidConfig(format!(
+                    "The task '{}' in '{}' uses the binary '{}', which is not declared in any image",
+                    task_name,
+                    config_filepath.display(),
+                    step.get_bin()
+                )));
+            }
+        }
+
+        for env in task_config
+            .get_env()
+            .iter()
+            .chain(steps.iter().filter_map(|step| step.get_env().as_ref()))
+        {
+            if env.contains_key("PATH") {
+                return Err(AvatarError::InvalidConfig(
+                    ERROR_MSG_FORBIDDEN_PATH_ENV_VAR.to_string(),
+                ));
+            }
         }
+
+        // Detects missing dependencies and dependency cycles
+        get_task_execution_order(tasks, task_name)?;
     }
+
+    Ok(())
 }
 
 fn customize_oci_image_path_env_var(
@@ -390,39 +1000,98 @@ fn customize_oci_image_path_env_var(
         .map(|p| playground_path.join(p))
         .collect::<Vec<PathBuf>>()
         .iter()
-        .map(|p| p.to_str())
-        .filter(|p| p.is_some())
-        .map(|p| p.unwrap())
+        .filter_map(|p| p.to_str())
         .collect::<Vec<&str>>()
         .join(":");
 
     format!("{}:{}", filtered_extra_paths, oci_image_path)
 }
 
+fn generate_run_config_lock(
+    run_config: &Option<OCIContainerRunConfig>,
+    project_internal_id: &str,
+    image_ref: &str,
+    binary_name: &str,
+) -> AvatarResult<Option<OCIContainerRunConfigLock>> {
+    run_config
+        .as_ref()
+        .map(|_run_config| {
+            check_extra_hosts(_run_config, binary_name)?;
+            check_ports(_run_config, binary_name)?;
+            check_resources(_run_config, binary_name)?;
+
+            Ok(OCIContainerRunConfigLock {
+                bindings: _run_config.bindings.clone(),
+                volumes: generate_volume_config_lock(
+                    &_run_config.volumes,
+                    project_internal_id,
+                    image_ref,
+                    binary_name,
+                )?,
+                env: _run_config.env.clone(),
+                env_from_host: _run_config.env_from_host.clone(),
+                extra_paths: _run_config.extra_paths.clone(),
+                network: _run_config.network,
+                extra_hosts: _run_config.extra_hosts.clone(),
+                ports: _run_config.ports.clone(),
+                resources: _run_config.resources.clone(),
+                security: _run_config.security.clone(),
+            })
+        })
+        .transpose()
+}
+
+pub fn generate_service_config_lock(
+    service_name: &str,
+    service_config: &ServiceConfig,
+    project_internal_id: &str,
+    image_name: &str,
+    image_hash: &str,
+) -> AvatarResult<ServiceConfigLock> {
+    if let Some(ports) = &service_config.ports {
+        check_port_mappings(ports, &format!("the service '{}'", service_name))?;
+    }
+
+    Ok(ServiceConfigLock {
+        oci_image_name: image_name.to_string(),
+        oci_image_hash: image_hash.to_string(),
+        env: service_config.env.clone(),
+        volumes: generate_volume_config_lock(
+            &service_config.volumes,
+            project_internal_id,
+            &format!("{}-{}", service_config.image, service_config.tag),
+            service_name,
+        )?,
+        ports: service_config.ports.clone(),
+    })
+}
+
 fn generate_volume_config_lock(
     image_volume_configs: &Option<BTreeMap<PathBuf, VolumeConfig>>,
     project_internal_id: &str,
     image_ref: &str,
     binary_name: &str,
-) -> Option<Vec<VolumeConfigLock>> {
-    match image_volume_configs {
-        Some(_src_volume_config) => Some(
+) -> AvatarResult<Option<Vec<VolumeConfigLock>>> {
+    image_volume_configs
+        .as_ref()
+        .map(|_src_volume_config| {
             _src_volume_config
                 .iter()
-                .map(|(container_path, volume_config)| VolumeConfigLock {
-                    container_path: container_path.clone(),
-                    volume_name: generate_volume_name(
-                        project_internal_id,
-                        image_ref,
-                        binary_name,
-                        volume_config,
-                        container_path,
-                    ),
+                .map(|(container_path, volume_config)| {
+                    Ok(VolumeConfigLock {
+                        container_path: container_path.clone(),
+                        volume_name: generate_volume_name(
+                            project_internal_id,
+                            image_ref,
+                            binary_name,
+                            volume_config,
+                            container_path,
+                        )?,
+                    })
                 })
-                .collect(),
-        ),
-        None => Option::<Vec<VolumeConfigLock>>::None,
-    }
+                .collect()
+        })
+        .transpose()
 }
 
 fn generate_volume_name(
@@ -430,25 +1099,24 @@ fn generate_volume_name(
     image_ref: &str,
     binary_name: &str,
     volume_config: &VolumeConfig,
-    container_path: &PathBuf,
-) -> String {
+    container_path: &Path,
+) -> AvatarResult<String> {
     match &volume_config.name {
-        Some(volume_name) => volume_name.clone(),
+        Some(volume_name) => Ok(volume_name.clone()),
         None => {
             let container_path_bytes = match container_path.to_str() {
                 Some(cp) => cp.as_bytes(),
                 None => {
-                    eprintln!(
+                    return Err(AvatarError::InvalidConfig(format!(
                         "The volume container path {} can't be properly converted to utf8 string",
                         container_path.to_string_lossy()
-                    );
-                    exit(exitcode::USAGE)
+                    )))
                 }
             };
-            let path_hash = digest(&SHA256, &container_path_bytes);
+            let path_hash = digest(&SHA256, container_path_bytes);
             let path_hash = hex::encode(&path_hash.as_ref()[0..16]);
 
-            match volume_config.scope {
+            Ok(match volume_config.scope {
                 VolumeScope::Project => format!("prj_{}_{}", project_internal_id, path_hash),
                 VolumeScope::OCIImage => format!(
                     "img_{}_{}_{}",
@@ -458,102 +1126,148 @@ fn generate_volume_name(
                 ),
                 VolumeScope::Binary => format!(
                     "bin_{}_{}_{}_{}",
-                    project_internal_id, image_ref, binary_name, path_hash
+                    project_internal_id,
+                    image_ref.replace('/', "."),
+                    binary_name,
+                    path_hash
                 ),
-            }
+            })
         }
     }
 }
 
-pub(crate) fn get_config(config_filepath: &PathBuf) -> (ProjectConfig, Digest) {
-    let config_bytes = get_file_bytes(config_filepath);
+/// Capabilities can be written in any case, with or without the `CAP_` prefix
+fn get_capability_key(capability: &str) -> String {
+    let capability = capability.trim().to_uppercase();
+    match capability.strip_prefix("CAP_") {
+        Some(capability) => capability.to_string(),
+        None => capability,
+    }
+}
 
-    (
-        match serde_yaml::from_slice::<ProjectConfig>(&config_bytes) {
-            Ok(_config) => _config,
-            Err(e) => {
-                let error_msg = match e.location() {
-                    Some(l) => format!(
-                        "Malformed config file '{}', line {}, column {}:\n\t{}",
-                        config_filepath.display(),
-                        l.line(),
-                        l.column(),
-                        e.to_string(),
-                    ),
-                    None => format!(
-                        "Malformed config file '{}':\n\t{}",
-                        config_filepath.display(),
-                        e.to_string(),
-                    ),
-                };
+pub fn get_config(config_filepath: &Path) -> AvatarResult<(ProjectConfig, Digest)> {
+    let config_bytes = get_file_bytes(config_filepath)?;
 
-                eprintln!("{}", error_msg);
-                exit(exitcode::DATAERR)
-            }
-        },
+    Ok((
+        parse_config(&config_bytes, config_filepath)?,
         digest(&SHA256, &config_bytes),
-    )
+    ))
 }
 
-pub(crate) fn get_config_lock(config_lock_filepath: &PathBuf) -> (ProjectConfigLock, Digest) {
-    let config_lock_bytes = get_file_bytes(config_lock_filepath);
-
-    (
-        match serde_yaml::from_slice::<ProjectConfigLock>(&config_lock_bytes) {
-            Ok(_config_lock) => _config_lock,
-            Err(e) => {
-                let error_msg = match e.location() {
-                    Some(l) => format!(
-                        "Malformed lock file '{}', line {}, column {}:\n\t{}",
-                        config_lock_filepath.display(),
-                        l.line(),
-                        l.column(),
-                        e.to_string(),
-                    ),
-                    None => format!(
-                        "Malformed lock file '{}':\n\t{}",
-                        config_lock_filepath.display(),
-                        e.to_string(),
-                    ),
-                };
+pub fn get_config_lock(config_lock_filepath: &Path) -> AvatarResult<(ProjectConfigLock, Digest)> {
+    let config_lock_bytes = get_file_bytes(config_lock_filepath)?;
 
-                eprintln!("{}", error_msg);
-                exit(exitcode::DATAERR)
-            }
-        },
+    Ok((
+        parse_config_lock(&config_lock_bytes, config_lock_filepath)?,
         digest(&SHA256, &config_lock_bytes),
-    )
+    ))
 }
 
-fn get_file_bytes(filepath: &PathBuf) -> Vec<u8> {
+pub fn get_file_bytes(filepath: &Path) -> AvatarResult<Vec<u8>> {
     if !filepath.exists() || !filepath.is_file() {
-        eprintln!("The file {} is not available", &filepath.display());
-        exit(exitcode::NOINPUT)
+        return Err(AvatarError::MissingFile(format!(
+            "The file {} is not available",
+            &filepath.display()
+        )));
     }
 
-    match read(filepath) {
-        Ok(s) => s,
-        Err(e) => match e.kind() {
-            ErrorKind::NotFound => {
-                eprintln!("The file {} is not available", filepath.display());
-                exit(exitcode::NOINPUT)
-            }
-            ErrorKind::PermissionDenied => {
-                eprintln!(
-                    "The file {} is not readable due to filesystem permissions",
-                    filepath.display()
-                );
-                exit(exitcode::IOERR)
-            }
-            _ => {
-                eprintln!(
-                    "Unknown IO error while reading the file {}",
-                    filepath.display()
-                );
-                exit(exitcode::IOERR)
+    read(filepath).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => {
+            AvatarError::MissingFile(format!("The file {} is not available", filepath.display()))
+        }
+        ErrorKind::PermissionDenied => AvatarError::Io(format!(
+            "The file {} is not readable due to filesystem permissions",
+            filepath.display()
+        )),
+        _ => AvatarError::Io(format!(
+            "Unknown IO error while reading the file {}",
+            filepath.display()
+        )),
+    })
+}
+
+/// Built images don't have repository digests, they're referenced by their IDs
+pub fn get_image_ref(image_name: &str, image_hash: &str) -> String {
+    match is_built_image_name(image_name) {
+        true => format!("sha256:{}", image_hash),
+        false => format!("{}@sha256:{}", image_name, image_hash),
+    }
+}
+
+/// The name of the network shared by the project services and tools
+pub fn get_project_network_name(project_internal_id: &str) -> String {
+    format!("prj_{}", project_internal_id)
+}
+
+/// Images declared with a `build` section are tagged (and locked) under
+/// project-scoped names, so they never clash with registry images
+pub fn get_built_image_name(project_internal_id: &str, image_name: &str) -> String {
+    format!(
+        "{}{}/{}",
+        BUILT_IMAGE_NAME_PREFIX, project_internal_id, image_name
+    )
+}
+
+/// Returns the names of the tasks to execute (in order) to run the requested
+/// one, each task appearing only once, after all its dependencies.
+pub fn get_task_execution_order<'a>(
+    tasks: &'a BTreeMap<String, TaskConfig>,
+    task_name: &'a str,
+) -> AvatarResult<Vec<&'a str>> {
+    fn visit<'a>(
+        tasks: &'a BTreeMap<String, TaskConfig>,
+        task_name: &'a str,
+        visiting: &mut Vec<&'a str>,
+        order: &mut Vec<&'a str>,
+    ) -> AvatarResult<()> {
+        if order.contains(&task_name) {
+            return Ok(());
+        }
+        if let Some(cycle_start) = visiting.iter().position(|name| *name == task_name) {
+            return Err(AvatarError::InvalidConfig(format!(
+                "Found a dependency cycle between tasks: {} → {}",
+                visiting[cycle_start..].join(" → "),
+                task_name
+            )));
+        }
+
+        let task_config = match tasks.get(task_name) {
+            Some(task_config) => task_config,
+            None => {
+                return Err(AvatarError::InvalidConfig(match visiting.last() {
+                    Some(parent_name) => format!(
+                        "The task '{}' depends on '{}', which is not defined",
+                        parent_name, task_name
+                    ),
+                    None => format!("The task '{}' is not defined", task_name),
+                }))
             }
-        },
+        };
+
+        visiting.push(task_name);
+        for dependency_name in task_config.get_depends_on().iter().flatten() {
+            visit(tasks, dependency_name, visiting, order)?;
+        }
+        visiting.pop();
+
+        order.push(task_name);
+        Ok(())
     }
+
+    let mut order: Vec<&str> = Vec::new();
+    visit(tasks, task_name, &mut Vec::new(), &mut order)?;
+    Ok(order)
+}
+
+pub fn is_built_image_name(image_name: &str) -> bool {
+    image_name.starts_with(BUILT_IMAGE_NAME_PREFIX)
+}
+
+fn is_valid_size(size: &str) -> bool {
+    let digits = size.trim_end_matches(|c: char| "bBkKmMgG".contains(c));
+    size.len() - digits.len() <= 1
+        && !digits.is_empty()
+        && digits.chars().all(|c| c.is_ascii_digit())
 }
 
 fn merge_bindings(
@@ -575,6 +1289,45 @@ fn merge_bindings(
     }
 }
 
+/// The capabilities of a more specific level also cancel the inherited ones of
+/// the opposite set (e.g. a binary's `capAdd` removes an image's `capDrop`
+/// entry for the same capability, and `capDrop: [ALL]` removes every inherited
+/// `capAdd` entry).
+fn merge_capabilities(
+    base_capabilities: &Option<BTreeSet<String>>,
+    new_capabilities: &Option<BTreeSet<String>>,
+    new_opposite_capabilities: &Option<BTreeSet<String>>,
+) -> Option<BTreeSet<String>> {
+    let cancelled_keys: BTreeSet<String> = new_opposite_capabilities
+        .iter()
+        .flatten()
+        .map(|capability| get_capability_key(capability))
+        .collect();
+    let inherited_capabilities = base_capabilities.as_ref().map(|_base_capabilities| {
+        _base_capabilities
+            .iter()
+            .filter(|capability| {
+                !cancelled_keys.contains("ALL")
+                    && !cancelled_keys.contains(&get_capability_key(capability))
+            })
+            .cloned()
+            .collect::<BTreeSet<String>>()
+    });
+
+    match inherited_capabilities {
+        Some(_inherited_capabilities) => match new_capabilities {
+            Some(_new_capabilities) => Some(
+                _inherited_capabilities
+                    .union(_new_capabilities)
+                    .cloned()
+                    .collect(),
+            ),
+            None => Some(_inherited_capabilities),
+        },
+        None => new_capabilities.clone(),
+    }
+}
+
 fn merge_envs(
     base_env: &Option<BTreeMap<String, String>>,
     new_env: &Option<BTreeMap<String, String>>,
@@ -607,6 +1360,25 @@ fn merge_envs_from_host(
     }
 }
 
+fn merge_extra_hosts(
+    base_extra_hosts: &Option<BTreeMap<String, String>>,
+    new_extra_hosts: &Option<BTreeMap<String, String>>,
+) -> Option<BTreeMap<String, String>> {
+    match base_extra_hosts {
+        Some(_base_extra_hosts) => match new_extra_hosts {
+            Some(_new_extra_hosts) => {
+                let mut merged_extra_hosts = _base_extra_hosts.clone();
+                for (host_name, ip_address) in _new_extra_hosts {
+                    merged_extra_hosts.insert(host_name.clone(), ip_address.clone());
+                }
+                Some(merged_extra_hosts)
+            }
+            None => base_extra_hosts.clone(),
+        },
+        None => new_extra_hosts.clone(),
+    }
+}
+
 fn merge_extra_paths(
     base_extra_paths: &Option<BTreeSet<PathBuf>>,
     new_extra_paths: &Option<BTreeSet<PathBuf>>,
@@ -622,37 +1394,87 @@ fn merge_extra_paths(
     }
 }
 
-pub(crate) fn merge_run_and_shell_configs(
-    base_config: &Option<OCIContainerRunConfig>,
-    new_config: &Option<OCIContainerRunConfig>,
+fn merge_ports(
+    base_ports: &Option<BTreeMap<String, String>>,
+    new_ports: &Option<BTreeMap<String, String>>,
+) -> Option<BTreeMap<String, String>> {
+    match base_ports {
+        Some(_base_ports) => match new_ports {
+            Some(_new_ports) => {
+                let mut merged_ports = _base_ports.clone();
+                for (host_spec, container_spec) in _new_ports {
+                    merged_ports.insert(host_spec.clone(), container_spec.clone());
+                }
+                Some(merged_ports)
+            }
+            None => base_ports.clone(),
+        },
+        None => new_ports.clone(),
+    }
+}
+
+fn merge_resources(
+    base_resources: &Option<ResourcesConfig>,
+    new_resources: &Option<ResourcesConfig>,
+) -> Option<ResourcesConfig> {
+    match base_resources {
+        Some(_base_resources) => match new_resources {
+            Some(_new_resources) => Some(ResourcesConfig {
+                memory: _new_resources
+                    .memory
+                    .clone()
+                    .or_else(|| _base_resources.memory.clone()),
+                cpus: _new_resources.cpus.or(_base_resources.cpus),
+                pids: _new_resources.pids.or(_base_resources.pids),
+                shm_size: _new_resources
+                    .shm_size
+                    .clone()
+                    .or_else(|| _base_resources.shm_size.clone()),
+                ulimits: match (&_base_resources.ulimits, &_new_resources.ulimits) {
+                    (Some(_base_ulimits), Some(_new_ulimits)) => {
+                        let mut merged_ulimits = _base_ulimits.clone();
+                        merged_ulimits.extend(_new_ulimits.clone());
+                        Some(merged_ulimits)
+                    }
+                    (_base_ulimits, None) => _base_ulimits.clone(),
+                    (None, _new_ulimits) => _new_ulimits.clone(),
+                },
+            }),
+            None => base_resources.clone(),
+        },
+        None => new_resources.clone(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn merge_run_and_shell_configs(
+    engine: &dyn ContainerEngine,
+    run_config: &Option<OCIContainerRunConfig>,
     shell_config: &Option<ShellConfig>,
     project_internal_id: &str,
     image_name: &str,
     image_tag: &str,
     image_hash: &str,
     binary_name: &str,
-) -> Option<OCIContainerRunConfigLock> {
-    let mut merged_run_config = merge_run_configs(
-        base_config,
-        new_config,
+) -> AvatarResult<Option<OCIContainerRunConfigLock>> {
+    let mut merged_run_config = generate_run_config_lock(
+        run_config,
         project_internal_id,
-        image_name,
-        image_tag,
+        &format!("{}-{}", image_name, image_tag),
         binary_name,
-    );
+    )?;
 
-    match shell_config {
+    let merged_run_config = match shell_config {
         Some(_shell_config) => match &mut merged_run_config {
             Some(_merged_run_config) => {
-                check_against_forbidden_path_var(_shell_config, _merged_run_config);
+                check_against_forbidden_path_var(_shell_config, _merged_run_config)?;
 
                 _merged_run_config.env = merge_envs(&_shell_config.env, &_merged_run_config.env);
 
                 if let Some(_extra_paths) = &_shell_config.extra_paths {
-                    if let Some(oci_image_path) = get_path_env_var_from_oci_image(&format!(
-                        "{}@sha256:{}",
-                        image_name, image_hash
-                    )) {
+                    if let Some(oci_image_path) =
+                        engine.get_image_env_var(&get_image_ref(image_name, image_hash), "PATH")?
+                    {
                         let customized_path =
                             customize_oci_image_path_env_var(&oci_image_path, _extra_paths);
 
@@ -674,17 +1496,17 @@ pub(crate) fn merge_run_and_shell_configs(
             None => {
                 if let Some(_shell_env) = &_shell_config.env {
                     if _shell_env.contains_key("PATH") {
-                        eprintln!("{}", ERROR_MSG_FORBIDDEN_PATH_ENV_VAR);
-                        exit(exitcode::USAGE)
+                        return Err(AvatarError::InvalidConfig(
+                            ERROR_MSG_FORBIDDEN_PATH_ENV_VAR.to_string(),
+                        ));
                     }
                 }
 
                 let _env = match &_shell_config.extra_paths {
                     Some(_extra_paths) => {
-                        if let Some(oci_image_path) = get_path_env_var_from_oci_image(&format!(
-                            "{}@sha256:{}",
-                            image_name, image_hash
-                        )) {
+                        if let Some(oci_image_path) = engine
+                            .get_image_env_var(&get_image_ref(image_name, image_hash), "PATH")?
+                        {
                             let customized_path =
                                 customize_oci_image_path_env_var(&oci_image_path, _extra_paths);
                             let mut _env = BTreeMap::<String, String>::new();
@@ -705,34 +1527,84 @@ pub(crate) fn merge_run_and_shell_configs(
                     extra_paths: None,
                     bindings: None,
                     volumes: None,
+                    network: None,
+                    extra_hosts: None,
+                    ports: None,
+                    resources: None,
+                    security: None,
                 })
             }
         },
         None => merged_run_config,
+    };
+
+    // The network mode is always recorded, so changes in the network access of
+    // the tools are easy to spot when reviewing the lock file
+    let mut merged_run_config = merged_run_config.unwrap_or_default();
+    merged_run_config.network = Some(merged_run_config.get_network());
+
+    Ok(Some(merged_run_config))
+}
+
+/// Merges a cascade of run configs, ordered from the least specific level to
+/// the most specific one (project → image → tag → binary).
+///
+/// Maps (`env`, `volumes`, `bindings`, `extraHosts` & `ports`) are merged key by key, and the values
+/// defined at the more specific levels override the ones inherited from the
+/// less specific levels. Sets (`envFromHost`, `extraPaths` and the security
+/// capabilities) are accumulated, and single values (like `network` or the
+/// resource limits) are taken from the most specific level defining them.
+pub fn merge_run_configs(
+    run_configs: &[&Option<OCIContainerRunConfig>],
+) -> Option<OCIContainerRunConfig> {
+    run_configs.iter().fold(None, |merged_config, run_config| {
+        merge_two_run_configs(&merged_config, run_config)
+    })
+}
+
+fn merge_security(
+    base_security: &Option<SecurityConfig>,
+    new_security: &Option<SecurityConfig>,
+) -> Option<SecurityConfig> {
+    match base_security {
+        Some(_base_security) => match new_security {
+            Some(_new_security) => Some(SecurityConfig {
+                cap_drop: merge_capabilities(
+                    &_base_security.cap_drop,
+                    &_new_security.cap_drop,
+                    &_new_security.cap_add,
+                ),
+                cap_add: merge_capabilities(
+                    &_base_security.cap_add,
+                    &_new_security.cap_add,
+                    &_new_security.cap_drop,
+                ),
+                read_only_rootfs: _new_security
+                    .read_only_rootfs
+                    .or(_base_security.read_only_rootfs),
+                no_new_privileges: _new_security
+                    .no_new_privileges
+                    .or(_base_security.no_new_privileges),
+                seccomp_profile: _new_security
+                    .seccomp_profile
+                    .clone()
+                    .or_else(|| _base_security.seccomp_profile.clone()),
+            }),
+            None => base_security.clone(),
+        },
+        None => new_security.clone(),
     }
 }
 
-fn merge_run_configs(
+fn merge_two_run_configs(
     base_config: &Option<OCIContainerRunConfig>,
     new_config: &Option<OCIContainerRunConfig>,
-    project_internal_id: &str,
-    image_name: &str,
-    image_tag: &str,
-    binary_name: &str,
-) -> Option<OCIContainerRunConfigLock> {
-    let image_ref_for_docker_objs_labels = format!("{}-{}", image_name, image_tag);
-
+) -> Option<OCIContainerRunConfig> {
     match base_config {
         Some(_base_config) => match new_config {
-            Some(_new_config) => Some(OCIContainerRunConfigLock {
+            Some(_new_config) => Some(OCIContainerRunConfig {
                 bindings: merge_bindings(_base_config.get_bindings(), _new_config.get_bindings()),
-                volumes: merge_volumes(
-                    _base_config.get_volumes(),
-                    _new_config.get_volumes(),
-                    project_internal_id,
-                    &image_ref_for_docker_objs_labels,
-                    binary_name,
-                ),
+                volumes: merge_volumes(_base_config.get_volumes(), _new_config.get_volumes()),
                 env: merge_envs(_base_config.get_env(), _new_config.get_env()),
                 env_from_host: merge_envs_from_host(
                     _base_config.get_env_from_host(),
@@ -742,73 +1614,100 @@ fn merge_run_configs(
                     _base_config.get_extra_paths(),
                     _new_config.get_extra_paths(),
                 ),
-            }),
-            None => Some(OCIContainerRunConfigLock {
-                bindings: _base_config.bindings.clone(),
-                volumes: generate_volume_config_lock(
-                    &_base_config.volumes,
-                    project_internal_id,
-                    &image_ref_for_docker_objs_labels,
-                    binary_name,
+                network: _new_config.network.or(_base_config.network),
+                extra_hosts: merge_extra_hosts(
+                    _base_config.get_extra_hosts(),
+                    _new_config.get_extra_hosts(),
                 ),
-                env: _base_config.env.clone(),
-                env_from_host: _base_config.env_from_host.clone(),
-                extra_paths: _base_config.extra_paths.clone(),
-            }),
-        },
-        None => match new_config {
-            Some(_new_config) => Some(OCIContainerRunConfigLock {
-                bindings: _new_config.bindings.clone(),
-                volumes: generate_volume_config_lock(
-                    &_new_config.volumes,
-                    project_internal_id,
-                    &image_ref_for_docker_objs_labels,
-                    binary_name,
+                ports: merge_ports(_base_config.get_ports(), _new_config.get_ports()),
+                resources: merge_resources(
+                    _base_config.get_resources(),
+                    _new_config.get_resources(),
                 ),
-                env: _new_config.env.clone(),
-                env_from_host: _new_config.env_from_host.clone(),
-                extra_paths: _new_config.extra_paths.clone(),
+                security: merge_security(_base_config.get_security(), _new_config.get_security()),
             }),
-            None => Option::<OCIContainerRunConfigLock>::None,
+            None => base_config.clone(),
         },
+        None => new_config.clone(),
     }
 }
 
 fn merge_volumes(
     base_volumes: &Option<BTreeMap<PathBuf, VolumeConfig>>,
     new_volumes: &Option<BTreeMap<PathBuf, VolumeConfig>>,
-    project_internal_id: &str,
-    image_ref: &str,
-    binary_name: &str,
-) -> Option<Vec<VolumeConfigLock>> {
+) -> Option<BTreeMap<PathBuf, VolumeConfig>> {
     match base_volumes {
         Some(_base_volumes) => match new_volumes {
             Some(_new_volumes) => {
                 let mut merged_volumes = _base_volumes.clone();
-                for (var_name, var_value) in _new_volumes {
-                    merged_volumes.insert(var_name.clone(), var_value.clone());
+                for (container_path, volume_config) in _new_volumes {
+                    merged_volumes.insert(container_path.clone(), volume_config.clone());
                 }
-                generate_volume_config_lock(
-                    &Some(merged_volumes),
-                    project_internal_id,
-                    image_ref,
-                    binary_name,
-                )
+                Some(merged_volumes)
             }
-            None => generate_volume_config_lock(
-                base_volumes,
-                project_internal_id,
-                image_ref,
-                binary_name,
-            ),
+            None => base_volumes.clone(),
         },
-        None => {
-            generate_volume_config_lock(new_volumes, project_internal_id, image_ref, binary_name)
+        None => new_volumes.clone(),
+    }
+}
+
+pub fn parse_config(config_bytes: &[u8], config_filepath: &Path) -> AvatarResult<ProjectConfig> {
+    check_config_version(config_bytes, config_filepath)?;
+
+    match serde_yaml::from_slice::<ProjectConfig>(config_bytes) {
+        Ok(_config) => {
+            check_tasks(&_config, config_filepath)?;
+            check_capabilities(&_config, config_filepath)?;
+            Ok(_config)
         }
+        Err(e) => Err(AvatarError::ConfigParse(match e.location() {
+            Some(l) => format!(
+                "Malformed config file '{}', line {}, column {}:\n\t{}",
+                config_filepath.display(),
+                l.line(),
+                l.column(),
+                e,
+            ),
+            None => format!(
+                "Malformed config file '{}':\n\t{}",
+                config_filepath.display(),
+                e,
+            ),
+        })),
     }
 }
 
-pub(crate) fn save_config(config_filepath: &PathBuf, config: &ProjectConfig) -> Vec<u8> {
+/// The file path is only used in the error messages
+pub fn parse_config_lock(
+    config_lock_bytes: &[u8],
+    config_lock_filepath: &Path,
+) -> AvatarResult<ProjectConfigLock> {
+    let config_lock = match get_migrated_lock(config_lock_bytes, config_lock_filepath)? {
+        Some(migrated_config_lock) => {
+            serde_yaml::from_value::<ProjectConfigLock>(migrated_config_lock)
+        }
+        None => serde_yaml::from_slice::<ProjectConfigLock>(config_lock_bytes),
+    };
+
+    config_lock.map_err(|e| {
+        AvatarError::ConfigParse(match e.location() {
+            Some(l) => format!(
+                "Malformed lock file '{}', line {}, column {}:\n\t{}",
+                config_lock_filepath.display(),
+                l.line(),
+                l.column(),
+                e,
+            ),
+            None => format!(
+                "Malformed lock file '{}':\n\t{}",
+                config_lock_filepath.display(),
+                e,
+            ),
+        })
+    })
+}
+
+pub fn save_config(config_filepath: &Path, config: &ProjectConfig) -> AvatarResult<Vec<u8>> {
     save_result_to_file(
         config_filepath,
         serde_yaml::to_vec(config),
@@ -816,10 +1715,10 @@ pub(crate) fn save_config(config_filepath: &PathBuf, config: &ProjectConfig) ->
     )
 }
 
-pub(crate) fn save_config_lock(
-    config_lock_filepath: &PathBuf,
+pub fn save_config_lock(
+    config_lock_filepath: &Path,
     config_lock: &ProjectConfigLock,
-) -> Vec<u8> {
+) -> AvatarResult<Vec<u8>> {
     save_result_to_file(
         config_lock_filepath,
         serde_yaml::to_vec(config_lock),
@@ -828,28 +1727,241 @@ pub(crate) fn save_config_lock(
 }
 
 fn save_result_to_file(
-    filepath: &PathBuf,
+    filepath: &Path,
     result: serde_yaml::Result<Vec<u8>>,
     result_type: &str,
-) -> Vec<u8> {
+) -> AvatarResult<Vec<u8>> {
     match result {
-        Ok(serialized_bytes) => {
-            if let Err(e) = write(filepath, &serialized_bytes) {
-                eprintln!(
-                    "Unknown error while persisting {}:\n\n{}\n",
-                    result_type,
-                    e.to_string()
-                );
+        Ok(serialized_bytes) => match write(filepath, &serialized_bytes) {
+            Ok(_) => Ok(serialized_bytes),
+            Err(e) => Err(AvatarError::Io(format!(
+                "Unknown error while persisting {}:\n\n{}\n",
+                result_type, e
+            ))),
+        },
+        Err(e) => Err(AvatarError::Internal(format!(
+            "Unknown error while serializing {}:\n\n{}\n",
+            result_type, e
+        ))),
+    }
+}
+
+/// Splits `name[:tag]`, taking into account that registry hosts can specify
+/// ports. For `name@sha256:hash` references, the digest takes the place of the
+/// tag (as the Docker Engine API expects).
+pub fn split_image_ref(image_ref: &str) -> (&str, Option<&str>) {
+    if let Some((image_name, image_digest)) = image_ref.split_once('@') {
+        return (image_name, Some(image_digest));
+    }
+
+    let name_start = image_ref.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match image_ref[name_start..].rfind(':') {
+        Some(i) => (
+            &image_ref[..name_start + i],
+            Some(&image_ref[name_start + i + 1..]),
+        ),
+        None => (image_ref, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_run_config(yaml: &str) -> Option<OCIContainerRunConfig> {
+        Some(serde_yaml::from_str(yaml).unwrap())
+    }
+
+    fn new_tasks(yaml: &str) -> BTreeMap<String, TaskConfig> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn get_task_execution_order_runs_dependencies_first_and_once() {
+        let tasks = new_tasks(
+            "build: {dependsOn: [deps, codegen]}\ncodegen: {dependsOn: [deps]}\ndeps: {}\nlint: {}\n",
+        );
+
+        assert_eq!(
+            get_task_execution_order(&tasks, "build").unwrap(),
+            vec!["deps", "codegen", "build"]
+        );
+        assert_eq!(
+            get_task_execution_order(&tasks, "lint").unwrap(),
+            vec!["lint"]
+        );
+    }
+
+    #[test]
+    fn get_task_execution_order_rejects_cycles() {
+        let tasks = new_tasks("a: {dependsOn: [b]}\nb: {dependsOn: [c]}\nc: {dependsOn: [b]}\n");
+
+        match get_task_execution_order(&tasks, "a") {
+            Err(AvatarError::InvalidConfig(message)) => {
+                assert_eq!(message, "Found a dependency cycle between tasks: b → c → b")
             }
-            serialized_bytes
+            result => panic!("unexpected result: {:?}", result),
         }
-        Err(e) => {
-            eprintln!(
-                "Unknown error while serializing {}:\n\n{}\n",
-                result_type,
-                e.to_string()
-            );
-            exit(exitcode::SOFTWARE)
+    }
+
+    #[test]
+    fn get_task_execution_order_rejects_undefined_tasks() {
+        let tasks = new_tasks("a: {dependsOn: [missing]}\n");
+
+        match get_task_execution_order(&tasks, "a") {
+            Err(AvatarError::InvalidConfig(message)) => assert_eq!(
+                message,
+                "The task 'a' depends on 'missing', which is not defined"
+            ),
+            result => panic!("unexpected result: {:?}", result),
         }
+        match get_task_execution_order(&tasks, "nope") {
+            Err(AvatarError::InvalidConfig(message)) => {
+                assert_eq!(message, "The task 'nope' is not defined")
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn is_valid_size_accepts_digits_with_an_optional_unit() {
+        for size in &["0", "512", "512b", "64k", "256m", "2g", "2G"] {
+            assert!(is_valid_size(size), "'{}' should be valid", size);
+        }
+        for size in &["", "m", "1.5g", "10mb", "-1", "1 g", "g1"] {
+            assert!(!is_valid_size(size), "'{}' should be invalid", size);
+        }
+    }
+
+    #[test]
+    fn merge_run_configs_lets_specific_levels_override_maps() {
+        let merged = merge_run_configs(&[
+            &new_run_config("env: {A: project, B: project}\nnetwork: host\n"),
+            &None,
+            &new_run_config("env: {B: tag, C: tag}\n"),
+        ])
+        .unwrap();
+
+        let expected_env: BTreeMap<String, String> =
+            vec![("A", "project"), ("B", "tag"), ("C", "tag")]
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+        assert_eq!(merged.get_env(), &Some(expected_env));
+        assert_eq!(merged.get_network(), &Some(NetworkMode::Host));
+    }
+
+    #[test]
+    fn merge_run_configs_accumulates_sets() {
+        let merged = merge_run_configs(&[
+            &new_run_config("envFromHost: [HOME]\nextraPaths: [/opt/a]\n"),
+            &new_run_config("envFromHost: [TERM, HOME]\nextraPaths: [/opt/b]\nnetwork: none\n"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            merged.get_env_from_host(),
+            &Some(
+                vec!["HOME".to_string(), "TERM".to_string()]
+                    .into_iter()
+                    .collect()
+            )
+        );
+        assert_eq!(
+            merged.get_extra_paths(),
+            &Some(
+                vec![PathBuf::from("/opt/a"), PathBuf::from("/opt/b")]
+                    .into_iter()
+                    .collect()
+            )
+        );
+        assert_eq!(merged.get_network(), &Some(NetworkMode::None));
+    }
+
+    #[test]
+    fn merge_run_configs_lets_specific_levels_cancel_inherited_capabilities() {
+        let merged = merge_run_configs(&[
+            &new_run_config("security: {capDrop: [ALL, NET_RAW], capAdd: [CHOWN, SYS_PTRACE]}\n"),
+            &new_run_config("security: {capAdd: [cap_net_raw], capDrop: [SYS_PTRACE]}\n"),
+        ])
+        .unwrap();
+        let security = merged.get_security().as_ref().unwrap();
+
+        assert_eq!(
+            security.get_cap_drop(),
+            &Some(
+                vec!["ALL".to_string(), "SYS_PTRACE".to_string()]
+                    .into_iter()
+                    .collect()
+            )
+        );
+        assert_eq!(
+            security.get_cap_add(),
+            &Some(
+                vec!["CHOWN".to_string(), "cap_net_raw".to_string()]
+                    .into_iter()
+                    .collect()
+            )
+        );
+    }
+
+    #[test]
+    fn merge_run_configs_drops_inherited_capabilities_when_dropping_all() {
+        let merged = merge_run_configs(&[
+            &new_run_config("security: {capAdd: [NET_ADMIN, CHOWN]}\n"),
+            &new_run_config("security: {capDrop: [ALL]}\n"),
+        ])
+        .unwrap();
+        let security = merged.get_security().as_ref().unwrap();
+
+        assert_eq!(
+            security.get_cap_drop(),
+            &Some(vec!["ALL".to_string()].into_iter().collect())
+        );
+        assert_eq!(security.get_cap_add(), &Some(BTreeSet::new()));
+    }
+
+    #[test]
+    fn check_run_config_capabilities_rejects_added_and_dropped_capabilities() {
+        assert_eq!(
+            check_run_config_capabilities(&new_run_config(
+                "security: {capDrop: [ALL], capAdd: [CHOWN]}\n"
+            )),
+            Ok(())
+        );
+        assert_eq!(
+            check_run_config_capabilities(&new_run_config(
+                "security: {capDrop: [NET_RAW], capAdd: [CAP_NET_RAW]}\n"
+            )),
+            Err("CAP_NET_RAW".to_string())
+        );
+        assert_eq!(check_run_config_capabilities(&None), Ok(()));
+    }
+
+    #[test]
+    fn merge_run_configs_returns_none_without_run_configs() {
+        assert_eq!(merge_run_configs(&[&None, &None]), None);
+    }
+
+    #[test]
+    fn split_image_ref_handles_tags_digests_and_registry_ports() {
+        assert_eq!(split_image_ref("node:14"), ("node", Some("14")));
+        assert_eq!(split_image_ref("node"), ("node", None));
+        assert_eq!(
+            split_image_ref("node@sha256:abc"),
+            ("node", Some("sha256:abc"))
+        );
+        assert_eq!(
+            split_image_ref("localhost:5000/library/node"),
+            ("localhost:5000/library/node", None)
+        );
+        assert_eq!(
+            split_image_ref("localhost:5000/library/node:14-buster"),
+            ("localhost:5000/library/node", Some("14-buster"))
+        );
+        assert_eq!(
+            split_image_ref("localhost:5000/node@sha256:abc"),
+            ("localhost:5000/node", Some("sha256:abc"))
+        );
     }
 }
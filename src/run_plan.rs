@@ -0,0 +1,260 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{metadata, read, rename, write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{avatar_env::CONTAINER_ENGINE, ports::PortMapping, subcommands::AVATAR_CLI_VERSION};
+
+// Constants:
+// -----------------------------------------------------------------------------
+pub const RUN_PLANS_DIR_NAME: &str = "run_plans";
+/// Bumped when the layout of the run plans changes
+const RUN_PLAN_FORMAT_VERSION: u32 = 1;
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileFingerprint {
+    path: PathBuf,
+    size: u64,
+    modified_secs: i64,
+    modified_nanos: i64,
+}
+
+impl FileFingerprint {
+    fn read(path: &PathBuf) -> Option<FileFingerprint> {
+        let file_metadata = metadata(path).ok()?;
+        if !file_metadata.is_file() {
+            return None;
+        }
+
+        Some(FileFingerprint {
+            path: path.clone(),
+            size: file_metadata.size(),
+            modified_secs: file_metadata.mtime(),
+            modified_nanos: file_metadata.mtime_nsec(),
+        })
+    }
+}
+
+/// Everything that, if changed, invalidates a precomputed run plan. The plans
+/// written by other Avatar CLI versions are discarded, as they could miss (or
+/// misplace) the flags added since then. The ones written before these fields
+/// existed can't be deserialized, so they are discarded as well.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunPlanKey {
+    format_version: u32,
+    avatar_version: String,
+    files: Vec<FileFingerprint>,
+    container_engine: Option<String>,
+}
+
+impl RunPlanKey {
+    /// Returns `None` if any of the files is not available, in that case the
+    /// caller must go through the full validation path.
+    pub fn read(file_paths: &[&PathBuf]) -> Option<RunPlanKey> {
+        Some(RunPlanKey {
+            format_version: RUN_PLAN_FORMAT_VERSION,
+            avatar_version: AVATAR_CLI_VERSION.to_string(),
+            files: file_paths
+                .iter()
+                .map(|p| FileFingerprint::read(p))
+                .collect::<Option<Vec<FileFingerprint>>>()?,
+            container_engine: env::var(CONTAINER_ENGINE).ok(),
+        })
+    }
+}
+
+/// The parts of the container's command line that only depend on the project
+/// state, precomputed so `avatar run` can skip re-reading and re-hashing the
+/// project configuration files on every call.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunPlan {
+    key: Option<RunPlanKey>,
+    program_name: String,
+    container_name_prefix: String,
+    static_args: Vec<String>,
+    env_from_host: Vec<String>,
+    image_ref: String,
+    binary_path: PathBuf,
+    binary_args: Vec<String>,
+    published_ports: Vec<PortMapping>,
+}
+
+impl RunPlan {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        key: Option<RunPlanKey>,
+        program_name: String,
+        container_name_prefix: String,
+        static_args: Vec<String>,
+        env_from_host: Vec<String>,
+        image_ref: String,
+        binary_path: PathBuf,
+        binary_args: Vec<String>,
+        published_ports: Vec<PortMapping>,
+    ) -> RunPlan {
+        RunPlan {
+            key,
+            program_name,
+            container_name_prefix,
+            static_args,
+            env_from_host,
+            image_ref,
+            binary_path,
+            binary_args,
+            published_ports,
+        }
+    }
+
+    /// Appends environment variables that take precedence over the ones
+    /// defined in the project configuration.
+    pub fn with_extra_env(mut self, extra_env: &BTreeMap<String, String>) -> RunPlan {
+        for (var_name, var_value) in extra_env {
+            self.static_args.push("--env".to_string());
+            self.static_args.push(format!("{}={}", var_name, var_value));
+        }
+        self
+    }
+
+    pub fn get_key(&self) -> &Option<RunPlanKey> {
+        &self.key
+    }
+
+    pub fn get_program_name(&self) -> &String {
+        &self.program_name
+    }
+
+    pub fn get_container_name_prefix(&self) -> &String {
+        &self.container_name_prefix
+    }
+
+    pub fn get_static_args(&self) -> &Vec<String> {
+        &self.static_args
+    }
+
+    pub fn get_env_from_host(&self) -> &Vec<String> {
+        &self.env_from_host
+    }
+
+    pub fn get_image_ref(&self) -> &String {
+        &self.image_ref
+    }
+
+    pub fn get_binary_path(&self) -> &PathBuf {
+        &self.binary_path
+    }
+
+    pub fn get_binary_args(&self) -> &Vec<String> {
+        &self.binary_args
+    }
+
+    pub fn get_published_ports(&self) -> &Vec<PortMapping> {
+        &self.published_ports
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+pub fn get_run_plan_path(volatile_path: &Path, binary_name: &str) -> PathBuf {
+    volatile_path
+        .join(RUN_PLANS_DIR_NAME)
+        .join(format!("{}.json", binary_name))
+}
+
+/// Returns the stored run plan only if it was generated for the same key, any
+/// problem reading it is treated as a cache miss.
+pub fn load_run_plan(run_plan_path: &PathBuf, key: &RunPlanKey) -> Option<RunPlan> {
+    let run_plan_bytes = read(run_plan_path).ok()?;
+    let run_plan = serde_json::from_slice::<RunPlan>(&run_plan_bytes).ok()?;
+
+    match run_plan.key.as_ref() == Some(key) {
+        true => Some(run_plan),
+        false => None,
+    }
+}
+
+/// The run plan is just a cache, failing to persist it is not an error. It is
+/// written into a temporary file first, so concurrent calls never observe a
+/// half-written plan.
+pub fn save_run_plan(run_plan_path: &PathBuf, run_plan: &RunPlan) {
+    let run_plan_bytes = match serde_json::to_vec(run_plan) {
+        Ok(bytes) => bytes,
+        Err(_) => return,
+    };
+
+    let tmp_path = run_plan_path.with_extension(format!("json.{}", std::process::id()));
+    if write(&tmp_path, run_plan_bytes).is_ok() && rename(&tmp_path, run_plan_path).is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, fs::remove_file, process};
+
+    use super::*;
+
+    fn new_run_plan(key: RunPlanKey) -> RunPlan {
+        RunPlan::new(
+            Some(key),
+            "docker".to_string(),
+            "prefix".to_string(),
+            vec!["--rm".to_string()],
+            vec![],
+            "node@sha256:abcd".to_string(),
+            PathBuf::from("/usr/local/bin/node"),
+            vec![],
+            vec![],
+        )
+    }
+
+    #[test]
+    fn load_run_plan_discards_plans_of_other_versions() {
+        let run_plan_path = temp_dir().join(format!("avatar-test-{}-run-plan.json", process::id()));
+        let key = RunPlanKey::read(&[]).unwrap();
+
+        save_run_plan(&run_plan_path, &new_run_plan(key.clone()));
+        let same_version_plan = load_run_plan(&run_plan_path, &key);
+
+        let older_key = RunPlanKey {
+            avatar_version: "0.17.0".to_string(),
+            ..key.clone()
+        };
+        save_run_plan(&run_plan_path, &new_run_plan(older_key));
+        let older_version_plan = load_run_plan(&run_plan_path, &key);
+
+        // Written before the run plan keys had versions
+        let mut unversioned_plan = serde_json::to_value(new_run_plan(key.clone())).unwrap();
+        let key_object = unversioned_plan["key"].as_object_mut().unwrap();
+        key_object.remove("formatVersion");
+        key_object.remove("avatarVersion");
+        write(
+            &run_plan_path,
+            serde_json::to_vec(&unversioned_plan).unwrap(),
+        )
+        .unwrap();
+        let unversioned_plan = load_run_plan(&run_plan_path, &key);
+        remove_file(&run_plan_path).unwrap();
+
+        assert_eq!(same_version_plan, Some(new_run_plan(key)));
+        assert_eq!(older_version_plan, None);
+        assert_eq!(unversioned_plan, None);
+    }
+}
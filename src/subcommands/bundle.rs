@@ -0,0 +1,850 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{remove_file, rename, write, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    container_engines::{get_container_engine, ContainerEngine},
+    directories::{get_required_project_path, AVATARFILE_LOCK_NAME, CONFIG_DIR_NAME},
+    error::{AvatarError, AvatarResult},
+    image_archive::{ImageArchive, ImageVerification},
+    project_config::{get_config_lock, get_file_bytes, get_image_ref, parse_config_lock},
+    subcommands::{
+        update::{get_config_lock_path, get_selected_tags, get_short_hash, print_table},
+        verify::{
+            check_layers_only_images, get_verification_status, verify_local_image,
+            LAYERS_ONLY_STATUS, MISSING_STATUS, VERIFIED_STATUS,
+        },
+        AVATAR_CLI_VERSION,
+    },
+    tar::{read_tar_entries, TarWriter},
+};
+
+const BUNDLE_MANIFEST_NAME: &str = "avatar-bundle.json";
+const BUNDLE_VERSION: u32 = 1;
+const UNREACHABLE_STATUS: &str = "loaded without its digest";
+
+/// Describes the content of a bundle, it's the first entry of the archive
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BundleManifest {
+    bundle_version: u32,
+    avatar_version: String,
+    images: Vec<BundleImage>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BundleImage {
+    image: String,
+    tag: String,
+    hash: String,
+    archive: String, // path inside the bundle
+}
+
+struct LoadedBundle {
+    manifest: BundleManifest,
+    lock_bytes: Vec<u8>,
+    // (image name, image tag) -> verification of the archive, as it was loaded
+    streamed_verifications: BTreeMap<(String, String), Result<ImageVerification, String>>,
+}
+
+/// Copies everything it reads to the writer
+struct TeeReader<'a> {
+    reader: &'a mut dyn Read,
+    writer: &'a mut dyn Write,
+}
+
+impl Read for TeeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_size = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..read_size])?;
+        Ok(read_size)
+    }
+}
+
+/// Packs the lock file and every locked image (as saved by the container
+/// engine) in a single archive, to be imported in machines without registry
+/// access.
+pub fn bundle_export_subcommand(output_path: &PathBuf, strict: bool) -> AvatarResult<()> {
+    let config_lock_path = get_config_lock_path()?;
+    let config_lock_bytes = get_file_bytes(&config_lock_path)?;
+    let (config_lock, _) = get_config_lock(&config_lock_path)?;
+
+    let engine = get_container_engine(config_lock.get_container_engine())?;
+    engine.check_client_availability()?;
+
+    // Tags sharing the same digest are saved only once
+    let mut archive_paths: BTreeMap<String, String> = BTreeMap::new();
+    let mut images: Vec<BundleImage> = vec![];
+    for (image_name, image_tag, hash) in get_selected_tags(&config_lock, None)? {
+        let image_ref = get_image_ref(&image_name, &hash);
+        if !engine.has_image(&image_ref)? {
+            return Err(AvatarError::MissingFile(format!(
+                "The image {}:{} is not available locally, run 'avatar install' first",
+                image_name, image_tag
+            )));
+        }
+
+        let archive_path = format!("images/{}.tar", archive_paths.len());
+        let archive_path = archive_paths
+            .entry(image_ref)
+            .or_insert(archive_path)
+            .clone();
+        images.push(BundleImage {
+            image: image_name,
+            tag: image_tag,
+            hash,
+            archive: archive_path,
+        });
+    }
+
+    let manifest = BundleManifest {
+        bundle_version: BUNDLE_VERSION,
+        avatar_version: AVATAR_CLI_VERSION.to_string(),
+        images,
+    };
+    let manifest_bytes = match serde_json::to_vec_pretty(&manifest) {
+        Ok(manifest_bytes) => manifest_bytes,
+        Err(e) => {
+            return Err(AvatarError::Internal(format!(
+                "Unknown error while serializing the bundle manifest:\n\n{}\n",
+                e
+            )))
+        }
+    };
+
+    // The bundle is written in a temporary path, so interrupted exports don't
+    // leave behind archives that look complete
+    let partial_path = PathBuf::from(format!("{}.partial", output_path.display()));
+    let image_partial_path = PathBuf::from(format!("{}.image.partial", output_path.display()));
+    let result = write_bundle(
+        engine.as_ref(),
+        &partial_path,
+        &image_partial_path,
+        &manifest,
+        &manifest_bytes,
+        &config_lock_bytes,
+    );
+    let _ = remove_file(&image_partial_path);
+    if let Err(e) =
+        result.and_then(|layers_only_images| check_layers_only_images(&layers_only_images, strict))
+    {
+        let _ = remove_file(&partial_path);
+        return Err(e);
+    }
+
+    if let Err(e) = rename(&partial_path, output_path) {
+        return Err(AvatarError::CantCreate(format!(
+            "Unable to create the bundle {}\n\n{}\n",
+            output_path.display(),
+            e
+        )));
+    }
+
+    println!(
+        "Exported {} image(s) to {}",
+        archive_paths.len(),
+        output_path.display()
+    );
+    Ok(())
+}
+
+/// Loads the images of a bundle into the container engine, and verifies them
+/// against the bundled lock file. The lock file is copied to the project if it
+/// does not have one yet.
+pub fn bundle_import_subcommand(input_path: &PathBuf, strict: bool) -> AvatarResult<()> {
+    let config_lock_path = get_required_project_path()?
+        .join(CONFIG_DIR_NAME)
+        .join(AVATARFILE_LOCK_NAME);
+
+    let bundle_file = match File::open(input_path) {
+        Ok(bundle_file) => bundle_file,
+        Err(e) => {
+            return Err(AvatarError::MissingFile(format!(
+                "Unable to open the bundle {}\n\n{}\n",
+                input_path.display(),
+                e
+            )))
+        }
+    };
+
+    let current_lock = match config_lock_path.is_file() {
+        true => Some(get_config_lock(&config_lock_path)?),
+        false => None,
+    };
+    let engine_kind = current_lock
+        .as_ref()
+        .and_then(|(config_lock, _)| *config_lock.get_container_engine());
+    let engine = get_container_engine(&engine_kind)?;
+    engine.check_client_availability()?;
+
+    let loaded_bundle = load_bundle(
+        engine.as_ref(),
+        &mut BufReader::new(bundle_file),
+        input_path,
+        current_lock
+            .as_ref()
+            .map(|(_, current_lock_hash)| (config_lock_path.as_path(), current_lock_hash.as_ref())),
+    )?;
+    verify_loaded_images(engine.as_ref(), &loaded_bundle, strict)?;
+
+    if current_lock.is_none() {
+        if let Err(e) = write(&config_lock_path, &loaded_bundle.lock_bytes) {
+            return Err(AvatarError::CantCreate(format!(
+                "Unable to create the lock file {}\n\n{}\n",
+                config_lock_path.display(),
+                e
+            )));
+        }
+        get_config_lock(&config_lock_path)?;
+    }
+
+    println!("The project can now be installed without registry access ('avatar install')");
+    Ok(())
+}
+
+/// The manifest is not trusted: it must list exactly the image tags pinned by
+/// the bundled lock file, with the same digests.
+fn check_bundle_manifest(
+    manifest: &BundleManifest,
+    lock_bytes: &[u8],
+    bundle_path: &Path,
+) -> AvatarResult<()> {
+    let config_lock = parse_config_lock(lock_bytes, &bundle_path.join(AVATARFILE_LOCK_NAME))?;
+    let locked_tags: BTreeSet<(String, String, String)> =
+        get_selected_tags(&config_lock, None)?.into_iter().collect();
+    let listed_tags: BTreeSet<(String, String, String)> = manifest
+        .images
+        .iter()
+        .map(|image| (image.image.clone(), image.tag.clone(), image.hash.clone()))
+        .collect();
+
+    if let Some((image_name, image_tag, hash)) = listed_tags.difference(&locked_tags).next() {
+        return Err(get_malformed_bundle_error(
+            bundle_path,
+            &format!(
+                "the manifest lists {}:{} ({}), which is not pinned by the bundled lock file",
+                image_name,
+                image_tag,
+                get_short_hash(hash)
+            ),
+        ));
+    }
+    if let Some((image_name, image_tag, _)) = locked_tags.difference(&listed_tags).next() {
+        return Err(get_malformed_bundle_error(
+            bundle_path,
+            &format!(
+                "the image {}:{} is pinned by the bundled lock file, but not listed in the manifest",
+                image_name, image_tag
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+fn get_malformed_bundle_error(bundle_path: &Path, reason: &str) -> AvatarError {
+    AvatarError::ConfigParse(format!(
+        "Malformed bundle {}:\n\t{}",
+        bundle_path.display(),
+        reason
+    ))
+}
+
+/// Loads the image archives of a bundle as they are read, after checking its
+/// manifest against its lock file (and the project's lock file, if any). The
+/// archives are also hashed while they are loaded.
+fn load_bundle(
+    engine: &dyn ContainerEngine,
+    reader: &mut dyn Read,
+    bundle_path: &Path,
+    current_lock: Option<(&Path, &[u8])>, // lock file path & hash
+) -> AvatarResult<LoadedBundle> {
+    let mut manifest: Option<BundleManifest> = None;
+    let mut bundled_lock_bytes: Option<Vec<u8>> = None;
+    let mut streamed_verifications: BTreeMap<(String, String), Result<ImageVerification, String>> =
+        BTreeMap::new();
+    let mut callback_error: Option<AvatarError> = None;
+
+    let read_result = read_tar_entries(reader, &mut |entry, entry_reader| match entry
+        .get_path()
+        .as_str()
+    {
+        BUNDLE_MANIFEST_NAME => {
+            let parsed_manifest: BundleManifest = serde_json::from_reader(entry_reader)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if parsed_manifest.bundle_version > BUNDLE_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "the bundle was exported by a newer Avatar CLI version ({})",
+                        parsed_manifest.avatar_version
+                    ),
+                ));
+            }
+            manifest = Some(parsed_manifest);
+            Ok(())
+        }
+        AVATARFILE_LOCK_NAME => {
+            let mut lock_bytes: Vec<u8> = vec![];
+            entry_reader.read_to_end(&mut lock_bytes)?;
+
+            // Checked before loading any image
+            if let Some((current_lock_path, current_lock_hash)) = current_lock {
+                if current_lock_hash != digest(&SHA256, &lock_bytes).as_ref() {
+                    callback_error = Some(AvatarError::HashMismatch(format!(
+                        "The bundle was exported from a different lock file than {}",
+                        current_lock_path.display()
+                    )));
+                    return Err(io::Error::other("lock file mismatch"));
+                }
+            }
+            let manifest = match &manifest {
+                Some(manifest) => manifest,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "the lock file precedes the bundle manifest",
+                    ))
+                }
+            };
+            if let Err(e) = check_bundle_manifest(manifest, &lock_bytes, bundle_path) {
+                callback_error = Some(e);
+                return Err(io::Error::other("bundle manifest mismatch"));
+            }
+
+            bundled_lock_bytes = Some(lock_bytes);
+            Ok(())
+        }
+        path if path.starts_with("images/") => {
+            let listed_images: Vec<&BundleImage> = match (&manifest, &bundled_lock_bytes) {
+                (Some(manifest), Some(_)) => manifest
+                    .images
+                    .iter()
+                    .filter(|image| image.archive == path)
+                    .collect(),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("'{}' precedes the bundle manifest or the lock file", path),
+                    ))
+                }
+            };
+            if listed_images.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("'{}' is not listed in the bundle manifest", path),
+                ));
+            }
+
+            let mut image_archive: Option<ImageArchive> = None;
+            let load_result = engine.load_image(&mut |writer| {
+                let mut tee_reader = TeeReader {
+                    reader: &mut *entry_reader,
+                    writer,
+                };
+                // The end-of-archive padding is loaded too
+                let read_result = ImageArchive::read(&mut tee_reader).and_then(|archive| {
+                    io::copy(&mut tee_reader, &mut io::sink()).map(|_| archive)
+                });
+                image_archive = Some(read_result.map_err(|e| {
+                    AvatarError::Io(format!("Unable to read the bundle\n\n{}\n", e))
+                })?);
+                Ok(())
+            });
+            if let Err(e) = load_result {
+                callback_error = Some(e);
+                return Err(io::Error::other("the container engine failed"));
+            }
+
+            println!("Loaded {}", path);
+            if let Some(image_archive) = image_archive {
+                for image in listed_images {
+                    streamed_verifications.insert(
+                        (image.image.clone(), image.tag.clone()),
+                        image_archive.verify(&image.hash),
+                    );
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    });
+
+    if let Some(e) = callback_error {
+        return Err(e);
+    }
+    if let Err(e) = read_result {
+        return Err(get_malformed_bundle_error(bundle_path, &e.to_string()));
+    }
+
+    let (manifest, lock_bytes) = match (manifest, bundled_lock_bytes) {
+        (Some(manifest), Some(lock_bytes)) => (manifest, lock_bytes),
+        (None, _) => {
+            return Err(get_malformed_bundle_error(
+                bundle_path,
+                "the bundle manifest is missing",
+            ))
+        }
+        (_, None) => {
+            return Err(get_malformed_bundle_error(
+                bundle_path,
+                "the lock file is missing",
+            ))
+        }
+    };
+    if let Some(image) = manifest.images.iter().find(|image| {
+        !streamed_verifications.contains_key(&(image.image.clone(), image.tag.clone()))
+    }) {
+        return Err(get_malformed_bundle_error(
+            bundle_path,
+            &format!("the archive of {}:{} is missing", image.image, image.tag),
+        ));
+    }
+
+    Ok(LoadedBundle {
+        manifest,
+        lock_bytes,
+        streamed_verifications,
+    })
+}
+
+/// Checks the loaded images the same way `avatar verify` does. Some engines
+/// (like Docker with its classic image store) drop the registry digests of the
+/// loaded images, which makes them unreachable through their locked references
+/// even when their archives were genuine: that is reported as such.
+fn verify_loaded_images(
+    engine: &dyn ContainerEngine,
+    loaded_bundle: &LoadedBundle,
+    strict: bool,
+) -> AvatarResult<()> {
+    let mut layers_only_images: Vec<String> = vec![];
+    let mut unreachable_images: Vec<String> = vec![];
+    let rows = loaded_bundle
+        .manifest
+        .images
+        .iter()
+        .map(|image| {
+            let verification = verify_local_image(engine, &image.image, &image.hash)?;
+            let streamed_verification = loaded_bundle
+                .streamed_verifications
+                .get(&(image.image.clone(), image.tag.clone()));
+            let image_tag_ref = format!("{}:{}", image.image, image.tag);
+
+            let status = match (&verification, streamed_verification) {
+                (Err(reason), Some(Ok(_))) if reason == MISSING_STATUS => {
+                    unreachable_images.push(image_tag_ref);
+                    UNREACHABLE_STATUS.to_string()
+                }
+                (Ok(ImageVerification::Layers { .. }), _) => {
+                    layers_only_images.push(image_tag_ref);
+                    LAYERS_ONLY_STATUS.to_string()
+                }
+                _ => get_verification_status(&verification),
+            };
+            Ok(vec![
+                image.image.clone(),
+                image.tag.clone(),
+                get_short_hash(&image.hash),
+                status,
+            ])
+        })
+        .collect::<AvatarResult<Vec<Vec<String>>>>()?;
+    print_table(&["IMAGE", "TAG", "DIGEST", "STATUS"], &rows);
+
+    if !unreachable_images.is_empty() {
+        return Err(AvatarError::ContainerEngine(format!(
+            "The container engine loaded {}, but dropped their registry digests (as Docker's classic image store does), so they can't be used through the lock file. Bundles can only be imported by engines keeping them, like Docker with the containerd image store enabled",
+            unreachable_images.join(", ")
+        )));
+    }
+    if rows
+        .iter()
+        .any(|row| row[3] != VERIFIED_STATUS && row[3] != LAYERS_ONLY_STATUS)
+    {
+        return Err(AvatarError::HashMismatch(
+            "The imported images do not match the bundled lock file".to_string(),
+        ));
+    }
+    check_layers_only_images(&layers_only_images, strict)
+}
+
+/// Returns the images that could only be verified by their layers
+fn write_bundle(
+    engine: &dyn ContainerEngine,
+    bundle_path: &PathBuf,
+    image_partial_path: &PathBuf,
+    manifest: &BundleManifest,
+    manifest_bytes: &[u8],
+    config_lock_bytes: &[u8],
+) -> AvatarResult<Vec<String>> {
+    let io_error = |e: io::Error| {
+        AvatarError::Io(format!(
+            "Unable to write the bundle {}\n\n{}\n",
+            bundle_path.display(),
+            e
+        ))
+    };
+
+    let bundle_file = File::create(bundle_path).map_err(|e| {
+        AvatarError::CantCreate(format!(
+            "Unable to create the bundle {}\n\n{}\n",
+            bundle_path.display(),
+            e
+        ))
+    })?;
+    let mut bundle = TarWriter::new(BufWriter::new(bundle_file));
+
+    bundle
+        .append_file(
+            BUNDLE_MANIFEST_NAME,
+            manifest_bytes.len() as u64,
+            &mut &manifest_bytes[..],
+        )
+        .map_err(io_error)?;
+    bundle
+        .append_file(
+            AVATARFILE_LOCK_NAME,
+            config_lock_bytes.len() as u64,
+            &mut &config_lock_bytes[..],
+        )
+        .map_err(io_error)?;
+
+    let mut written_archives: Vec<&String> = vec![];
+    let mut layers_only_images: Vec<String> = vec![];
+    for image in manifest.images.iter() {
+        if written_archives.contains(&&image.archive) {
+            continue;
+        }
+
+        // The engine's archive size is not known in advance, and tar entries
+        // need it in their headers
+        let image_ref = get_image_ref(&image.image, &image.hash);
+        let mut image_file = File::create(image_partial_path).map_err(io_error)?;
+        engine.save_image(&image_ref, &mut |reader| {
+            io::copy(reader, &mut image_file)
+                .map(|_| ())
+                .map_err(io_error)
+        })?;
+
+        // Corrupted images are not exported
+        let image_archive = ImageArchive::read(&mut BufReader::new(
+            File::open(image_partial_path).map_err(io_error)?,
+        ))
+        .map_err(io_error)?;
+        match image_archive.verify(&image.hash) {
+            Ok(ImageVerification::Manifest { .. }) => {}
+            Ok(ImageVerification::Layers { .. }) => {
+                layers_only_images.push(format!("{}:{}", image.image, image.tag));
+            }
+            Err(reason) => {
+                return Err(AvatarError::HashMismatch(format!(
+                    "The local image {}:{} does not match the lock file: {}",
+                    image.image, image.tag, reason
+                )));
+            }
+        }
+
+        let image_file = File::open(image_partial_path).map_err(io_error)?;
+        let image_size = image_file.metadata().map_err(io_error)?.len();
+        bundle
+            .append_file(&image.archive, image_size, &mut BufReader::new(image_file))
+            .map_err(io_error)?;
+
+        println!("Saved {}:{}", image.image, image.tag);
+        written_archives.push(&image.archive);
+    }
+
+    bundle.finish().map_err(io_error)?;
+    Ok(layers_only_images)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, fs::read, process, sync::Mutex};
+
+    use super::*;
+    use crate::project_config::{OCIImageTagConfigLock, ProjectConfigLock};
+
+    const FAKE_REF_PATH: &str = "fake-ref";
+
+    /// Keeps the image archives in memory. Like Docker's classic image store,
+    /// it can drop the registry digests of the images it loads.
+    struct FakeImageStore {
+        images: Mutex<BTreeMap<String, Vec<u8>>>, // image ref -> archive
+        keeps_loaded_digests: bool,
+    }
+
+    impl FakeImageStore {
+        fn new(images: &[(&str, Vec<u8>)], keeps_loaded_digests: bool) -> FakeImageStore {
+            FakeImageStore {
+                images: Mutex::new(
+                    images
+                        .iter()
+                        .map(|(image_ref, archive)| (image_ref.to_string(), archive.clone()))
+                        .collect(),
+                ),
+                keeps_loaded_digests,
+            }
+        }
+
+        fn get_image_refs(&self) -> Vec<String> {
+            self.images.lock().unwrap().keys().cloned().collect()
+        }
+    }
+
+    impl ContainerEngine for FakeImageStore {
+        fn get_program_name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn has_image(&self, image_ref: &str) -> AvatarResult<bool> {
+            Ok(self.images.lock().unwrap().contains_key(image_ref))
+        }
+
+        fn save_image(
+            &self,
+            image_ref: &str,
+            reader_fn: &mut dyn FnMut(&mut dyn Read) -> AvatarResult<()>,
+        ) -> AvatarResult<()> {
+            let archive = self.images.lock().unwrap()[image_ref].clone();
+            reader_fn(&mut &archive[..])
+        }
+
+        fn load_image(
+            &self,
+            writer_fn: &mut dyn FnMut(&mut dyn Write) -> AvatarResult<()>,
+        ) -> AvatarResult<()> {
+            let mut archive: Vec<u8> = vec![];
+            writer_fn(&mut archive)?;
+
+            let mut image_ref = String::new();
+            read_tar_entries(&mut &archive[..], &mut |entry, entry_reader| {
+                if entry.get_path() == FAKE_REF_PATH {
+                    entry_reader.read_to_string(&mut image_ref)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+            if !self.keeps_loaded_digests {
+                image_ref = format!("{}:<none>", image_ref.split('@').next().unwrap());
+            }
+
+            self.images.lock().unwrap().insert(image_ref, archive);
+            Ok(())
+        }
+    }
+
+    /// Archives a built image (locked by its image ID) in the classic `docker
+    /// save` format, returning the archive & the image ID
+    fn new_image_archive(image_name: &str) -> (Vec<u8>, String) {
+        let layer = format!("{} layer", image_name);
+        let config = format!(
+            r#"{{"rootfs":{{"type":"layers","diff_ids":["sha256:{}"]}}}}"#,
+            hex::encode(digest(&SHA256, layer.as_bytes()).as_ref())
+        );
+        let image_id = hex::encode(digest(&SHA256, config.as_bytes()).as_ref());
+        let config_path = format!("{}.json", image_id);
+        let manifest = format!(
+            r#"[{{"Config":"{}","Layers":["layer/layer.tar"]}}]"#,
+            config_path
+        );
+        let image_ref = get_image_ref(image_name, &image_id);
+
+        let mut tar_writer = TarWriter::new(Vec::new());
+        for (path, content) in &[
+            (config_path.as_str(), config.as_bytes()),
+            (FAKE_REF_PATH, image_ref.as_bytes()),
+            ("layer/layer.tar", layer.as_bytes()),
+            ("manifest.json", manifest.as_bytes()),
+        ] {
+            tar_writer
+                .append_file(path, content.len() as u64, &mut &content[..])
+                .unwrap();
+        }
+
+        (tar_writer.finish().unwrap(), image_id)
+    }
+
+    fn new_lock_bytes(locked_tags: &[(&str, &str, &str)]) -> Vec<u8> {
+        let mut images: BTreeMap<String, BTreeMap<String, OCIImageTagConfigLock>> = BTreeMap::new();
+        for (image_name, image_tag, hash) in locked_tags {
+            images.entry(image_name.to_string()).or_default().insert(
+                image_tag.to_string(),
+                OCIImageTagConfigLock::new(hash.to_string(), None, None),
+            );
+        }
+
+        serde_yaml::to_vec(&ProjectConfigLock::new(
+            vec![],
+            "abcdefghijklmnop".to_string(),
+            None,
+            None,
+            images,
+            BTreeMap::new(),
+            None,
+        ))
+        .unwrap()
+    }
+
+    /// Exports a bundle listing the given images, and returns its content
+    fn export_bundle(
+        engine: &dyn ContainerEngine,
+        test_name: &str,
+        listed_tags: &[(&str, &str, &str)],
+        lock_bytes: &[u8],
+    ) -> Vec<u8> {
+        let manifest = BundleManifest {
+            bundle_version: BUNDLE_VERSION,
+            avatar_version: AVATAR_CLI_VERSION.to_string(),
+            images: listed_tags
+                .iter()
+                .enumerate()
+                .map(|(i, (image_name, image_tag, hash))| BundleImage {
+                    image: image_name.to_string(),
+                    tag: image_tag.to_string(),
+                    hash: hash.to_string(),
+                    archive: format!("images/{}.tar", i),
+                })
+                .collect(),
+        };
+        let manifest_bytes = serde_json::to_vec_pretty(&manifest).unwrap();
+
+        let bundle_path =
+            temp_dir().join(format!("avatar-test-{}-{}.tar", process::id(), test_name));
+        let image_partial_path = PathBuf::from(format!("{}.image", bundle_path.display()));
+        let result = write_bundle(
+            engine,
+            &bundle_path,
+            &image_partial_path,
+            &manifest,
+            &manifest_bytes,
+            lock_bytes,
+        );
+        let bundle = read(&bundle_path);
+        let _ = remove_file(&bundle_path);
+        let _ = remove_file(&image_partial_path);
+
+        assert_eq!(result.unwrap(), Vec::<String>::new());
+        bundle.unwrap()
+    }
+
+    #[test]
+    fn bundles_restore_the_locked_images() {
+        let (node_archive, node_hash) = new_image_archive("node");
+        let (rust_archive, rust_hash) = new_image_archive("rust");
+        let locked_tags = [
+            ("node", "14", node_hash.as_str()),
+            ("node", "latest", node_hash.as_str()),
+            ("rust", "1", rust_hash.as_str()),
+        ];
+        let lock_bytes = new_lock_bytes(&locked_tags);
+        let source_store = FakeImageStore::new(
+            &[
+                (&get_image_ref("node", &node_hash), node_archive),
+                (&get_image_ref("rust", &rust_hash), rust_archive),
+            ],
+            true,
+        );
+        let bundle = export_bundle(&source_store, "round-trip", &locked_tags, &lock_bytes);
+
+        let target_store = FakeImageStore::new(&[], true);
+        let loaded_bundle =
+            load_bundle(&target_store, &mut &bundle[..], Path::new("b.tar"), None).unwrap();
+
+        assert_eq!(loaded_bundle.lock_bytes, lock_bytes);
+        assert_eq!(target_store.get_image_refs(), source_store.get_image_refs());
+        assert_eq!(
+            loaded_bundle.streamed_verifications[&("node".to_string(), "latest".to_string())],
+            Ok(ImageVerification::Manifest { layers: 1 })
+        );
+        assert!(verify_loaded_images(&target_store, &loaded_bundle, true).is_ok());
+    }
+
+    #[test]
+    fn load_bundle_refuses_manifests_not_matching_the_bundled_lock() {
+        let (node_archive, node_hash) = new_image_archive("node");
+        let (rust_archive, rust_hash) = new_image_archive("rust");
+        let source_store = FakeImageStore::new(
+            &[
+                (&get_image_ref("node", &node_hash), node_archive),
+                (&get_image_ref("rust", &rust_hash), rust_archive),
+            ],
+            true,
+        );
+        // The manifest smuggles an image that is not pinned by the lock file
+        let bundle = export_bundle(
+            &source_store,
+            "unpinned",
+            &[("node", "14", &node_hash), ("rust", "1", &rust_hash)],
+            &new_lock_bytes(&[("node", "14", &node_hash)]),
+        );
+
+        let target_store = FakeImageStore::new(&[], true);
+        let result = load_bundle(&target_store, &mut &bundle[..], Path::new("b.tar"), None);
+
+        assert!(matches!(result, Err(AvatarError::ConfigParse(_))));
+        assert!(target_store.get_image_refs().is_empty());
+    }
+
+    #[test]
+    fn load_bundle_refuses_bundles_of_another_lock_file() {
+        let (node_archive, node_hash) = new_image_archive("node");
+        let source_store =
+            FakeImageStore::new(&[(&get_image_ref("node", &node_hash), node_archive)], true);
+        let locked_tags = [("node", "14", node_hash.as_str())];
+        let bundle = export_bundle(
+            &source_store,
+            "other-lock",
+            &locked_tags,
+            &new_lock_bytes(&locked_tags),
+        );
+
+        let target_store = FakeImageStore::new(&[], true);
+        let current_lock_hash = digest(&SHA256, b"another lock file");
+        let result = load_bundle(
+            &target_store,
+            &mut &bundle[..],
+            Path::new("b.tar"),
+            Some((Path::new("Avatarfile.lock"), current_lock_hash.as_ref())),
+        );
+
+        assert!(matches!(result, Err(AvatarError::HashMismatch(_))));
+        assert!(target_store.get_image_refs().is_empty());
+    }
+
+    #[test]
+    fn verify_loaded_images_detects_engines_dropping_digests() {
+        let (node_archive, node_hash) = new_image_archive("node");
+        let source_store =
+            FakeImageStore::new(&[(&get_image_ref("node", &node_hash), node_archive)], true);
+        let locked_tags = [("node", "14", node_hash.as_str())];
+        let bundle = export_bundle(
+            &source_store,
+            "dropped-digests",
+            &locked_tags,
+            &new_lock_bytes(&locked_tags),
+        );
+
+        let target_store = FakeImageStore::new(&[], false);
+        let loaded_bundle =
+            load_bundle(&target_store, &mut &bundle[..], Path::new("b.tar"), None).unwrap();
+
+        assert!(matches!(
+            verify_loaded_images(&target_store, &loaded_bundle, false),
+            Err(AvatarError::ContainerEngine(_))
+        ));
+    }
+}
@@ -0,0 +1,642 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::{
+    env,
+    fs::{read_dir, read_link},
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use nix::unistd::{access, getgroups, getuid, AccessFlags, Group, User};
+use ring::digest::Digest;
+use serde::Serialize;
+
+use crate::{
+    avatar_env::{get_max_parallel_pulls, is_offline_mode, PROJECT_PATH, SESSION_TOKEN},
+    container_engines::{docker_api::get_socket_path, get_container_engine, ContainerEngine},
+    directories::{
+        get_project_path, AVATARFILE_LOCK_NAME, AVATARFILE_NAME, CONFIG_DIR_NAME, STATEFILE_NAME,
+        VOLATILE_DIR_NAME,
+    },
+    error::{AvatarError, AvatarResult},
+    project_config::{
+        get_config, get_config_lock, get_image_ref, ProjectConfig, ProjectConfigLock,
+    },
+    subcommands::update::print_table,
+};
+
+// Constants:
+// -----------------------------------------------------------------------------
+const PATH_PROBE_DIR: &str = "/avatar-cli-doctor-probe";
+const PATH_PROBE_MARKER: &str = "AVATAR_CLI_DOCTOR_PATH=";
+const PATH_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const PATH_REMEDIATION: &str = "Wrap the PATH changes of your shell startup files (like ~/.bashrc) with `if [ -z \"${AVATAR_CLI_SESSION_TOKEN}\" ]; then ... fi`, as explained in the README's \"Customized $PATH\" section";
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl CheckStatus {
+    fn get_name(&self) -> &'static str {
+        match self {
+            CheckStatus::Pass => "pass",
+            CheckStatus::Warn => "warn",
+            CheckStatus::Fail => "fail",
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CheckResult {
+    check: &'static str,
+    status: CheckStatus,
+    message: String,
+    remediation: Option<String>,
+}
+
+impl CheckResult {
+    fn new(
+        check: &'static str,
+        status: CheckStatus,
+        message: &str,
+        remediation: Option<&str>,
+    ) -> CheckResult {
+        CheckResult {
+            check,
+            status,
+            // Error messages span several lines, they're joined to fit in the
+            // report table
+            message: message.split_whitespace().collect::<Vec<&str>>().join(" "),
+            remediation: remediation.map(|r| r.to_string()),
+        }
+    }
+
+    fn pass(check: &'static str, message: &str) -> CheckResult {
+        CheckResult::new(check, CheckStatus::Pass, message, None)
+    }
+
+    fn warn(check: &'static str, message: &str, remediation: &str) -> CheckResult {
+        CheckResult::new(check, CheckStatus::Warn, message, Some(remediation))
+    }
+
+    fn fail(check: &'static str, message: &str, remediation: &str) -> CheckResult {
+        CheckResult::new(check, CheckStatus::Fail, message, Some(remediation))
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+/// Checks the environment Avatar CLI depends on (container engine, shell
+/// configuration, project files...), reporting how to fix the problems found.
+pub fn doctor_subcommand(json_format: bool) -> AvatarResult<()> {
+    let mut results: Vec<CheckResult> = vec![check_environment_variables()];
+
+    let (project_result, project) = check_project();
+    results.push(project_result);
+
+    let configured_engine = project
+        .as_ref()
+        .and_then(|(_, config, _)| *config.get_container_engine());
+    let engine = match get_container_engine(&configured_engine) {
+        Ok(engine) => Some(engine),
+        Err(e) => {
+            results.push(CheckResult::fail(
+                "container engine",
+                &e.to_string(),
+                "Select a valid container engine through AVATAR_CLI_CONTAINER_ENGINE or the containerEngine setting",
+            ));
+            None
+        }
+    };
+
+    let mut server_available = false;
+    if let Some(engine) = &engine {
+        let client_result = check_engine_client(engine.as_ref());
+        let client_available = client_result.status == CheckStatus::Pass;
+        results.push(client_result);
+
+        if client_available {
+            let server_result = check_engine_server(engine.as_ref());
+            server_available = server_result.status == CheckStatus::Pass;
+            results.push(server_result);
+        }
+        if engine.get_program_name() == "docker" {
+            results.push(check_docker_socket_access());
+        }
+        results.push(check_tar_availability(engine.as_ref()));
+    }
+
+    results.push(check_path());
+
+    if let Some((project_path, _, config_hash)) = &project {
+        let (project_results, config_lock) = check_project_files(project_path, config_hash);
+        results.extend(project_results);
+
+        if let (Some(engine), Some(config_lock), true) = (&engine, &config_lock, server_available) {
+            results.push(check_locked_images(engine.as_ref(), config_lock));
+        }
+    }
+
+    if json_format {
+        match serde_json::to_string_pretty(&results) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                return Err(AvatarError::Internal(format!(
+                    "Unknown error while serializing the report:\n\n{}\n",
+                    e
+                )))
+            }
+        }
+    } else {
+        print_report(&results);
+    }
+
+    match results
+        .iter()
+        .filter(|result| result.status == CheckStatus::Fail)
+        .count()
+    {
+        0 => Ok(()),
+        1 => Err(AvatarError::Environment("1 check failed".to_string())),
+        n => Err(AvatarError::Environment(format!("{} checks failed", n))),
+    }
+}
+
+fn check_docker_socket_access() -> CheckResult {
+    const CHECK: &str = "docker socket";
+
+    if getuid().is_root() {
+        return CheckResult::pass(CHECK, "Running as root");
+    }
+    let socket_path = match get_socket_path() {
+        Ok(socket_path) => socket_path,
+        Err(_) => {
+            return CheckResult::pass(CHECK, "DOCKER_HOST does not point to a unix socket");
+        }
+    };
+    if !socket_path.exists() {
+        return CheckResult::warn(
+            CHECK,
+            &format!("The socket {} does not exist", socket_path.display()),
+            "Start the Docker daemon, or point DOCKER_HOST to its socket",
+        );
+    }
+    if access(&socket_path, AccessFlags::R_OK | AccessFlags::W_OK).is_ok() {
+        return CheckResult::pass(
+            CHECK,
+            &format!("The socket {} is accessible", socket_path.display()),
+        );
+    }
+
+    let (is_docker_group_member, has_docker_group) = get_docker_group_membership();
+    match (is_docker_group_member, has_docker_group) {
+        (true, true) => CheckResult::fail(
+            CHECK,
+            &format!(
+                "Your user is in the docker group, but it can't access the socket {}",
+                socket_path.display()
+            ),
+            "Make sure the socket belongs to the docker group, with read & write permissions for it",
+        ),
+        (true, false) => CheckResult::fail(
+            CHECK,
+            &format!(
+                "Your user was added to the docker group, but the current session doesn't have it yet, so it can't access the socket {}",
+                socket_path.display()
+            ),
+            "Log out and in again (or run `newgrp docker`) so the group membership is applied",
+        ),
+        (false, _) => CheckResult::fail(
+            CHECK,
+            &format!(
+                "Your user can't access the socket {}, and it's not in the docker group",
+                socket_path.display()
+            ),
+            "Add your user to the docker group (`sudo usermod -aG docker $USER`), then log out and in again",
+        ),
+    }
+}
+
+fn check_engine_client(engine: &dyn ContainerEngine) -> CheckResult {
+    const CHECK: &str = "engine client";
+
+    match engine.check_client_availability() {
+        Ok(_) => CheckResult::pass(
+            CHECK,
+            &format!("The {} client is available", engine.get_program_name()),
+        ),
+        Err(e) => CheckResult::fail(
+            CHECK,
+            &e.to_string(),
+            &format!(
+                "Install {}, or select another container engine through AVATAR_CLI_CONTAINER_ENGINE",
+                engine.get_program_name()
+            ),
+        ),
+    }
+}
+
+fn check_engine_server(engine: &dyn ContainerEngine) -> CheckResult {
+    const CHECK: &str = "engine server";
+
+    match engine.check_server_availability() {
+        Ok(_) => CheckResult::pass(
+            CHECK,
+            &format!("The {} server is reachable", engine.get_program_name()),
+        ),
+        Err(e) => {
+            let message = e.to_string();
+            let remediation = match message.to_lowercase().contains("permission denied") {
+                true => "Add your user to the docker group (`sudo usermod -aG docker $USER`), then log out and in again".to_string(),
+                false => format!(
+                    "Start the {} service (e.g. `sudo systemctl start {}`), and check the DOCKER_HOST variable",
+                    engine.get_program_name(),
+                    engine.get_program_name()
+                ),
+            };
+            CheckResult::fail(CHECK, &message, &remediation)
+        }
+    }
+}
+
+fn check_environment_variables() -> CheckResult {
+    const CHECK: &str = "environment variables";
+
+    let errors: Vec<String> = [
+        is_offline_mode().err(),
+        get_max_parallel_pulls().err(),
+        get_container_engine(&None).err(),
+    ]
+    .iter()
+    .flatten()
+    .map(|e| e.to_string())
+    .collect();
+
+    match errors.is_empty() {
+        true => CheckResult::pass(CHECK, "The AVATAR_CLI_* variables are valid"),
+        false => CheckResult::fail(
+            CHECK,
+            &errors.join(" "),
+            "Fix or unset the reported environment variables",
+        ),
+    }
+}
+
+fn check_locked_images(
+    engine: &dyn ContainerEngine,
+    config_lock: &ProjectConfigLock,
+) -> CheckResult {
+    const CHECK: &str = "locked images";
+
+    let mut image_count = 0;
+    let mut missing_image_refs: Vec<String> = vec![];
+    for (image_name, image_tags) in config_lock.get_images() {
+        for (image_tag, image_tag_config) in image_tags {
+            image_count += 1;
+            let image_ref = get_image_ref(image_name, image_tag_config.get_hash());
+            // Engine errors are already reported by the server check
+            if !engine.has_image(&image_ref).unwrap_or(false) {
+                missing_image_refs.push(format!("{}:{}", image_name, image_tag));
+            }
+        }
+    }
+
+    match missing_image_refs.is_empty() {
+        true => CheckResult::pass(
+            CHECK,
+            &format!("The {} locked images are available locally", image_count),
+        ),
+        false => CheckResult::warn(
+            CHECK,
+            &format!(
+                "{} of the {} locked images are not available locally: {}",
+                missing_image_refs.len(),
+                image_count,
+                missing_image_refs.join(", ")
+            ),
+            "Run `avatar install` (or `avatar bundle import` in machines without registry access)",
+        ),
+    }
+}
+
+/// Inside a session the project tools must be the first ones found in PATH.
+/// Outside of it, an interactive shell is started (as a session would) to
+/// check whether its startup files put other paths in front of them.
+fn check_path() -> CheckResult {
+    const CHECK: &str = "PATH";
+
+    let path_var = env::var("PATH").unwrap_or_default();
+    if let (Ok(_), Ok(project_path)) = (env::var(SESSION_TOKEN), env::var(PROJECT_PATH)) {
+        let bin_path = PathBuf::from(project_path)
+            .join(CONFIG_DIR_NAME)
+            .join(VOLATILE_DIR_NAME)
+            .join("bin");
+
+        return match path_var.split(':').next() == bin_path.to_str() {
+            true => CheckResult::pass(CHECK, "The project tools come first in PATH"),
+            false => CheckResult::fail(
+                CHECK,
+                &format!(
+                    "PATH was changed inside the Avatar CLI session, {} is not its first entry",
+                    bin_path.display()
+                ),
+                PATH_REMEDIATION,
+            ),
+        };
+    }
+
+    let shell = match env::var("SHELL") {
+        Ok(shell) if !shell.is_empty() => shell,
+        _ => {
+            return CheckResult::pass(
+                CHECK,
+                "SHELL is not set, there are no startup files to check",
+            )
+        }
+    };
+
+    match get_session_shell_path(&shell, &path_var) {
+        Some(session_path) => match session_path.split(':').next() == Some(PATH_PROBE_DIR) {
+            true => CheckResult::pass(
+                CHECK,
+                &format!("The {} startup files keep PATH untouched in sessions", shell),
+            ),
+            false => CheckResult::warn(
+                CHECK,
+                &format!(
+                    "The {} startup files put other paths in front of the project tools in sessions",
+                    shell
+                ),
+                PATH_REMEDIATION,
+            ),
+        },
+        None => CheckResult::warn(
+            CHECK,
+            &format!("Unable to check how the {} startup files change PATH", shell),
+            PATH_REMEDIATION,
+        ),
+    }
+}
+
+fn check_project() -> (CheckResult, Option<(PathBuf, ProjectConfig, Digest)>) {
+    const CHECK: &str = "project";
+
+    let project_path = match get_project_path() {
+        Ok(Some(project_path)) => project_path,
+        Ok(None) => return (
+            CheckResult::warn(
+                CHECK,
+                "Not inside an Avatar CLI project, its checks are skipped",
+                "Run `avatar doctor` inside a project directory, or create one with `avatar init`",
+            ),
+            None,
+        ),
+        Err(e) => {
+            return (
+                CheckResult::fail(
+                    CHECK,
+                    &e.to_string(),
+                    "Run `avatar doctor` from an existing directory",
+                ),
+                None,
+            )
+        }
+    };
+
+    let config_path = project_path.join(CONFIG_DIR_NAME).join(AVATARFILE_NAME);
+    match get_config(&config_path) {
+        Ok((config, config_hash)) => (
+            CheckResult::pass(
+                CHECK,
+                &format!("The Avatarfile {} is valid", config_path.display()),
+            ),
+            Some((project_path, config, config_hash)),
+        ),
+        Err(e) => (
+            CheckResult::fail(
+                CHECK,
+                &e.to_string(),
+                "Fix the Avatarfile, or run `avatar migrate` if it was written for an older version",
+            ),
+            None,
+        ),
+    }
+}
+
+/// The lock file, and the volatile directory (generated from it by
+/// `avatar install`) have to be in sync with the Avatarfile.
+fn check_project_files(
+    project_path: &Path,
+    config_hash: &Digest,
+) -> (Vec<CheckResult>, Option<ProjectConfigLock>) {
+    const LOCK_CHECK: &str = "lock file";
+    const VOLATILE_CHECK: &str = "volatile directory";
+
+    let config_lock_path = project_path
+        .join(CONFIG_DIR_NAME)
+        .join(AVATARFILE_LOCK_NAME);
+    if !config_lock_path.is_file() {
+        return (
+            vec![CheckResult::warn(
+                LOCK_CHECK,
+                "The project has no lock file yet",
+                "Run `avatar install`, and commit the generated lock file",
+            )],
+            None,
+        );
+    }
+
+    let (config_lock, config_lock_hash) = match get_config_lock(&config_lock_path) {
+        Ok(config_lock) => config_lock,
+        Err(e) => {
+            return (
+                vec![CheckResult::fail(
+                LOCK_CHECK,
+                &e.to_string(),
+                "Run `avatar migrate` if the lock file was written by an older version, or fix it",
+            )],
+                None,
+            )
+        }
+    };
+
+    let lock_result = match config_hash.as_ref() == &config_lock.get_project_config_hash()[..] {
+        true => CheckResult::pass(LOCK_CHECK, "The lock file is in sync with the Avatarfile"),
+        false => CheckResult::warn(
+            LOCK_CHECK,
+            "The lock file is out of sync with the Avatarfile",
+            "Run `avatar install`, and commit the updated lock file",
+        ),
+    };
+
+    let volatile_path = project_path.join(CONFIG_DIR_NAME).join(VOLATILE_DIR_NAME);
+    let volatile_result = match get_config_lock(&volatile_path.join(STATEFILE_NAME)) {
+        Err(_) => CheckResult::warn(
+            VOLATILE_CHECK,
+            "The project is not installed in this machine",
+            "Run `avatar install`",
+        ),
+        Ok((project_state, _))
+            if config_lock_hash.as_ref() != &project_state.get_project_config_hash()[..] =>
+        {
+            CheckResult::warn(
+                VOLATILE_CHECK,
+                "The volatile directory was generated from another version of the lock file",
+                "Run `avatar install`",
+            )
+        }
+        Ok(_) => match get_stale_bin_links(&volatile_path.join("bin")) {
+            Some(link_target) => CheckResult::warn(
+                VOLATILE_CHECK,
+                &format!(
+                    "The project tools point to {}, instead of the current avatar binary",
+                    link_target.display()
+                ),
+                &format!(
+                    "Remove the {} directory, and run `avatar install`",
+                    volatile_path.display()
+                ),
+            ),
+            None => CheckResult::pass(VOLATILE_CHECK, "The volatile directory is up to date"),
+        },
+    };
+
+    (vec![lock_result, volatile_result], Some(config_lock))
+}
+
+fn check_tar_availability(engine: &dyn ContainerEngine) -> CheckResult {
+    const CHECK: &str = "tar";
+
+    if !engine.needs_passwd_files() {
+        return CheckResult::pass(
+            CHECK,
+            &format!("Not needed by {}", engine.get_program_name()),
+        );
+    }
+
+    match engine.can_read_container_files() {
+        true => CheckResult::pass(CHECK, "tar is available"),
+        false => CheckResult::warn(
+            CHECK,
+            "tar is not available, so the passwd files improving the integration with ssh-agent are not generated",
+            "Install tar with your system's package manager, and run `avatar install` again",
+        ),
+    }
+}
+
+/// Returns whether the user is a member of the docker group, and whether the
+/// current session already has it (memberships are applied on login)
+fn get_docker_group_membership() -> (bool, bool) {
+    let docker_group = match Group::from_name("docker") {
+        Ok(Some(docker_group)) => docker_group,
+        _ => return (false, false),
+    };
+
+    let has_docker_group = getgroups().is_ok_and(|groups| groups.contains(&docker_group.gid));
+    let is_docker_group_member = has_docker_group
+        || User::from_uid(getuid())
+            .ok()
+            .flatten()
+            .is_some_and(|user| docker_group.mem.contains(&user.name));
+
+    (is_docker_group_member, has_docker_group)
+}
+
+/// Runs an interactive shell, as an Avatar CLI session would, and returns the
+/// PATH value it ends up with. Some startup files wait for input or take ages,
+/// so the shell is killed after a while.
+fn get_session_shell_path(shell: &str, path_var: &str) -> Option<String> {
+    let mut child = Command::new(shell)
+        .args([
+            "-i",
+            "-c",
+            &format!("printf '\\n{}%s\\n' \"$PATH\"", PATH_PROBE_MARKER),
+        ])
+        .env("PATH", format!("{}:{}", PATH_PROBE_DIR, path_var))
+        .env(SESSION_TOKEN, "avatar-cli-doctor")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+
+    let started_at = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if started_at.elapsed() < PATH_PROBE_TIMEOUT => {
+                sleep(Duration::from_millis(50))
+            }
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    }
+
+    let mut stdout = String::new();
+    child.stdout.take()?.read_to_string(&mut stdout).ok()?;
+    stdout
+        .lines()
+        .find_map(|line| line.strip_prefix(PATH_PROBE_MARKER))
+        .map(|session_path| session_path.to_string())
+}
+
+/// Returns the target of the first link that does not point to the running
+/// avatar binary (it was moved, or another copy was used to install the project)
+fn get_stale_bin_links(bin_path: &PathBuf) -> Option<PathBuf> {
+    let avatar_path = env::current_exe().ok()?;
+
+    read_dir(bin_path)
+        .ok()?
+        .flatten()
+        .filter_map(|entry| read_link(entry.path()).ok())
+        .find(|link_target| link_target != &avatar_path)
+}
+
+fn print_report(results: &[CheckResult]) {
+    let rows: Vec<Vec<String>> = results
+        .iter()
+        .map(|result| {
+            vec![
+                result.check.to_string(),
+                result.status.get_name().to_string(),
+                result.message.clone(),
+            ]
+        })
+        .collect();
+    print_table(&["CHECK", "STATUS", "DETAILS"], &rows);
+
+    let remediations: Vec<String> = results
+        .iter()
+        .filter_map(|result| {
+            result
+                .remediation
+                .as_ref()
+                .map(|remediation| format!("  - {}: {}", result.check, remediation))
+        })
+        .collect();
+    if !remediations.is_empty() {
+        println!(
+            "\nHow to fix the problems found:\n{}",
+            remediations.join("\n")
+        );
+    }
+}
@@ -0,0 +1,239 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::{
+    fs::{remove_file, write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    avatarfile_editor::{quote_scalar, AvatarfileEditor},
+    directories::{
+        get_required_project_path, AVATARFILE_LOCK_NAME, AVATARFILE_NAME, CONFIG_DIR_NAME,
+    },
+    error::{AvatarError, AvatarResult},
+    project_config::{get_file_bytes, parse_config, split_image_ref, ProjectConfig},
+    subcommands::install::{check_not_in_session, install_subcommand},
+};
+
+pub fn add_subcommand(
+    image_ref: &str,
+    binary_names: &[&str],
+    envs: &[&str],
+    volumes: &[&str],
+) -> AvatarResult<()> {
+    check_not_in_session()?;
+
+    // The Avatarfile declares tags, their digests are pinned by the lock file
+    let (image_name, image_tag) = match split_image_ref(image_ref) {
+        (name, Some(tag)) if !image_ref.contains('@') => (name, tag),
+        _ => {
+            return Err(AvatarError::Usage(format!(
+                "The image reference '{}' must specify a tag (for example: node:14-buster)",
+                image_ref
+            )))
+        }
+    };
+
+    let config_path = get_config_path()?;
+    let (config_text, config) = read_config(&config_path)?;
+
+    for binary_name in binary_names {
+        if let Some((other_name, other_tag)) = find_binary(&config, binary_name) {
+            if other_name != image_name || other_tag != image_tag {
+                return Err(AvatarError::InvalidConfig(format!(
+                    "The binary '{}' is already provided by the image {}:{}",
+                    binary_name, other_name, other_tag
+                )));
+            }
+        }
+    }
+
+    let tag_path = ["images", image_name, "tags", image_tag];
+    let binaries_path = [&tag_path[..], &["binaries"]].concat();
+    let env_path = [&tag_path[..], &["runConfig", "env"]].concat();
+    let volumes_path = [&tag_path[..], &["runConfig", "volumes"]].concat();
+
+    let mut editor = AvatarfileEditor::new(&config_text);
+    editor.ensure_entry(&tag_path);
+
+    let existing_binaries = editor.get_keys(&binaries_path);
+    for binary_name in binary_names {
+        if !existing_binaries.iter().any(|b| b == binary_name) {
+            editor.set_entry(&binaries_path, binary_name, "{}");
+        }
+    }
+
+    for env in envs {
+        let (env_name, env_value) = match env.find('=') {
+            Some(i) if i > 0 => (&env[..i], &env[i + 1..]),
+            _ => {
+                return Err(AvatarError::Usage(format!(
+                    "Invalid environment variable '{}', expected the form KEY=VALUE",
+                    env
+                )))
+            }
+        };
+        editor.set_entry(&env_path, env_name, &quote_scalar(env_value));
+    }
+
+    let existing_volumes = editor.get_keys(&volumes_path);
+    for volume in volumes {
+        if !Path::new(volume).is_absolute() {
+            return Err(AvatarError::Usage(format!(
+                "Invalid volume '{}', the container path must be absolute",
+                volume
+            )));
+        }
+        if !existing_volumes.iter().any(|v| v == volume) {
+            editor.set_entry(&volumes_path, volume, "{}");
+        }
+    }
+
+    save_and_install(&config_path, &config_text, &editor)
+}
+
+pub fn remove_subcommand(image_ref: Option<&str>, binary_names: &[&str]) -> AvatarResult<()> {
+    check_not_in_session()?;
+
+    let config_path = get_config_path()?;
+    let (config_text, config) = read_config(&config_path)?;
+    let mut editor = AvatarfileEditor::new(&config_text);
+
+    for binary_name in binary_names {
+        let (image_name, image_tag) = match find_binary(&config, binary_name) {
+            Some(image) => image,
+            None => {
+                return Err(AvatarError::Usage(format!(
+                    "The binary '{}' is not defined in the Avatarfile",
+                    binary_name
+                )))
+            }
+        };
+
+        let tags_path = ["images", image_name, "tags"];
+        let tag_path = [&tags_path[..], &[image_tag]].concat();
+        let binaries_path = [&tag_path[..], &["binaries"]].concat();
+
+        editor.remove_entry(&[&binaries_path[..], &[binary_name]].concat());
+        if editor.get_keys(&binaries_path).is_empty() {
+            editor.remove_entry(&binaries_path);
+        }
+        // An entry without children would be parsed as null, not as an empty tag config
+        if editor.get_keys(&tag_path).is_empty() {
+            editor.set_entry(&tags_path, image_tag, "{}");
+        }
+    }
+
+    if let Some(image_ref) = image_ref {
+        let removed = match split_image_ref(image_ref) {
+            (image_name, Some(image_tag)) => {
+                let tags_path = ["images", image_name, "tags"];
+                let removed = editor.remove_entry(&[&tags_path[..], &[image_tag]].concat());
+                if editor.get_keys(&tags_path).is_empty() {
+                    editor.remove_entry(&["images", image_name]);
+                }
+                removed
+            }
+            (image_name, None) => editor.remove_entry(&["images", image_name]),
+        };
+
+        if !removed {
+            return Err(AvatarError::Usage(format!(
+                "The image '{}' is not defined in the Avatarfile",
+                image_ref
+            )));
+        }
+    }
+
+    if editor.get_keys(&["images"]).is_empty() {
+        editor.remove_entry(&["images"]);
+    }
+
+    save_and_install(&config_path, &config_text, &editor)
+}
+
+fn find_binary<'a>(config: &'a ProjectConfig, binary_name: &str) -> Option<(&'a str, &'a str)> {
+    for (image_name, image_config) in config.get_images().as_ref()? {
+        for (image_tag, tag_config) in image_config.get_tags() {
+            if let Some(binaries) = tag_config.get_binaries() {
+                if binaries.contains_key(binary_name) {
+                    return Some((image_name, image_tag));
+                }
+            }
+        }
+    }
+    None
+}
+
+fn get_config_path() -> AvatarResult<PathBuf> {
+    Ok(get_required_project_path()?
+        .join(CONFIG_DIR_NAME)
+        .join(AVATARFILE_NAME))
+}
+
+fn read_config(config_path: &Path) -> AvatarResult<(String, ProjectConfig)> {
+    let config_text = match String::from_utf8(get_file_bytes(config_path)?) {
+        Ok(text) => text,
+        Err(_) => {
+            return Err(AvatarError::ConfigParse(format!(
+                "The file {} is not valid UTF-8",
+                config_path.display()
+            )))
+        }
+    };
+    let config = parse_config(config_text.as_bytes(), config_path)?;
+
+    Ok((config_text, config))
+}
+
+/// The original Avatarfile (and its lock file) are restored when the install
+/// step fails, so the project is never left edited but unlocked.
+fn save_and_install(
+    config_path: &Path,
+    original_config_text: &str,
+    editor: &AvatarfileEditor,
+) -> AvatarResult<()> {
+    let config_text = editor.get_text();
+
+    // We refuse to write anything that wouldn't be accepted by the install step
+    parse_config(config_text.as_bytes(), config_path)?;
+
+    let config_lock_path = config_path.with_file_name(AVATARFILE_LOCK_NAME);
+    let original_config_lock = match config_lock_path.exists() {
+        true => Some(get_file_bytes(&config_lock_path)?),
+        false => None,
+    };
+
+    write_config(config_path, config_text.as_bytes())?;
+    if let Err(e) = install_subcommand(true) {
+        write_config(config_path, original_config_text.as_bytes())?;
+        match &original_config_lock {
+            Some(config_lock_bytes) => write_config(&config_lock_path, config_lock_bytes)?,
+            None => {
+                let _ = remove_file(&config_lock_path);
+            }
+        }
+        eprintln!(
+            "The install step failed, the file '{}' has been restored",
+            config_path.display()
+        );
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn write_config(config_path: &Path, config_bytes: &[u8]) -> AvatarResult<()> {
+    match write(config_path, config_bytes) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(AvatarError::CantCreate(format!(
+            "Unable to write file '{}'\n\n{}\n",
+            config_path.display(),
+            e
+        ))),
+    }
+}
@@ -0,0 +1,521 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{read, write},
+    path::Path,
+};
+
+use crate::{
+    container_engines::{get_container_engine, ContainerEngine},
+    directories::{PINNED_IMAGES_FILE_NAME, VOLATILE_DIR_NAME},
+    error::{AvatarError, AvatarResult},
+    project_config::{get_config_lock, get_image_ref, is_built_image_name, ProjectConfigLock},
+    subcommands::update::{get_config_lock_path, print_table},
+};
+
+// Constants:
+// -----------------------------------------------------------------------------
+const INSTALL_HELPER_ROLE_LABEL: &str = "install_helper.container_role.avatar-cli";
+const MANAGED_TOOL_ROLE_LABEL: &str = "managed_tool.container_role.avatar-cli";
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+/// Image name -> digests (without the `sha256:` prefix) pinned by the project
+type PinnedImages = BTreeMap<String, BTreeSet<String>>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum ResourceKind {
+    Container,
+    Image,
+    Volume,
+}
+
+impl ResourceKind {
+    fn get_name(&self) -> &'static str {
+        match self {
+            ResourceKind::Container => "container",
+            ResourceKind::Image => "image",
+            ResourceKind::Volume => "volume",
+        }
+    }
+}
+
+/// A resource created for the project, but not needed anymore
+struct StaleResource {
+    kind: ResourceKind,
+    name: String,
+    size: Option<u64>, // in bytes, when the engine reports it
+}
+
+impl StaleResource {
+    fn remove(&self, engine: &dyn ContainerEngine) -> AvatarResult<()> {
+        match self.kind {
+            ResourceKind::Container => engine.remove_containers(std::slice::from_ref(&self.name)),
+            ResourceKind::Image => engine.remove_image(&self.name),
+            ResourceKind::Volume => engine.remove_volume(&self.name),
+        }
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+/// Removes the project resources that the lock file does not reference anymore:
+/// stopped helper & tool containers, images pinned by previous lock revisions,
+/// and managed volumes.
+pub fn gc_subcommand(dry_run: bool) -> AvatarResult<()> {
+    let config_lock_path = get_config_lock_path()?;
+    let (config_lock, _) = get_config_lock(&config_lock_path)?;
+    let volatile_path = config_lock_path.with_file_name(VOLATILE_DIR_NAME);
+    let pinned_images = get_pinned_images(&volatile_path)?;
+
+    let engine = get_container_engine(config_lock.get_container_engine())?;
+    engine.check_client_availability()?;
+
+    let project_filter = format!(
+        "{}.byid.projects.avatar-cli",
+        config_lock.get_project_internal_id()
+    );
+
+    // Containers go first, as they keep their images & volumes in use
+    let mut stale_resources = find_orphaned_containers(engine.as_ref(), &project_filter)?;
+    stale_resources.extend(find_stale_images(
+        engine.as_ref(),
+        &config_lock,
+        &pinned_images,
+        &project_filter,
+    )?);
+    stale_resources.extend(find_stale_volumes(
+        engine.as_ref(),
+        &config_lock,
+        &project_filter,
+    )?);
+
+    if dry_run {
+        println!(
+            "Only the image digests pinned by previous lock revisions of this project (as \
+             recorded by 'avatar install' on this machine) are removed, other local digests of \
+             the same repositories are kept\n"
+        );
+    }
+    if stale_resources.is_empty() {
+        println!("There is nothing to remove");
+        return Ok(());
+    }
+
+    let mut errors: Vec<String> = vec![];
+    let mut removed_resources: Vec<&StaleResource> = vec![];
+    let mut rows: Vec<Vec<String>> = vec![];
+    for stale_resource in &stale_resources {
+        let status = match dry_run {
+            true => {
+                removed_resources.push(stale_resource);
+                "stale"
+            }
+            false => match stale_resource.remove(engine.as_ref()) {
+                Ok(_) => {
+                    removed_resources.push(stale_resource);
+                    "removed"
+                }
+                Err(e) => {
+                    errors.push(e.to_string());
+                    "failed"
+                }
+            },
+        };
+
+        rows.push(vec![
+            stale_resource.kind.get_name().to_string(),
+            stale_resource.name.clone(),
+            stale_resource
+                .size
+                .map(get_human_size)
+                .unwrap_or_else(|| "-".to_string()),
+            status.to_string(),
+        ]);
+    }
+
+    print_table(&["KIND", "NAME", "SIZE", "STATUS"], &rows);
+    if !dry_run {
+        forget_removed_images(
+            &volatile_path,
+            &config_lock,
+            &pinned_images,
+            &removed_resources,
+        )?;
+    }
+    println!(
+        "\n{} {} ({} of images, the engines don't report the volume sizes)",
+        match dry_run {
+            true => "Would remove",
+            false => "Removed",
+        },
+        get_resources_summary(&removed_resources),
+        get_human_size(
+            removed_resources
+                .iter()
+                .filter_map(|stale_resource| stale_resource.size)
+                .sum()
+        )
+    );
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(AvatarError::ContainerEngine(errors.join("\n"))),
+    }
+}
+
+/// Helper containers are removed at the end of the install step, and the tool
+/// containers when the tools exit, so the stopped ones were left behind by
+/// interrupted executions.
+fn find_orphaned_containers(
+    engine: &dyn ContainerEngine,
+    project_filter: &str,
+) -> AvatarResult<Vec<StaleResource>> {
+    let mut stale_containers: Vec<StaleResource> = vec![];
+    for role_label in &[INSTALL_HELPER_ROLE_LABEL, MANAGED_TOOL_ROLE_LABEL] {
+        let labels = [project_filter, role_label];
+        let running_containers: BTreeSet<String> = engine
+            .find_containers(&labels, false)?
+            .into_iter()
+            .collect();
+
+        for container_name in engine.find_containers(&labels, true)? {
+            if !running_containers.contains(&container_name) {
+                stale_containers.push(StaleResource {
+                    kind: ResourceKind::Container,
+                    name: container_name,
+                    size: None,
+                });
+            }
+        }
+    }
+
+    Ok(stale_containers)
+}
+
+/// Pulled images carry no labels, and other projects (or the user) can use
+/// other digests of the same repositories, so only the digests recorded by
+/// `record_pinned_images` are considered. Built images are labelled with the
+/// project id.
+fn find_stale_images(
+    engine: &dyn ContainerEngine,
+    config_lock: &ProjectConfigLock,
+    pinned_images: &PinnedImages,
+    project_filter: &str,
+) -> AvatarResult<Vec<StaleResource>> {
+    let mut stale_image_refs: Vec<String> = vec![];
+    let locked_images = get_locked_images(config_lock);
+    let built_image_ids: BTreeSet<&String> = config_lock
+        .get_images()
+        .iter()
+        .filter(|(image_name, _)| is_built_image_name(image_name))
+        .flat_map(|(_, image_tags)| image_tags.values().map(|tag| tag.get_hash()))
+        .collect();
+
+    for (image_name, pinned_digests) in pinned_images {
+        let locked_digests = locked_images.get(image_name);
+        let local_digests: BTreeSet<String> =
+            engine.find_image_digests(image_name)?.into_iter().collect();
+
+        for digest in pinned_digests {
+            if local_digests.contains(digest)
+                && !locked_digests.is_some_and(|locked_digests| locked_digests.contains(digest))
+            {
+                stale_image_refs.push(get_image_ref(image_name, digest));
+            }
+        }
+    }
+
+    for image_id in engine.find_image_ids(&["avatar_cli", project_filter])? {
+        if !built_image_ids.contains(&image_id) {
+            stale_image_refs.push(format!("sha256:{}", image_id));
+        }
+    }
+
+    stale_image_refs
+        .into_iter()
+        .map(|image_ref| {
+            Ok(StaleResource {
+                kind: ResourceKind::Image,
+                size: engine.get_image_size(&image_ref)?,
+                name: image_ref,
+            })
+        })
+        .collect()
+}
+
+fn find_stale_volumes(
+    engine: &dyn ContainerEngine,
+    config_lock: &ProjectConfigLock,
+    project_filter: &str,
+) -> AvatarResult<Vec<StaleResource>> {
+    let binaries_volumes = config_lock
+        .get_binaries_configs()
+        .filter_map(|(_, binary_config)| binary_config.get_run_config().as_ref())
+        .flat_map(|run_config| run_config.get_volumes().iter().flatten());
+    let services_volumes = config_lock
+        .get_services()
+        .iter()
+        .flat_map(|services| services.values())
+        .flat_map(|service_config| service_config.get_volumes().iter().flatten());
+    let referenced_volumes: BTreeSet<&String> = binaries_volumes
+        .chain(services_volumes)
+        .map(|volume_config| volume_config.get_name())
+        .collect();
+
+    Ok(engine
+        .find_volumes(&["avatar_cli", project_filter])?
+        .into_iter()
+        .filter(|volume_name| !referenced_volumes.contains(volume_name))
+        .map(|volume_name| StaleResource {
+            kind: ResourceKind::Volume,
+            name: volume_name,
+            size: None,
+        })
+        .collect())
+}
+
+/// Keeps recording the digests that are still locked, or that could not be
+/// removed. The ones not available anymore are not the project's concern.
+fn forget_removed_images(
+    volatile_path: &Path,
+    config_lock: &ProjectConfigLock,
+    pinned_images: &PinnedImages,
+    removed_resources: &[&StaleResource],
+) -> AvatarResult<()> {
+    if pinned_images.is_empty() {
+        return Ok(());
+    }
+
+    let removed_image_refs: BTreeSet<&String> = removed_resources
+        .iter()
+        .filter(|resource| resource.kind == ResourceKind::Image)
+        .map(|resource| &resource.name)
+        .collect();
+
+    let mut kept_images = get_locked_images(config_lock);
+    for (image_name, pinned_digests) in pinned_images {
+        for digest in pinned_digests {
+            if !removed_image_refs.contains(&get_image_ref(image_name, digest)) {
+                kept_images
+                    .entry(image_name.clone())
+                    .or_default()
+                    .insert(digest.clone());
+            }
+        }
+    }
+
+    save_pinned_images(volatile_path, &kept_images)
+}
+
+/// Decimal units, as the container engines use
+fn get_human_size(size: u64) -> String {
+    let units = ["B", "kB", "MB", "GB", "TB"];
+    let mut value = size as f64;
+    let mut unit_index = 0;
+    while value >= 1000.0 && unit_index < units.len() - 1 {
+        value /= 1000.0;
+        unit_index += 1;
+    }
+
+    match unit_index {
+        0 => format!("{} {}", size, units[0]),
+        _ => format!("{:.1} {}", value, units[unit_index]),
+    }
+}
+
+/// Pulled images only, built images are found through their labels
+fn get_locked_images(config_lock: &ProjectConfigLock) -> PinnedImages {
+    config_lock
+        .get_images()
+        .iter()
+        .filter(|(image_name, _)| !is_built_image_name(image_name))
+        .map(|(image_name, image_tags)| {
+            (
+                image_name.clone(),
+                image_tags
+                    .values()
+                    .map(|tag| tag.get_hash().clone())
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+fn get_pinned_images(volatile_path: &Path) -> AvatarResult<PinnedImages> {
+    let pinned_images_path = volatile_path.join(PINNED_IMAGES_FILE_NAME);
+    if !pinned_images_path.is_file() {
+        return Ok(PinnedImages::new());
+    }
+
+    let pinned_images_bytes = read(&pinned_images_path).map_err(|e| {
+        AvatarError::Io(format!(
+            "Unable to read {}\n\n{}\n",
+            pinned_images_path.display(),
+            e
+        ))
+    })?;
+    serde_yaml::from_slice(&pinned_images_bytes).map_err(|e| {
+        AvatarError::ConfigParse(format!(
+            "Unable to parse {}\n\n{}\n",
+            pinned_images_path.display(),
+            e
+        ))
+    })
+}
+
+fn get_resources_summary(resources: &[&StaleResource]) -> String {
+    let counts: Vec<String> = [
+        ResourceKind::Container,
+        ResourceKind::Image,
+        ResourceKind::Volume,
+    ]
+    .iter()
+    .map(|kind| {
+        let count = resources
+            .iter()
+            .filter(|resource| resource.kind == *kind)
+            .count();
+        match count {
+            1 => format!("1 {}", kind.get_name()),
+            _ => format!("{} {}s", count, kind.get_name()),
+        }
+    })
+    .collect();
+
+    counts.join(", ")
+}
+
+/// Remembers the digests pinned by the lock file, so `avatar gc` can remove
+/// them once a later lock revision stops pinning them.
+pub fn record_pinned_images(
+    volatile_path: &Path,
+    config_lock: &ProjectConfigLock,
+) -> AvatarResult<()> {
+    let pinned_images = get_pinned_images(volatile_path)?;
+    let mut updated_images = pinned_images.clone();
+    for (image_name, locked_digests) in get_locked_images(config_lock) {
+        updated_images
+            .entry(image_name)
+            .or_default()
+            .extend(locked_digests);
+    }
+
+    match updated_images == pinned_images {
+        true => Ok(()),
+        false => save_pinned_images(volatile_path, &updated_images),
+    }
+}
+
+fn save_pinned_images(volatile_path: &Path, pinned_images: &PinnedImages) -> AvatarResult<()> {
+    let pinned_images_path = volatile_path.join(PINNED_IMAGES_FILE_NAME);
+    let pinned_images_bytes = serde_yaml::to_vec(pinned_images).map_err(|e| {
+        AvatarError::Internal(format!(
+            "Unknown error while serializing the pinned images:\n\n{}\n",
+            e
+        ))
+    })?;
+
+    write(&pinned_images_path, pinned_images_bytes).map_err(|e| {
+        AvatarError::Io(format!(
+            "Unable to write {}\n\n{}\n",
+            pinned_images_path.display(),
+            e
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs::{create_dir_all, remove_dir_all},
+        process,
+    };
+
+    use super::*;
+    use crate::project_config::OCIImageTagConfigLock;
+
+    /// Lists the same digests for all the repositories
+    struct FakeImageStore {
+        digests: Vec<String>,
+    }
+
+    impl ContainerEngine for FakeImageStore {
+        fn get_program_name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn find_image_digests(&self, _image_name: &str) -> AvatarResult<Vec<String>> {
+            Ok(self.digests.clone())
+        }
+
+        fn find_image_ids(&self, _labels: &[&str]) -> AvatarResult<Vec<String>> {
+            Ok(vec![])
+        }
+
+        fn get_image_size(&self, _image_ref: &str) -> AvatarResult<Option<u64>> {
+            Ok(None)
+        }
+    }
+
+    fn new_config_lock(locked_tags: &[(&str, &str, &str)]) -> ProjectConfigLock {
+        let mut images: BTreeMap<String, BTreeMap<String, OCIImageTagConfigLock>> = BTreeMap::new();
+        for (image_name, image_tag, hash) in locked_tags {
+            images.entry(image_name.to_string()).or_default().insert(
+                image_tag.to_string(),
+                OCIImageTagConfigLock::new(hash.to_string(), None, None),
+            );
+        }
+
+        ProjectConfigLock::new(
+            vec![],
+            "abcdefghijklmnop".to_string(),
+            None,
+            None,
+            images,
+            BTreeMap::new(),
+            None,
+        )
+    }
+
+    #[test]
+    fn find_stale_images_only_removes_digests_pinned_before() {
+        let volatile_path = temp_dir().join(format!("avatar-test-{}-gc", process::id()));
+        create_dir_all(&volatile_path).unwrap();
+
+        record_pinned_images(&volatile_path, &new_config_lock(&[("node", "14", "aaaa")])).unwrap();
+        let config_lock = new_config_lock(&[("node", "14", "bbbb")]);
+        record_pinned_images(&volatile_path, &config_lock).unwrap();
+        let pinned_images = get_pinned_images(&volatile_path).unwrap();
+
+        // "cccc" was pulled by another project, or by the user
+        let engine = FakeImageStore {
+            digests: vec!["aaaa".to_string(), "bbbb".to_string(), "cccc".to_string()],
+        };
+        let stale_images =
+            find_stale_images(&engine, &config_lock, &pinned_images, "project").unwrap();
+        let stale_image_names: Vec<&String> =
+            stale_images.iter().map(|resource| &resource.name).collect();
+        assert_eq!(stale_image_names, vec!["node@sha256:aaaa"]);
+
+        forget_removed_images(
+            &volatile_path,
+            &config_lock,
+            &pinned_images,
+            &stale_images.iter().collect::<Vec<&StaleResource>>(),
+        )
+        .unwrap();
+        let kept_images = get_pinned_images(&volatile_path).unwrap();
+        remove_dir_all(&volatile_path).unwrap();
+
+        assert_eq!(kept_images, get_locked_images(&config_lock));
+    }
+}
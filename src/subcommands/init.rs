@@ -5,75 +5,72 @@
  */
 
 use std::fs::{create_dir, read, remove_dir_all, write};
-use std::{path::PathBuf, process::exit};
+use std::path::Path;
 
 use crate::{
     directories::{get_project_path, AVATARFILE_NAME, CONFIG_DIR_NAME},
+    error::{AvatarError, AvatarResult},
     project_config::{save_config, ProjectConfig},
 };
 
-pub(crate) fn init_subcommand(project_path: &PathBuf) {
-    if let Some(p) = get_project_path() {
-        eprintln!(
+pub fn init_subcommand(project_path: &Path) -> AvatarResult<()> {
+    if let Some(p) = get_project_path()? {
+        return Err(AvatarError::Usage(format!(
             "avatar init cannot create a new project over an existing one, in {}",
             p.display()
-        );
-        exit(exitcode::USAGE)
+        )));
     }
 
     let config_dir = project_path.join(CONFIG_DIR_NAME);
     if config_dir.exists() {
         if config_dir.is_file() {
-            eprintln!(
+            return Err(AvatarError::Usage(format!(
                 "The path {} must point to a directory, found something else",
                 config_dir.display()
-            );
-            exit(exitcode::USAGE)
+            )));
         }
 
         if let Err(e) = remove_dir_all(&config_dir) {
-            eprintln!(
+            return Err(AvatarError::Os(format!(
                 "Unable to delete broken settings directory {}\n\n{}\n",
                 config_dir.display(),
-                e.to_string()
-            );
-            exit(exitcode::OSERR)
+                e
+            )));
         }
     }
 
     if let Err(e) = create_dir(&config_dir) {
-        eprintln!(
+        return Err(AvatarError::CantCreate(format!(
             "Unable to create settings directory {}\n\n{}\n",
             config_dir.display(),
-            e.to_string()
-        );
-        exit(exitcode::CANTCREAT)
+            e
+        )));
     }
 
     let config = ProjectConfig::new();
     let config_filepath = config_dir.join(AVATARFILE_NAME);
-    save_config(&config_filepath, &config);
+    save_config(&config_filepath, &config)?;
 
-    patch_gitignore(project_path);
+    patch_gitignore(project_path)
 }
 
-fn patch_gitignore(project_path: &PathBuf) {
+fn patch_gitignore(project_path: &Path) -> AvatarResult<()> {
     let gitignore_path = project_path.join(".gitignore");
 
     if gitignore_path.exists() {
         if !gitignore_path.is_file() {
-            eprintln!("The file .gitignore must be a file, but found something else.");
-            exit(exitcode::USAGE)
+            return Err(AvatarError::Usage(
+                "The file .gitignore must be a file, but found something else.".to_string(),
+            ));
         }
 
         let mut gitignore_bytes = match read(&gitignore_path) {
             Ok(t) => t,
             Err(e) => {
-                eprintln!(
+                return Err(AvatarError::Io(format!(
                     "Unable to read .gitignore file due to unknwon reasons.\n\n{}\n",
-                    e.to_string()
-                );
-                exit(exitcode::IOERR)
+                    e
+                )))
             }
         };
 
@@ -81,27 +78,27 @@ fn patch_gitignore(project_path: &PathBuf) {
             // TODO: Optimize this, just append, instead of rewriting the entire file
             gitignore_bytes.extend("\n# Avatar-CLI\n.avatar-cli/volatile/\n".as_bytes());
             if let Err(e) = write(&gitignore_path, gitignore_bytes) {
-                eprintln!(
+                return Err(AvatarError::Io(format!(
                     "Unable to modify .gitignore file due to unknown reasons.\n\n{}\n",
-                    e.to_string()
-                );
-                exit(exitcode::IOERR);
+                    e
+                )));
             }
         }
     } else {
         if !project_path.join(".git").exists() {
-            return;
+            return Ok(());
         }
 
         if let Err(e) = write(
             &gitignore_path,
             "# Avatar-CLI\n.avatar-cli/volatile/\n".as_bytes(),
         ) {
-            eprintln!(
+            return Err(AvatarError::CantCreate(format!(
                 "Unable to create .gitignore file due to unknown reasons.\n\n{}\n",
-                e.to_string()
-            );
-            exit(exitcode::CANTCREAT);
+                e
+            )));
         }
     }
+
+    Ok(())
 }
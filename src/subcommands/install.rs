@@ -5,83 +5,165 @@
  */
 
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     env,
     fs::{create_dir_all, remove_dir_all, set_permissions, write, Permissions},
     os::unix::fs::{symlink, PermissionsExt},
-    path::PathBuf,
-    process::{exit, Command},
+    path::{Path, PathBuf},
     str::from_utf8,
 };
 
-use duct::cmd;
 use ring::digest::{digest, Digest, SHA256};
 
 use crate::{
-    avatar_env::SESSION_TOKEN,
+    avatar_env::{is_offline_mode, SESSION_TOKEN},
+    container_engines::{get_container_engine, get_inspect_output_error_msg, ContainerEngine},
     directories::{
-        get_project_path, AVATARFILE_LOCK_NAME, AVATARFILE_NAME, CONFIG_DIR_NAME,
+        get_required_project_path, AVATARFILE_LOCK_NAME, AVATARFILE_NAME, CONFIG_DIR_NAME,
         CONTAINER_HOME_PATH, STATEFILE_NAME, VOLATILE_DIR_NAME,
     },
-    docker::ERROR_MSG_DOCKER_INSPECT_OUTPUT,
+    error::{AvatarError, AvatarResult},
+    image_builds::{get_build_hash, get_build_paths},
+    image_pulls::run_image_jobs,
+    policy::{check_policy, get_outdated_lock_violation, get_policy_error, is_lock_required},
     project_config::{
-        get_config, get_config_lock, merge_run_and_shell_configs, save_config_lock,
-        ImageBinaryConfig, ImageBinaryConfigLock, OCIContainerRunConfig, OCIImageConfig,
-        OCIImageTagConfigLock, ProjectConfig, ProjectConfigLock, VolumeConfigLock,
+        generate_service_config_lock, get_built_image_name, get_config, get_config_lock,
+        get_image_ref, get_project_network_name, is_built_image_name, merge_run_and_shell_configs,
+        merge_run_configs, save_config_lock, ImageBinaryConfig, ImageBinaryConfigLock,
+        ImageBuildConfig, OCIContainerRunConfig, OCIImageConfig, OCIImageTagConfigLock,
+        ProjectConfig, ProjectConfigLock, ServiceConfigLock, VolumeConfigLock,
     },
+    run_plan::RUN_PLANS_DIR_NAME,
+    subcommands::gc::record_pinned_images,
 };
 
-const BIN_WRAPPER_TMPL: &'static [u8; 797] = include_bytes!("../embedded_files/bin_wrapper.sh");
-
-fn change_volume_permissions(volume_name: &str, container_path: &PathBuf) {
-    match Command::new("docker")
-        .args(&[
-            "run",
-            "--rm",
-            "--volume",
-            &format!("{}:{}", volume_name, container_path.display()),
-            "alpine:3.12",
-            "sh",
-            "-c",
-            &format!(
-                "chown -R {}:{} {}",
-                nix::unistd::getuid(),
-                nix::unistd::getgid(),
-                container_path.display()
-            ),
-        ])
-        .output()
-    {
-        Ok(output) => {
-            if !output.status.success() {
-                eprintln!("Unable to change permissions for volume {}", volume_name);
-                exit(exitcode::SOFTWARE);
-            }
-        }
-        Err(e) => {
-            eprintln!(
-                "Unable to change permissions for volume {}\n\n{}\n",
-                volume_name,
-                e.to_string()
-            );
-            exit(exitcode::OSERR)
+/// (image name, image tag) -> image hash
+pub type ImageTagHashes = BTreeMap<(String, String), String>;
+
+/// image name -> (image ID, build hash)
+type BuiltImages = BTreeMap<String, (String, String)>;
+
+/// What the project image tags resolve to, before building the lock file
+struct ResolvedImages {
+    pulled: ImageTagHashes,
+    built: BuiltImages,
+}
+
+impl ResolvedImages {
+    fn get_tag_config_lock(
+        &self,
+        image_name: &str,
+        image_tag: &str,
+        run_config: Option<OCIContainerRunConfig>,
+    ) -> AvatarResult<OCIImageTagConfigLock> {
+        match self.built.get(image_name) {
+            Some((image_id, build_hash)) => Ok(OCIImageTagConfigLock::new(
+                image_id.clone(),
+                run_config,
+                Some(build_hash.clone()),
+            )),
+            None => Ok(OCIImageTagConfigLock::new(
+                get_resolved_image_tag_hash(&self.pulled, image_name, image_tag)?,
+                run_config,
+                None,
+            )),
         }
     }
 }
 
+const BIN_WRAPPER_TMPL: &[u8; 797] = include_bytes!("../embedded_files/bin_wrapper.sh");
+
+/// Builds the project images declared with a `build` section, unless the
+/// previous lock file points to a locally available image built from the same
+/// inputs.
+fn build_project_images(
+    engine: &dyn ContainerEngine,
+    project_path: &Path,
+    config: &ProjectConfig,
+    previous_config_lock: Option<&ProjectConfigLock>,
+    show_output: bool,
+) -> AvatarResult<BuiltImages> {
+    let project_internal_id = config.get_project_internal_id();
+    let project_filter = format!("{}.byid.projects.avatar-cli", project_internal_id);
+
+    let mut built_images: BuiltImages = BTreeMap::new();
+    for (image_name, image_config, build_config) in get_image_build_configs(config) {
+        let built_image_name = get_built_image_name(project_internal_id, image_name);
+        let build_hash = get_build_hash(project_path, build_config)?;
+
+        let image_id = match get_reusable_image_id(
+            engine,
+            previous_config_lock,
+            &built_image_name,
+            &build_hash,
+        )? {
+            Some(image_id) => image_id,
+            None => {
+                let image_refs: Vec<String> = image_config
+                    .get_tags()
+                    .keys()
+                    .map(|image_tag| format!("{}:{}", built_image_name, image_tag))
+                    .collect();
+                let first_image_ref = match image_refs.first() {
+                    Some(image_ref) => image_ref.clone(),
+                    None => {
+                        return Err(AvatarError::InvalidConfig(format!(
+                            "No tags are defined for image {}",
+                            image_name
+                        )))
+                    }
+                };
+
+                let (context_path, dockerfile_path) = get_build_paths(project_path, build_config)?;
+                if show_output {
+                    println!("Building image {}", image_name);
+                }
+                engine.build_image(
+                    &context_path,
+                    &dockerfile_path,
+                    build_config.get_args(),
+                    build_config.get_target(),
+                    &image_refs,
+                    &["avatar_cli", &project_filter],
+                    show_output,
+                )?;
+
+                match engine.get_image_id(&first_image_ref)? {
+                    Some(image_id) => image_id,
+                    None => {
+                        return Err(AvatarError::ContainerEngineProtocol(format!(
+                            "The image {} is not available after building it",
+                            first_image_ref
+                        )))
+                    }
+                }
+            }
+        };
+
+        built_images.insert(image_name.clone(), (image_id, build_hash));
+    }
+
+    Ok(built_images)
+}
+
 fn check_etc_passwd_files(
-    volatile_path: &PathBuf,
+    engine: &dyn ContainerEngine,
+    volatile_path: &Path,
     project_state: &ProjectConfigLock,
     changed_state: bool,
-) {
-    if which::which("tar").is_err() {
+) -> AvatarResult<()> {
+    if !engine.needs_passwd_files() {
+        return Ok(());
+    }
+
+    if !engine.can_read_container_files() {
         eprintln!("WARNING: tar tool is not available, and passwd files won't be generated to improve integration with ssh-agent");
-        return;
+        return Ok(());
     }
 
-    let images_path = match recreate_volatile_subdir(volatile_path, "images", changed_state) {
+    let images_path = match recreate_volatile_subdir(volatile_path, "images", changed_state)? {
         Some(_images_path) => _images_path,
-        None => return,
+        None => return Ok(()),
     };
 
     let project_internal_id = project_state.get_project_internal_id();
@@ -91,23 +173,17 @@ fn check_etc_passwd_files(
     let (username, gid) = match nix::unistd::User::from_uid(uid) {
         Ok(Some(user)) => (user.name, user.gid),
         _ => {
-            eprintln!("Unable to get current user name");
-            exit(exitcode::OSERR)
+            return Err(AvatarError::Os(
+                "Unable to get current user name".to_string(),
+            ))
         }
     };
 
-    let mut errors = false;
-    for (image_name, image_tags) in project_state.get_images() {
+    let mut result = Ok(());
+    'images: for (image_name, image_tags) in project_state.get_images() {
         for (image_tag, image_config) in image_tags {
             let image_hash = image_config.get_hash();
-            let image_ref = format!("{}@sha256:{}", image_name, image_hash);
-            let image_config_path = images_path.join(&image_ref);
-
-            if create_dir_all(&image_config_path).is_err() {
-                eprintln!("Unable to create directory {}", image_config_path.display());
-                exit(exitcode::CANTCREAT)
-            }
-
+            let image_ref = get_image_ref(image_name, image_hash);
             let install_container_name = format!(
                 "{}_{}_{}_{}_passwd",
                 project_internal_id,
@@ -115,326 +191,260 @@ fn check_etc_passwd_files(
                 image_tag,
                 image_hash
             );
-            match Command::new("docker")
-                .args(&[
-                    "create",
-                    "--name",
-                    &install_container_name,
-                    "--label",
-                    "avatar_cli",
-                    "--label",
-                    &project_filter,
-                    "--label",
-                    "install_helper.container_role.avatar-cli",
-                    &image_ref,
-                ])
-                .output()
-            {
-                Ok(output) => {
-                    if !output.status.success() {
-                        eprintln!(
-                            "Unable to create temporary install container\n\n{}",
-                            from_utf8(&output.stderr).unwrap()
-                        );
-                        errors = true;
-                        break;
-                    }
-                }
-                Err(e) => {
-                    eprintln!(
-                        "Unable to create temporary install container\n\n{}\n",
-                        e.to_string()
-                    );
-                    errors = true;
-                    break;
-                }
-            }
 
-            let container_files_list = match cmd!("docker", "export", &install_container_name)
-                .pipe(cmd!("tar", "t"))
-                .read()
-            {
-                Ok(output) => output,
-                Err(e) => {
-                    eprintln!(
-                        "Unable to list contents of container {}\n\n{}\n",
-                        &install_container_name,
-                        e.to_string()
-                    );
-                    errors = true;
-                    break;
-                }
-            };
-
-            // TODO: fish, and others
-            let mut found_passwd = false;
-            let mut found_bash = false;
-            let mut found_csh = false;
-            let mut found_dash = false;
-            let mut found_ksh = false;
-            let mut found_zsh = false;
-            for file_name in container_files_list.lines() {
-                match file_name.trim() {
-                    "etc/passwd" => found_passwd = true,
-                    "bin/bash" => found_bash = true,
-                    "bin/csh" => found_csh = true,
-                    "bin/dash" => found_dash = true,
-                    "bin/ksh" => found_ksh = true,
-                    "bin/zsh" => found_zsh = true,
-                    _ => {}
-                }
-            }
-            let inferred_passwd_shell = if found_bash {
-                "/bin/bash"
-            } else if found_zsh {
-                "/bin/zsh"
-            } else if found_dash {
-                "/bin/dash"
-            } else if found_ksh {
-                "/bin/ksh"
-            } else if found_csh {
-                "/bin/csh"
-            } else {
-                "/bin/sh"
-            };
-
-            let local_etc_passwd_path = image_config_path.join("passwd");
-            if !found_passwd {
-                if let Err(e) = write(
-                    &local_etc_passwd_path,
-                    format!(
-                        "{}:x:{}:{}::{}:{}\n",
-                        username, uid, gid, CONTAINER_HOME_PATH, inferred_passwd_shell
-                    )
-                    .as_bytes(),
-                ) {
-                    eprintln!(
-                        "Unable to create custom passwd file for {}\n\n{}\n",
-                        &image_ref,
-                        e.to_string()
-                    );
-                    errors = true;
-                    break;
-                }
-            } else {
-                let passwd_src_contents = match cmd!("docker", "export", &install_container_name)
-                    .pipe(cmd!("tar", "--extract", "-O", "etc/passwd"))
-                    .read()
-                {
-                    Ok(_contents) => _contents,
-                    Err(e) => {
-                        eprintln!(
-                            "Unable to export passwd file from {} image\n\n{}\n",
-                            image_ref,
-                            e.to_string()
-                        );
-                        errors = true;
-                        break;
-                    }
-                };
-
-                let mut found_user_line = false;
-                let mut passwd_dst_contents = String::with_capacity(passwd_src_contents.len());
-
-                for user_line in passwd_src_contents.lines() {
-                    let trimmed_user_line = user_line.trim();
-                    let mut user_line_parts = trimmed_user_line.split(':');
-                    if let Some(passwd_uid) = user_line_parts.nth(2) {
-                        if passwd_uid == uid.to_string() {
-                            let passwd_shell = match user_line_parts.last() {
-                                Some(_passwd_shell) => _passwd_shell,
-                                None => inferred_passwd_shell,
-                            };
-
-                            found_user_line = true;
-                            passwd_dst_contents.push_str(&format!(
-                                "{}:x:{}:{}::{}:{}\n",
-                                username, uid, gid, CONTAINER_HOME_PATH, passwd_shell
-                            ))
-                        } else {
-                            passwd_dst_contents.push_str(trimmed_user_line);
-                            passwd_dst_contents.push('\n')
-                        }
-                    } else {
-                        eprintln!("Unable to process exported passwd file from {} image, found corrupted line:\n\n{}\n", image_ref, user_line);
-                        errors = true;
-                        break;
-                    }
-                }
-                if !found_user_line {
-                    passwd_dst_contents.push_str(&format!(
-                        "{}:x:{}:{}::{}:{}\n",
-                        username, uid, gid, CONTAINER_HOME_PATH, inferred_passwd_shell
-                    ))
-                }
-                if let Err(e) = write(&local_etc_passwd_path, passwd_dst_contents.as_bytes()) {
-                    eprintln!(
-                        "Unable to create custom passwd file for {}\n\n{}\n",
-                        &image_ref,
-                        e.to_string()
-                    );
-                    errors = true;
-                    break;
-                }
+            result = generate_etc_passwd_file(
+                engine,
+                &images_path.join(&image_ref),
+                &image_ref,
+                &install_container_name,
+                &project_filter,
+                (&username, uid, gid),
+            );
+            if result.is_err() {
+                break 'images;
             }
         }
     }
 
-    if let Err(e) = Command::new("docker")
-        .args(&[
-            "container",
-            "prune",
-            "--force",
-            "--filter",
-            &format!("label={}", project_filter),
-            "--filter",
-            "label=install_helper.container_role.avatar-cli",
-        ])
-        .output()
+    // The helper containers have to be removed even if something failed
+    if let Err(e) =
+        engine.prune_containers(&[&project_filter, "install_helper.container_role.avatar-cli"])
     {
-        eprintln!(
-            "Unable to prune containers generated during install step\n\n{}\n",
-            e.to_string()
-        );
-        errors = true;
+        match result {
+            Ok(_) => result = Err(e),
+            Err(_) => eprintln!("{}", e),
+        }
     }
 
-    if errors {
-        exit(exitcode::IOERR)
-    }
+    result
 }
 
-fn check_managed_volumes_availability(project_state: &ProjectConfigLock) {
+fn check_managed_volumes_availability(
+    engine: &dyn ContainerEngine,
+    project_state: &ProjectConfigLock,
+) -> AvatarResult<()> {
     for (_, binary_config) in project_state.get_binaries_configs() {
         if let Some(run_config) = binary_config.get_run_config() {
             if let Some(volume_configs) = run_config.get_volumes() {
-                volume_configs.iter().for_each(|vc| {
-                    check_managed_volume_existence(vc, project_state.get_project_internal_id())
-                });
+                for vc in volume_configs {
+                    check_managed_volume_existence(
+                        engine,
+                        vc,
+                        project_state.get_project_internal_id(),
+                        true,
+                    )?;
+                }
             }
         }
     }
-}
 
-fn check_managed_volume_existence(volume_config: &VolumeConfigLock, project_internal_id: &str) {
-    match Command::new("docker")
-        .args(&["volume", "inspect", volume_config.get_name()])
-        .output()
-    {
-        Ok(output) => {
-            if !output.status.success() {
-                create_volume(
-                    volume_config.get_name(),
-                    volume_config.get_container_path(),
-                    project_internal_id,
-                );
-            }
-        }
-        Err(e) => {
-            eprintln!(
-                "Unable to inspect volume {}\n\n{}\n",
-                volume_config.get_name(),
-                e.to_string()
-            );
-            exit(exitcode::OSERR)
+    // Services run with the users defined by their images, so we don't change
+    // the ownership of their volumes
+    for service_config in project_state.get_services().iter().flat_map(|s| s.values()) {
+        for vc in service_config.get_volumes().iter().flatten() {
+            check_managed_volume_existence(
+                engine,
+                vc,
+                project_state.get_project_internal_id(),
+                false,
+            )?;
         }
     }
+
+    Ok(())
 }
 
-fn check_oci_images_availability(project_state: &ProjectConfigLock, show_output: bool) -> bool {
+fn check_managed_volume_existence(
+    engine: &dyn ContainerEngine,
+    volume_config: &VolumeConfigLock,
+    project_internal_id: &str,
+    change_permissions: bool,
+) -> AvatarResult<()> {
+    if !engine.has_volume(volume_config.get_name())? {
+        create_volume(
+            engine,
+            volume_config.get_name(),
+            volume_config.get_container_path(),
+            project_internal_id,
+            change_permissions,
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn check_not_in_session() -> AvatarResult<()> {
+    match env::var(SESSION_TOKEN) {
+        Ok(session_token) => Err(AvatarError::Usage(format!(
+            "You are already in an Avatar CLI session (with token '{}').\nIf the environment changed, consider typing 'exit' and trying again.",
+            session_token
+        ))),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Creates the project network (shared by the project services and tools) when
+/// it does not exist yet
+pub fn check_project_network_existence(
+    engine: &dyn ContainerEngine,
+    project_internal_id: &str,
+) -> AvatarResult<()> {
+    let network_name = get_project_network_name(project_internal_id);
+    if !engine.has_network(&network_name)? {
+        let project_filter = format!("{}.byid.projects.avatar-cli", project_internal_id);
+        engine.create_network(&network_name, &["avatar_cli", &project_filter])?;
+    }
+
+    Ok(())
+}
+
+fn check_oci_images_availability(
+    engine: &dyn ContainerEngine,
+    project_state: &ProjectConfigLock,
+    show_output: bool,
+) -> AvatarResult<bool> {
     let images = project_state.get_images();
 
-    if which::which("docker").is_err() {
-        eprintln!("docker client is not available");
-        exit(exitcode::UNAVAILABLE)
+    engine.check_client_availability()?;
+
+    // Built images can't be pulled, they're rebuilt (when missing) along the
+    // lock file
+    let image_refs: Vec<(String, String)> = images
+        .iter()
+        .filter(|(image_name, _)| !is_built_image_name(image_name))
+        .flat_map(|(image_name, image_tags)| {
+            image_tags.values().map(move |image_config| {
+                let image_ref = get_image_ref(image_name, image_config.get_hash());
+                (image_ref.clone(), image_ref)
+            })
+        })
+        .collect();
+
+    let availability = run_image_jobs(&image_refs, "inspecting", false, &|image_ref| {
+        engine.has_image(image_ref)
+    })?;
+    let missing_image_refs: Vec<(String, String)> = image_refs
+        .into_iter()
+        .zip(availability)
+        .filter(|(_, is_available)| !is_available)
+        .map(|(image_ref, _)| image_ref)
+        .collect();
+
+    if missing_image_refs.is_empty() {
+        return Ok(false);
+    }
+    if is_offline_mode()? {
+        return Err(get_offline_missing_images_error(
+            &missing_image_refs
+                .into_iter()
+                .map(|(image_ref, _)| image_ref)
+                .collect::<Vec<String>>(),
+        ));
     }
 
-    let mut changed_state = false;
+    run_image_jobs(&missing_image_refs, "pulling", show_output, &|image_ref| {
+        engine.pull_image(image_ref, false)
+    })?;
 
-    for (image_name, image_tags) in images.iter() {
-        for (_, image_config) in image_tags.iter() {
-            let inspect_output = Command::new("docker")
-                .args(&[
-                    "inspect",
-                    &format!("{}@sha256:{}", image_name, image_config.get_hash()),
-                ])
-                .output();
-
-            match inspect_output {
-                Ok(output) => {
-                    if !output.status.success() {
-                        pull_oci_image_by_fqn(
-                            &format!("{}@sha256:{}", image_name, image_config.get_hash()),
-                            show_output,
-                        );
-                        changed_state = true;
-                    }
-                }
-                Err(err) => {
-                    eprintln!(
-                        "Unable to use docker to inspect image {}@sha256:{}.\n\n{}\n",
-                        image_name,
-                        image_config.get_hash(),
-                        err.to_string()
-                    );
-                    exit(exitcode::OSERR)
-                }
-            }
+    Ok(true)
+}
+
+/// In offline mode, the lock file can only be generated from the RepoDigests
+/// of the locally available image tags, so we check all of them beforehand
+/// to report every missing tag at once.
+fn check_offline_image_tags_availability(
+    engine: &dyn ContainerEngine,
+    config: &ProjectConfig,
+) -> AvatarResult<()> {
+    let mut missing_image_refs: Vec<String> = vec![];
+    for (image_name, image_tag) in get_config_image_tags(config) {
+        let image_ref = format!("{}:{}", image_name, image_tag);
+        if engine.get_image_repo_digests(&image_ref)?.is_none() {
+            missing_image_refs.push(image_ref);
         }
     }
 
-    changed_state
+    match missing_image_refs.is_empty() {
+        true => Ok(()),
+        false => Err(get_offline_missing_images_error(&missing_image_refs)),
+    }
 }
 
 fn check_project_settings(
-    config_path: &PathBuf,
-    config_lock_path: &PathBuf,
-    project_state_path: &PathBuf,
+    project_path: &Path,
+    config_path: &Path,
+    config_lock_path: &Path,
+    project_state_path: &Path,
     show_output: bool,
-) -> (ProjectConfigLock, bool) {
+) -> AvatarResult<(ProjectConfigLock, bool)> {
     let mut changed_state = false;
-    let (config, config_hash) = get_config(&config_path);
+    let (config, config_hash) = get_config(config_path)?;
+    check_policy(&config, project_path)?;
+    let engine = get_container_engine(config.get_container_engine())?;
 
     let (config_lock, config_lock_hash) = match config_lock_path.exists() {
         true => {
             if !config_lock_path.is_file() {
-                eprintln!(
+                return Err(AvatarError::InvalidConfig(format!(
                     "The path {} must point to a regular file, found something else",
                     project_state_path.display()
-                );
-                exit(exitcode::DATAERR)
+                )));
             }
 
-            let (_config_lock, _config_lock_hash) = get_config_lock(&config_lock_path);
+            let (_config_lock, _config_lock_hash) = get_config_lock(config_lock_path)?;
+            let is_outdated_lock =
+                config_hash.as_ref() != &_config_lock.get_project_config_hash()[..];
 
-            if config_hash.as_ref() != &_config_lock.get_project_config_hash()[..] {
+            // Regenerating it would silently re-resolve the image tags
+            if is_outdated_lock && is_lock_required(&config) {
+                return Err(get_policy_error(&[get_outdated_lock_violation(
+                    config_lock_path,
+                )]));
+            }
+
+            if is_outdated_lock
+                || has_outdated_image_builds(engine.as_ref(), project_path, &config, &_config_lock)?
+            {
                 changed_state = true;
-                generate_config_lock(config_lock_path, &config, &config_hash, show_output)
+                generate_config_lock(
+                    engine.as_ref(),
+                    project_path,
+                    config_lock_path,
+                    &config,
+                    &config_hash,
+                    show_output,
+                )?
             } else {
                 (_config_lock, _config_lock_hash)
             }
         }
         false => {
             changed_state = true;
-            generate_config_lock(config_lock_path, &config, &config_hash, show_output)
+            generate_config_lock(
+                engine.as_ref(),
+                project_path,
+                config_lock_path,
+                &config,
+                &config_hash,
+                show_output,
+            )?
         }
     };
 
     let project_state = match project_state_path.exists() {
         true => {
             if !project_state_path.is_file() {
-                eprintln!(
+                return Err(AvatarError::InvalidConfig(format!(
                     "The path {} must point to a regular file, found something else",
                     project_state_path.display()
-                );
-                exit(exitcode::DATAERR)
+                )));
             }
 
-            let (_project_state, _) = get_config_lock(&project_state_path);
+            let (_project_state, _) = get_config_lock(project_state_path)?;
 
             if config_lock_hash.as_ref() != &_project_state.get_project_config_hash()[..] {
                 changed_state = true;
-                update_project_state(project_state_path, config_lock, config_lock_hash.as_ref())
+                update_project_state(project_state_path, config_lock, config_lock_hash.as_ref())?
             } else {
                 _project_state
             }
@@ -444,225 +454,555 @@ fn check_project_settings(
 
             let volatile_dir = project_state_path.parent().unwrap();
             if !volatile_dir.exists() && create_dir_all(volatile_dir).is_err() {
-                eprintln!("Unable to create directory {}", volatile_dir.display());
-                exit(exitcode::CANTCREAT)
+                return Err(AvatarError::CantCreate(format!(
+                    "Unable to create directory {}",
+                    volatile_dir.display()
+                )));
             }
 
-            update_project_state(project_state_path, config_lock, config_lock_hash.as_ref())
+            update_project_state(project_state_path, config_lock, config_lock_hash.as_ref())?
         }
     };
 
-    (project_state, changed_state)
+    Ok((project_state, changed_state))
 }
 
 fn compile_image_configs(
-    (image_name, image_config, show_output): (&String, &OCIImageConfig, bool),
-) -> (String, BTreeMap<String, OCIImageTagConfigLock>) {
+    (config, resolved_images, image_name, image_config, project_run_config): (
+        &ProjectConfig,
+        &ResolvedImages,
+        &String,
+        &OCIImageConfig,
+        &Option<OCIContainerRunConfig>,
+    ),
+) -> AvatarResult<(String, BTreeMap<String, OCIImageTagConfigLock>)> {
     let tags = image_config.get_tags();
 
     if tags.is_empty() {
-        eprintln!("No tags are defined for image {}", image_name);
-        exit(exitcode::DATAERR)
+        return Err(AvatarError::InvalidConfig(format!(
+            "No tags are defined for image {}",
+            image_name
+        )));
     }
 
-    (
-        image_name.clone(),
+    Ok((
+        get_locked_image_name(config, image_name),
         tags.iter()
             .map(|(image_tag, image_tag_config)| {
                 (
+                    resolved_images,
                     image_name,
                     image_tag,
-                    image_tag_config.get_run_config().clone(),
-                    show_output,
+                    merge_run_configs(&[
+                        project_run_config,
+                        image_config.get_run_config(),
+                        image_tag_config.get_run_config(),
+                    ]),
                 )
             })
             .map(get_image_config_by_tag)
-            .collect(),
-    )
+            .collect::<AvatarResult<_>>()?,
+    ))
 }
 
-fn create_volume(volume_name: &str, container_path: &PathBuf, project_internal_id: &str) {
+fn create_volume(
+    engine: &dyn ContainerEngine,
+    volume_name: &str,
+    container_path: &Path,
+    project_internal_id: &str,
+    change_permissions: bool,
+) -> AvatarResult<()> {
     let project_filter = format!("{}.byid.projects.avatar-cli", project_internal_id);
 
-    match Command::new("docker")
-        .args(&[
-            "volume",
-            "create",
-            volume_name,
-            "--label",
-            "avatar_cli",
-            "--label",
-            &project_filter,
-        ])
-        .output()
-    {
-        Ok(output) => {
-            if !output.status.success() {
-                eprintln!("Unable to create volume {}", volume_name);
-                exit(exitcode::SOFTWARE);
-            }
-
-            change_volume_permissions(volume_name, container_path)
-        }
-        Err(e) => {
-            eprintln!(
-                "Unable to create volume {}\n\n{}\n",
-                volume_name,
-                e.to_string()
-            );
-            exit(exitcode::OSERR)
-        }
+    engine.create_volume(volume_name, &["avatar_cli", &project_filter])?;
+    match change_permissions {
+        true => engine.change_volume_permissions(volume_name, container_path),
+        false => Ok(()),
     }
 }
 
-fn generate_config_lock(
-    config_lock_path: &PathBuf,
+/// Resolves the image tags declared in the project configuration (building
+/// the project images when needed), and writes the resulting lock file.
+pub fn generate_config_lock(
+    engine: &dyn ContainerEngine,
+    project_path: &Path,
+    config_lock_path: &Path,
     config: &ProjectConfig,
     config_hash: &Digest,
     show_output: bool,
-) -> (ProjectConfigLock, Digest) {
-    let image_configs = get_image_compiled_configs(config, show_output);
-    let binaries_settings = get_binaries_settings(config, &image_configs);
+) -> AvatarResult<(ProjectConfigLock, Digest)> {
+    if is_offline_mode()? {
+        check_offline_image_tags_availability(engine, config)?;
+    }
+
+    let previous_config_lock = match config_lock_path.is_file() {
+        true => get_config_lock(config_lock_path).ok().map(|(lock, _)| lock),
+        false => None,
+    };
+
+    // Resolved all at once (concurrently) before building the lock, so its
+    // content does not depend on the order in which the pulls finish
+    let resolved_images = ResolvedImages {
+        pulled: resolve_image_tag_hashes(engine, config, show_output)?,
+        built: build_project_images(
+            engine,
+            project_path,
+            config,
+            previous_config_lock.as_ref(),
+            show_output,
+        )?,
+    };
+    compile_config_lock(
+        engine,
+        config_lock_path,
+        config,
+        config_hash,
+        &resolved_images,
+    )
+}
+
+fn compile_config_lock(
+    engine: &dyn ContainerEngine,
+    config_lock_path: &Path,
+    config: &ProjectConfig,
+    config_hash: &Digest,
+    resolved_images: &ResolvedImages,
+) -> AvatarResult<(ProjectConfigLock, Digest)> {
+    let mut image_configs = get_image_compiled_configs(config, resolved_images)?;
+    let binaries_settings = get_binaries_settings(engine, config, &image_configs)?;
+    let services_settings = get_services_settings(config, &mut image_configs, resolved_images)?;
 
     let config_lock = ProjectConfigLock::new(
         Vec::<u8>::from(config_hash.as_ref()),
         config.get_project_internal_id().clone(),
+        *config.get_container_engine(),
         config.get_shell_config().clone(),
         image_configs,
         binaries_settings,
+        services_settings,
     );
 
-    let config_lock_bytes = save_config_lock(config_lock_path, &config_lock);
-    (config_lock, digest(&SHA256, &config_lock_bytes))
+    let config_lock_bytes = save_config_lock(config_lock_path, &config_lock)?;
+    Ok((config_lock, digest(&SHA256, &config_lock_bytes)))
+}
+
+fn generate_etc_passwd_file(
+    engine: &dyn ContainerEngine,
+    image_config_path: &PathBuf,
+    image_ref: &str,
+    install_container_name: &str,
+    project_filter: &str,
+    (username, uid, gid): (&str, nix::unistd::Uid, nix::unistd::Gid),
+) -> AvatarResult<()> {
+    if create_dir_all(image_config_path).is_err() {
+        return Err(AvatarError::CantCreate(format!(
+            "Unable to create directory {}",
+            image_config_path.display()
+        )));
+    }
+
+    let container_labels = [
+        "avatar_cli",
+        project_filter,
+        "install_helper.container_role.avatar-cli",
+    ];
+    engine.create_container(install_container_name, &container_labels, image_ref)?;
+
+    let container_files = engine.find_container_files(
+        install_container_name,
+        &[
+            "etc/passwd",
+            "bin/bash",
+            "bin/csh",
+            "bin/dash",
+            "bin/ksh",
+            "bin/zsh",
+        ],
+    )?;
+
+    // TODO: fish, and others
+    let found_passwd = container_files.contains("etc/passwd");
+    let inferred_passwd_shell = if container_files.contains("bin/bash") {
+        "/bin/bash"
+    } else if container_files.contains("bin/zsh") {
+        "/bin/zsh"
+    } else if container_files.contains("bin/dash") {
+        "/bin/dash"
+    } else if container_files.contains("bin/ksh") {
+        "/bin/ksh"
+    } else if container_files.contains("bin/csh") {
+        "/bin/csh"
+    } else {
+        "/bin/sh"
+    };
+
+    let passwd_dst_contents = if !found_passwd {
+        format!(
+            "{}:x:{}:{}::{}:{}\n",
+            username, uid, gid, CONTAINER_HOME_PATH, inferred_passwd_shell
+        )
+    } else {
+        let passwd_src_contents =
+            engine.read_container_file(install_container_name, "etc/passwd")?;
+
+        let mut found_user_line = false;
+        let mut passwd_dst_contents = String::with_capacity(passwd_src_contents.len());
+
+        for user_line in passwd_src_contents.lines() {
+            let trimmed_user_line = user_line.trim();
+            let mut user_line_parts = trimmed_user_line.split(':');
+            if let Some(passwd_uid) = user_line_parts.nth(2) {
+                if passwd_uid == uid.to_string() {
+                    let passwd_shell = match user_line_parts.next_back() {
+                        Some(_passwd_shell) => _passwd_shell,
+                        None => inferred_passwd_shell,
+                    };
+
+                    found_user_line = true;
+                    passwd_dst_contents.push_str(&format!(
+                        "{}:x:{}:{}::{}:{}\n",
+                        username, uid, gid, CONTAINER_HOME_PATH, passwd_shell
+                    ))
+                } else {
+                    passwd_dst_contents.push_str(trimmed_user_line);
+                    passwd_dst_contents.push('\n')
+                }
+            } else {
+                return Err(AvatarError::ContainerEngineProtocol(format!(
+                    "Unable to process exported passwd file from {} image, found corrupted line:\n\n{}\n",
+                    image_ref, user_line
+                )));
+            }
+        }
+        if !found_user_line {
+            passwd_dst_contents.push_str(&format!(
+                "{}:x:{}:{}::{}:{}\n",
+                username, uid, gid, CONTAINER_HOME_PATH, inferred_passwd_shell
+            ))
+        }
+
+        passwd_dst_contents
+    };
+
+    write(
+        image_config_path.join("passwd"),
+        passwd_dst_contents.as_bytes(),
+    )
+    .map_err(|e| {
+        AvatarError::Io(format!(
+            "Unable to create custom passwd file for {}\n\n{}\n",
+            image_ref, e
+        ))
+    })
 }
 
 fn get_binaries_settings(
+    engine: &dyn ContainerEngine,
     config: &ProjectConfig,
     images_name_tag_hash_rel: &BTreeMap<String, BTreeMap<String, OCIImageTagConfigLock>>,
-) -> BTreeMap<String, ImageBinaryConfigLock> {
+) -> AvatarResult<BTreeMap<String, ImageBinaryConfigLock>> {
     let mut dst_binaries: BTreeMap<String, ImageBinaryConfigLock> = BTreeMap::new();
 
     if let Some(images) = config.get_images() {
         for (image_name, image_config) in images {
             set_binaries_settings_from_image_tags(
+                engine,
                 &mut dst_binaries,
-                image_name,
+                &get_locked_image_name(config, image_name),
                 image_config,
                 config,
                 images_name_tag_hash_rel,
-            );
+            )?;
         }
     }
 
-    dst_binaries
+    Ok(dst_binaries)
+}
+
+fn get_image_build_configs(
+    config: &ProjectConfig,
+) -> Vec<(&String, &OCIImageConfig, &ImageBuildConfig)> {
+    config
+        .get_images()
+        .iter()
+        .flatten()
+        .filter_map(|(image_name, image_config)| {
+            image_config
+                .get_build()
+                .as_ref()
+                .map(|build_config| (image_name, image_config, build_config))
+        })
+        .collect()
 }
 
 fn get_image_compiled_configs(
     config: &ProjectConfig,
-    show_output: bool,
-) -> BTreeMap<String, BTreeMap<String, OCIImageTagConfigLock>> {
+    resolved_images: &ResolvedImages,
+) -> AvatarResult<BTreeMap<String, BTreeMap<String, OCIImageTagConfigLock>>> {
     match config.get_images() {
         Some(images) => images
             .iter()
-            .map(|(image_name, image_tags)| {
-                compile_image_configs((image_name, image_tags, show_output))
+            .map(|(image_name, image_config)| {
+                compile_image_configs((
+                    config,
+                    resolved_images,
+                    image_name,
+                    image_config,
+                    config.get_run_config(),
+                ))
             })
             .collect(),
-        None => BTreeMap::new(),
+        None => Ok(BTreeMap::new()),
     }
 }
 
 fn get_image_config_by_tag(
-    (image_name, image_tag, run_config, show_output): (
+    (resolved_images, image_name, image_tag, run_config): (
+        &ResolvedImages,
         &String,
         &String,
         Option<OCIContainerRunConfig>,
-        bool,
     ),
-) -> (String, OCIImageTagConfigLock) {
-    let image_fqn = format!("{}:{}", image_name, image_tag);
+) -> AvatarResult<(String, OCIImageTagConfigLock)> {
+    Ok((
+        image_tag.clone(),
+        resolved_images.get_tag_config_lock(image_name, image_tag, run_config)?,
+    ))
+}
 
-    match Command::new("docker")
-        .args(&[
-            "inspect",
-            "--format={{range .RepoDigests}}{{println .}}{{end}}",
-            &image_fqn,
-        ])
-        .output()
-    {
-        Ok(output) => match output.status.success() {
-            true => match from_utf8(&output.stdout) {
-                Ok(stdout) => {
-                    let hash = get_hash_from_repo_digests_str(stdout, image_name);
+/// Returns the (image name, image tag) pairs used by the project tools and
+/// services that have to be pulled (the built ones are excluded), without
+/// duplicates & sorted.
+fn get_config_image_tags(config: &ProjectConfig) -> Vec<(String, String)> {
+    let built_image_names: BTreeSet<&String> = get_image_build_configs(config)
+        .into_iter()
+        .map(|(image_name, _, _)| image_name)
+        .collect();
+
+    config
+        .get_images()
+        .iter()
+        .flatten()
+        .flat_map(|(image_name, image_config)| {
+            image_config
+                .get_tags()
+                .keys()
+                .map(move |image_tag| (image_name.clone(), image_tag.clone()))
+        })
+        .chain(
+            config
+                .get_services()
+                .iter()
+                .flatten()
+                .map(|(_, service_config)| {
                     (
-                        image_tag.clone(),
-                        OCIImageTagConfigLock::new(hash, run_config),
+                        service_config.get_image().clone(),
+                        service_config.get_tag().clone(),
                     )
-                }
-                Err(e) => {
-                    eprintln!(
-                        "{}.\n\n{}\n",
-                        ERROR_MSG_DOCKER_INSPECT_OUTPUT,
-                        e.to_string()
-                    );
-                    exit(exitcode::PROTOCOL)
-                }
-            },
-            false => {
-                pull_oci_image_by_fqn(&image_fqn, show_output);
-                get_image_config_by_tag((image_name, image_tag, run_config, show_output))
-            }
-        },
-        Err(e) => {
-            eprintln!(
-                "Unknow error while trying to inspect OCI image {}:\n\n{}\n",
-                &image_fqn,
-                e.to_string()
-            );
-            exit(exitcode::OSERR)
+                }),
+        )
+        .filter(|(image_name, _)| !built_image_names.contains(image_name))
+        .collect::<BTreeSet<(String, String)>>()
+        .into_iter()
+        .collect()
+}
+
+/// Resolves the hash that an image tag points to. Unless `force_pull` is set,
+/// the locally available image is trusted, and the tag is only pulled when
+/// missing (never in offline mode).
+pub fn get_image_tag_hash(
+    engine: &dyn ContainerEngine,
+    image_name: &str,
+    image_tag: &str,
+    force_pull: bool,
+    show_output: bool,
+) -> AvatarResult<String> {
+    let image_fqn = format!("{}:{}", image_name, image_tag);
+    let offline_mode = is_offline_mode()?;
+
+    if force_pull {
+        if offline_mode {
+            return Err(AvatarError::Usage(format!(
+                "The tag {} can't be resolved against its registry in offline mode",
+                image_fqn
+            )));
         }
+        engine.pull_image(&image_fqn, show_output)?;
     }
-}
 
-fn get_hash_from_repo_digests_str(repo_difests_str: &str, image_name: &str) -> String {
-    let repo_digests = repo_difests_str.trim().split('\n');
+    match engine.get_image_repo_digests(&image_fqn)? {
+        Some(repo_digests) => get_hash_from_repo_digests(engine, &repo_digests, image_name),
+        None if offline_mode => Err(get_offline_missing_images_error(&[image_fqn])),
+        None => {
+            engine.pull_image(&image_fqn, show_output)?;
+            get_image_tag_hash(engine, image_name, image_tag, false, show_output)
+        }
+    }
+}
 
+fn get_hash_from_repo_digests(
+    engine: &dyn ContainerEngine,
+    repo_digests: &[String],
+    image_name: &str,
+) -> AvatarResult<String> {
     for repo_digest in repo_digests {
         if let Some(repo_digest_name) = repo_digest.split('@').next() {
             if repo_digest_name == image_name {
-                match repo_digest.split(':').nth(1) {
-                    Some(hash) => return hash.to_string(),
-                    None => {
-                        eprintln!("{}", ERROR_MSG_DOCKER_INSPECT_OUTPUT);
-                        exit(exitcode::PROTOCOL)
-                    }
+                if let Some(hash) = repo_digest.split(':').nth(1) {
+                    return Ok(hash.to_string());
                 }
+                break;
             }
         }
     }
 
-    eprintln!("{}", ERROR_MSG_DOCKER_INSPECT_OUTPUT);
-    exit(exitcode::PROTOCOL)
+    Err(AvatarError::ContainerEngineProtocol(
+        get_inspect_output_error_msg(engine.get_program_name()),
+    ))
 }
 
-pub(crate) fn install_subcommand(
-    show_output: bool,
-) -> (PathBuf, PathBuf, PathBuf, PathBuf, ProjectConfigLock) {
-    if let Ok(session_token) = env::var(SESSION_TOKEN) {
-        eprintln!(
-        "You are already in an Avatar CLI session (with token '{}').\nIf the environment changed, consider typing 'exit' and trying again.",
-        session_token
-    );
-        exit(exitcode::USAGE)
+/// The name under which an image is locked: the project-scoped one for the
+/// built images, the declared one for the rest.
+fn get_locked_image_name(config: &ProjectConfig, image_name: &str) -> String {
+    match config
+        .get_images()
+        .as_ref()
+        .and_then(|images| images.get(image_name))
+        .and_then(|image_config| image_config.get_build().as_ref())
+    {
+        Some(_) => get_built_image_name(config.get_project_internal_id(), image_name),
+        None => image_name.to_string(),
     }
+}
 
-    let project_path = match get_project_path() {
-        Some(p) => p,
-        None => {
-            eprintln!("The command was not executed inside an Avatar CLI project directory");
-            exit(exitcode::USAGE)
-        }
+/// Returns the ID of the image built for the previous lock file, as long as
+/// it was built from the same inputs and it's still locally available.
+fn get_reusable_image_id(
+    engine: &dyn ContainerEngine,
+    config_lock: Option<&ProjectConfigLock>,
+    built_image_name: &str,
+    build_hash: &str,
+) -> AvatarResult<Option<String>> {
+    let image_tag_config = match config_lock
+        .and_then(|lock| lock.get_images().get(built_image_name))
+        .and_then(|image_tags| image_tags.values().next())
+    {
+        Some(image_tag_config) => image_tag_config,
+        None => return Ok(None),
     };
+    if image_tag_config.get_build_hash().as_deref() != Some(build_hash) {
+        return Ok(None);
+    }
+
+    let image_id = image_tag_config.get_hash();
+    match engine.has_image(&get_image_ref(built_image_name, image_id))? {
+        true => Ok(Some(image_id.clone())),
+        false => Ok(None),
+    }
+}
+
+fn get_resolved_image_tag_hash(
+    image_tag_hashes: &ImageTagHashes,
+    image_name: &str,
+    image_tag: &str,
+) -> AvatarResult<String> {
+    match image_tag_hashes.get(&(image_name.to_string(), image_tag.to_string())) {
+        Some(image_hash) => Ok(image_hash.clone()),
+        // This branch should be unreachable
+        None => Err(AvatarError::Internal(format!(
+            "The image {}:{} was not resolved",
+            image_name, image_tag
+        ))),
+    }
+}
+
+fn get_offline_missing_images_error(image_refs: &[String]) -> AvatarError {
+    AvatarError::ContainerEngineUnavailable(format!(
+        "The following images are not available locally, and they can't be pulled in offline mode:\n{}",
+        image_refs
+            .iter()
+            .map(|image_ref| format!("\t- {}", image_ref))
+            .collect::<Vec<String>>()
+            .join("\n")
+    ))
+}
+
+/// Services are locked like the tools: their image tags are resolved (and
+/// registered in the lock's images section, so they're pulled, updated &
+/// reported along the other images) and pinned to their hashes.
+fn get_services_settings(
+    config: &ProjectConfig,
+    image_configs: &mut BTreeMap<String, BTreeMap<String, OCIImageTagConfigLock>>,
+    resolved_images: &ResolvedImages,
+) -> AvatarResult<Option<BTreeMap<String, ServiceConfigLock>>> {
+    let services = match config.get_services() {
+        Some(services) => services,
+        None => return Ok(None),
+    };
+
+    let mut dst_services: BTreeMap<String, ServiceConfigLock> = BTreeMap::new();
+    for (service_name, service_config) in services {
+        let image_name = service_config.get_image();
+        let image_tag = service_config.get_tag();
+        let locked_image_name = get_locked_image_name(config, image_name);
+
+        let image_tags = image_configs.entry(locked_image_name.clone()).or_default();
+        if !image_tags.contains_key(image_tag) {
+            // Built images are only tagged with their declared tags
+            if is_built_image_name(&locked_image_name) {
+                return Err(AvatarError::InvalidConfig(format!(
+                    "The service {} uses the tag {} of the image {}, but that tag is not declared",
+                    service_name, image_tag, image_name
+                )));
+            }
+            image_tags.insert(
+                image_tag.clone(),
+                resolved_images.get_tag_config_lock(image_name, image_tag, None)?,
+            );
+        }
+
+        dst_services.insert(
+            service_name.clone(),
+            generate_service_config_lock(
+                service_name,
+                service_config,
+                config.get_project_internal_id(),
+                &locked_image_name,
+                image_tags[image_tag].get_hash(),
+            )?,
+        );
+    }
+
+    Ok(Some(dst_services))
+}
+
+/// Whether any built image has to be rebuilt: its inputs changed since the lock
+/// file was generated, or it's not locally available anymore.
+fn has_outdated_image_builds(
+    engine: &dyn ContainerEngine,
+    project_path: &Path,
+    config: &ProjectConfig,
+    config_lock: &ProjectConfigLock,
+) -> AvatarResult<bool> {
+    for (image_name, _, build_config) in get_image_build_configs(config) {
+        let built_image_name = get_built_image_name(config.get_project_internal_id(), image_name);
+        let build_hash = get_build_hash(project_path, build_config)?;
+        if get_reusable_image_id(engine, Some(config_lock), &built_image_name, &build_hash)?
+            .is_none()
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+pub fn install_subcommand(
+    show_output: bool,
+) -> AvatarResult<(PathBuf, PathBuf, PathBuf, PathBuf, ProjectConfigLock)> {
+    check_not_in_session()?;
+
+    let project_path = get_required_project_path()?;
 
     let project_data_path = project_path.join(CONFIG_DIR_NAME);
     let config_path = project_data_path.join(AVATARFILE_NAME);
@@ -671,108 +1011,119 @@ pub(crate) fn install_subcommand(
     let project_state_path = volatile_path.join(STATEFILE_NAME);
 
     let (project_state, changed_state) = check_project_settings(
+        &project_path,
         &config_path,
         &config_lock_path,
         &project_state_path,
         show_output,
-    );
-    let pulled_oci_images = check_oci_images_availability(&project_state, show_output);
-    check_managed_volumes_availability(&project_state);
+    )?;
+    let engine = get_container_engine(project_state.get_container_engine())?;
+    let pulled_oci_images =
+        check_oci_images_availability(engine.as_ref(), &project_state, show_output)?;
+    record_pinned_images(&volatile_path, &project_state)?;
+    check_managed_volumes_availability(engine.as_ref(), &project_state)?;
+    check_project_network_existence(engine.as_ref(), project_state.get_project_internal_id())?;
     populate_volatile_bin_dir(
         &volatile_path,
         &project_state,
         pulled_oci_images || changed_state,
-    );
+    )?;
     populate_volatile_wrappers_dir(
         &project_path,
         &volatile_path,
         &project_state,
         pulled_oci_images || changed_state,
-    );
-    populate_volatile_home_dir(&volatile_path, pulled_oci_images || changed_state);
+    )?;
+    populate_volatile_home_dir(&volatile_path, pulled_oci_images || changed_state)?;
+    populate_volatile_run_plans_dir(&volatile_path, pulled_oci_images || changed_state)?;
     check_etc_passwd_files(
+        engine.as_ref(),
         &volatile_path,
         &project_state,
         pulled_oci_images || changed_state,
-    );
+    )?;
 
-    (
+    Ok((
         project_path,
         config_path,
         config_lock_path,
         project_state_path,
         project_state,
-    )
+    ))
 }
 
 fn populate_volatile_bin_dir(
-    volatile_path: &PathBuf,
+    volatile_path: &Path,
     project_state: &ProjectConfigLock,
     changed_state: bool,
-) {
-    let bin_path = match recreate_volatile_subdir(volatile_path, "bin", changed_state) {
+) -> AvatarResult<()> {
+    let bin_path = match recreate_volatile_subdir(volatile_path, "bin", changed_state)? {
         Some(_bin_path) => _bin_path,
-        None => return,
+        None => return Ok(()),
     };
 
-    let avatar_path = match env::current_exe() {
-        Ok(p) => p,
-        Err(e) => {
-            eprintln!(
-                "Unable to retrieve avatar's binary path.\n\n{}\n",
-                e.to_string()
-            );
-            exit(exitcode::OSERR)
-        }
-    };
+    let avatar_path = env::current_exe().map_err(|e| {
+        AvatarError::Os(format!(
+            "Unable to retrieve avatar's binary path.\n\n{}\n",
+            e
+        ))
+    })?;
 
     for binary_name in project_state.get_binary_names() {
         if symlink(&avatar_path, bin_path.join(binary_name)).is_err() {
-            eprintln!("Unable to create symlink to {} binary", binary_name);
-            exit(exitcode::CANTCREAT)
+            return Err(AvatarError::CantCreate(format!(
+                "Unable to create symlink to {} binary",
+                binary_name
+            )));
         }
     }
+
+    Ok(())
+}
+
+fn populate_volatile_home_dir(volatile_path: &Path, changed_state: bool) -> AvatarResult<()> {
+    recreate_volatile_subdir(volatile_path, "home", changed_state).map(|_| ())
 }
 
-fn populate_volatile_home_dir(volatile_path: &PathBuf, changed_state: bool) {
-    recreate_volatile_subdir(volatile_path, "home", changed_state);
+fn populate_volatile_run_plans_dir(volatile_path: &Path, changed_state: bool) -> AvatarResult<()> {
+    recreate_volatile_subdir(volatile_path, RUN_PLANS_DIR_NAME, changed_state).map(|_| ())
 }
 
 fn populate_volatile_wrappers_dir(
-    project_path: &PathBuf,
-    volatile_path: &PathBuf,
+    project_path: &Path,
+    volatile_path: &Path,
     project_state: &ProjectConfigLock,
     changed_state: bool,
-) {
-    let wrappers_path = match recreate_volatile_subdir(volatile_path, "wrappers", changed_state) {
+) -> AvatarResult<()> {
+    let wrappers_path = match recreate_volatile_subdir(volatile_path, "wrappers", changed_state)? {
         Some(_wrappers_path) => _wrappers_path,
-        None => return,
+        None => return Ok(()),
     };
 
     let avatar_path = match env::current_exe() {
         Ok(p) => match p.to_str() {
             Some(_p) => _p.to_string(),
             None => {
-                eprintln!(
+                return Err(AvatarError::Internal(
                     "The path to `avatar` contains non-utf8 characters that are not supported"
-                );
-                exit(exitcode::SOFTWARE)
+                        .to_string(),
+                ))
             }
         },
         Err(e) => {
-            eprintln!(
+            return Err(AvatarError::Os(format!(
                 "Unable to retrieve avatar's binary path.\n\n{}\n",
-                e.to_string()
-            );
-            exit(exitcode::OSERR)
+                e
+            )))
         }
     };
 
     let project_path = match project_path.to_str() {
         Some(p) => p.to_string(),
         None => {
-            eprintln!("The project path contains non-utf8 characters that are not supported");
-            exit(exitcode::SOFTWARE)
+            return Err(AvatarError::Internal(
+                "The project path contains non-utf8 characters that are not supported".to_string(),
+            ))
         }
     };
 
@@ -786,127 +1137,175 @@ fn populate_volatile_wrappers_dir(
     for binary_name in project_state.get_binary_names() {
         let wrapper_path = wrappers_path.join(binary_name);
         if let Err(e) = write(&wrapper_path, wrapper_content) {
-            eprintln!(
+            return Err(AvatarError::CantCreate(format!(
                 "Unable to create wrapper script for {}\n\n{}\n",
-                binary_name,
-                e.to_string()
-            );
-            exit(exitcode::CANTCREAT)
+                binary_name, e
+            )));
         }
         if let Err(e) = set_permissions(&wrapper_path, Permissions::from_mode(0o755)) {
-            eprintln!(
+            return Err(AvatarError::Os(format!(
                 "Unable to set permissions for wrapper script ({}).\n\n{}",
                 wrapper_path.display(),
-                e.to_string()
-            );
-            exit(exitcode::OSERR)
+                e
+            )));
         }
     }
-}
 
-fn pull_oci_image_by_fqn(image_ref: &str, show_output: bool) {
-    // This code assumes that the existence of the docker command has been checked before
-    if show_output {
-        match Command::new("docker").args(&["pull", image_ref]).status() {
-            Ok(status) => {
-                if !status.success() {
-                    eprintln!("Unable to pull OCI image {}", image_ref);
-                    exit(exitcode::UNAVAILABLE)
-                }
-            }
-            Err(err) => {
-                eprintln!(
-                    "Unable to pull OCI image {}.\n\n{}\n",
-                    image_ref,
-                    err.to_string()
-                );
-                exit(exitcode::OSERR)
-            }
-        }
-    } else {
-        match Command::new("docker").args(&["pull", image_ref]).output() {
-            Ok(output) => {
-                if !output.status.success() {
-                    eprintln!("Unable to pull OCI image {}", image_ref);
-                    exit(exitcode::UNAVAILABLE)
-                }
-            }
-            Err(err) => {
-                eprintln!(
-                    "Unable to pull OCI image {}.\n\n{}\n",
-                    image_ref,
-                    err.to_string()
-                );
-                exit(exitcode::OSERR)
-            }
-        }
-    }
+    Ok(())
 }
 
 fn recreate_volatile_subdir(
-    volatile_path: &PathBuf,
+    volatile_path: &Path,
     subdir_name: &str,
     changed_state: bool,
-) -> Option<PathBuf> {
+) -> AvatarResult<Option<PathBuf>> {
     let subdir_path = volatile_path.join(subdir_name);
 
     if subdir_path.exists() {
         if !subdir_path.is_dir() {
-            eprintln!(
+            return Err(AvatarError::Usage(format!(
                 "The path {} must be a directory, but found something else",
                 subdir_path.display()
-            );
-            exit(exitcode::USAGE)
+            )));
         }
 
         if !changed_state {
-            return None;
+            return Ok(None);
         }
 
         if let Err(e) = remove_dir_all(&subdir_path) {
-            eprintln!(
+            return Err(AvatarError::Io(format!(
                 "Unable to delete broken directory {}\n\n{}\n",
                 subdir_path.display(),
-                e.to_string()
-            );
-            exit(exitcode::IOERR)
+                e
+            )));
         }
     }
 
     if create_dir_all(&subdir_path).is_err() {
-        eprintln!("Unable to create directory {}", subdir_path.display());
-        exit(exitcode::CANTCREAT)
+        return Err(AvatarError::CantCreate(format!(
+            "Unable to create directory {}",
+            subdir_path.display()
+        )));
     }
 
-    Some(subdir_path)
+    Ok(Some(subdir_path))
+}
+
+/// Compiles the lock file again, with some image tags pinned to new hashes
+/// (the other ones keep their locked hashes). This way the binaries & services
+/// relying on those tags, and the settings derived from their images (like
+/// the `PATH` variable), are regenerated as well.
+pub fn regenerate_config_lock(
+    engine: &dyn ContainerEngine,
+    project_path: &Path,
+    config_lock_path: &Path,
+    config: &ProjectConfig,
+    config_hash: &Digest,
+    updated_hashes: &ImageTagHashes,
+) -> AvatarResult<(ProjectConfigLock, Digest)> {
+    let (previous_config_lock, _) = get_config_lock(config_lock_path)?;
+
+    let mut pulled_hashes = ImageTagHashes::new();
+    for image_name_tag in get_config_image_tags(config) {
+        let locked_hash = previous_config_lock
+            .get_images()
+            .get(&image_name_tag.0)
+            .and_then(|image_tags| image_tags.get(&image_name_tag.1))
+            .map(|tag_config| tag_config.get_hash());
+
+        let image_hash = match updated_hashes.get(&image_name_tag).or(locked_hash) {
+            Some(image_hash) => {
+                let image_ref = get_image_ref(&image_name_tag.0, image_hash);
+                if !engine.has_image(&image_ref)? && !is_offline_mode()? {
+                    engine.pull_image(&image_ref, false)?;
+                }
+                image_hash.clone()
+            }
+            // Declared after the last install
+            None => get_image_tag_hash(engine, &image_name_tag.0, &image_name_tag.1, false, false)?,
+        };
+        pulled_hashes.insert(image_name_tag, image_hash);
+    }
+
+    let resolved_images = ResolvedImages {
+        pulled: pulled_hashes,
+        built: build_project_images(
+            engine,
+            project_path,
+            config,
+            Some(&previous_config_lock),
+            false,
+        )?,
+    };
+
+    compile_config_lock(
+        engine,
+        config_lock_path,
+        config,
+        config_hash,
+        &resolved_images,
+    )
+}
+
+/// Resolves the hashes of all the image tags used by the project, pulling them
+/// concurrently when they are not locally available.
+fn resolve_image_tag_hashes(
+    engine: &dyn ContainerEngine,
+    config: &ProjectConfig,
+    show_output: bool,
+) -> AvatarResult<ImageTagHashes> {
+    let image_tags: Vec<(String, (String, String))> = get_config_image_tags(config)
+        .into_iter()
+        .map(|(image_name, image_tag)| {
+            (
+                format!("{}:{}", image_name, image_tag),
+                (image_name, image_tag),
+            )
+        })
+        .collect();
+
+    let image_hashes = run_image_jobs(&image_tags, "resolving", show_output, &|(
+        image_name,
+        image_tag,
+    )| {
+        get_image_tag_hash(engine, image_name, image_tag, false, false)
+    })?;
+
+    Ok(image_tags
+        .into_iter()
+        .map(|(_, image_tag)| image_tag)
+        .zip(image_hashes)
+        .collect())
 }
 
 fn set_binaries_settings_from_binaries_defs(
+    engine: &dyn ContainerEngine,
     dst_binaries: &mut BTreeMap<String, ImageBinaryConfigLock>,
     image_name: &String,
     image_tag: &str,
     src_binaries: &BTreeMap<String, ImageBinaryConfig>,
     config: &ProjectConfig,
     images_name_tag_hash_rel: &BTreeMap<String, BTreeMap<String, OCIImageTagConfigLock>>,
-) {
+) -> AvatarResult<()> {
     for (binary_name, binary_config) in src_binaries {
-        let image_tag_config = match images_name_tag_hash_rel.get(image_name) {
-            Some(images_tag_config_rel) => match images_tag_config_rel.get(image_tag) {
-                Some(_image_tag_config) => _image_tag_config,
-                None => {
-                    eprintln!("A theoretically impossible error just happened.");
-                    exit(exitcode::SOFTWARE)
-                }
-            },
+        let image_tag_config = match images_name_tag_hash_rel
+            .get(image_name)
+            .and_then(|images_tag_config_rel| images_tag_config_rel.get(image_tag))
+        {
+            Some(_image_tag_config) => _image_tag_config,
             None => {
-                eprintln!("A theoretically impossible error just happened.");
-                exit(exitcode::SOFTWARE)
+                return Err(AvatarError::Internal(
+                    "A theoretically impossible error just happened.".to_string(),
+                ))
             }
         };
 
         if dst_binaries.contains_key(binary_name) {
-            eprintln!("Duplicated binary definition for '{}'", binary_name);
-            exit(exitcode::DATAERR)
+            return Err(AvatarError::InvalidConfig(format!(
+                "Duplicated binary definition for '{}'",
+                binary_name
+            )));
         }
 
         dst_binaries.insert(
@@ -918,51 +1317,61 @@ fn set_binaries_settings_from_binaries_defs(
                     .get_path()
                     .clone()
                     .unwrap_or(PathBuf::from(binary_name)),
+                binary_config.get_args().clone(),
                 merge_run_and_shell_configs(
-                    image_tag_config.get_run_config(),
-                    binary_config.get_run_config(),
+                    engine,
+                    &merge_run_configs(&[
+                        image_tag_config.get_run_config(),
+                        binary_config.get_run_config(),
+                    ]),
                     config.get_shell_config(),
                     config.get_project_internal_id(),
                     image_name,
                     image_tag,
                     image_tag_config.get_hash(),
                     binary_name,
-                ),
+                )?,
             ),
         );
     }
+
+    Ok(())
 }
 
 fn set_binaries_settings_from_image_tags(
+    engine: &dyn ContainerEngine,
     dst_binaries: &mut BTreeMap<String, ImageBinaryConfigLock>,
     image_name: &String,
     image_config: &OCIImageConfig,
     config: &ProjectConfig,
     images_name_tag_hash_rel: &BTreeMap<String, BTreeMap<String, OCIImageTagConfigLock>>,
-) {
+) -> AvatarResult<()> {
     for (image_tag, image_tag_config) in image_config.get_tags() {
         match image_tag_config.get_binaries() {
             Some(src_binaries) => {
                 set_binaries_settings_from_binaries_defs(
+                    engine,
                     dst_binaries,
                     image_name,
                     image_tag,
                     src_binaries,
                     config,
                     images_name_tag_hash_rel,
-                );
+                )?;
             }
             None => { /* Do nothing */ }
         }
     }
+
+    Ok(())
 }
 
 fn update_project_state(
-    project_state_path: &PathBuf,
+    project_state_path: &Path,
     mut project_state: ProjectConfigLock,
     config_lock_hash: &[u8],
-) -> ProjectConfigLock {
+) -> AvatarResult<ProjectConfigLock> {
     project_state = project_state.update_project_config_hash(config_lock_hash);
-    save_config_lock(project_state_path, &project_state);
-    project_state
+    save_config_lock(project_state_path, &project_state)?;
+    Ok(project_state)
 }
@@ -0,0 +1,168 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::fs::{remove_file, write};
+
+use ring::digest::{digest, SHA256};
+use serde_yaml::Value;
+
+use crate::{
+    avatarfile_editor::AvatarfileEditor,
+    directories::{
+        get_required_project_path, AVATARFILE_LOCK_NAME, AVATARFILE_NAME, CONFIG_DIR_NAME,
+        STATEFILE_NAME, VOLATILE_DIR_NAME,
+    },
+    error::{AvatarError, AvatarResult},
+    migrations::{
+        get_cli_version, get_config_version, get_lock_version, get_newer_config_error,
+        get_newer_lock_error, get_pending_config_migrations, migrate_lock, LOCK_VERSION,
+    },
+    project_config::{get_file_bytes, parse_config, save_config_lock, ProjectConfigLock},
+    subcommands::{
+        install::{check_not_in_session, install_subcommand},
+        AVATAR_CLI_VERSION,
+    },
+};
+
+pub fn migrate_subcommand() -> AvatarResult<()> {
+    check_not_in_session()?;
+
+    let project_data_path = get_required_project_path()?.join(CONFIG_DIR_NAME);
+    let config_path = project_data_path.join(AVATARFILE_NAME);
+    let config_lock_path = project_data_path.join(AVATARFILE_LOCK_NAME);
+    let project_state_path = project_data_path
+        .join(VOLATILE_DIR_NAME)
+        .join(STATEFILE_NAME);
+
+    // Avatarfile
+    let config_bytes = get_file_bytes(&config_path)?;
+    let config_text = match String::from_utf8(config_bytes.clone()) {
+        Ok(text) => text,
+        Err(_) => {
+            return Err(AvatarError::ConfigParse(format!(
+                "The file {} is not valid UTF-8",
+                config_path.display()
+            )))
+        }
+    };
+    let config_version = match get_config_version(&config_bytes, &config_path)? {
+        Some(v) => v,
+        None => {
+            return Err(AvatarError::InvalidConfig(format!(
+                "The file '{}' does not declare its avatarVersion",
+                config_path.display()
+            )))
+        }
+    };
+
+    let cli_version = get_cli_version();
+    if config_version > cli_version {
+        return Err(get_newer_config_error(&config_version, &config_path));
+    }
+
+    let mut editor = AvatarfileEditor::new(&config_text);
+    for (step_version, migration_step) in get_pending_config_migrations(&config_version) {
+        migration_step(&mut editor);
+        println!(
+            "Avatarfile: applied the layout changes of Avatar CLI {}",
+            step_version
+        );
+    }
+    if config_version < cli_version {
+        editor.set_entry(&[], "avatarVersion", AVATAR_CLI_VERSION);
+        println!(
+            "Avatarfile: avatarVersion {} → {}",
+            config_version, AVATAR_CLI_VERSION
+        );
+    }
+
+    let new_config_text = editor.get_text();
+    let config_changed = new_config_text != config_text;
+    parse_config(new_config_text.as_bytes(), &config_path)?;
+
+    // Avatarfile.lock
+    let mut lock_changed = false;
+    let mut migrated_config_lock: Option<ProjectConfigLock> = None;
+    if config_lock_path.exists() {
+        let mut raw_config_lock =
+            match serde_yaml::from_slice::<Value>(&get_file_bytes(&config_lock_path)?) {
+                Ok(Value::Mapping(raw_config_lock)) => raw_config_lock,
+                _ => {
+                    return Err(AvatarError::ConfigParse(format!(
+                        "Malformed lock file '{}'",
+                        config_lock_path.display()
+                    )))
+                }
+            };
+
+        let lock_version = get_lock_version(&raw_config_lock);
+        if lock_version > LOCK_VERSION {
+            return Err(get_newer_lock_error(lock_version, &config_lock_path));
+        }
+        if lock_version < LOCK_VERSION {
+            migrate_lock(&mut raw_config_lock);
+            lock_changed = true;
+            println!(
+                "Avatarfile.lock: lockVersion {} → {}",
+                lock_version, LOCK_VERSION
+            );
+        }
+
+        let mut config_lock =
+            match serde_yaml::from_value::<ProjectConfigLock>(Value::Mapping(raw_config_lock)) {
+                Ok(config_lock) => config_lock,
+                Err(e) => {
+                    return Err(AvatarError::ConfigParse(format!(
+                        "Malformed lock file '{}':\n\t{}",
+                        config_lock_path.display(),
+                        e
+                    )))
+                }
+            };
+
+        // Keeping the lock in sync with the Avatarfile avoids re-resolving the
+        // image tags (and silently changing the locked digests).
+        let old_config_hash = digest(&SHA256, config_text.as_bytes());
+        if config_changed && old_config_hash.as_ref() == &config_lock.get_project_config_hash()[..]
+        {
+            let new_config_hash = digest(&SHA256, new_config_text.as_bytes());
+            config_lock = config_lock.update_project_config_hash(new_config_hash.as_ref());
+            lock_changed = true;
+        }
+
+        if lock_changed {
+            migrated_config_lock = Some(config_lock);
+        }
+    }
+
+    if !config_changed && !lock_changed {
+        println!("The project files are already up to date");
+        return Ok(());
+    }
+
+    if config_changed {
+        if let Err(e) = write(&config_path, new_config_text) {
+            return Err(AvatarError::CantCreate(format!(
+                "Unable to write file '{}'\n\n{}\n",
+                config_path.display(),
+                e
+            )));
+        }
+    }
+    if let Some(config_lock) = migrated_config_lock {
+        save_config_lock(&config_lock_path, &config_lock)?;
+    }
+
+    // The project state is derived from the lock file, it will be regenerated
+    if project_state_path.exists() && remove_file(&project_state_path).is_err() {
+        return Err(AvatarError::Io(format!(
+            "Unable to remove file {}",
+            project_state_path.display()
+        )));
+    }
+
+    install_subcommand(true).map(|_| ())
+}
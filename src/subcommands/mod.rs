@@ -4,24 +4,138 @@
  *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
  */
 
-use std::{env, path::PathBuf, process::exit};
+use std::{env, path::PathBuf};
 
-use clap::{App, AppSettings, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
-pub(crate) mod init;
-pub(crate) mod install;
-pub(crate) mod run;
-pub(crate) mod shell;
+use crate::{
+    avatar_env::OFFLINE,
+    error::{AvatarError, AvatarResult},
+};
 
-pub(crate) const AVATAR_CLI_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub mod bundle;
+pub mod doctor;
+pub mod edit;
+pub mod gc;
+pub mod init;
+pub mod install;
+pub mod migrate;
+pub mod policy;
+pub mod run;
+pub mod services;
+pub mod shell;
+pub mod task;
+pub mod update;
+pub mod verify;
 
-pub(crate) fn select() {
+pub const AVATAR_CLI_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub fn select() -> AvatarResult<()> {
     let matches = App::new("avatar")
         .version(AVATAR_CLI_VERSION)
         .setting(AppSettings::SubcommandRequired)
+        .arg(
+            Arg::with_name("offline")
+                .long("offline")
+                .global(true)
+                .help("Never pulls images, failing when they are not locally available (same as AVATAR_CLI_OFFLINE=1)"),
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Adds an image (and optionally some of its binaries) to the Avatarfile, then installs it")
+                .arg(
+                    Arg::with_name("image")
+                        .index(1)
+                        .value_name("IMAGE:TAG")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("bin")
+                        .long("bin")
+                        .short("b")
+                        .value_name("BINARY")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("env")
+                        .long("env")
+                        .short("e")
+                        .value_name("KEY=VALUE")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("volume")
+                        .long("volume")
+                        .short("v")
+                        .value_name("CONTAINER_PATH")
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bundle")
+                .about("Packs & unpacks the locked images, to use them in machines without registry access")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("Saves the lock file and all the locked images into a single archive")
+                        .arg(
+                            Arg::with_name("output_path")
+                                .index(1)
+                                .value_name("OUTPUT_PATH")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("strict")
+                                .long("strict")
+                                .help("Fails when only the layers of an image can be verified"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Loads the images of a bundle, verifying them against its lock file")
+                        .arg(
+                            Arg::with_name("input_path")
+                                .index(1)
+                                .value_name("INPUT_PATH")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("strict")
+                                .long("strict")
+                                .help("Fails when only the layers of an image can be verified"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Checks the environment Avatar CLI depends on, explaining how to fix the problems found")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["table", "json"])
+                        .default_value("table"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("down")
+                .about("Stops and removes the project service containers"),
+        )
         .subcommand(SubCommand::with_name("export-env").about(
             "Prints shell variable exports to create a new Avatar-CLI session. Useful for scripts.",
         ))
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Removes the project volumes, images & leftover containers that the lock file does not reference anymore")
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Lists the stale resources without removing them"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("init")
                 .about("It generates a new Avatar-CLI project configuration")
@@ -40,9 +154,82 @@ pub(crate) fn select() {
             SubCommand::with_name("shell")
                 .about("Starts a new subshell exposing the wrapped project tools"),
         )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Upgrades the Avatarfile and its lock file to the layout used by this Avatar CLI version"),
+        )
+        .subcommand(
+            SubCommand::with_name("outdated")
+                .about("Compares the locked image digests with the ones their tags currently point to")
+                .arg(
+                    Arg::with_name("image")
+                        .index(1)
+                        .value_name("IMAGE[:TAG]")
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["table", "json"])
+                        .default_value("table"),
+                )
+                .arg(
+                    Arg::with_name("exit-code")
+                        .long("exit-code")
+                        .help("Exits with code 1 when any locked image tag is outdated"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("policy")
+                .about("Checks the project configuration against the rules of its policy section")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Reports all the policy violations, exiting with a non-zero code if there is any")
+                        .arg(
+                            Arg::with_name("format")
+                                .long("format")
+                                .value_name("FORMAT")
+                                .possible_values(&["table", "json"])
+                                .default_value("table"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("remove")
+                .about("Removes images, image tags or binaries from the Avatarfile, then re-installs the project")
+                .arg(
+                    Arg::with_name("image")
+                        .index(1)
+                        .value_name("IMAGE[:TAG]")
+                        .required_unless("bin"),
+                )
+                .arg(
+                    Arg::with_name("bin")
+                        .long("bin")
+                        .short("b")
+                        .value_name("BINARY")
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("run")
                 .about("Executes a wrapped project tool without having to enter into a subshell")
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Prints the container command instead of executing it (secret env values are redacted)"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["shell", "json"])
+                        .default_value("shell")
+                        .help("The dry run output format"),
+                )
                 .arg(Arg::with_name("program_name").index(1).required(true))
                 .arg(
                     Arg::with_name("program_args")
@@ -50,12 +237,96 @@ pub(crate) fn select() {
                         .required(false),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("task")
+                .about("Runs a task defined in the Avatarfile, after the tasks it depends on")
+                .arg(
+                    Arg::with_name("task_name")
+                        .index(1)
+                        .value_name("TASK")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("up")
+                .about("Installs the project and starts its service containers in the background"),
+        )
+        .subcommand(
+            SubCommand::with_name("update")
+                .about("Re-pulls the project image tags and pins their new digests in the lock file")
+                .arg(
+                    Arg::with_name("image")
+                        .index(1)
+                        .value_name("IMAGE[:TAG]")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks the content of the locally stored images against the digests pinned in the lock file")
+                .arg(
+                    Arg::with_name("image")
+                        .index(1)
+                        .value_name("IMAGE[:TAG]")
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["table", "json"])
+                        .default_value("table"),
+                )
+                .arg(
+                    Arg::with_name("strict")
+                        .long("strict")
+                        .help("Fails when only the layers of an image can be verified"),
+                ),
+        )
         .get_matches();
 
+    // Exported, so it's also honoured by the project shims & the subshell
+    if is_offline_flag_present(&matches) {
+        env::set_var(OFFLINE, "1");
+    }
+
     match matches.subcommand_name() {
         Some(subcommand_name) => match subcommand_name {
-            "avatar" => exit(exitcode::OK),
+            "add" => {
+                let add_matches = matches.subcommand_matches("add").unwrap();
+                edit::add_subcommand(
+                    add_matches.value_of("image").unwrap(),
+                    &get_values(add_matches, "bin"),
+                    &get_values(add_matches, "env"),
+                    &get_values(add_matches, "volume"),
+                )
+            }
+            "avatar" => Ok(()),
+            "bundle" => {
+                let bundle_matches = matches.subcommand_matches("bundle").unwrap();
+                match bundle_matches.subcommand() {
+                    ("export", Some(export_matches)) => bundle::bundle_export_subcommand(
+                        &PathBuf::from(export_matches.value_of("output_path").unwrap()),
+                        export_matches.is_present("strict"),
+                    ),
+                    ("import", Some(import_matches)) => bundle::bundle_import_subcommand(
+                        &PathBuf::from(import_matches.value_of("input_path").unwrap()),
+                        import_matches.is_present("strict"),
+                    ),
+                    // This branch should be unreachable
+                    _ => Err(AvatarError::Usage("Invalid bundle subcommand".to_string())),
+                }
+            }
+            "doctor" => {
+                let doctor_matches = matches.subcommand_matches("doctor").unwrap();
+                doctor::doctor_subcommand(doctor_matches.value_of("format") == Some("json"))
+            }
+            "down" => services::down_subcommand(),
             "export-env" => shell::export_env_subcommand(),
+            "gc" => {
+                let gc_matches = matches.subcommand_matches("gc").unwrap();
+                gc::gc_subcommand(gc_matches.is_present("dry_run"))
+            }
             "init" => {
                 let init_matches = matches.subcommand_matches("init").unwrap();
                 let project_path = match init_matches.value_of("project_path") {
@@ -63,23 +334,97 @@ pub(crate) fn select() {
                     None => match env::current_dir() {
                         Ok(p) => p,
                         Err(_) => {
-                            eprintln!("Unable to get current working directory");
-                            exit(exitcode::OSERR)
+                            return Err(AvatarError::Os(
+                                "Unable to get current working directory".to_string(),
+                            ))
                         }
                     },
                 };
                 init::init_subcommand(&project_path)
             }
-            "install" => {
-                install::install_subcommand(true);
+            "install" => install::install_subcommand(true).map(|_| ()),
+            "migrate" => migrate::migrate_subcommand(),
+            "outdated" => {
+                let outdated_matches = matches.subcommand_matches("outdated").unwrap();
+                update::outdated_subcommand(
+                    outdated_matches.value_of("image"),
+                    outdated_matches.value_of("format") == Some("json"),
+                    outdated_matches.is_present("exit-code"),
+                )
+            }
+            "policy" => {
+                let policy_matches = matches.subcommand_matches("policy").unwrap();
+                match policy_matches.subcommand_matches("check") {
+                    Some(check_matches) => policy::policy_check_subcommand(
+                        check_matches.value_of("format") == Some("json"),
+                    ),
+                    // This branch should be unreachable
+                    None => Err(AvatarError::Usage("Invalid policy subcommand".to_string())),
+                }
+            }
+            "remove" => {
+                let remove_matches = matches.subcommand_matches("remove").unwrap();
+                edit::remove_subcommand(
+                    remove_matches.value_of("image"),
+                    &get_values(remove_matches, "bin"),
+                )
+            }
+            "run" => {
+                let run_matches = matches.subcommand_matches("run").unwrap();
+                run::run_subcommand(
+                    run_matches.value_of("program_name").unwrap(),
+                    &get_values(run_matches, "program_args")
+                        .into_iter()
+                        .map(|arg| arg.to_string())
+                        .collect::<Vec<String>>(),
+                    match (
+                        run_matches.is_present("dry_run"),
+                        run_matches.value_of("format"),
+                    ) {
+                        (true, Some("json")) => Some(run::DryRunFormat::Json),
+                        (true, _) => Some(run::DryRunFormat::Shell),
+                        (false, _) => None,
+                    },
+                )
             }
-            "run" => run::run_subcommand(),
             "shell" => shell::shell_subcommand(),
-            _ => {
-                eprintln!("Invalid subcommand");
-                exit(exitcode::USAGE)
+            "task" => {
+                let task_matches = matches.subcommand_matches("task").unwrap();
+                task::task_subcommand(task_matches.value_of("task_name").unwrap())
+            }
+            "up" => services::up_subcommand(),
+            "update" => {
+                let update_matches = matches.subcommand_matches("update").unwrap();
+                update::update_subcommand(update_matches.value_of("image"))
+            }
+            "verify" => {
+                let verify_matches = matches.subcommand_matches("verify").unwrap();
+                verify::verify_subcommand(
+                    verify_matches.value_of("image"),
+                    verify_matches.value_of("format") == Some("json"),
+                    verify_matches.is_present("strict"),
+                )
             }
+            _ => Err(AvatarError::Usage("Invalid subcommand".to_string())),
         },
-        None => exit(exitcode::SOFTWARE), // This branch should be unreachable
-    };
+        // This branch should be unreachable
+        None => Err(AvatarError::Internal("Missing subcommand".to_string())),
+    }
+}
+
+fn get_values<'a>(matches: &'a ArgMatches, arg_name: &str) -> Vec<&'a str> {
+    match matches.values_of(arg_name) {
+        Some(values) => values.collect(),
+        None => vec![],
+    }
+}
+
+/// Global flags can be passed before or after the subcommands' names
+fn is_offline_flag_present(matches: &ArgMatches) -> bool {
+    match matches.subcommand() {
+        (_, Some(subcommand_matches)) => {
+            matches.is_present("offline") || is_offline_flag_present(subcommand_matches)
+        }
+        _ => matches.is_present("offline"),
+    }
 }
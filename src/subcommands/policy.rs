@@ -0,0 +1,89 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use serde::Serialize;
+
+use crate::{
+    directories::{
+        get_required_project_path, AVATARFILE_LOCK_NAME, AVATARFILE_NAME, CONFIG_DIR_NAME,
+    },
+    error::{AvatarError, AvatarResult},
+    policy::{
+        get_image_policy_violations, get_missing_lock_violation, get_outdated_lock_violation,
+        is_lock_required, PolicyViolation,
+    },
+    project_config::{get_config, get_config_lock},
+    subcommands::update::print_table,
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PolicyReport {
+    violations: Vec<PolicyViolation>,
+}
+
+/// Reports every policy violation (instead of stopping at the first one), so
+/// it can be used as a CI check.
+pub fn policy_check_subcommand(json_format: bool) -> AvatarResult<()> {
+    let project_path = get_required_project_path()?;
+    let project_data_path = project_path.join(CONFIG_DIR_NAME);
+    let config_path = project_data_path.join(AVATARFILE_NAME);
+    let config_lock_path = project_data_path.join(AVATARFILE_LOCK_NAME);
+
+    let (config, config_hash) = get_config(&config_path)?;
+    let mut violations = get_image_policy_violations(&config, &project_path)?;
+
+    if is_lock_required(&config) {
+        if !config_lock_path.is_file() {
+            violations.push(get_missing_lock_violation(&config_lock_path));
+        } else {
+            let (config_lock, _) = get_config_lock(&config_lock_path)?;
+            if config_hash.as_ref() != &config_lock.get_project_config_hash()[..] {
+                violations.push(get_outdated_lock_violation(&config_lock_path));
+            }
+        }
+    }
+
+    if json_format {
+        let report = PolicyReport {
+            violations: violations.clone(),
+        };
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                return Err(AvatarError::Internal(format!(
+                    "Unknown error while serializing the report:\n\n{}\n",
+                    e
+                )))
+            }
+        }
+    } else if violations.is_empty() {
+        println!("No policy violations found");
+    } else {
+        let rows: Vec<Vec<String>> = violations
+            .iter()
+            .map(|violation| {
+                vec![
+                    violation.get_rule().clone(),
+                    violation.get_subject().clone(),
+                    violation.get_message().clone(),
+                ]
+            })
+            .collect();
+        print_table(&["RULE", "SUBJECT", "MESSAGE"], &rows);
+    }
+
+    match violations.len() {
+        0 => Ok(()),
+        1 => Err(AvatarError::PolicyViolation(
+            "Found 1 policy violation".to_string(),
+        )),
+        n => Err(AvatarError::PolicyViolation(format!(
+            "Found {} policy violations",
+            n
+        ))),
+    }
+}
@@ -6,182 +6,302 @@
 
 use std::env;
 use std::os::unix::process::CommandExt; // Brings trait that allows us to use exec
-use std::path::PathBuf;
-use std::{
-    process::{exit, Command},
-    str::from_utf8,
-};
+use std::path::{Path, PathBuf};
+use std::{process::Command, str::from_utf8};
 
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::Serialize;
 
 use crate::avatar_env::{
-    AvatarEnv, FORCE_PROJECT_PATH, MOUNT_TMP_PATHS, PROCESS_ID, PROJECT_INTERNAL_ID, SESSION_TOKEN,
+    AvatarEnv, DRY_RUN, FORCE_PROJECT_PATH, MOUNT_TMP_PATHS, PROCESS_ID, PROJECT_INTERNAL_ID,
+    SESSION_TOKEN,
 };
+use crate::container_engines::{get_container_engine, ContainerEngine};
 use crate::directories::{
-    check_if_inside_project_dir, get_project_path, is_inside_project_dir, AVATARFILE_LOCK_NAME,
-    AVATARFILE_NAME, CONFIG_DIR_NAME, CONTAINER_HOME_PATH, STATEFILE_NAME, VOLATILE_DIR_NAME,
+    check_if_inside_project_dir, get_required_project_path, is_inside_project_dir,
+    AVATARFILE_LOCK_NAME, AVATARFILE_NAME, CONFIG_DIR_NAME, CONTAINER_HOME_PATH, STATEFILE_NAME,
+    VOLATILE_DIR_NAME,
+};
+use crate::error::{AvatarError, AvatarResult};
+use crate::policy::check_policy;
+use crate::ports::PortMapping;
+use crate::project_config::{
+    get_config, get_config_lock, get_image_ref, get_project_network_name, ImageBinaryConfigLock,
+    NetworkMode, ResourcesConfig, SecurityConfig,
 };
-use crate::project_config::{get_config, get_config_lock, ImageBinaryConfigLock};
+use crate::run_plan::{get_run_plan_path, load_run_plan, save_run_plan, RunPlan, RunPlanKey};
+use crate::subcommands::install::check_project_network_existence;
+
+/// Substrings that, when found in an environment variable name, cause its value
+/// to be hidden in the dry-run output.
+const SECRET_ENV_NAME_PARTS: &[&str] =
+    &["CREDENTIAL", "KEY", "PASSWD", "PASSWORD", "SECRET", "TOKEN"];
+const REDACTED_VALUE: &str = "<redacted>";
+
+/// How the container command is printed when running in dry-run mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DryRunFormat {
+    Shell,
+    Json,
+}
 
-pub(crate) fn run_subcommand() {
-    let project_path = match get_project_path() {
-        Some(p) => p,
-        None => {
-            eprintln!("The command was not executed inside an Avatar CLI project directory");
-            exit(exitcode::USAGE)
-        }
-    };
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DryRunCommand<'a> {
+    program: &'a str,
+    args: Vec<String>,
+}
 
-    let used_program_name = match env::args().nth(2) {
-        Some(n) => n,
-        None => {
-            eprintln!("A program name must be passed to 'avatar run'");
-            exit(exitcode::USAGE)
-        }
-    };
+pub fn run_subcommand(
+    used_program_name: &str,
+    program_args: &[String],
+    dry_run: Option<DryRunFormat>,
+) -> AvatarResult<()> {
+    let project_path = get_required_project_path()?;
 
     let session_token = match env::var(SESSION_TOKEN) {
         Ok(st) => st,
         Err(_) => thread_rng().sample_iter(&Alphanumeric).take(16).collect(),
     };
 
-    run(&project_path, &used_program_name, &session_token, 4)
+    let dry_run = match dry_run {
+        Some(format) => Some(format),
+        None => get_env_dry_run_format()?,
+    };
+
+    run(
+        &project_path,
+        used_program_name,
+        &session_token,
+        program_args,
+        dry_run,
+    )
 }
 
-pub(crate) fn run_in_subshell_mode(used_program_name: &str) {
-    let project_env = AvatarEnv::read();
+pub fn run_in_subshell_mode(used_program_name: &str) -> AvatarResult<()> {
+    let project_env = AvatarEnv::read()?;
     let project_path = project_env.get_project_path();
 
     run(
         project_path,
         used_program_name,
         project_env.get_session_token(),
-        1,
-    );
+        &env::args().skip(1).collect::<Vec<String>>(),
+        get_env_dry_run_format()?,
+    )
 }
 
-fn run(project_path: &PathBuf, used_program_name: &str, session_token: &str, skip_args: usize) {
+/// Reads the dry-run mode from the environment, so it can also be enabled for
+/// binaries called through the project shims.
+pub fn get_env_dry_run_format() -> AvatarResult<Option<DryRunFormat>> {
+    match env::var(DRY_RUN) {
+        Ok(v) => match v.to_lowercase().as_str() {
+            "" | "0" | "false" => Ok(None),
+            "1" | "true" | "shell" => Ok(Some(DryRunFormat::Shell)),
+            "json" => Ok(Some(DryRunFormat::Json)),
+            _ => Err(AvatarError::Environment(format!(
+                "Invalid value '{}' for the '{}' environment variable, expected one of: 1, true, shell, json",
+                v, DRY_RUN
+            ))),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+fn run(
+    project_path: &PathBuf,
+    used_program_name: &str,
+    session_token: &str,
+    program_args: &[String],
+    dry_run: Option<DryRunFormat>,
+) -> AvatarResult<()> {
     let current_dir = match env::current_dir() {
         Ok(p) => p,
         Err(_) => {
-            eprintln!("Unable to get current working directory");
-            exit(exitcode::NOINPUT)
+            return Err(AvatarError::MissingFile(
+                "Unable to get current working directory".to_string(),
+            ))
         }
     };
 
-    check_if_inside_project_dir(project_path, &current_dir);
+    check_if_inside_project_dir(project_path, &current_dir)?;
 
-    let config_path = project_path.join(CONFIG_DIR_NAME).join(AVATARFILE_NAME);
-    if !config_path.exists() || !config_path.is_file() {
-        eprintln!("The config file '{}' is not available anymore, please check if there is any background process modifying files in your project directory", config_path.display());
-        exit(exitcode::NOINPUT)
-    }
+    let run_plan = get_run_plan(project_path, used_program_name)?;
 
+    run_docker_command(
+        &run_plan,
+        &current_dir,
+        project_path,
+        session_token,
+        program_args,
+        dry_run,
+    )
+}
+
+/// Loads the cached run plan for a binary, or computes it (and caches it) after
+/// validating that the project files are in sync.
+pub fn get_run_plan(project_path: &Path, used_program_name: &str) -> AvatarResult<RunPlan> {
+    let config_path = project_path.join(CONFIG_DIR_NAME).join(AVATARFILE_NAME);
     let config_lock_path = project_path
         .join(CONFIG_DIR_NAME)
         .join(AVATARFILE_LOCK_NAME);
+    let volatile_path = project_path.join(CONFIG_DIR_NAME).join(VOLATILE_DIR_NAME);
+    let project_state_path = volatile_path.join(STATEFILE_NAME);
+    let run_plan_path = get_run_plan_path(&volatile_path, used_program_name);
+
+    // Fast path: nothing changed since the last time this binary was executed
+    let run_plan_key = RunPlanKey::read(&[&config_path, &config_lock_path, &project_state_path]);
+    if let Some(_run_plan_key) = &run_plan_key {
+        if let Some(run_plan) = load_run_plan(&run_plan_path, _run_plan_key) {
+            return Ok(run_plan);
+        }
+    }
+
+    if !config_path.exists() || !config_path.is_file() {
+        return Err(AvatarError::MissingFile(format!("The config file '{}' is not available anymore, please check if there is any background process modifying files in your project directory", config_path.display())));
+    }
+
     if !config_lock_path.exists() || !config_lock_path.is_file() {
-        eprintln!("The config lock file '{}' is not available anymore, please check if there is any background process modifying files in your project directory", config_lock_path.display());
-        exit(exitcode::NOINPUT)
+        return Err(AvatarError::MissingFile(format!("The config lock file '{}' is not available anymore, please check if there is any background process modifying files in your project directory", config_lock_path.display())));
     }
 
-    let project_state_path = project_path
-        .join(CONFIG_DIR_NAME)
-        .join(VOLATILE_DIR_NAME)
-        .join(STATEFILE_NAME);
     if !project_state_path.exists() || !project_state_path.is_file() {
-        eprintln!("The project state file '{}' is not available anymore, please check if there is any background process modifying files in your project directory", project_state_path.display());
-        exit(exitcode::NOINPUT)
+        return Err(AvatarError::MissingFile(format!("The project state file '{}' is not available anymore, please check if there is any background process modifying files in your project directory", project_state_path.display())));
     }
 
-    let (_, config_hash) = get_config(&config_path);
-    let (config_lock, config_lock_hash) = get_config_lock(&config_lock_path);
+    let (config, config_hash) = get_config(&config_path)?;
+    check_policy(&config, project_path)?;
+    let (config_lock, config_lock_hash) = get_config_lock(&config_lock_path)?;
 
     if config_hash.as_ref() != &config_lock.get_project_config_hash()[..] {
-        eprintln!(
-        "The hash for the file '{}' does not match with the one in '{}', considering exiting the avatar subshell and entering again",
-        config_path.display(),
-        config_lock_path.display()
-    );
-        exit(exitcode::DATAERR)
+        return Err(AvatarError::HashMismatch(format!(
+            "The hash for the file '{}' does not match with the one in '{}', considering exiting the avatar subshell and entering again",
+            config_path.display(),
+            config_lock_path.display()
+        )));
     }
 
-    let (project_state, _) = get_config_lock(&project_state_path);
+    let (project_state, _) = get_config_lock(&project_state_path)?;
 
     if config_lock_hash.as_ref() != &project_state.get_project_config_hash()[..] {
-        eprintln!(
-        "The hash for the file '{}' does not match with the one in '{}', considering exiting the avatar subshell and entering again",
-        config_lock_path.display(),
-        project_state_path.display()
-    );
-        exit(exitcode::DATAERR)
+        return Err(AvatarError::HashMismatch(format!(
+            "The hash for the file '{}' does not match with the one in '{}', considering exiting the avatar subshell and entering again",
+            config_lock_path.display(),
+            project_state_path.display()
+        )));
     }
 
-    let binary_configuration = match project_state.get_binary_configuration(&used_program_name) {
+    let binary_configuration = match project_state.get_binary_configuration(used_program_name) {
         Some(c) => c,
         None => {
-            eprintln!(
+            return Err(AvatarError::InvalidConfig(format!(
                 "Binary '{}' not properly configured in lock file '{}'",
                 used_program_name,
                 project_state_path.display()
-            );
-            exit(1)
+            )))
         }
     };
 
-    run_docker_command(
+    let engine = get_container_engine(project_state.get_container_engine())?;
+    engine.check_client_availability()?;
+
+    let run_plan = build_run_plan(
+        engine.as_ref(),
         binary_configuration,
-        &current_dir,
         project_path,
         project_state.get_project_internal_id(),
-        session_token,
-        skip_args,
-    );
+        run_plan_key,
+    )?;
+    if let Some(_run_plan_key) =
+        RunPlanKey::read(&[&config_path, &config_lock_path, &project_state_path])
+    {
+        // We only persist the plan if the files did not change while we were
+        // validating them
+        if Some(&_run_plan_key) == run_plan.get_key().as_ref() {
+            save_run_plan(&run_plan_path, &run_plan);
+        }
+    }
+
+    Ok(run_plan)
 }
 
-fn run_docker_command(
+/// Computes the parts of the container's command line that only depend on the
+/// project state.
+pub fn build_run_plan(
+    engine: &dyn ContainerEngine,
     binary_configuration: &ImageBinaryConfigLock,
-    current_dir: &PathBuf,
-    project_path: &PathBuf,
+    project_path: &Path,
     project_internal_id: &str,
-    session_token: &str,
-    skip_args: usize,
-) {
-    if which::which("docker").is_err() {
-        eprintln!("docker client is not available");
-        exit(exitcode::UNAVAILABLE)
-    }
+    run_plan_key: Option<RunPlanKey>,
+) -> AvatarResult<RunPlan> {
+    let mut static_args: Vec<String> = Vec::new();
+    let mut env_from_host: Vec<String> = Vec::new();
 
-    let mut interactive_options: Vec<&str> = vec!["-i"]; // TODO: Check if stdin is open
-    if atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stdout) {
-        interactive_options.push("-t")
-    }
-
-    let mut dynamic_env: Vec<String> = Vec::new();
-    let mut dynamic_mounts: Vec<String> = Vec::new();
     if let Some(run_config) = binary_configuration.get_run_config() {
         if let Some(used_defined_env_vars) = run_config.get_env() {
             for (var_name, var_value) in used_defined_env_vars {
                 // Notice: The PATH variable has already been checked during the `install` step
-                dynamic_env.push("--env".to_string());
-                dynamic_env.push(format!("{}={}", var_name, var_value));
+                static_args.push("--env".to_string());
+                static_args.push(format!("{}={}", var_name, var_value));
             }
         }
 
         if let Some(host_var_names) = run_config.get_env_from_host() {
-            for var_name in host_var_names {
-                if let Ok(var_value) = env::var(var_name) {
-                    // Notice: The PATH variable has already been checked during the `install` step
-                    dynamic_env.push("--env".to_string());
-                    dynamic_env.push(format!("{}={}", var_name, var_value));
-                }
-            }
+            // Notice: The PATH variable has already been checked during the `install` step
+            env_from_host.extend(host_var_names.iter().cloned());
         }
+    }
+
+    let project_name = project_path.file_name().unwrap().to_str().unwrap_or("xxx");
+    let program_name = binary_configuration
+        .get_path()
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap_or("yyy");
+
+    let home_path = project_path
+        .join(CONFIG_DIR_NAME)
+        .join(VOLATILE_DIR_NAME)
+        .join("home");
+
+    let image_ref = get_image_ref(
+        binary_configuration.get_oci_image_name(),
+        binary_configuration.get_oci_image_hash(),
+    );
 
+    static_args.extend(
+        [
+            "--label",
+            "managed_tool.container_role.avatar-cli",
+            "--label",
+            &format!("{}.byid.projects.avatar-cli", project_internal_id),
+            "--env",
+            &format!("{}={}", PROJECT_INTERNAL_ID, project_internal_id),
+            "--mount",
+            &format!(
+                "type=bind,source={},target=/playground",
+                project_path.display() // TODO: Escape commas?
+            ),
+            "--mount",
+            &format!(
+                "type=bind,source={},target={}",
+                home_path.display(), // TODO: Escape commas?
+                CONTAINER_HOME_PATH
+            ),
+            "--env",
+            &format!("HOME={}", CONTAINER_HOME_PATH),
+        ]
+        .iter()
+        .map(|arg| arg.to_string()),
+    );
+    static_args.extend(engine.get_user_args(nix::unistd::getuid(), nix::unistd::getgid()));
+
+    if let Some(run_config) = binary_configuration.get_run_config() {
         if let Some(volumes) = run_config.get_volumes() {
             for volume_config in volumes {
-                dynamic_mounts.push("--volume".to_string());
-                dynamic_mounts.push(format!(
+                static_args.push("--volume".to_string());
+                static_args.push(format!(
                     "{}:{}",
                     volume_config.get_name(),
                     volume_config.get_container_path().display()
@@ -191,20 +311,160 @@ fn run_docker_command(
 
         if let Some(bindings) = run_config.get_bindings() {
             for (container_path, host_path) in bindings {
-                dynamic_mounts.push("--mount".to_string());
-                dynamic_mounts.push(format!(
+                static_args.push("--mount".to_string());
+                static_args.push(format!(
                     "type=bind,source={},target={}",
                     host_path.display(),
                     container_path.display()
                 ));
             }
         }
+
+        for (host_name, ip_address) in run_config.get_extra_hosts().iter().flatten() {
+            static_args.push("--add-host".to_string());
+            static_args.push(format!("{}:{}", host_name, ip_address));
+        }
+
+        if let Some(resources) = run_config.get_resources() {
+            push_resources_args(resources, &mut static_args);
+        }
+
+        if let Some(security) = run_config.get_security() {
+            push_security_args(security, project_path, &mut static_args)?;
+        }
     }
 
+    let mut published_ports: Vec<PortMapping> = Vec::new();
+    if let Some(run_config) = binary_configuration.get_run_config() {
+        for (host_spec, container_spec) in run_config.get_ports().iter().flatten() {
+            // The mappings have already been checked during the `install` step
+            published_ports.push(PortMapping::parse(host_spec, container_spec).map_err(|e| {
+                AvatarError::InvalidConfig(format!(
+                    "Invalid port mapping '{}: {}': {}",
+                    host_spec, container_spec, e
+                ))
+            })?);
+        }
+    }
+
+    let network_mode = match binary_configuration.get_run_config() {
+        Some(run_config) => run_config.get_network(),
+        None => NetworkMode::default(),
+    };
+    let network_name = match network_mode {
+        NetworkMode::Project => {
+            // The project could have been installed by an older Avatar CLI
+            // version, not creating the network
+            check_project_network_existence(engine, project_internal_id)?;
+            Some(get_project_network_name(project_internal_id))
+        }
+        NetworkMode::Default => None,
+        NetworkMode::Host => Some("host".to_string()),
+        NetworkMode::None => Some("none".to_string()),
+    };
+    if let Some(network_name) = network_name {
+        static_args.push("--network".to_string());
+        static_args.push(network_name);
+    }
+
+    if engine.needs_passwd_files() {
+        push_passwd_args(&image_ref, project_path, &mut static_args)?;
+    }
+
+    Ok(RunPlan::new(
+        run_plan_key,
+        engine.get_program_name().to_string(),
+        format!("{}_{}_{}", project_name, program_name, project_internal_id),
+        static_args,
+        env_from_host,
+        image_ref,
+        binary_configuration.get_path().clone(),
+        binary_configuration.get_args().clone().unwrap_or_default(),
+        published_ports,
+    ))
+}
+
+/// Replaces the current process by the container engine client, or just prints
+/// the command when in dry-run mode. It only returns on error, or after a dry
+/// run.
+fn run_docker_command(
+    run_plan: &RunPlan,
+    current_dir: &Path,
+    project_path: &PathBuf,
+    session_token: &str,
+    program_args: &[String],
+    dry_run: Option<DryRunFormat>,
+) -> AvatarResult<()> {
+    let command_args = get_docker_command_args(
+        run_plan,
+        current_dir,
+        project_path,
+        session_token,
+        program_args,
+    )?;
+
+    if let Some(format) = dry_run {
+        return print_dry_run_command(run_plan.get_program_name(), &command_args, format);
+    }
+
+    check_published_ports_availability(run_plan)?;
+
+    let exec_error = Command::new(run_plan.get_program_name())
+        .args(command_args)
+        .exec(); // Only for UNIX
+
+    Err(AvatarError::Os(format!(
+        "Unable to execute {} client\n\n{}\n",
+        run_plan.get_program_name(),
+        exec_error
+    )))
+}
+
+/// Fails early when the host ports are taken (most probably by another running
+/// instance of the same binary), instead of letting the container engine fail
+/// with a less helpful message.
+pub fn check_published_ports_availability(run_plan: &RunPlan) -> AvatarResult<()> {
+    for port_mapping in run_plan.get_published_ports() {
+        if let Some(port) = port_mapping.find_host_port_in_use() {
+            return Err(AvatarError::PortInUse(format!(
+                "The host port {} (needed to publish '{}') is already in use. Is another instance of '{}' still running? Its containers are named '{}_*'",
+                port,
+                port_mapping.get_publish_arg(),
+                run_plan.get_binary_path().display(),
+                run_plan.get_container_name_prefix()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves the full argument list passed to the container engine client
+pub fn get_docker_command_args(
+    run_plan: &RunPlan,
+    current_dir: &Path,
+    project_path: &PathBuf,
+    session_token: &str,
+    program_args: &[String],
+) -> AvatarResult<Vec<String>> {
+    let mut interactive_options: Vec<&str> = vec!["-i"]; // TODO: Check if stdin is open
+    if atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stdout) {
+        interactive_options.push("-t")
+    }
+
+    let mut dynamic_env: Vec<String> = Vec::new();
+    for var_name in run_plan.get_env_from_host() {
+        if let Ok(var_value) = env::var(var_name) {
+            dynamic_env.push("--env".to_string());
+            dynamic_env.push(format!("{}={}", var_name, var_value));
+        }
+    }
+
+    let mut dynamic_mounts: Vec<String> = Vec::new();
     if let Ok(mount_tmp_paths) = env::var(MOUNT_TMP_PATHS) {
         if mount_tmp_paths.to_lowercase() == "true" {
-            for arg in env::args().skip(skip_args) {
-                let potential_path = PathBuf::from(&arg);
+            for arg in program_args {
+                let potential_path = PathBuf::from(arg);
                 if potential_path.is_absolute() && potential_path.starts_with("/tmp") {
                     let tmp_path = potential_path.display();
                     dynamic_mounts.push("--mount".to_string());
@@ -221,95 +481,148 @@ fn run_docker_command(
             if env::var(FORCE_PROJECT_PATH).is_ok() {
                 PathBuf::from("") // We loose some guarantees in this corner case ‾\_('')_/‾
             } else {
-                eprintln!("A precondition of run_docker_command does not hold: working directory inside project directory");
-                exit(exitcode::SOFTWARE)
+                return Err(AvatarError::Internal("A precondition of run_docker_command does not hold: working directory inside project directory".to_string()));
             }
         }
     };
 
     let process_id: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
-    let project_name = match project_path.file_name().unwrap().to_str() {
-        Some(pn) => pn,
-        None => "xxx",
-    };
-    let program_name = match binary_configuration
-        .get_path()
-        .file_name()
-        .unwrap()
-        .to_str()
-    {
-        Some(pn) => pn,
-        None => "yyy",
-    };
 
-    let uid = nix::unistd::getuid();
-    let home_path = project_path
-        .join(CONFIG_DIR_NAME)
-        .join(VOLATILE_DIR_NAME)
-        .join("home");
+    let mut command_args: Vec<String> = ["run", "--rm", "--init"]
+        .iter()
+        .chain(interactive_options.iter())
+        .map(|arg| arg.to_string())
+        .collect();
+    command_args.extend(dynamic_env);
+    command_args.extend(vec![
+        "--name".to_string(),
+        format!(
+            "{}_{}_{}",
+            run_plan.get_container_name_prefix(),
+            session_token,
+            process_id
+        ),
+        "--env".to_string(),
+        format!("{}={}", PROCESS_ID, process_id),
+        "--env".to_string(),
+        format!("{}={}", SESSION_TOKEN, session_token),
+        "--workdir".to_string(),
+        format!("/playground/{}", working_dir.display()),
+    ]);
+    command_args.extend(run_plan.get_static_args().iter().cloned());
+    for port_mapping in run_plan.get_published_ports() {
+        command_args.push("--publish".to_string());
+        command_args.push(port_mapping.get_publish_arg());
+    }
+    command_args.extend(dynamic_mounts);
+    command_args.extend(get_user_integration_args(nix::unistd::getuid()));
+    command_args.push(run_plan.get_image_ref().clone());
+    command_args.push(run_plan.get_binary_path().display().to_string());
+    command_args.extend(transform_command_args(
+        run_plan.get_binary_args(),
+        program_args,
+        project_path,
+    ));
 
-    let image_ref = format!(
-        "{}@sha256:{}",
-        binary_configuration.get_oci_image_name(),
-        binary_configuration.get_oci_image_hash()
-    );
+    Ok(command_args)
+}
 
-    Command::new("docker")
-        .args(&["run", "--rm", "--init"])
-        .args(interactive_options)
-        .args(dynamic_env)
-        .args(&[
-            "--name",
-            &format!(
-                "{}_{}_{}_{}_{}",
-                project_name, program_name, project_internal_id, session_token, process_id
-            ),
-            "--label",
-            "managed_tool.container_role.avatar-cli",
-            "--label",
-            &format!("{}.byid.projects.avatar-cli", project_internal_id),
-            "--env",
-            &format!("{}={}", PROCESS_ID, process_id),
-            "--env",
-            &format!("{}={}", PROJECT_INTERNAL_ID, project_internal_id),
-            "--env",
-            &format!("{}={}", SESSION_TOKEN, session_token),
-            "--user",
-            &format!("{}:{}", uid, nix::unistd::getgid()),
-            "--mount",
-            &format!(
-                "type=bind,source={},target=/playground",
-                project_path.display() // TODO: Escape commas?
-            ),
-            "--workdir",
-            &format!("/playground/{}", working_dir.display()),
-            "--mount",
-            &format!(
-                "type=bind,source={},target={}",
-                home_path.display(), // TODO: Escape commas?
-                CONTAINER_HOME_PATH
-            ),
-            "--env",
-            &format!("HOME={}", CONTAINER_HOME_PATH),
-        ])
-        .args(dynamic_mounts)
-        .args(get_user_integration_args(uid, &image_ref, project_path))
-        .arg(&image_ref)
-        .arg(binary_configuration.get_path())
-        .args(transform_command_args(skip_args, project_path))
-        .exec(); // Only for UNIX
+fn print_dry_run_command(
+    program_name: &str,
+    command_args: &[String],
+    format: DryRunFormat,
+) -> AvatarResult<()> {
+    let redacted_args = redact_env_args(command_args);
+
+    match format {
+        DryRunFormat::Shell => println!(
+            "{}",
+            std::iter::once(program_name)
+                .chain(redacted_args.iter().map(|arg| arg.as_str()))
+                .map(shell_quote)
+                .collect::<Vec<String>>()
+                .join(" ")
+        ),
+        DryRunFormat::Json => {
+            let dry_run_command = DryRunCommand {
+                program: program_name,
+                args: redacted_args,
+            };
+            match serde_json::to_string_pretty(&dry_run_command) {
+                Ok(json) => println!("{}", json),
+                Err(e) => {
+                    return Err(AvatarError::Internal(format!(
+                        "Unknown error while serializing the command:\n\n{}\n",
+                        e
+                    )))
+                }
+            }
+        }
+    }
+
+    Ok(())
 }
 
-fn transform_command_args(
-    skip_args: usize,
-    project_path: &PathBuf,
-) -> impl Iterator<Item = String> {
-    let project_path = project_path.clone();
+/// Hides the values of the `--env` arguments whose names look like secrets
+fn redact_env_args(command_args: &[String]) -> Vec<String> {
+    let mut redacted_args: Vec<String> = Vec::with_capacity(command_args.len());
+    let mut is_env_value = false;
+
+    for arg in command_args {
+        if is_env_value {
+            redacted_args.push(match arg.find('=') {
+                Some(i) if is_secret_env_name(&arg[..i]) => {
+                    format!("{}={}", &arg[..i], REDACTED_VALUE)
+                }
+                _ => arg.clone(),
+            });
+        } else {
+            redacted_args.push(arg.clone());
+        }
+        is_env_value = !is_env_value && arg == "--env";
+    }
+
+    redacted_args
+}
+
+fn is_secret_env_name(var_name: &str) -> bool {
+    // Avatar CLI's own variables are not secret, and they help when debugging
+    if var_name.starts_with("AVATAR_CLI_") {
+        return false;
+    }
+
+    let upper_var_name = var_name.to_uppercase();
+    SECRET_ENV_NAME_PARTS
+        .iter()
+        .any(|part| upper_var_name.contains(part))
+}
+
+/// Quotes an argument (only when necessary) so it can be pasted into a POSIX
+/// shell.
+pub fn shell_quote(arg: &str) -> String {
+    let is_safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c));
+
+    match is_safe {
+        true => arg.to_string(),
+        false => format!("'{}'", arg.replace('\'', "'\\''")),
+    }
+}
 
-    env::args().skip(skip_args).map(move |arg| {
+/// The preset binary arguments go first, then the user's arguments, with their
+/// absolute project paths translated into container paths.
+fn transform_command_args<'a>(
+    binary_args: &'a [String],
+    program_args: &'a [String],
+    project_path: &'a PathBuf,
+) -> impl Iterator<Item = String> + 'a {
+    binary_args.iter().chain(program_args).map(move |arg| {
+        let arg = arg.clone();
         let potential_path = PathBuf::from(&arg);
-        if potential_path.is_absolute() && is_inside_project_dir(&project_path, &potential_path) {
-            match potential_path.strip_prefix(&project_path) {
+        if potential_path.is_absolute() && is_inside_project_dir(project_path, &potential_path) {
+            match potential_path.strip_prefix(project_path) {
                 Ok(relative_path) => {
                     match PathBuf::from("/playground").join(relative_path).to_str() {
                         Some(stringified_path) => stringified_path.to_string(),
@@ -324,11 +637,7 @@ fn transform_command_args(
     })
 }
 
-fn get_user_integration_args(
-    uid: nix::unistd::Uid,
-    image_ref: &str,
-    project_path: &PathBuf,
-) -> Vec<String> {
+fn get_user_integration_args(uid: nix::unistd::Uid) -> Vec<String> {
     let mut dynamic_args: Vec<String> = vec![];
 
     if let Ok(v) = env::var("TERM") {
@@ -357,14 +666,13 @@ fn get_user_integration_args(
         push_home_config_args(&home_dir, ".gnupg", &mut dynamic_args);
     }
 
-    push_passwd_args(image_ref, project_path, &mut dynamic_args);
     push_git_args(&mut dynamic_args);
 
     dynamic_args
 }
 
 fn push_git_args(dynamic_args: &mut Vec<String>) {
-    if let Ok(output) = Command::new("git").args(&["config", "user.name"]).output() {
+    if let Ok(output) = Command::new("git").args(["config", "user.name"]).output() {
         if output.status.success() {
             if let Ok(git_user_name) = from_utf8(&output.stdout) {
                 let trimmed_name = git_user_name.trim();
@@ -377,7 +685,7 @@ fn push_git_args(dynamic_args: &mut Vec<String>) {
         }
     }
 
-    if let Ok(output) = Command::new("git").args(&["config", "user.email"]).output() {
+    if let Ok(output) = Command::new("git").args(["config", "user.email"]).output() {
         if output.status.success() {
             if let Ok(git_user_email) = from_utf8(&output.stdout) {
                 let trimmed_email = git_user_email.trim();
@@ -391,7 +699,11 @@ fn push_git_args(dynamic_args: &mut Vec<String>) {
     }
 }
 
-fn push_passwd_args(image_ref: &str, project_path: &PathBuf, dynamic_args: &mut Vec<String>) {
+fn push_passwd_args(
+    image_ref: &str,
+    project_path: &Path,
+    dynamic_args: &mut Vec<String>,
+) -> AvatarResult<()> {
     let passwd_path = project_path
         .join(CONFIG_DIR_NAME)
         .join(VOLATILE_DIR_NAME)
@@ -400,11 +712,10 @@ fn push_passwd_args(image_ref: &str, project_path: &PathBuf, dynamic_args: &mut
         .join("passwd");
     if passwd_path.exists() {
         if !passwd_path.is_file() {
-            eprintln!(
+            return Err(AvatarError::Usage(format!(
                 "The path {} must point to a regular file, found something else",
                 passwd_path.display()
-            );
-            exit(exitcode::USAGE)
+            )));
         }
 
         dynamic_args.push("--mount".to_string());
@@ -413,6 +724,73 @@ fn push_passwd_args(image_ref: &str, project_path: &PathBuf, dynamic_args: &mut
             passwd_path.display()
         ));
     }
+
+    Ok(())
+}
+
+fn push_resources_args(resources: &ResourcesConfig, static_args: &mut Vec<String>) {
+    if let Some(memory) = resources.get_memory() {
+        static_args.push("--memory".to_string());
+        static_args.push(memory.clone());
+    }
+    if let Some(cpus) = resources.get_cpus() {
+        static_args.push("--cpus".to_string());
+        static_args.push(cpus.to_string());
+    }
+    if let Some(pids) = resources.get_pids() {
+        static_args.push("--pids-limit".to_string());
+        static_args.push(pids.to_string());
+    }
+    if let Some(shm_size) = resources.get_shm_size() {
+        static_args.push("--shm-size".to_string());
+        static_args.push(shm_size.clone());
+    }
+    for (ulimit_name, ulimit_config) in resources.get_ulimits().iter().flatten() {
+        static_args.push("--ulimit".to_string());
+        static_args.push(ulimit_config.get_ulimit_arg(ulimit_name));
+    }
+}
+
+fn push_security_args(
+    security: &SecurityConfig,
+    project_path: &Path,
+    static_args: &mut Vec<String>,
+) -> AvatarResult<()> {
+    for capability in security.get_cap_drop().iter().flatten() {
+        static_args.push("--cap-drop".to_string());
+        static_args.push(capability.clone());
+    }
+    for capability in security.get_cap_add().iter().flatten() {
+        static_args.push("--cap-add".to_string());
+        static_args.push(capability.clone());
+    }
+
+    if security.get_read_only_rootfs() {
+        // Many tools expect a writable /tmp directory
+        static_args.extend(
+            ["--read-only", "--tmpfs", "/tmp"]
+                .iter()
+                .map(|arg| arg.to_string()),
+        );
+    }
+    if security.get_no_new_privileges() {
+        static_args.push("--security-opt".to_string());
+        static_args.push("no-new-privileges".to_string());
+    }
+
+    if let Some(seccomp_profile) = security.get_seccomp_profile() {
+        let seccomp_profile = project_path.join(seccomp_profile);
+        if !seccomp_profile.is_file() {
+            return Err(AvatarError::MissingFile(format!(
+                "The seccomp profile '{}' does not exist",
+                seccomp_profile.display()
+            )));
+        }
+        static_args.push("--security-opt".to_string());
+        static_args.push(format!("seccomp={}", seccomp_profile.display()));
+    }
+
+    Ok(())
 }
 
 #[cfg(target_os = "macos")]
@@ -444,7 +822,7 @@ fn push_socket_dir_args(socket_var_name: &str, dynamic_args: &mut Vec<String>) {
     }
 }
 
-fn push_home_config_args(home_dir: &PathBuf, config_name: &str, dynamic_args: &mut Vec<String>) {
+fn push_home_config_args(home_dir: &Path, config_name: &str, dynamic_args: &mut Vec<String>) {
     let config_dir = home_dir.join(config_name);
     if config_dir.exists() && config_dir.is_dir() {
         dynamic_args.push("--mount".to_string());
@@ -456,3 +834,29 @@ fn push_home_config_args(home_dir: &PathBuf, config_name: &str, dynamic_args: &m
         ));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_quote_leaves_safe_arguments_untouched() {
+        for arg in &[
+            "ls",
+            "--color=auto",
+            "./src/main.rs",
+            "user@host:22",
+            "a,b+c%d",
+        ] {
+            assert_eq!(&shell_quote(arg), arg);
+        }
+    }
+
+    #[test]
+    fn shell_quote_quotes_unsafe_arguments() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("hello world"), "'hello world'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+}
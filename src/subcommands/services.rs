@@ -0,0 +1,186 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use crate::{
+    container_engines::{get_container_engine, ContainerEngine},
+    directories::{get_required_project_path, AVATARFILE_NAME, CONFIG_DIR_NAME},
+    error::{AvatarError, AvatarResult},
+    ports::PortMapping,
+    project_config::{
+        get_config, get_image_ref, get_project_network_name, ProjectConfigLock, ServiceConfigLock,
+    },
+    subcommands::install::install_subcommand,
+};
+
+pub const SERVICE_ROLE_LABEL: &str = "service.container_role.avatar-cli";
+
+pub fn up_subcommand() -> AvatarResult<()> {
+    let (_, _, _, _, project_state) = install_subcommand(true)?;
+    let engine = get_container_engine(project_state.get_container_engine())?;
+
+    start_services(engine.as_ref(), &project_state)
+}
+
+pub fn down_subcommand() -> AvatarResult<()> {
+    // Only the project id is needed, so it works even with outdated lock files
+    let config_path = get_required_project_path()?
+        .join(CONFIG_DIR_NAME)
+        .join(AVATARFILE_NAME);
+    let (config, _) = get_config(&config_path)?;
+    let engine = get_container_engine(config.get_container_engine())?;
+    engine.check_client_availability()?;
+
+    let stopped_containers = stop_services(engine.as_ref(), config.get_project_internal_id())?;
+    if stopped_containers.is_empty() {
+        println!("There are no service containers to stop");
+    }
+    for container_name in stopped_containers {
+        println!("Stopped {}", container_name);
+    }
+
+    Ok(())
+}
+
+/// Starts the project services that are not already running
+pub fn start_services(
+    engine: &dyn ContainerEngine,
+    project_state: &ProjectConfigLock,
+) -> AvatarResult<()> {
+    let services = match project_state.get_services() {
+        Some(services) => services,
+        None => return Ok(()),
+    };
+
+    let project_internal_id = project_state.get_project_internal_id();
+    let project_filter = format!("{}.byid.projects.avatar-cli", project_internal_id);
+    let labels = [project_filter.as_str(), SERVICE_ROLE_LABEL];
+
+    let running_containers = engine.find_containers(&labels, false)?;
+    let existing_containers = engine.find_containers(&labels, true)?;
+
+    let mut services_to_start: Vec<(&String, &ServiceConfigLock, Vec<PortMapping>)> = vec![];
+    for (service_name, service_config) in services {
+        let container_name = get_service_container_name(project_internal_id, service_name);
+        if running_containers.contains(&container_name) {
+            println!("The service {} is already running", service_name);
+            continue;
+        }
+
+        let published_ports = get_published_ports(service_name, service_config)?;
+        services_to_start.push((service_name, service_config, published_ports));
+    }
+
+    // Checked before starting anything, so we don't leave half of the services
+    // running
+    for (service_name, _, published_ports) in &services_to_start {
+        check_published_ports_availability(service_name, published_ports)?;
+    }
+
+    for (service_name, service_config, published_ports) in services_to_start {
+        let container_name = get_service_container_name(project_internal_id, service_name);
+
+        // Stopped containers are replaced, as their settings could be outdated
+        if existing_containers.contains(&container_name) {
+            engine.remove_containers(std::slice::from_ref(&container_name))?;
+        }
+
+        // The service name is resolvable from the tool containers
+        let mut args: Vec<String> = vec![
+            "--network".to_string(),
+            get_project_network_name(project_internal_id),
+            "--network-alias".to_string(),
+            service_name.clone(),
+        ];
+        for (var_name, var_value) in service_config.get_env().iter().flatten() {
+            args.push("--env".to_string());
+            args.push(format!("{}={}", var_name, var_value));
+        }
+        for volume_config in service_config.get_volumes().iter().flatten() {
+            args.push("--volume".to_string());
+            args.push(format!(
+                "{}:{}",
+                volume_config.get_name(),
+                volume_config.get_container_path().display()
+            ));
+        }
+        for port_mapping in published_ports {
+            args.push("--publish".to_string());
+            args.push(port_mapping.get_publish_arg());
+        }
+
+        let image_ref = get_image_ref(
+            service_config.get_oci_image_name(),
+            service_config.get_oci_image_hash(),
+        );
+        engine.run_detached_container(
+            &container_name,
+            &["avatar_cli", &project_filter, SERVICE_ROLE_LABEL],
+            &args,
+            &image_ref,
+        )?;
+        println!("Started the service {} ({})", service_name, image_ref);
+    }
+
+    Ok(())
+}
+
+/// Stops and removes all the service containers of the project, returning
+/// their names
+pub fn stop_services(
+    engine: &dyn ContainerEngine,
+    project_internal_id: &str,
+) -> AvatarResult<Vec<String>> {
+    let project_filter = format!("{}.byid.projects.avatar-cli", project_internal_id);
+    let service_containers =
+        engine.find_containers(&[&project_filter, SERVICE_ROLE_LABEL], true)?;
+
+    engine.remove_containers(&service_containers)?;
+    Ok(service_containers)
+}
+
+/// Fails early when the host ports are taken, instead of letting the container
+/// engine fail with a less helpful message.
+fn check_published_ports_availability(
+    service_name: &str,
+    published_ports: &[PortMapping],
+) -> AvatarResult<()> {
+    for port_mapping in published_ports {
+        if let Some(port) = port_mapping.find_host_port_in_use() {
+            return Err(AvatarError::PortInUse(format!(
+                "The host port {} (needed to publish '{}' for the service {}) is already in use",
+                port,
+                port_mapping.get_publish_arg(),
+                service_name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn get_published_ports(
+    service_name: &str,
+    service_config: &ServiceConfigLock,
+) -> AvatarResult<Vec<PortMapping>> {
+    service_config
+        .get_ports()
+        .iter()
+        .flatten()
+        .map(|(host_spec, container_spec)| {
+            // The mappings have already been checked during the `install` step
+            PortMapping::parse(host_spec, container_spec).map_err(|e| {
+                AvatarError::InvalidConfig(format!(
+                    "Invalid port mapping '{}: {}' for the service {}: {}",
+                    host_spec, container_spec, service_name, e
+                ))
+            })
+        })
+        .collect()
+}
+
+fn get_service_container_name(project_internal_id: &str, service_name: &str) -> String {
+    format!("{}_{}_service", project_internal_id, service_name)
+}
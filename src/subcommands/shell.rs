@@ -5,11 +5,11 @@
  */
 
 use std::env;
-use std::os::unix::process::CommandExt; // Brings trait that allows us to use exec
+use std::os::unix::process::{CommandExt, ExitStatusExt}; // CommandExt allows us to use exec
 use std::{
     collections::{BTreeMap, BTreeSet},
-    path::PathBuf,
-    process::{exit, Command},
+    path::{Path, PathBuf},
+    process::Command,
 };
 
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
@@ -18,23 +18,27 @@ use crate::avatar_env::{
     CONFIG_LOCK_PATH, CONFIG_PATH, PROJECT_INTERNAL_ID, PROJECT_PATH, SESSION_TOKEN, STATE_PATH,
 };
 use crate::{
+    container_engines::get_container_engine,
     directories::{CONFIG_DIR_NAME, VOLATILE_DIR_NAME},
+    error::{AvatarError, AvatarResult},
     project_config::ProjectConfigLock,
-    subcommands::install::install_subcommand,
+    subcommands::{
+        install::install_subcommand,
+        services::{start_services, stop_services},
+    },
 };
 
 fn get_path_and_extra_env(
     project_state: &ProjectConfigLock,
-    project_path: &PathBuf,
-) -> (String, BTreeMap<String, String>) {
+    project_path: &Path,
+) -> AvatarResult<(String, BTreeMap<String, String>)> {
     let path_var = match env::var("PATH") {
         Ok(p) => p,
         Err(e) => {
-            eprintln!(
+            return Err(AvatarError::Os(format!(
                 "Unable to load PATH environment variable\n\n{}\n",
-                e.to_string()
-            );
-            exit(exitcode::OSERR)
+                e
+            )))
         }
     };
 
@@ -60,9 +64,7 @@ fn get_path_and_extra_env(
         })
         .collect::<Vec<PathBuf>>()
         .iter()
-        .map(|p| p.to_str())
-        .filter(|p| p.is_some())
-        .map(|p| p.unwrap())
+        .filter_map(|p| p.to_str())
         .collect::<Vec<&str>>()
         .join(":");
 
@@ -71,17 +73,17 @@ fn get_path_and_extra_env(
         .join(VOLATILE_DIR_NAME)
         .join("bin");
 
-    (
+    Ok((
         format!("{}:{}:{}", avatar_bin_path.display(), extra_paths, path_var),
         shell_env,
-    )
+    ))
 }
 
-pub(crate) fn export_env_subcommand() {
+pub fn export_env_subcommand() -> AvatarResult<()> {
     let (project_path, config_path, config_lock_path, project_state_path, project_state) =
-        install_subcommand(false);
+        install_subcommand(false)?;
 
-    let (path_var, shell_env) = get_path_and_extra_env(&project_state, &project_path);
+    let (path_var, shell_env) = get_path_and_extra_env(&project_state, &project_path)?;
     let session_token: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
 
     for (shell_var_name, shell_var_value) in shell_env {
@@ -102,13 +104,15 @@ pub(crate) fn export_env_subcommand() {
     );
     println!("export {}=\"{}\"", SESSION_TOKEN, session_token);
     println!("export {}=\"{}\"", STATE_PATH, project_state_path.display());
+
+    Ok(())
 }
 
-pub(crate) fn shell_subcommand() {
+pub fn shell_subcommand() -> AvatarResult<()> {
     let (project_path, config_path, config_lock_path, project_state_path, project_state) =
-        install_subcommand(true);
+        install_subcommand(true)?;
 
-    let (path_var, shell_env) = get_path_and_extra_env(&project_state, &project_path);
+    let (path_var, shell_env) = get_path_and_extra_env(&project_state, &project_path)?;
     let session_token: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
 
     let shell_path = match env::var("SHELL") {
@@ -116,7 +120,8 @@ pub(crate) fn shell_subcommand() {
         Err(_) => "/bin/sh".to_string(),
     };
 
-    Command::new(shell_path)
+    let mut shell_command = Command::new(&shell_path);
+    shell_command
         .envs(shell_env)
         .env("PATH", path_var)
         .env(CONFIG_PATH, config_path)
@@ -124,6 +129,41 @@ pub(crate) fn shell_subcommand() {
         .env(PROJECT_PATH, project_path)
         .env(PROJECT_INTERNAL_ID, project_state.get_project_internal_id())
         .env(SESSION_TOKEN, session_token)
-        .env(STATE_PATH, project_state_path)
-        .exec();
+        .env(STATE_PATH, project_state_path);
+
+    let should_start_services = project_state
+        .get_shell_config()
+        .as_ref()
+        .is_some_and(|shell_config| shell_config.get_start_services());
+    if !should_start_services {
+        let exec_error = shell_command.exec();
+        return Err(get_shell_error(&shell_path, exec_error));
+    }
+
+    // The services have to be stopped once the shell finishes, so we can't
+    // replace the current process.
+    let engine = get_container_engine(project_state.get_container_engine())?;
+    start_services(engine.as_ref(), &project_state)?;
+
+    let shell_result = shell_command.status();
+    stop_services(engine.as_ref(), project_state.get_project_internal_id())?;
+
+    match shell_result {
+        Ok(status) if status.success() => Ok(()),
+        // The shell already reported its own errors, so no message is needed
+        Ok(status) => Err(AvatarError::TaskFailed(
+            String::new(),
+            status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+        )),
+        Err(e) => Err(get_shell_error(&shell_path, e)),
+    }
+}
+
+fn get_shell_error(shell_path: &str, error: std::io::Error) -> AvatarError {
+    AvatarError::Os(format!(
+        "Unable to start shell {}\n\n{}\n",
+        shell_path, error
+    ))
 }
@@ -0,0 +1,142 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::{env, os::unix::process::ExitStatusExt, process::Command};
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+
+use crate::{
+    avatar_env::SESSION_TOKEN,
+    directories::{get_required_project_path, AVATARFILE_NAME, CONFIG_DIR_NAME},
+    error::{AvatarError, AvatarResult},
+    project_config::{get_config, get_task_execution_order, TaskStepConfig},
+    subcommands::{
+        run::{
+            check_published_ports_availability, get_docker_command_args, get_run_plan, shell_quote,
+        },
+        update::print_table,
+    },
+};
+
+/// Runs the steps of a task (after the ones of its dependencies), stopping at
+/// the first failing step.
+pub fn task_subcommand(task_name: &str) -> AvatarResult<()> {
+    let project_path = get_required_project_path()?;
+    let current_dir = match env::current_dir() {
+        Ok(p) => p,
+        Err(_) => {
+            return Err(AvatarError::MissingFile(
+                "Unable to get current working directory".to_string(),
+            ))
+        }
+    };
+
+    let config_path = project_path.join(CONFIG_DIR_NAME).join(AVATARFILE_NAME);
+    let (config, _) = get_config(&config_path)?;
+
+    let tasks = match config.get_tasks() {
+        Some(tasks) if tasks.contains_key(task_name) => tasks,
+        _ => {
+            return Err(AvatarError::Usage(format!(
+                "The task '{}' is not defined in '{}'",
+                task_name,
+                config_path.display()
+            )))
+        }
+    };
+
+    let session_token: String = match env::var(SESSION_TOKEN) {
+        Ok(st) => st,
+        Err(_) => thread_rng().sample_iter(&Alphanumeric).take(16).collect(),
+    };
+
+    let mut rows: Vec<Vec<String>> = vec![];
+    let mut failure: Option<AvatarError> = None;
+
+    for current_task_name in get_task_execution_order(tasks, task_name)? {
+        let task_config = &tasks[current_task_name];
+        let steps = task_config.get_steps().as_deref().unwrap_or_default();
+
+        for (step_index, step) in steps.iter().enumerate() {
+            let command_line = get_step_command_line(step);
+            let mut row = vec![
+                current_task_name.to_string(),
+                (step_index + 1).to_string(),
+                command_line.clone(),
+            ];
+
+            if failure.is_some() {
+                row.push("skipped".to_string());
+                rows.push(row);
+                continue;
+            }
+
+            println!("==> [{}] {}", current_task_name, command_line);
+
+            let mut step_env = task_config.get_env().clone().unwrap_or_default();
+            step_env.extend(step.get_env().clone().unwrap_or_default());
+
+            let run_plan = get_run_plan(&project_path, step.get_bin())?.with_extra_env(&step_env);
+            check_published_ports_availability(&run_plan)?;
+            let command_args = get_docker_command_args(
+                &run_plan,
+                &current_dir,
+                &project_path,
+                &session_token,
+                step.get_args().as_deref().unwrap_or_default(),
+            )?;
+
+            let status = match Command::new(run_plan.get_program_name())
+                .args(command_args)
+                .status()
+            {
+                Ok(status) => status,
+                Err(e) => {
+                    return Err(AvatarError::Os(format!(
+                        "Unable to execute {} client\n\n{}\n",
+                        run_plan.get_program_name(),
+                        e
+                    )))
+                }
+            };
+
+            // Processes killed by a signal follow the usual shell convention
+            let exit_code = status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or(0));
+            row.push(exit_code.to_string());
+            rows.push(row);
+
+            if !status.success() {
+                failure = Some(AvatarError::TaskFailed(
+                    format!(
+                        "The step {} of the task '{}' failed with exit code {}",
+                        step_index + 1,
+                        current_task_name,
+                        exit_code
+                    ),
+                    exit_code,
+                ));
+            }
+        }
+    }
+
+    println!();
+    print_table(&["TASK", "STEP", "COMMAND", "EXIT CODE"], &rows);
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn get_step_command_line(step: &TaskStepConfig) -> String {
+    std::iter::once(step.get_bin())
+        .chain(step.get_args().iter().flatten())
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
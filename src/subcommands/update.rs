@@ -0,0 +1,434 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::{
+    container_engines::{get_container_engine, ContainerEngine},
+    directories::{
+        get_required_project_path, AVATARFILE_LOCK_NAME, AVATARFILE_NAME, CONFIG_DIR_NAME,
+    },
+    error::{AvatarError, AvatarResult},
+    policy::check_policy,
+    project_config::{
+        get_config, get_config_lock, is_built_image_name, split_image_ref, ProjectConfigLock,
+    },
+    subcommands::install::{
+        check_not_in_session, get_image_tag_hash, install_subcommand, regenerate_config_lock,
+        ImageTagHashes,
+    },
+};
+
+const SHORT_HASH_LENGTH: usize = 12;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutdatedReportEntry {
+    image: String,
+    tag: String,
+    locked_hash: String,
+    current_hash: String,
+    outdated: bool,
+}
+
+/// Compares the locked image digests with the ones their tags resolve to now,
+/// without modifying the lock file. With `exit_code`, finding outdated tags is
+/// reported as an error, so CI pipelines don't have to parse the report.
+pub fn outdated_subcommand(
+    image_ref: Option<&str>,
+    json_format: bool,
+    exit_code: bool,
+) -> AvatarResult<()> {
+    let config_lock_path = get_config_lock_path()?;
+    let (config_lock, _) = get_config_lock(&config_lock_path)?;
+    let selected_tags = get_pulled_tags(get_selected_tags(&config_lock, image_ref)?);
+
+    let engine = get_container_engine(config_lock.get_container_engine())?;
+    engine.check_client_availability()?;
+
+    let report = get_outdated_report(engine.as_ref(), selected_tags)?;
+
+    if json_format {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                return Err(AvatarError::Internal(format!(
+                    "Unknown error while serializing the report:\n\n{}\n",
+                    e
+                )))
+            }
+        }
+    } else {
+        let rows: Vec<Vec<String>> = report
+            .iter()
+            .map(|entry| {
+                vec![
+                    entry.image.clone(),
+                    entry.tag.clone(),
+                    get_short_hash(&entry.locked_hash),
+                    get_short_hash(&entry.current_hash),
+                    match entry.outdated {
+                        true => "outdated".to_string(),
+                        false => "up to date".to_string(),
+                    },
+                ]
+            })
+            .collect();
+
+        print_table(&["IMAGE", "TAG", "LOCKED", "CURRENT", "STATUS"], &rows);
+    }
+
+    match (
+        exit_code,
+        report.iter().filter(|entry| entry.outdated).count(),
+    ) {
+        (false, _) | (true, 0) => Ok(()),
+        (true, 1) => Err(AvatarError::OutdatedLock(
+            "1 locked image tag is outdated".to_string(),
+        )),
+        (true, n) => Err(AvatarError::OutdatedLock(format!(
+            "{} locked image tags are outdated",
+            n
+        ))),
+    }
+}
+
+pub fn update_subcommand(image_ref: Option<&str>) -> AvatarResult<()> {
+    check_not_in_session()?;
+
+    let project_path = get_required_project_path()?;
+    let config_lock_path = get_config_lock_path()?;
+    let (config, config_hash) = get_config(&config_lock_path.with_file_name(AVATARFILE_NAME))?;
+    check_policy(&config, &project_path)?;
+
+    let (config_lock, _) = get_config_lock(&config_lock_path)?;
+    // Otherwise, the pending Avatarfile changes would be folded into the lock
+    if config_hash.as_ref() != &config_lock.get_project_config_hash()[..] {
+        return Err(AvatarError::HashMismatch(
+            "The Avatarfile changed after the lock file was generated, run 'avatar install' \
+             before updating the locked images"
+                .to_string(),
+        ));
+    }
+    let selected_tags = get_pulled_tags(get_selected_tags(&config_lock, image_ref)?);
+
+    let engine = get_container_engine(config_lock.get_container_engine())?;
+    engine.check_client_availability()?;
+
+    let mut rows: Vec<Vec<String>> = vec![];
+    let mut updated_hashes = ImageTagHashes::new();
+    for (image_name, image_tag, old_hash) in selected_tags {
+        let new_hash = get_image_tag_hash(engine.as_ref(), &image_name, &image_tag, true, true)?;
+
+        rows.push(vec![
+            image_name.clone(),
+            image_tag.clone(),
+            get_short_hash(&old_hash),
+            get_short_hash(&new_hash),
+            match old_hash == new_hash {
+                true => "unchanged".to_string(),
+                false => "updated".to_string(),
+            },
+        ]);
+
+        if old_hash != new_hash {
+            updated_hashes.insert((image_name, image_tag), new_hash);
+        }
+    }
+
+    if !updated_hashes.is_empty() {
+        regenerate_config_lock(
+            engine.as_ref(),
+            &project_path,
+            &config_lock_path,
+            &config,
+            &config_hash,
+            &updated_hashes,
+        )?;
+    }
+
+    // The Avatarfile is in sync with the lock (checked above), so the install
+    // step keeps the lock, but it refreshes the project state, wrappers & helper
+    // files.
+    install_subcommand(true)?;
+
+    print_table(&["IMAGE", "TAG", "BEFORE", "AFTER", "STATUS"], &rows);
+
+    Ok(())
+}
+
+pub fn get_config_lock_path() -> AvatarResult<PathBuf> {
+    let config_lock_path = get_required_project_path()?
+        .join(CONFIG_DIR_NAME)
+        .join(AVATARFILE_LOCK_NAME);
+
+    if !config_lock_path.is_file() {
+        return Err(AvatarError::MissingFile(format!(
+            "The lock file {} is not available, run 'avatar install' first",
+            config_lock_path.display()
+        )));
+    }
+
+    Ok(config_lock_path)
+}
+
+/// Built images have no registry tags to compare with, 'avatar install'
+/// rebuilds them when their build context changes
+fn get_pulled_tags(selected_tags: Vec<(String, String, String)>) -> Vec<(String, String, String)> {
+    selected_tags
+        .into_iter()
+        .filter(|(image_name, _, _)| !is_built_image_name(image_name))
+        .collect()
+}
+
+/// Returns the (image name, image tag, locked hash) triplets matching the
+/// `image[:tag]` selector (or all of them, when there's no selector)
+pub fn get_selected_tags(
+    config_lock: &ProjectConfigLock,
+    image_ref: Option<&str>,
+) -> AvatarResult<Vec<(String, String, String)>> {
+    let (selected_name, selected_tag) = match image_ref {
+        Some(image_ref) => {
+            let (name, tag) = split_image_ref(image_ref);
+            (Some(name), tag)
+        }
+        None => (None, None),
+    };
+
+    let selected_tags: Vec<(String, String, String)> = config_lock
+        .get_images()
+        .iter()
+        .filter(|(image_name, _)| selected_name.is_none_or(|n| n == image_name.as_str()))
+        .flat_map(|(image_name, tags)| {
+            tags.iter()
+                .filter(|(image_tag, _)| selected_tag.is_none_or(|t| t == image_tag.as_str()))
+                .map(move |(image_tag, tag_config)| {
+                    (
+                        image_name.clone(),
+                        image_tag.clone(),
+                        tag_config.get_hash().clone(),
+                    )
+                })
+        })
+        .collect();
+
+    if let (Some(image_ref), true) = (image_ref, selected_tags.is_empty()) {
+        return Err(AvatarError::Usage(format!(
+            "The image '{}' is not declared in the lock file",
+            image_ref
+        )));
+    }
+
+    Ok(selected_tags)
+}
+
+fn get_outdated_report(
+    engine: &dyn ContainerEngine,
+    selected_tags: Vec<(String, String, String)>,
+) -> AvatarResult<Vec<OutdatedReportEntry>> {
+    selected_tags
+        .into_iter()
+        .map(|(image_name, image_tag, locked_hash)| {
+            let current_hash = get_image_tag_hash(engine, &image_name, &image_tag, true, false)?;
+
+            Ok(OutdatedReportEntry {
+                outdated: locked_hash != current_hash,
+                image: image_name,
+                tag: image_tag,
+                locked_hash,
+                current_hash,
+            })
+        })
+        .collect()
+}
+
+pub fn get_short_hash(hash: &str) -> String {
+    hash.chars().take(SHORT_HASH_LENGTH).collect()
+}
+
+pub fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let widths: Vec<usize> = headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| {
+            rows.iter()
+                .map(|row| row[i].len())
+                .chain(std::iter::once(header.len()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let format_row = |cells: Vec<&str>| -> String {
+        cells
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<String>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    println!("{}", format_row(headers.to_vec()));
+    for row in rows {
+        println!("{}", format_row(row.iter().map(|c| c.as_str()).collect()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    use super::*;
+    use crate::project_config::{get_built_image_name, OCIImageTagConfigLock};
+
+    /// Stands in for a registry: pulling a tag makes its current digest
+    /// available locally.
+    struct FakeRegistry {
+        remote_hashes: BTreeMap<String, String>,
+        local_hashes: Mutex<BTreeMap<String, String>>,
+    }
+
+    impl FakeRegistry {
+        fn new(remote_hashes: &[(&str, &str)]) -> FakeRegistry {
+            FakeRegistry {
+                remote_hashes: remote_hashes
+                    .iter()
+                    .map(|(image_ref, hash)| (image_ref.to_string(), hash.to_string()))
+                    .collect(),
+                local_hashes: Mutex::new(BTreeMap::new()),
+            }
+        }
+    }
+
+    impl ContainerEngine for FakeRegistry {
+        fn get_program_name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn pull_image(&self, image_ref: &str, _show_output: bool) -> AvatarResult<()> {
+            match self.remote_hashes.get(image_ref) {
+                Some(hash) => {
+                    let mut local_hashes = self.local_hashes.lock().unwrap();
+                    local_hashes.insert(image_ref.to_string(), hash.clone());
+                    Ok(())
+                }
+                None => Err(AvatarError::ContainerEngineUnavailable(format!(
+                    "manifest for {} not found",
+                    image_ref
+                ))),
+            }
+        }
+
+        fn get_image_repo_digests(&self, image_ref: &str) -> AvatarResult<Option<Vec<String>>> {
+            let image_name = image_ref.rsplit_once(':').unwrap().0;
+            Ok(self
+                .local_hashes
+                .lock()
+                .unwrap()
+                .get(image_ref)
+                .map(|hash| vec![format!("{}@sha256:{}", image_name, hash)]))
+        }
+    }
+
+    fn new_config_lock(locked_tags: &[(&str, &str, &str)]) -> ProjectConfigLock {
+        let mut images: BTreeMap<String, BTreeMap<String, OCIImageTagConfigLock>> = BTreeMap::new();
+        for (image_name, image_tag, hash) in locked_tags {
+            images.entry(image_name.to_string()).or_default().insert(
+                image_tag.to_string(),
+                OCIImageTagConfigLock::new(hash.to_string(), None, None),
+            );
+        }
+
+        ProjectConfigLock::new(
+            vec![],
+            "abcdefghijklmnop".to_string(),
+            None,
+            None,
+            images,
+            BTreeMap::new(),
+            None,
+        )
+    }
+
+    #[test]
+    fn get_selected_tags_filters_by_image_and_tag() {
+        let config_lock = new_config_lock(&[
+            ("node", "14", "aaa"),
+            ("node", "16", "bbb"),
+            ("postgres", "13", "ccc"),
+        ]);
+
+        assert_eq!(get_selected_tags(&config_lock, None).unwrap().len(), 3);
+        assert_eq!(
+            get_selected_tags(&config_lock, Some("node")).unwrap(),
+            vec![
+                ("node".to_string(), "14".to_string(), "aaa".to_string()),
+                ("node".to_string(), "16".to_string(), "bbb".to_string()),
+            ]
+        );
+        assert_eq!(
+            get_selected_tags(&config_lock, Some("node:16")).unwrap(),
+            vec![("node".to_string(), "16".to_string(), "bbb".to_string())]
+        );
+        assert!(matches!(
+            get_selected_tags(&config_lock, Some("node:18")),
+            Err(AvatarError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn get_pulled_tags_skips_built_images() {
+        let built_image_name = get_built_image_name("abcdefghijklmnop", "tools");
+        let config_lock =
+            new_config_lock(&[("node", "14", "aaa"), (&built_image_name, "1", "bbb")]);
+
+        assert_eq!(
+            get_pulled_tags(get_selected_tags(&config_lock, None).unwrap()),
+            vec![("node".to_string(), "14".to_string(), "aaa".to_string())]
+        );
+    }
+
+    #[test]
+    fn get_outdated_report_compares_locked_and_current_hashes() {
+        let registry = FakeRegistry::new(&[("node:14", "aaa"), ("node:16", "ddd")]);
+        let config_lock = new_config_lock(&[("node", "14", "aaa"), ("node", "16", "bbb")]);
+
+        let report =
+            get_outdated_report(&registry, get_selected_tags(&config_lock, None).unwrap()).unwrap();
+        let outdated: Vec<(&str, &str, bool)> = report
+            .iter()
+            .map(|entry| {
+                (
+                    entry.tag.as_str(),
+                    entry.current_hash.as_str(),
+                    entry.outdated,
+                )
+            })
+            .collect();
+        assert_eq!(outdated, vec![("14", "aaa", false), ("16", "ddd", true)]);
+    }
+
+    #[test]
+    fn get_outdated_report_fails_when_a_tag_disappears() {
+        let registry = FakeRegistry::new(&[]);
+        let config_lock = new_config_lock(&[("node", "14", "aaa")]);
+
+        assert!(matches!(
+            get_outdated_report(&registry, get_selected_tags(&config_lock, None).unwrap()),
+            Err(AvatarError::ContainerEngineUnavailable(_))
+        ));
+    }
+
+    #[test]
+    fn get_short_hash_truncates_hashes() {
+        assert_eq!(get_short_hash("0123456789abcdef"), "0123456789ab");
+        assert_eq!(get_short_hash("abc"), "abc");
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+use serde::Serialize;
+
+use crate::{
+    container_engines::{get_container_engine, ContainerEngine},
+    error::{AvatarError, AvatarResult},
+    image_archive::{ImageArchive, ImageVerification},
+    project_config::{get_config_lock, get_image_ref},
+    subcommands::update::{get_config_lock_path, get_selected_tags, get_short_hash, print_table},
+};
+
+// Constants:
+// -----------------------------------------------------------------------------
+pub const LAYERS_ONLY_STATUS: &str = "layers only";
+pub const MISSING_STATUS: &str = "missing";
+pub const VERIFIED_STATUS: &str = "verified";
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyReportEntry {
+    image: String,
+    tag: String,
+    locked_hash: String,
+    status: String,
+    verified: bool,
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+/// Recomputes the digests of the locally stored images from their archives,
+/// and checks them against the lock file.
+pub fn verify_subcommand(
+    image_ref: Option<&str>,
+    json_format: bool,
+    strict: bool,
+) -> AvatarResult<()> {
+    let config_lock_path = get_config_lock_path()?;
+    let (config_lock, _) = get_config_lock(&config_lock_path)?;
+    let selected_tags = get_selected_tags(&config_lock, image_ref)?;
+
+    let engine = get_container_engine(config_lock.get_container_engine())?;
+    engine.check_client_availability()?;
+
+    let mut layers_only_images: Vec<String> = vec![];
+    let report = selected_tags
+        .into_iter()
+        .map(|(image_name, image_tag, locked_hash)| {
+            let verification = verify_local_image(engine.as_ref(), &image_name, &locked_hash)?;
+            if let Ok(ImageVerification::Layers { .. }) = verification {
+                layers_only_images.push(format!("{}:{}", image_name, image_tag));
+            }
+
+            Ok(VerifyReportEntry {
+                status: get_verification_status(&verification),
+                verified: matches!(verification, Ok(ImageVerification::Manifest { .. })),
+                image: image_name,
+                tag: image_tag,
+                locked_hash,
+            })
+        })
+        .collect::<AvatarResult<Vec<VerifyReportEntry>>>()?;
+
+    if json_format {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                return Err(AvatarError::Internal(format!(
+                    "Unknown error while serializing the report:\n\n{}\n",
+                    e
+                )))
+            }
+        }
+    } else {
+        let rows: Vec<Vec<String>> = report
+            .iter()
+            .map(|entry| {
+                vec![
+                    entry.image.clone(),
+                    entry.tag.clone(),
+                    get_short_hash(&entry.locked_hash),
+                    entry.status.clone(),
+                ]
+            })
+            .collect();
+        print_table(&["IMAGE", "TAG", "DIGEST", "STATUS"], &rows);
+    }
+
+    match report
+        .iter()
+        .filter(|entry| entry.status != LAYERS_ONLY_STATUS && !entry.verified)
+        .count()
+    {
+        0 => check_layers_only_images(&layers_only_images, strict),
+        1 => Err(AvatarError::HashMismatch(
+            "1 image does not match the lock file".to_string(),
+        )),
+        n => Err(AvatarError::HashMismatch(format!(
+            "{} images do not match the lock file",
+            n
+        ))),
+    }
+}
+
+/// Layers-only verifications don't tie the images to their locked digests, so
+/// they are not a pass: they are reported as a warning, or as an error in
+/// strict mode.
+pub fn check_layers_only_images(layers_only_images: &[String], strict: bool) -> AvatarResult<()> {
+    if layers_only_images.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!(
+        "Only the layers of {} could be verified, the container engine does not keep the registry manifests their locked digests refer to",
+        layers_only_images.join(", ")
+    );
+    match strict {
+        true => Err(AvatarError::HashMismatch(message)),
+        false => {
+            eprintln!("WARNING: {}", message);
+            Ok(())
+        }
+    }
+}
+
+pub fn get_verification_status(verification: &Result<ImageVerification, String>) -> String {
+    match verification {
+        Ok(ImageVerification::Manifest { .. }) => VERIFIED_STATUS.to_string(),
+        Ok(ImageVerification::Layers { .. }) => LAYERS_ONLY_STATUS.to_string(),
+        Err(reason) => reason.clone(),
+    }
+}
+
+/// The outer error is for engine failures, the inner one describes why the
+/// image does not match its locked digest.
+pub fn verify_local_image(
+    engine: &dyn ContainerEngine,
+    image_name: &str,
+    locked_hash: &str,
+) -> AvatarResult<Result<ImageVerification, String>> {
+    let image_ref = get_image_ref(image_name, locked_hash);
+    if !engine.has_image(&image_ref)? {
+        return Ok(Err(MISSING_STATUS.to_string()));
+    }
+
+    let mut archive: Option<Result<ImageArchive, String>> = None;
+    engine.save_image(&image_ref, &mut |reader| {
+        archive = Some(ImageArchive::read(reader).map_err(|e| e.to_string()));
+        Ok(())
+    })?;
+
+    Ok(match archive {
+        Some(Ok(archive)) => archive
+            .verify(locked_hash)
+            .map_err(|reason| format!("corrupted: {}", reason)),
+        Some(Err(e)) => Err(format!("unreadable archive: {}", e)),
+        None => Err("unreadable archive".to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_layers_only_images_only_fails_in_strict_mode() {
+        let layers_only_images = vec!["node:14".to_string()];
+
+        assert!(check_layers_only_images(&layers_only_images, false).is_ok());
+        assert!(matches!(
+            check_layers_only_images(&layers_only_images, true),
+            Err(AvatarError::HashMismatch(_))
+        ));
+        assert!(check_layers_only_images(&[], true).is_ok());
+    }
+
+    #[test]
+    fn get_verification_status_does_not_pass_layers_only_images() {
+        assert_eq!(
+            get_verification_status(&Ok(ImageVerification::Manifest { layers: 2 })),
+            VERIFIED_STATUS
+        );
+        assert_eq!(
+            get_verification_status(&Ok(ImageVerification::Layers { layers: 2 })),
+            LAYERS_ONLY_STATUS
+        );
+        assert_eq!(
+            get_verification_status(&Err("missing".to_string())),
+            "missing"
+        );
+    }
+}
@@ -0,0 +1,383 @@
+/*
+ *  Avatar CLI: Magic wrapper to run containerized CLI tools
+ *  Copyright (C) 2019-2020  Andres Correa Casablanca
+ *  License: GPL 3.0 (See the LICENSE file in the repository root directory)
+ */
+
+//! Minimal streaming support for the tar archives produced & consumed by the
+//! container engines (`docker save` & `docker load`).
+
+use std::{
+    io::{self, Read, Write},
+    str::from_utf8,
+};
+
+// Constants:
+// -----------------------------------------------------------------------------
+const BLOCK_SIZE: usize = 512;
+/// Extended headers are read in memory, this protects us from malformed ones
+const MAX_EXTENDED_HEADER_SIZE: u64 = 1024 * 1024;
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+// Structs, Enums & their Impl blocks:
+// -----------------------------------------------------------------------------
+
+pub enum TarEntryKind {
+    File,
+    Symlink(String), // link target, relative to the entry's directory
+    Other,
+}
+
+pub struct TarEntry {
+    path: String,
+    kind: TarEntryKind,
+    size: u64,
+}
+
+impl TarEntry {
+    pub fn get_path(&self) -> &String {
+        &self.path
+    }
+
+    pub fn get_kind(&self) -> &TarEntryKind {
+        &self.kind
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+}
+
+#[derive(Default)]
+struct TarEntryOverrides {
+    path: Option<String>,
+    link_path: Option<String>,
+    size: Option<u64>,
+}
+
+/// Writes ustar archives, entries' sizes must be known beforehand
+pub struct TarWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(writer: W) -> TarWriter<W> {
+        TarWriter { writer }
+    }
+
+    pub fn append_file(&mut self, path: &str, size: u64, reader: &mut dyn Read) -> io::Result<()> {
+        self.writer.write_all(&get_file_header(path, size)?)?;
+
+        let copied_size = io::copy(&mut reader.take(size), &mut self.writer)?;
+        if copied_size != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("'{}' is shorter than expected", path),
+            ));
+        }
+
+        self.writer.write_all(&vec![0u8; get_padding_size(size)])
+    }
+
+    /// Writes the end-of-archive marker, and returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(&[0u8; 2 * BLOCK_SIZE])?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Functions:
+// -----------------------------------------------------------------------------
+
+/// Calls `on_entry` for every entry of the archive, with a reader limited to
+/// the entry's content (that does not have to be fully consumed). GNU long
+/// names and PAX extended headers are resolved before reaching `on_entry`.
+pub fn read_tar_entries(
+    reader: &mut dyn Read,
+    on_entry: &mut dyn FnMut(&TarEntry, &mut dyn Read) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut overrides = TarEntryOverrides::default();
+    let mut header = [0u8; BLOCK_SIZE];
+
+    loop {
+        if !read_block(reader, &mut header)? || header.iter().all(|b| *b == 0) {
+            return Ok(());
+        }
+
+        let size = match overrides.size {
+            Some(size) => size,
+            None => parse_size(&header[124..136])?,
+        };
+
+        match header[156] {
+            // GNU long names
+            b'L' => {
+                overrides.path = Some(get_c_string(&read_extended_header(reader, size)?));
+                continue;
+            }
+            // PAX extended headers
+            b'x' => {
+                parse_pax_records(&read_extended_header(reader, size)?, &mut overrides);
+                continue;
+            }
+            _ => {}
+        }
+
+        let entry = TarEntry {
+            path: match overrides.path.take() {
+                Some(path) => path,
+                None => get_entry_path(&header),
+            },
+            kind: match header[156] {
+                b'0' | 0 => TarEntryKind::File,
+                b'2' => TarEntryKind::Symlink(match overrides.link_path.take() {
+                    Some(link_path) => link_path,
+                    None => get_c_string(&header[157..257]),
+                }),
+                _ => TarEntryKind::Other,
+            },
+            size,
+        };
+
+        let mut entry_reader = reader.take(size);
+        on_entry(&entry, &mut entry_reader)?;
+        io::copy(&mut entry_reader, &mut io::sink())?;
+        skip_padding(reader, size)?;
+
+        overrides = TarEntryOverrides::default();
+    }
+}
+
+fn get_c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+fn get_entry_path(header: &[u8; BLOCK_SIZE]) -> String {
+    let name = get_c_string(&header[0..100]);
+    // ustar archives can split long paths in two parts
+    let prefix = match &header[257..262] == b"ustar" {
+        true => get_c_string(&header[345..500]),
+        false => String::new(),
+    };
+
+    match prefix.is_empty() {
+        true => name,
+        false => format!("{}/{}", prefix, name),
+    }
+}
+
+fn get_file_header(path: &str, size: u64) -> io::Result<[u8; BLOCK_SIZE]> {
+    if path.len() > MAX_NAME_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the path '{}' is too long", path),
+        ));
+    }
+
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..path.len()].copy_from_slice(path.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    if size <= MAX_OCTAL_SIZE {
+        header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    } else {
+        // Base-256 encoding, for files bigger than 8GiB
+        header[124] = 0x80;
+        header[128..136].copy_from_slice(&size.to_be_bytes());
+    }
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    Ok(header)
+}
+
+fn get_padding_size(size: u64) -> usize {
+    ((BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64) % BLOCK_SIZE as u64) as usize
+}
+
+fn parse_pax_records(content: &[u8], overrides: &mut TarEntryOverrides) {
+    let content = match from_utf8(content) {
+        Ok(content) => content,
+        Err(_) => return,
+    };
+
+    // Each record has the form "<length> <key>=<value>\n"
+    for record in content.lines() {
+        let (key, value) = match record.split_once(' ').and_then(|(_, r)| r.split_once('=')) {
+            Some(key_value) => key_value,
+            None => continue,
+        };
+        match key {
+            "path" => overrides.path = Some(value.to_string()),
+            "linkpath" => overrides.link_path = Some(value.to_string()),
+            "size" => overrides.size = value.parse::<u64>().ok(),
+            _ => {}
+        }
+    }
+}
+
+fn parse_size(field: &[u8]) -> io::Result<u64> {
+    // Base-256 encoding, used for big files
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold((field[0] & 0x7f) as u64, |size, b| (size << 8) | *b as u64));
+    }
+
+    let octal_size = get_c_string(field);
+    match u64::from_str_radix(octal_size.trim(), 8) {
+        Ok(size) => Ok(size),
+        Err(_) if octal_size.trim().is_empty() => Ok(0),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid tar entry size '{}'", octal_size),
+        )),
+    }
+}
+
+/// Returns `false` when the stream ended before the block
+fn read_block(reader: &mut dyn Read, block: &mut [u8; BLOCK_SIZE]) -> io::Result<bool> {
+    match reader.read_exact(block) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_extended_header(reader: &mut dyn Read, size: u64) -> io::Result<Vec<u8>> {
+    if size > MAX_EXTENDED_HEADER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the archive contains a malformed extended header",
+        ));
+    }
+
+    let mut content = vec![0u8; size as usize];
+    reader.read_exact(&mut content)?;
+    skip_padding(reader, size)?;
+
+    Ok(content)
+}
+
+fn skip_padding(reader: &mut dyn Read, size: u64) -> io::Result<()> {
+    io::copy(
+        &mut reader.take(get_padding_size(size) as u64),
+        &mut io::sink(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_entries(archive: &[u8]) -> Vec<(String, u64, Vec<u8>)> {
+        let mut entries: Vec<(String, u64, Vec<u8>)> = vec![];
+        read_tar_entries(&mut &archive[..], &mut |entry, reader| {
+            let mut content: Vec<u8> = vec![];
+            reader.read_to_end(&mut content)?;
+            entries.push((entry.get_path().clone(), entry.get_size(), content));
+            Ok(())
+        })
+        .unwrap();
+        entries
+    }
+
+    #[test]
+    fn tar_writer_output_is_read_back() {
+        let big_content = vec![b'x'; BLOCK_SIZE + 1];
+        let mut tar_writer = TarWriter::new(Vec::new());
+        tar_writer
+            .append_file("manifest.json", 2, &mut &b"[]"[..])
+            .unwrap();
+        tar_writer
+            .append_file("empty", 0, &mut io::empty())
+            .unwrap();
+        tar_writer
+            .append_file(
+                "blobs/sha256/abc",
+                big_content.len() as u64,
+                &mut &big_content[..],
+            )
+            .unwrap();
+        let archive = tar_writer.finish().unwrap();
+
+        assert_eq!(archive.len() % BLOCK_SIZE, 0);
+        assert_eq!(
+            read_entries(&archive),
+            vec![
+                ("manifest.json".to_string(), 2, b"[]".to_vec()),
+                ("empty".to_string(), 0, vec![]),
+                (
+                    "blobs/sha256/abc".to_string(),
+                    big_content.len() as u64,
+                    big_content
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_tar_entries_skips_unread_contents() {
+        let mut tar_writer = TarWriter::new(Vec::new());
+        tar_writer.append_file("a", 3, &mut &b"aaa"[..]).unwrap();
+        tar_writer.append_file("b", 3, &mut &b"bbb"[..]).unwrap();
+        let archive = tar_writer.finish().unwrap();
+
+        let mut paths: Vec<String> = vec![];
+        read_tar_entries(&mut &archive[..], &mut |entry, _| {
+            paths.push(entry.get_path().clone());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(paths, vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn read_tar_entries_resolves_pax_paths() {
+        let long_path = format!("blobs/sha256/{}", "f".repeat(120));
+        let record = format!(" path={}\n", long_path);
+        // The record length includes its own digits
+        let record = format!("{}{}", record.len() + 3, record);
+
+        let mut tar_writer = TarWriter::new(Vec::new());
+        tar_writer
+            .append_file("PaxHeader", record.len() as u64, &mut record.as_bytes())
+            .unwrap();
+        tar_writer.append_file("short", 2, &mut &b"ok"[..]).unwrap();
+        let mut archive = tar_writer.finish().unwrap();
+        // Turns the first entry into a PAX extended header
+        archive[156] = b'x';
+
+        assert_eq!(read_entries(&archive), vec![(long_path, 2, b"ok".to_vec())]);
+    }
+
+    #[test]
+    fn tar_writer_rejects_long_paths() {
+        let mut tar_writer = TarWriter::new(Vec::new());
+        let error = tar_writer
+            .append_file(&"a".repeat(MAX_NAME_LENGTH + 1), 0, &mut io::empty())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn tar_writer_rejects_short_contents() {
+        let mut tar_writer = TarWriter::new(Vec::new());
+        let error = tar_writer
+            .append_file("a", 10, &mut &b"abc"[..])
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}